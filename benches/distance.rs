@@ -1,44 +1,69 @@
-#![feature(test)]
-extern crate test;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rand::{rngs::StdRng, SeedableRng};
-use test::Bencher;
 
 use terminusdb_semantic_indexer::vecmath::*;
 
-#[bench]
-fn bench_cpu_distance(b: &mut Bencher) {
-    let seed: u64 = 42;
-    let mut rng = StdRng::seed_from_u64(seed);
+fn bench_cpu_distance(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
     let e1 = random_normalized_embedding(&mut rng);
     let e2 = random_normalized_embedding(&mut rng);
 
-    b.iter(move || normalized_cosine_distance_scalar(&e1, &e2));
+    c.bench_function("cpu_distance", |b| {
+        b.iter(|| normalized_cosine_distance_scalar(black_box(&e1), black_box(&e2)))
+    });
+}
+
+fn bench_cpu_normalize(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let e = random_embedding(&mut rng);
+
+    c.bench_function("cpu_normalize", |b| {
+        b.iter(|| {
+            let mut e = black_box(e);
+            normalize_vec_scalar(&mut e);
+            e
+        })
+    });
 }
 
 #[cfg(feature = "simd")]
 mod simd_benches {
+    use criterion::{black_box, Criterion};
     use rand::{rngs::StdRng, SeedableRng};
     use terminusdb_semantic_indexer::vecmath::simd::*;
     use terminusdb_semantic_indexer::vecmath::*;
-    use test::Bencher;
 
-    #[bench]
-    fn bench_simd_aligned_distance(b: &mut Bencher) {
-        let seed: u64 = 42;
-        let mut rng = StdRng::seed_from_u64(seed);
+    pub fn bench_simd_aligned_distance(c: &mut Criterion) {
+        let mut rng = StdRng::seed_from_u64(42);
         let e1 = aligned_box(random_normalized_embedding(&mut rng));
         let e2 = aligned_box(random_normalized_embedding(&mut rng));
 
-        b.iter(move || unsafe { normalized_cosine_distance_simd_aligned_unchecked(&e1, &e2) });
+        c.bench_function("simd_aligned_distance", |b| {
+            b.iter(|| unsafe {
+                normalized_cosine_distance_simd_aligned_unchecked(black_box(&e1), black_box(&e2))
+            })
+        });
     }
 
-    #[bench]
-    fn bench_simd_unaligned_distance(b: &mut Bencher) {
-        let seed: u64 = 42;
-        let mut rng = StdRng::seed_from_u64(seed);
+    pub fn bench_simd_unaligned_distance(c: &mut Criterion) {
+        let mut rng = StdRng::seed_from_u64(42);
         let e1 = random_normalized_embedding(&mut rng);
         let e2 = random_normalized_embedding(&mut rng);
 
-        b.iter(move || normalized_cosine_distance_simd_unaligned(&e1, &e2));
+        c.bench_function("simd_unaligned_distance", |b| {
+            b.iter(|| normalized_cosine_distance_simd_unaligned(black_box(&e1), black_box(&e2)))
+        });
     }
 }
+
+#[cfg(not(feature = "simd"))]
+criterion_group!(benches, bench_cpu_distance, bench_cpu_normalize);
+#[cfg(feature = "simd")]
+criterion_group!(
+    benches,
+    bench_cpu_distance,
+    bench_cpu_normalize,
+    simd_benches::bench_simd_aligned_distance,
+    simd_benches::bench_simd_unaligned_distance
+);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand::{rngs::StdRng, SeedableRng};
+
+use terminusdb_semantic_indexer::indexer::*;
+use terminusdb_semantic_indexer::vecmath::*;
+use terminusdb_semantic_indexer::vectors::VectorStore;
+
+const POINTS: usize = 500;
+
+/// Storing uploaded vectors and inserting them into a new index, as an
+/// upload of embedded records does.
+fn bench_ingest(c: &mut Criterion) {
+    let tempdir = tempfile::tempdir().unwrap();
+    let store = VectorStore::new(tempdir.path(), 4 * POINTS);
+    let mut rng = StdRng::seed_from_u64(42);
+    let records: Vec<_> = (0..POINTS)
+        .map(|i| {
+            (
+                format!("Point/{i}"),
+                random_normalized_embedding(&mut rng),
+                None,
+            )
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("ingest");
+    group.throughput(Throughput::Elements(POINTS as u64));
+    group.sample_size(10);
+    let mut run = 0;
+    group.bench_function("store_and_index", |b| {
+        b.iter_batched(
+            || {
+                // a fresh domain each time, so that the vector file
+                // doesn't keep growing
+                run += 1;
+                store.get_domain(&format!("bench{run}")).unwrap()
+            },
+            |domain| {
+                let operations =
+                    records_to_point_operations(&domain, &store, records.clone()).unwrap();
+                start_indexing_from_operations(new_index(Some(42)), operations).unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_ingest);
+criterion_main!(benches);
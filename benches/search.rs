@@ -0,0 +1,48 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, SeedableRng};
+
+use terminusdb_semantic_indexer::indexer::*;
+use terminusdb_semantic_indexer::vecmath::*;
+use terminusdb_semantic_indexer::vectors::VectorStore;
+
+const POINTS: usize = 2000;
+
+fn bench_search(c: &mut Criterion) {
+    let tempdir = tempfile::tempdir().unwrap();
+    let store = VectorStore::new(tempdir.path(), POINTS);
+    let domain = store.get_domain("bench").unwrap();
+    let mut rng = StdRng::seed_from_u64(42);
+    let vecs: Vec<Embedding> = (0..POINTS)
+        .map(|_| random_normalized_embedding(&mut rng))
+        .collect();
+    let operations = store
+        .add_and_load_vecs(&domain, vecs.iter())
+        .unwrap()
+        .into_iter()
+        .map(|vec| PointOperation::Insert {
+            point: Point::Stored {
+                id: vec.id().to_string(),
+                vec,
+            },
+        })
+        .collect();
+    let hnsw = start_indexing_from_operations(new_index(Some(42)), operations).unwrap();
+    let query = Point::Mem {
+        vec: Box::new(random_normalized_embedding(&mut rng)),
+    };
+
+    let mut group = c.benchmark_group("hnsw_search");
+    for ef in [10, 100, 400] {
+        group.bench_with_input(BenchmarkId::from_parameter(ef), &ef, |b, &ef| {
+            b.iter(|| search_with_ef(black_box(&query), 10, ef, &hnsw).unwrap())
+        });
+    }
+    group.finish();
+
+    c.bench_function("exact_search", |b| {
+        b.iter(|| search_exact(black_box(&query), 10, &hnsw).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_search);
+criterion_main!(benches);
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+use crate::vecmath::{self, Embedding};
+
+/// The most vectors a combination may be made of. Every one is looked
+/// up and added in, so combinations of many vectors are refused.
+pub const MAX_COMBINED_TERMS: usize = 64;
+
+/// A query vector made of indexed vectors, known by their ids.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Combination {
+    /// The centroid of the vectors.
+    Mean(Vec<String>),
+    /// The vectors, each scaled by its weight, added up. Weights may
+    /// be negative, to search away from a vector.
+    Weighted(Vec<WeightedId>),
+    /// `a - b + c`, the vector that is to `c` as `a` is to `b`.
+    Analogy { a: String, b: String, c: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WeightedId {
+    pub id: String,
+    pub weight: f32,
+}
+
+impl Combination {
+    /// The ids this combination is made of, with their weights. An id
+    /// may occur more than once.
+    pub fn terms(&self) -> Vec<(&str, f32)> {
+        match self {
+            Combination::Mean(ids) => {
+                let weight = 1.0 / ids.len().max(1) as f32;
+                ids.iter().map(|id| (id.as_str(), weight)).collect()
+            }
+            Combination::Weighted(terms) => terms
+                .iter()
+                .map(|term| (term.id.as_str(), term.weight))
+                .collect(),
+            Combination::Analogy { a, b, c } => vec![(a, 1.0), (b, -1.0), (c, 1.0)],
+        }
+    }
+
+    /// What is wrong with this combination, if anything.
+    pub fn problem(&self) -> Option<String> {
+        let terms = match self {
+            Combination::Mean(ids) => ids.len(),
+            Combination::Weighted(terms) => terms.len(),
+            Combination::Analogy { .. } => return None,
+        };
+        if terms > MAX_COMBINED_TERMS {
+            Some(format!(
+                "a combination is made of at most {MAX_COMBINED_TERMS} vectors, not {terms}"
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Pseudo-relevance feedback, after Rocchio: the query is moved
+/// towards the mean of its `m` nearest results, and searched again.
+/// This helps short or ambiguous queries find what their best
+/// matches are like.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Feedback {
+    /// How much of the query is kept, from 0 to 1. The rest is the
+    /// mean of the results.
+    #[serde(default = "default_feedback_alpha")]
+    pub alpha: f32,
+    /// How many of the nearest results to move towards.
+    #[serde(default = "default_feedback_m")]
+    pub m: usize,
+}
+
+fn default_feedback_alpha() -> f32 {
+    0.7
+}
+
+fn default_feedback_m() -> usize {
+    5
+}
+
+impl Feedback {
+    /// What is wrong with this feedback, if anything.
+    pub fn problem(&self) -> Option<&'static str> {
+        if !(0.0..=1.0).contains(&self.alpha) {
+            Some("the alpha of feedback goes from 0 to 1")
+        } else if self.m == 0 {
+            Some("the m of feedback has to be more than 0")
+        } else {
+            None
+        }
+    }
+
+    /// The query moved towards the results. Without results, or when
+    /// they cancel the query out, the query stays as it is.
+    pub fn expand(&self, query: &Embedding, results: &[&Embedding]) -> Embedding {
+        if results.is_empty() {
+            return *query;
+        }
+        let weight = (1.0 - self.alpha) / results.len() as f32;
+        let terms = results.iter().map(|vec| (*vec, weight));
+        combine(std::iter::once((query, self.alpha)).chain(terms)).unwrap_or(*query)
+    }
+}
+
+/// The sum of the vectors scaled by their weights, normalized. This
+/// is `None` when the vectors cancel out, or there are none, as there
+/// is no direction to search in then.
+pub fn combine<'a>(terms: impl Iterator<Item = (&'a Embedding, f32)>) -> Option<Embedding> {
+    let mut sum = vecmath::empty_embedding();
+    for (vec, weight) in terms {
+        for (s, f) in sum.iter_mut().zip(vec.iter()) {
+            *s += weight * f;
+        }
+    }
+    let norm: f32 = sum.iter().map(|f| f * f).sum::<f32>().sqrt();
+    if !norm.is_normal() || norm < f32::EPSILON {
+        return None;
+    }
+    vecmath::normalize_vec(&mut sum);
+    Some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axis(i: usize) -> Embedding {
+        let mut vec = vecmath::empty_embedding();
+        vec[i] = 1.0;
+        vec
+    }
+
+    #[test]
+    fn combine_vectors() {
+        let (x, y) = (axis(0), axis(1));
+
+        let mean = combine([(&x, 0.5), (&y, 0.5)].into_iter()).unwrap();
+        assert!((mean[0] - mean[1]).abs() < 1e-6);
+        assert!((mean[0] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+
+        let away = combine([(&x, 1.0), (&y, -3.0)].into_iter()).unwrap();
+        assert!(away[0] > 0.0 && away[1] < 0.0);
+
+        assert_eq!(None, combine([(&x, 1.0), (&x, -1.0)].into_iter()));
+        assert_eq!(None, combine(std::iter::empty()));
+    }
+
+    #[test]
+    fn terms_of_combinations() {
+        let mean: Combination = serde_json::from_str(r#"{"mean": ["x", "y"]}"#).unwrap();
+        assert_eq!(vec![("x", 0.5), ("y", 0.5)], mean.terms());
+
+        let weighted: Combination =
+            serde_json::from_str(r#"{"weighted": [{"id": "x", "weight": -2.0}]}"#).unwrap();
+        assert_eq!(vec![("x", -2.0)], weighted.terms());
+
+        let analogy: Combination =
+            serde_json::from_str(r#"{"analogy": {"a": "king", "b": "man", "c": "woman"}}"#)
+                .unwrap();
+        assert_eq!(
+            vec![("king", 1.0), ("man", -1.0), ("woman", 1.0)],
+            analogy.terms()
+        );
+        assert_eq!(None, analogy.problem());
+
+        let ids = vec!["x".to_string(); MAX_COMBINED_TERMS];
+        assert_eq!(None, Combination::Mean(ids.clone()).problem());
+        let too_many = Combination::Mean([ids, vec!["y".to_string()]].concat());
+        assert!(too_many.problem().is_some());
+    }
+
+    #[test]
+    fn expand_towards_results() {
+        let (x, y, z) = (axis(0), axis(1), axis(2));
+        let feedback: Feedback = serde_json::from_str(r#"{"alpha": 0.5}"#).unwrap();
+        assert_eq!(5, feedback.m);
+        assert_eq!(None, feedback.problem());
+
+        let expanded = feedback.expand(&x, &[&y, &z]);
+        assert!((expanded[0] - 2.0 * expanded[1]).abs() < 1e-6);
+        assert!((expanded[1] - expanded[2]).abs() < 1e-6);
+        assert_eq!(x, feedback.expand(&x, &[]));
+
+        let keep = Feedback { alpha: 1.0, m: 3 };
+        assert_eq!(x, keep.expand(&x, &[&y]));
+        assert!(Feedback { alpha: 1.5, m: 3 }.problem().is_some());
+        assert!(Feedback { alpha: 0.5, m: 0 }.problem().is_some());
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::Unpoisoned;
+use crate::openai::{count_tokens, EmbeddingError};
+
+/// Where to log every batch of texts sent to be embedded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddingAuditConfig {
+    /// The NDJSON file that records are appended to.
+    pub path: String,
+    /// Log the texts themselves, and not just their hashes.
+    #[serde(default)]
+    pub include_text: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditText {
+    /// The hex encoded SHA-256 of the text.
+    pub sha256: String,
+    /// The tokens OpenAI counts for the text.
+    pub tokens: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+/// One batch of texts that was sent to be embedded.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditRecord {
+    pub time: String,
+    pub model: String,
+    pub texts: Vec<AuditText>,
+    pub latency_ms: u64,
+    /// `ok` or `error`.
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(
+        model: &str,
+        texts: &[String],
+        include_text: bool,
+        latency: Duration,
+        result: Result<(), &EmbeddingError>,
+    ) -> Self {
+        let texts = texts
+            .iter()
+            .map(|text| AuditText {
+                sha256: Sha256::digest(text.as_bytes())
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect(),
+                tokens: count_tokens(text),
+                text: include_text.then(|| text.clone()),
+            })
+            .collect();
+        AuditRecord {
+            time: chrono::Utc::now().to_rfc3339(),
+            model: model.to_string(),
+            texts,
+            latency_ms: latency.as_millis() as u64,
+            status: if result.is_ok() { "ok" } else { "error" }.to_string(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+
+    fn append_to(&self, path: &Path) -> io::Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        File::options()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&line)
+    }
+}
+
+lazy_static! {
+    static ref AUDIT: RwLock<Option<EmbeddingAuditConfig>> = RwLock::new(None);
+    /// Keeps the lines of concurrent batches apart.
+    static ref AUDIT_FILE: Mutex<()> = Mutex::new(());
+}
+
+pub fn set_audit_log(config: Option<&EmbeddingAuditConfig>) {
+    *AUDIT.write().unpoisoned() = config.cloned();
+}
+
+/// Log a batch, if embedding requests are audited. A log that can't
+/// be written doesn't fail the embedding.
+pub fn record(
+    model: &str,
+    texts: &[String],
+    latency: Duration,
+    result: Result<(), &EmbeddingError>,
+) {
+    let Some(config) = AUDIT.read().unpoisoned().clone() else {
+        return;
+    };
+    let record = AuditRecord::new(model, texts, config.include_text, latency, result);
+    let _lock = AUDIT_FILE.lock().unpoisoned();
+    if let Err(e) = record.append_to(Path::new(&config.path)) {
+        tracing::warn!(error = %e, path = config.path, "could not write embedding audit log");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_texts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("audit.jsonl");
+        let texts = vec!["Luke Skywalker".to_string()];
+        let redacted = AuditRecord::new("m", &texts, false, Duration::from_millis(5), Ok(()));
+        redacted.append_to(&path).unwrap();
+        let error = EmbeddingError::UnsupportedModel("m".to_string());
+        let full = AuditRecord::new("m", &texts, true, Duration::ZERO, Err(&error));
+        full.append_to(&path).unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<AuditRecord> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, records.len());
+        assert!(!log.lines().next().unwrap().contains("Luke"));
+        assert_eq!(64, records[0].texts[0].sha256.len());
+        assert_eq!(records[0].texts[0].sha256, records[1].texts[0].sha256);
+        assert_eq!("ok", records[0].status);
+        assert_eq!(5, records[0].latency_ms);
+        assert_eq!("error", records[1].status);
+        assert_eq!(Some("Luke Skywalker"), records[1].texts[0].text.as_deref());
+    }
+}
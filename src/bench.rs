@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::error;
+use crate::groundtruth;
+use crate::indexer::{
+    new_index, search_with_ef, start_indexing_from_operations, HnswIndex, IndexError, Point,
+    PointOperation,
+};
+use crate::progress::Progress;
+use crate::vecmath::normalized_cosine_distance;
+use crate::vectors::{Domain, LoadedVec, VectorStore};
+
+/// What to measure on the vectors of a domain.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Vectors of the domain to measure with, from the start.
+    pub sample: usize,
+    /// Searches to time, with the first vectors of the sample as
+    /// queries.
+    pub queries: usize,
+    pub k: usize,
+    pub ef: usize,
+    /// Seed of the index built from the sample.
+    pub seed: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BenchReport {
+    pub domain: String,
+    pub vectors: usize,
+    pub distance: DistanceReport,
+    pub insertion: InsertionReport,
+    pub search: SearchReport,
+    /// Measurements that weren't taken, and why.
+    pub skipped: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DistanceReport {
+    pub comparisons: usize,
+    pub nanos_per_distance: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InsertionReport {
+    pub points: usize,
+    pub elapsed_ms: u64,
+    pub points_per_second: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchReport {
+    /// Whether the index of a commit was searched, or the one built
+    /// from the sample.
+    pub index: String,
+    pub points: usize,
+    pub queries: usize,
+    pub queries_per_second: f64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    /// The share of the exact `k` nearest neighbours that the search
+    /// found.
+    pub recall: f64,
+}
+
+fn per_second(count: usize, elapsed: Duration) -> f64 {
+    count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = (sorted.len() * percent / 100).min(sorted.len() - 1);
+    sorted[index].as_micros() as u64
+}
+
+fn measure_distance(vecs: &[LoadedVec]) -> DistanceReport {
+    let start = Instant::now();
+    let mut comparisons = 0;
+    let mut sum = 0.0;
+    for left in vecs {
+        for right in vecs.iter().take(100) {
+            sum += normalized_cosine_distance(left, right);
+            comparisons += 1;
+        }
+    }
+    std::hint::black_box(sum);
+    DistanceReport {
+        comparisons,
+        nanos_per_distance: start.elapsed().as_nanos() as f64 / comparisons.max(1) as f64,
+    }
+}
+
+fn measure_insertion(vecs: &[LoadedVec], seed: u64) -> error::Result<(HnswIndex, InsertionReport)> {
+    let operations = vecs
+        .iter()
+        .map(|vec| PointOperation::Insert {
+            point: Point::Stored {
+                id: vec.id().to_string(),
+                vec: vec.clone(),
+            },
+        })
+        .collect();
+    let start = Instant::now();
+    let hnsw = start_indexing_from_operations(new_index(Some(seed)), operations)
+        .map_err(IndexError::from)?;
+    let elapsed = start.elapsed();
+    let report = InsertionReport {
+        points: vecs.len(),
+        elapsed_ms: elapsed.as_millis() as u64,
+        points_per_second: per_second(vecs.len(), elapsed),
+    };
+    Ok((hnsw, report))
+}
+
+fn measure_search(
+    hnsw: &HnswIndex,
+    index: String,
+    vecs: &[LoadedVec],
+    options: &BenchOptions,
+    progress: &dyn Progress,
+) -> error::Result<SearchReport> {
+    let queries: Vec<Point> = vecs
+        .iter()
+        .take(options.queries)
+        .map(|vec| Point::Mem {
+            vec: Box::new(**vec),
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(queries.len());
+    let mut found = Vec::with_capacity(queries.len());
+    let start = Instant::now();
+    for query in &queries {
+        let search_start = Instant::now();
+        let results = search_with_ef(query, options.k, options.ef, hnsw)?;
+        latencies.push(search_start.elapsed());
+        found.push(results);
+    }
+    let elapsed = start.elapsed();
+    latencies.sort();
+
+    // recall against the ground truth of the same points
+    let truth = groundtruth::exact_neighbors(hnsw, &queries, options.k, |_| false, progress)?;
+    let mut hits = 0;
+    let mut expected = 0;
+    for (neighbors, results) in truth.iter().zip(found) {
+        let exact: HashSet<usize> = neighbors.iter().map(|(id, _)| *id).collect();
+        expected += exact.len();
+        hits += results
+            .iter()
+            .filter(|result| exact.contains(&result.vector_id()))
+            .count();
+    }
+
+    Ok(SearchReport {
+        index,
+        points: hnsw.layer_len(0),
+        queries: queries.len(),
+        queries_per_second: per_second(queries.len(), elapsed),
+        p50_us: percentile(&latencies, 50),
+        p99_us: percentile(&latencies, 99),
+        recall: if expected == 0 {
+            1.0
+        } else {
+            hits as f64 / expected as f64
+        },
+    })
+}
+
+/// Measurements this build can't take, reported as skipped rather
+/// than left out of the report without a word.
+const NOT_MEASURED: [&str; 1] =
+    ["pq scoring: indexes are not product quantized, so there is no PQ scoring to time"];
+
+/// Measure distances, insertion and search on the vectors of a domain,
+/// to compare hardware or catch regressions on real data. Searches go
+/// to the given index of the domain, or else to the index built from
+/// the sample. The exact search for the recall of the index reports
+/// to `progress`, as it compares every query with every point.
+pub fn run(
+    store: &VectorStore,
+    domain: &Domain,
+    index: Option<(String, &HnswIndex)>,
+    options: &BenchOptions,
+    progress: &dyn Progress,
+) -> error::Result<BenchReport> {
+    let _span = tracing::info_span!("bench", domain = %domain.name()).entered();
+    let sample = options.sample.min(domain.num_vecs());
+    let vecs = (0..sample)
+        .map(|i| store.get_stored_vec(domain, i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let distance = measure_distance(&vecs);
+    let (built, insertion) = measure_insertion(&vecs, options.seed)?;
+    let search = match index {
+        Some((name, hnsw)) => measure_search(hnsw, name, &vecs, options, progress)?,
+        None => measure_search(&built, "sample".to_string(), &vecs, options, progress)?,
+    };
+    Ok(BenchReport {
+        domain: domain.name().to_string(),
+        vectors: domain.num_vecs(),
+        distance,
+        insertion,
+        search,
+        skipped: NOT_MEASURED.iter().map(|skip| skip.to_string()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+    use crate::vecmath::random_normalized_embedding;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn bench_a_domain() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 100);
+        let domain = store.get_domain("admin/bench").unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let vecs: Vec<_> = (0..60)
+            .map(|_| random_normalized_embedding(&mut rng))
+            .collect();
+        store.add_vecs(&domain, vecs.iter()).unwrap();
+
+        let options = BenchOptions {
+            sample: 50,
+            queries: 10,
+            k: 5,
+            ef: 100,
+            seed: 42,
+        };
+        let report = run(&store, &domain, None, &options, &NoProgress).unwrap();
+        assert_eq!(60, report.vectors);
+        assert_eq!(50, report.insertion.points);
+        assert_eq!(50, report.search.points);
+        assert_eq!(10, report.search.queries);
+        assert_eq!(50 * 50, report.distance.comparisons);
+        assert!(report.search.recall > 0.9);
+        assert!(report.search.p50_us <= report.search.p99_us);
+        assert_eq!(1, report.skipped.len());
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use roaring::RoaringBitmap;
+use serde_json::Value;
+
+use crate::filter::{self, CompareOp, Filter};
+
+/// Bitmaps of the vectors that have each value of some metadata
+/// fields, like tags, categories or tenants. Filters on these fields
+/// are then answered by combining bitmaps, rather than by looking at
+/// the metadata of every candidate of a search.
+///
+/// Only scalar values get a bitmap of their own, as only those can be
+/// equal to the value of a filter. Vectors are known by their id in
+/// the domain, which has to fit in a u32.
+#[derive(Debug, Default)]
+pub struct BitmapIndex {
+    fields: HashMap<String, FieldBitmaps>,
+    /// Set when a vector didn't fit, after which no filter is answered.
+    overflow: bool,
+}
+
+#[derive(Debug, Default)]
+struct FieldBitmaps {
+    /// The vectors that have the field at all.
+    present: RoaringBitmap,
+    values: HashMap<Key, RoaringBitmap>,
+}
+
+/// A value as filters compare it. Numbers are equal when their f64s
+/// are, as in `1 = 1.0`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+fn key(value: &Value) -> Option<Key> {
+    match value {
+        Value::Null => Some(Key::Null),
+        Value::Bool(b) => Some(Key::Bool(*b)),
+        // + 0.0 turns -0.0 into 0.0, which it is equal to
+        Value::Number(n) => n.as_f64().map(|f| Key::Number((f + 0.0).to_bits())),
+        Value::String(s) => Some(Key::String(s.clone())),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// The vectors that may match a filter. When `exact`, these are just
+/// the ones that do, and the filter needn't be evaluated anymore.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidates {
+    pub vectors: RoaringBitmap,
+    pub exact: bool,
+}
+
+impl Candidates {
+    pub fn contains(&self, vector: usize) -> bool {
+        u32::try_from(vector).is_ok_and(|v| self.vectors.contains(v))
+    }
+}
+
+impl BitmapIndex {
+    pub fn new(fields: &[String]) -> Self {
+        BitmapIndex {
+            fields: fields
+                .iter()
+                .map(|field| (field.clone(), FieldBitmaps::default()))
+                .collect(),
+            overflow: false,
+        }
+    }
+
+    /// Whether this index has bitmaps for exactly these fields.
+    pub fn has_fields(&self, fields: &[String]) -> bool {
+        fields.len() == self.fields.len() && fields.iter().all(|f| self.fields.contains_key(f))
+    }
+
+    pub fn insert(&mut self, vector: usize, metadata: &Value) {
+        let Ok(vector) = u32::try_from(vector) else {
+            self.overflow = true;
+            return;
+        };
+        for (field, bitmaps) in self.fields.iter_mut() {
+            let Some(value) = filter::lookup(Some(metadata), field) else {
+                continue;
+            };
+            bitmaps.present.insert(vector);
+            if let Some(key) = key(value) {
+                bitmaps.values.entry(key).or_default().insert(vector);
+            }
+        }
+    }
+
+    /// Take a vector out of the bitmaps of the metadata it had.
+    pub fn remove(&mut self, vector: usize, metadata: &Value) {
+        let Ok(vector) = u32::try_from(vector) else {
+            return;
+        };
+        for (field, bitmaps) in self.fields.iter_mut() {
+            let Some(value) = filter::lookup(Some(metadata), field) else {
+                continue;
+            };
+            bitmaps.present.remove(vector);
+            if let Some(key) = key(value) {
+                if let Some(bitmap) = bitmaps.values.get_mut(&key) {
+                    bitmap.remove(vector);
+                    if bitmap.is_empty() {
+                        bitmaps.values.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The vectors among the first `num_vecs` that may match `filter`,
+    /// or `None` if the bitmaps don't narrow them down. Equality,
+    /// inequality and `IN` on indexed fields are answered exactly, and
+    /// so are `AND`, `OR` and `NOT` of exact answers. An `AND` with
+    /// one side answered narrows down to that side.
+    pub fn candidates(&self, filter: &Filter, num_vecs: usize) -> Option<Candidates> {
+        if self.overflow {
+            return None;
+        }
+        let exact = |vectors| {
+            Some(Candidates {
+                vectors,
+                exact: true,
+            })
+        };
+        match filter {
+            Filter::Compare { field, op, value } => {
+                let bitmaps = self.fields.get(field)?;
+                let equal = bitmaps.equal(value);
+                match op {
+                    CompareOp::Eq => exact(equal),
+                    CompareOp::Ne => exact(&bitmaps.present - equal),
+                    _ => None,
+                }
+            }
+            Filter::In { field, values } => {
+                let bitmaps = self.fields.get(field)?;
+                exact(values.iter().map(|value| bitmaps.equal(value)).fold(
+                    RoaringBitmap::new(),
+                    |mut all, bitmap| {
+                        all |= bitmap;
+                        all
+                    },
+                ))
+            }
+            Filter::Not(filter) => match self.candidates(filter, num_vecs)? {
+                Candidates {
+                    vectors,
+                    exact: true,
+                } => {
+                    let mut all = RoaringBitmap::new();
+                    all.insert_range(0..num_vecs as u32);
+                    exact(all - vectors)
+                }
+                _ => None,
+            },
+            Filter::And(left, right) => {
+                match (
+                    self.candidates(left, num_vecs),
+                    self.candidates(right, num_vecs),
+                ) {
+                    (Some(left), Some(right)) => Some(Candidates {
+                        vectors: left.vectors & right.vectors,
+                        exact: left.exact && right.exact,
+                    }),
+                    (Some(one), None) | (None, Some(one)) => Some(Candidates {
+                        vectors: one.vectors,
+                        exact: false,
+                    }),
+                    (None, None) => None,
+                }
+            }
+            Filter::Or(left, right) => {
+                let left = self.candidates(left, num_vecs)?;
+                let right = self.candidates(right, num_vecs)?;
+                Some(Candidates {
+                    vectors: left.vectors | right.vectors,
+                    exact: left.exact && right.exact,
+                })
+            }
+        }
+    }
+}
+
+impl FieldBitmaps {
+    fn equal(&self, value: &Value) -> RoaringBitmap {
+        key(value)
+            .and_then(|key| self.values.get(&key))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn agree_with_filters() {
+        let metadata = [
+            json!({"genre": "scifi", "year": 1977, "tags": ["space"]}),
+            json!({"genre": "drama", "year": 1994.0}),
+            json!({"genre": "scifi", "year": 1982, "studio": {"name": "ladd"}}),
+            json!({"year": 1977}),
+            json!({"genre": null}),
+        ];
+        let fields: Vec<String> = ["genre", "year", "tags", "studio.name"]
+            .map(String::from)
+            .to_vec();
+        let mut index = BitmapIndex::new(&fields);
+        assert!(index.has_fields(&fields));
+        for (vector, m) in metadata.iter().enumerate() {
+            index.insert(vector, m);
+        }
+        // vector 5 has no metadata at all
+        let num_vecs = 6;
+        let matching = |filter: &Filter| -> RoaringBitmap {
+            (0..num_vecs as u32)
+                .filter(|v| filter.matches(metadata.get(*v as usize)))
+                .collect()
+        };
+
+        for expression in [
+            r#"genre = "scifi""#,
+            r#"genre != "scifi""#,
+            "year = 1994",
+            "year IN (1977, 1982)",
+            "genre = null",
+            r#"NOT genre = "scifi""#,
+            r#"genre = "scifi" OR year = 1994"#,
+            r#"studio.name = "ladd""#,
+            r#"tags != "space""#,
+        ] {
+            let filter = Filter::parse(expression).unwrap();
+            let candidates = index.candidates(&filter, num_vecs).unwrap();
+            assert!(candidates.exact, "{expression}");
+            assert_eq!(matching(&filter), candidates.vectors, "{expression}");
+        }
+
+        // only one side of this is indexed, so it narrows down
+        let filter = Filter::parse(r#"genre = "scifi" AND rating > 3"#).unwrap();
+        let candidates = index.candidates(&filter, num_vecs).unwrap();
+        assert!(!candidates.exact);
+        assert_eq!(RoaringBitmap::from_iter([0, 2]), candidates.vectors);
+        for filter in ["year > 1980", r#"genre = "scifi" OR rating > 3"#] {
+            assert_eq!(None, index.candidates(&Filter::parse(filter).unwrap(), 6));
+        }
+
+        index.remove(0, &metadata[0]);
+        index.insert(0, &json!({"genre": "drama"}));
+        let filter = Filter::parse(r#"genre = "drama""#).unwrap();
+        let candidates = index.candidates(&filter, num_vecs).unwrap();
+        assert_eq!(RoaringBitmap::from_iter([0, 1]), candidates.vectors);
+        assert!(candidates.contains(1) && !candidates.contains(2));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use lru::LruCache;
+
+use crate::error::Unpoisoned;
+use crate::metrics::{self, Counter};
+
+lazy_static! {
+    static ref HITS: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_query_cache_hits_total",
+        "Searches answered from the query cache."
+    );
+    static ref MISSES: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_query_cache_misses_total",
+        "Searches that were not in the query cache."
+    );
+}
+
+/// Results of recent queries, kept so that repeated queries don't
+/// embed and search again.
+///
+/// Entries are keyed by the full domain, generation and query, not a
+/// hash of them, so two queries can never share an entry.
+///
+/// Every domain has a generation that is part of the key of its
+/// entries. Writing to a domain bumps the generation, which makes all
+/// earlier entries of that domain unreachable. They are then evicted
+/// like any other unused entry.
+pub struct QueryCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<CacheKey, (Instant, Arc<String>)>>,
+    generations: Mutex<HashMap<String, u64>>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        let capacity = NonZeroUsize::new(capacity.max(1)).unwrap();
+        QueryCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity)),
+            generations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The key of a query against a domain. Keys taken before the
+    /// domain is invalidated never match again.
+    pub fn key<Q: Hash>(&self, domain: &str, query: &Q) -> CacheKey {
+        let generation = self
+            .generations
+            .lock()
+            .unpoisoned()
+            .get(domain)
+            .copied()
+            .unwrap_or(0);
+        let mut key = KeyWriter(Vec::new());
+        (domain, generation).hash(&mut key);
+        query.hash(&mut key);
+        CacheKey(key.0)
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<String>> {
+        let mut entries = self.entries.lock().unpoisoned();
+        let result = match entries.get(key) {
+            Some((inserted, result)) if inserted.elapsed() < self.ttl => Some(result.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        match result {
+            Some(_) => HITS.inc(),
+            None => MISSES.inc(),
+        }
+        result
+    }
+
+    pub fn insert(&self, key: &CacheKey, result: Arc<String>) {
+        self.entries
+            .lock()
+            .unpoisoned()
+            .put(key.clone(), (Instant::now(), result));
+    }
+
+    /// Forget all results for the domain.
+    pub fn invalidate(&self, domain: &str) {
+        *self
+            .generations
+            .lock()
+            .unpoisoned()
+            .entry(domain.to_string())
+            .or_insert(0) += 1;
+    }
+}
+
+/// The key of a cached query.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CacheKey(Vec<u8>);
+
+/// Collects everything a key hashes instead of hashing it. The `Hash`
+/// implementations of the standard types write lengths or terminators
+/// along with the contents, so different queries give different bytes.
+struct KeyWriter(Vec<u8>);
+
+impl Hasher for KeyWriter {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("key writers are never finished")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_expire_and_invalidate() {
+        let cache = QueryCache::new(2, Duration::from_secs(60));
+        let a = cache.key("admin/a", &"query");
+        let b = cache.key("admin/b", &"query");
+        assert_ne!(a, b);
+        cache.insert(&a, Arc::new("a".to_string()));
+        cache.insert(&b, Arc::new("b".to_string()));
+        assert_eq!("a", *cache.get(&a).unwrap());
+
+        // b is the least recently used entry now
+        let c = cache.key("admin/a", &"other query");
+        cache.insert(&c, Arc::new("c".to_string()));
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&a).is_some());
+
+        cache.invalidate("admin/a");
+        assert_ne!(a, cache.key("admin/a", &"query"));
+        assert_eq!(b, cache.key("admin/b", &"query"));
+
+        let expiring = QueryCache::new(2, Duration::ZERO);
+        let key = expiring.key("admin/a", &"query");
+        expiring.insert(&key, Arc::new("a".to_string()));
+        assert!(expiring.get(&key).is_none());
+    }
+
+    #[test]
+    fn keys_are_whole_queries() {
+        let cache = QueryCache::new(4, Duration::from_secs(60));
+        // Moving bytes between the domain and the query, or between
+        // the parts of a query, still gives another key.
+        assert_ne!(cache.key("admin/ab", &"c"), cache.key("admin/a", &"bc"));
+        assert_ne!(
+            cache.key("admin/a", &("search", &b"xy"[..])),
+            cache.key("admin/a", &("searchx", &b"y"[..]))
+        );
+        assert_eq!(
+            cache.key("admin/a", &"query"),
+            cache.key("admin/a", &"query")
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use futures::future::BoxFuture;
+use reqwest::{header, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::openai::{
+    self, execute_with_retries, padded_embedding, EmbeddingError, EmbeddingLimits,
+    EmbeddingProvider, ImageInput,
+};
+use crate::vecmath::Embedding;
+
+/// Texts or images sent to the service in one request.
+const BATCH_SIZE: usize = 16;
+
+/// A request to clip-as-service, whose documents are either texts or
+/// image URIs.
+#[derive(Serialize)]
+struct ClipRequest {
+    data: Vec<ClipDocument>,
+    #[serde(rename = "execEndpoint")]
+    exec_endpoint: &'static str,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ClipDocument {
+    Text(String),
+    Uri(String),
+}
+
+/// The documents of the request again, each with its embedding.
+#[derive(Deserialize)]
+struct ClipResponse {
+    data: Vec<ClipEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct ClipEmbedding {
+    embedding: Vec<f32>,
+}
+
+/// The endpoint that clip-as-service takes documents at.
+pub fn post_url(endpoint: &str) -> Result<Url, EmbeddingError> {
+    Url::parse(&format!("{}/post", endpoint.trim_end_matches('/')))
+        .map_err(|e| EmbeddingError::BadEndpoint(e.to_string()))
+}
+
+/// Embeds texts and images with a CLIP model.
+pub struct ClipEmbedder {
+    url: Url,
+    model: String,
+    dimension: usize,
+}
+
+impl ClipEmbedder {
+    pub fn new(url: Url, model: &str, dimension: usize) -> Self {
+        ClipEmbedder {
+            url,
+            model: model.to_string(),
+            dimension,
+        }
+    }
+
+    async fn embed_documents(
+        &self,
+        documents: Vec<ClipDocument>,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let expected = documents.len();
+        let request = openai::client()
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&ClipRequest {
+                data: documents,
+                exec_endpoint: "/",
+            })?)
+            .build()?;
+        let response_bytes = execute_with_retries(request).await?;
+        let response: ClipResponse = serde_json::from_slice(&response_bytes)?;
+        if response.data.len() != expected {
+            return Err(EmbeddingError::WrongCount {
+                expected,
+                got: response.data.len(),
+            });
+        }
+        response
+            .data
+            .iter()
+            .map(|document| padded_embedding(&document.embedding, self.dimension))
+            .collect()
+    }
+}
+
+impl EmbeddingProvider for ClipEmbedder {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn limits(&self) -> EmbeddingLimits {
+        EmbeddingLimits {
+            batch_size: BATCH_SIZE,
+            concurrency: openai::concurrency(),
+        }
+    }
+
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<Embedding>, EmbeddingError>> {
+        let documents = texts.iter().cloned().map(ClipDocument::Text).collect();
+        Box::pin(self.embed_documents(documents))
+    }
+
+    fn embed_image_batch<'a>(
+        &'a self,
+        images: &'a [ImageInput],
+    ) -> BoxFuture<'a, Result<Vec<Embedding>, EmbeddingError>> {
+        let documents = images
+            .iter()
+            .map(|image| ClipDocument::Uri(image.uri()))
+            .collect();
+        Box::pin(self.embed_documents(documents))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_as_service_requests() {
+        let request = ClipRequest {
+            data: vec![
+                ClipDocument::Text("a wookiee".to_string()),
+                ClipDocument::Uri(ImageInput::Data("iVBORw0KGgo=".to_string()).uri()),
+            ],
+            exec_endpoint: "/",
+        };
+        assert_eq!(
+            r#"{"data":[{"text":"a wookiee"},{"uri":"data:application/octet-stream;base64,iVBORw0KGgo="}],"execEndpoint":"/"}"#,
+            serde_json::to_string(&request).unwrap()
+        );
+        let response: ClipResponse = serde_json::from_str(
+            r#"{"data": [{"id": "a", "text": "a wookiee", "embedding": [0.5, -0.5]}], "parameters": null}"#,
+        )
+        .unwrap();
+        assert_eq!(vec![0.5, -0.5], response.data[0].embedding);
+        assert_eq!(
+            "http://localhost:51000/post",
+            post_url("http://localhost:51000/").unwrap().as_str()
+        );
+    }
+}
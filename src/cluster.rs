@@ -0,0 +1,211 @@
+use std::io;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use reqwest::{header, Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc::Receiver;
+use tokio_stream::wrappers::ReceiverStream;
+use urlencoding::encode;
+
+use crate::config::ShardConfig;
+
+/// The shard that holds the vector with this external id.
+///
+/// This is FNV-1a rather than the std hasher, as the std hasher may
+/// change between builds, and ids have to stay on the shard they were
+/// uploaded to. There has to be at least one shard, which validating
+/// the configuration makes sure of.
+pub fn shard_for(id: &str, num_shards: usize) -> usize {
+    let hash = id.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % num_shards as u64) as usize
+}
+
+/// A search hit as returned by a shard. Only the fields that order
+/// hits are read, the others are passed on as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardHit {
+    pub id: String,
+    pub distance: f32,
+    #[serde(flatten)]
+    pub rest: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Merge the hits of all shards into the `k` closest, in the order a
+/// single server would have returned them.
+pub fn merge_hits(hits: Vec<Vec<ShardHit>>, k: usize) -> Vec<ShardHit> {
+    let mut hits: Vec<ShardHit> = hits.into_iter().flatten().collect();
+    hits.sort_by(|a, b| {
+        a.distance
+            .total_cmp(&b.distance)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    hits.truncate(k);
+    hits
+}
+
+#[derive(Debug, Error)]
+pub enum ShardError {
+    #[error("shard {url} could not be reached: {source}")]
+    Http {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("shard {url} answered with status {status}: {message}")]
+    Status {
+        url: String,
+        status: StatusCode,
+        message: String,
+    },
+    #[error("shard {url} sent an invalid response: {source}")]
+    InvalidResponse {
+        url: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Sends requests for a domain to the servers holding its shards.
+#[derive(Default)]
+pub struct ShardClient {
+    client: Client,
+}
+
+fn shard_url(shard: &ShardConfig, domain: &str, route: &str) -> String {
+    let domain = shard.domain.as_deref().unwrap_or(domain);
+    format!(
+        "{}/domains/{}/{route}",
+        shard.url.trim_end_matches('/'),
+        encode(domain)
+    )
+}
+
+impl ShardClient {
+    fn post(&self, shard: &ShardConfig, url: &str) -> RequestBuilder {
+        let request = self.client.post(url);
+        match &shard.api_key {
+            Some(key) => request.bearer_auth(key.expose()),
+            None => request,
+        }
+    }
+
+    async fn send<T: for<'de> Deserialize<'de>>(
+        url: String,
+        request: RequestBuilder,
+    ) -> Result<T, ShardError> {
+        let http_error = |source| ShardError::Http {
+            url: url.clone(),
+            source,
+        };
+        let response = request.send().await.map_err(http_error)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(http_error)?;
+        if !status.is_success() {
+            return Err(ShardError::Status {
+                url,
+                status,
+                message: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        serde_json::from_slice(&body).map_err(|source| ShardError::InvalidResponse { url, source })
+    }
+
+    /// Run the same search on every shard, and return the hits of
+    /// each.
+    pub async fn search<B: Serialize>(
+        &self,
+        shards: &[ShardConfig],
+        domain: &str,
+        body: &B,
+    ) -> Result<Vec<Vec<ShardHit>>, ShardError> {
+        let body = serde_json::to_vec(body).expect("search requests serialize");
+        let searches = shards.iter().map(|shard| {
+            let url = shard_url(shard, domain, "search");
+            let request = self
+                .post(shard, &url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            Self::send(url, request)
+        });
+        futures::future::try_join_all(searches).await
+    }
+
+    /// Upload the vector records arriving on `records` to a shard.
+    /// `query` is passed on, so the shard indexes the same commit.
+    /// Returns the number of vectors the shard took.
+    pub async fn upload(
+        &self,
+        shard: &ShardConfig,
+        domain: &str,
+        query: Option<&str>,
+        records: Receiver<Bytes>,
+    ) -> Result<usize, ShardError> {
+        let mut url = shard_url(shard, domain, "vectors");
+        if let Some(query) = query {
+            url = format!("{url}?{query}");
+        }
+        let body = ReceiverStream::new(records).map(Ok::<_, io::Error>);
+        let request = self
+            .post(shard, &url)
+            .header(header::CONTENT_TYPE, "application/x-ndjson")
+            .body(reqwest::Body::wrap_stream(body));
+        #[derive(Deserialize)]
+        struct Uploaded {
+            vectors: usize,
+        }
+        let uploaded: Uploaded = Self::send(url, request).await?;
+        Ok(uploaded.vectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ids_stay_on_their_shard() {
+        // changing these would move every uploaded vector
+        assert_eq!(0xaf63dc4c8601ec8c % 3, shard_for("a", 3) as u64);
+        assert_eq!(shard_for("Person/1", 4), shard_for("Person/1", 4));
+        let mut counts = [0; 4];
+        for i in 0..1000 {
+            counts[shard_for(&format!("Person/{i}"), 4)] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 150));
+    }
+
+    #[test]
+    fn merge_shard_hits() {
+        let hit = |id: &str, distance: f32| ShardHit {
+            id: id.to_string(),
+            distance,
+            rest: json!({ "score": 1.0 - distance })
+                .as_object()
+                .unwrap()
+                .clone(),
+        };
+        let merged = merge_hits(
+            vec![
+                vec![hit("a", 0.1), hit("c", 0.3)],
+                vec![hit("b", 0.2), hit("d", 0.3)],
+                vec![],
+            ],
+            3,
+        );
+        let ids: Vec<&str> = merged.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(vec!["a", "b", "c"], ids);
+
+        let parsed: ShardHit =
+            serde_json::from_str(r#"{"id": "a", "distance": 0.5, "cursor": "x"}"#).unwrap();
+        assert_eq!(Some(&json!("x")), parsed.rest.get("cursor"));
+        assert_eq!(
+            json!({"id": "a", "distance": 0.5, "cursor": "x"}),
+            serde_json::to_value(&parsed).unwrap()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::io;
+
+use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::{header, Body, Response, StatusCode};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// The name of the encoding in `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Pick an encoding from an `Accept-Encoding` header. The encoding
+/// with the highest quality wins, and zstd wins over gzip when both
+/// are equally welcome.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let encoding = match parts
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "zstd" => Encoding::Zstd,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map(|q| q.trim().parse().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        if quality <= 0.0 {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((best_encoding, best_quality)) => {
+                quality > best_quality
+                    || (quality == best_quality
+                        && encoding == Encoding::Zstd
+                        && best_encoding != Encoding::Zstd)
+            }
+        };
+        if better {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether compressing the response would be worth it. Responses of
+/// a known size below `min_size` bytes are sent as they are, and so
+/// are raw vectors and file ranges, which hardly compress.
+pub fn is_compressible(response: &Response<Body>, min_size: usize) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) || headers.contains_key(header::CONTENT_RANGE)
+    {
+        return false;
+    }
+    if headers
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type == "application/octet-stream")
+        .unwrap_or(false)
+    {
+        return false;
+    }
+    match response.body().size_hint().exact() {
+        Some(size) => size >= min_size as u64,
+        None => true,
+    }
+}
+
+/// Compress a body as it is streamed out.
+pub fn compress(encoding: Encoding, body: Body) -> Body {
+    let reader = StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_encodings() {
+        assert_eq!(Some(Encoding::Gzip), negotiate("gzip, deflate, br"));
+        assert_eq!(Some(Encoding::Zstd), negotiate("gzip, zstd"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("zstd;q=0.5, gzip;q=0.8"));
+        assert_eq!(Some(Encoding::Zstd), negotiate("gzip;q=0, zstd;q=0.1"));
+        assert_eq!(None, negotiate("gzip;q=0"));
+        assert_eq!(None, negotiate("identity"));
+        assert_eq!(None, negotiate(""));
+    }
+
+    #[test]
+    fn compressible_responses() {
+        let large = || "x".repeat(2048);
+        assert!(is_compressible(&Response::new(Body::from(large())), 1024));
+        assert!(!is_compressible(&Response::new(Body::from("small")), 1024));
+        let octets = Response::builder()
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(large()))
+            .unwrap();
+        assert!(!is_compressible(&octets, 1024));
+        let empty = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_compressible(&empty, 0));
+    }
+}
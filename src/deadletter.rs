@@ -0,0 +1,340 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use urlencoding::encode;
+
+use crate::encryption::Cipher;
+use crate::error::Unpoisoned;
+use crate::server::Operation;
+
+/// Operations of an index job that could not be embedded, kept to be
+/// replayed once the provider works again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub job: String,
+    /// The commit whose index the operations belong in.
+    pub commit: String,
+    pub error: String,
+    pub failed_at: String,
+    pub operations: Vec<Operation>,
+}
+
+/// The dead letters of every domain, one NDJSON file per domain in
+/// the `dead_letters` directory of the storage directory. Letters hold
+/// the texts of documents, so in an encrypted store every line is
+/// sealed, bound to its domain and line number like the lines of the
+/// domain files.
+pub struct DeadLetters {
+    dir: PathBuf,
+    /// Rewrites must not lose letters that are added meanwhile.
+    lock: Mutex<()>,
+    /// The domains whose letters are being replayed.
+    replaying: Mutex<HashSet<String>>,
+}
+
+impl DeadLetters {
+    pub fn new(dir: &Path) -> Self {
+        DeadLetters {
+            dir: dir.join("dead_letters"),
+            lock: Mutex::new(()),
+            replaying: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn file_name(domain: &str) -> String {
+        format!("{}.jsonl", encode(domain))
+    }
+
+    fn path(&self, domain: &str) -> PathBuf {
+        self.dir.join(Self::file_name(domain))
+    }
+
+    fn aad(domain: &str, line: usize) -> Vec<u8> {
+        let mut aad = Self::file_name(domain).into_bytes();
+        aad.extend_from_slice(&(line as u64).to_le_bytes());
+        aad
+    }
+
+    pub fn add(
+        &self,
+        domain: &str,
+        letter: &DeadLetter,
+        cipher: Option<&Cipher>,
+    ) -> io::Result<()> {
+        let _lock = self.lock.lock().unpoisoned();
+        std::fs::create_dir_all(&self.dir)?;
+        let number = match cipher {
+            Some(_) => self.lines(domain)?,
+            None => 0,
+        };
+        let line = Self::line(domain, number, letter, cipher)?;
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(self.path(domain))?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    /// A letter as the given line of the file of a domain.
+    fn line(
+        domain: &str,
+        number: usize,
+        letter: &DeadLetter,
+        cipher: Option<&Cipher>,
+    ) -> io::Result<Vec<u8>> {
+        let mut line = serde_json::to_vec(letter)?;
+        if let Some(cipher) = cipher {
+            line = cipher
+                .seal_line(&line, &Self::aad(domain, number))
+                .into_bytes();
+        }
+        line.push(b'\n');
+        Ok(line)
+    }
+
+    pub fn list(&self, domain: &str, cipher: Option<&Cipher>) -> io::Result<Vec<DeadLetter>> {
+        let _lock = self.lock.lock().unpoisoned();
+        self.read(domain, cipher)
+    }
+
+    /// The lines of the file of a domain.
+    fn read_lines(&self, domain: &str) -> io::Result<Vec<String>> {
+        match File::open(self.path(domain)) {
+            Ok(file) => BufReader::new(file).lines().collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn lines(&self, domain: &str) -> io::Result<usize> {
+        Ok(self.read_lines(domain)?.len())
+    }
+
+    fn read(&self, domain: &str, cipher: Option<&Cipher>) -> io::Result<Vec<DeadLetter>> {
+        self.read_lines(domain)?
+            .into_iter()
+            .enumerate()
+            .map(|(number, line)| match cipher {
+                None => Ok(serde_json::from_str(&line)?),
+                Some(cipher) => {
+                    let line = cipher.open_line(&line, &Self::aad(domain, number))?;
+                    Ok(serde_json::from_slice(&line)?)
+                }
+            })
+            .collect()
+    }
+
+    /// Take the dead letters of a domain to replay them, or `None` if
+    /// they are being replayed already. The letters stay in the file
+    /// until the replay is finished with `Replay::put_back`, so a
+    /// replay that never finishes loses nothing.
+    pub fn take(&self, domain: &str, cipher: Option<&Cipher>) -> io::Result<Option<Replay<'_>>> {
+        let _lock = self.lock.lock().unpoisoned();
+        if !self
+            .replaying
+            .lock()
+            .unpoisoned()
+            .insert(domain.to_string())
+        {
+            return Ok(None);
+        }
+        let mut replay = Replay {
+            dead_letters: self,
+            domain: domain.to_string(),
+            letters: Vec::new(),
+        };
+        replay.letters = self.read(domain, cipher)?;
+        Ok(Some(replay))
+    }
+
+    fn rewrite(
+        &self,
+        domain: &str,
+        taken: usize,
+        failed: &[DeadLetter],
+        cipher: Option<&Cipher>,
+    ) -> io::Result<()> {
+        let added = self.read(domain, cipher)?.into_iter().skip(taken);
+        let letters: Vec<_> = failed.iter().cloned().chain(added).collect();
+        let path = self.path(domain);
+        if letters.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let tmp = path.with_extension("jsonl.tmp");
+        let mut file = File::create(&tmp)?;
+        for (number, letter) in letters.iter().enumerate() {
+            file.write_all(&Self::line(domain, number, letter, cipher)?)?;
+        }
+        file.sync_data()?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+/// The letters of a domain that are being replayed. Nobody else can
+/// replay the domain until this is dropped.
+pub struct Replay<'a> {
+    dead_letters: &'a DeadLetters,
+    domain: String,
+    pub letters: Vec<DeadLetter>,
+}
+
+impl Replay<'_> {
+    /// Finish the replay, replacing the taken letters with those that
+    /// failed again. Letters that were added during the replay are kept.
+    pub fn put_back(self, failed: &[DeadLetter], cipher: Option<&Cipher>) -> io::Result<()> {
+        let _lock = self.dead_letters.lock.lock().unpoisoned();
+        self.dead_letters
+            .rewrite(&self.domain, self.letters.len(), failed, cipher)
+    }
+}
+
+impl Drop for Replay<'_> {
+    fn drop(&mut self) {
+        self.dead_letters
+            .replaying
+            .lock()
+            .unpoisoned()
+            .remove(&self.domain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_and_list() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let letters = DeadLetters::new(tempdir.path());
+        assert!(letters.list("admin/star_wars", None).unwrap().is_empty());
+        let letter = DeadLetter {
+            job: "job1".to_string(),
+            commit: "c1".to_string(),
+            error: "response had bad status code: 503".to_string(),
+            failed_at: "2024-01-01T00:00:00Z".to_string(),
+            operations: vec![Operation::Deleted {
+                id: "Doc/1".to_string(),
+            }],
+        };
+        letters.add("admin/star_wars", &letter, None).unwrap();
+        letters.add("admin/star_wars", &letter, None).unwrap();
+        assert_eq!(2, letters.list("admin/star_wars", None).unwrap().len());
+        assert!(letters.list("admin/other", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn take_and_put_back() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let letters = DeadLetters::new(tempdir.path());
+        let cipher =
+            Cipher::new(&serde_json::from_str(r#""correct horse battery staple""#).unwrap());
+        let letter = |id: &str| DeadLetter {
+            job: "job1".to_string(),
+            commit: "c1".to_string(),
+            error: "response had bad status code: 503".to_string(),
+            failed_at: "2024-01-01T00:00:00Z".to_string(),
+            operations: vec![Operation::Deleted { id: id.to_string() }],
+        };
+        let ids = |letters: Vec<DeadLetter>| -> Vec<String> {
+            letters
+                .into_iter()
+                .map(|letter| match &letter.operations[0] {
+                    Operation::Deleted { id } => id.clone(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        for id in ["Doc/1", "Doc/2", "Doc/3"] {
+            letters
+                .add("admin/star_wars", &letter(id), Some(&cipher))
+                .unwrap();
+        }
+        let taken = letters
+            .take("admin/star_wars", Some(&cipher))
+            .unwrap()
+            .unwrap();
+        assert_eq!(3, taken.letters.len());
+        // the letters are still there while they are replayed, and
+        // nobody else can replay them meanwhile
+        assert_eq!(
+            3,
+            letters
+                .list("admin/star_wars", Some(&cipher))
+                .unwrap()
+                .len()
+        );
+        assert!(letters
+            .take("admin/star_wars", Some(&cipher))
+            .unwrap()
+            .is_none());
+        letters
+            .add("admin/star_wars", &letter("Doc/4"), Some(&cipher))
+            .unwrap();
+        let failed = taken.letters[1..2].to_vec();
+        taken.put_back(&failed, Some(&cipher)).unwrap();
+        assert_eq!(
+            vec!["Doc/2", "Doc/4"],
+            ids(letters.list("admin/star_wars", Some(&cipher)).unwrap())
+        );
+
+        let taken = letters
+            .take("admin/star_wars", Some(&cipher))
+            .unwrap()
+            .unwrap();
+        taken.put_back(&[], Some(&cipher)).unwrap();
+        assert!(!letters.path("admin/star_wars").exists());
+
+        // a replay that is given up frees the domain and keeps its letters
+        letters
+            .add("admin/star_wars", &letter("Doc/5"), Some(&cipher))
+            .unwrap();
+        drop(letters.take("admin/star_wars", Some(&cipher)).unwrap());
+        let taken = letters
+            .take("admin/star_wars", Some(&cipher))
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, taken.letters.len());
+    }
+
+    #[test]
+    fn sealed_letters() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let letters = DeadLetters::new(tempdir.path());
+        let cipher =
+            Cipher::new(&serde_json::from_str(r#""correct horse battery staple""#).unwrap());
+        let letter = |id: &str| DeadLetter {
+            job: "job1".to_string(),
+            commit: "c1".to_string(),
+            error: "response had bad status code: 503".to_string(),
+            failed_at: "2024-01-01T00:00:00Z".to_string(),
+            operations: vec![Operation::Deleted { id: id.to_string() }],
+        };
+        letters
+            .add("admin/star_wars", &letter("Doc/1"), Some(&cipher))
+            .unwrap();
+        letters
+            .add("admin/star_wars", &letter("Doc/2"), Some(&cipher))
+            .unwrap();
+        let path = letters.path("admin/star_wars");
+        let file = std::fs::read_to_string(&path).unwrap();
+        assert!(!file.contains("Doc/1"));
+        let listed = letters.list("admin/star_wars", Some(&cipher)).unwrap();
+        assert_eq!(2, listed.len());
+        assert!(matches!(&listed[1].operations[0], Operation::Deleted { id } if id == "Doc/2"));
+
+        // lines that are dropped or moved to another domain are refused
+        let second = file.lines().nth(1).unwrap();
+        std::fs::write(&path, format!("{second}\n")).unwrap();
+        assert!(letters.list("admin/star_wars", Some(&cipher)).is_err());
+        std::fs::write(letters.path("admin/other"), &file).unwrap();
+        assert!(letters.list("admin/other", Some(&cipher)).is_err());
+    }
+}
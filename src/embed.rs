@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use crate::error::Unpoisoned;
+use crate::openai::EmbeddingModel;
+use crate::vecmath::Embedding;
+
+/// Embeddings of recently embedded texts by model, so that texts that
+/// are sent again aren't paid for again.
+pub struct EmbeddingCache {
+    entries: Mutex<LruCache<(EmbeddingModel, String), Arc<Embedding>>>,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity.max(1)).unwrap();
+        EmbeddingCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, model: &EmbeddingModel, text: &str) -> Option<Arc<Embedding>> {
+        self.entries
+            .lock()
+            .unpoisoned()
+            .get(&(model.clone(), text.to_string()))
+            .cloned()
+    }
+
+    pub fn insert(&self, model: &EmbeddingModel, text: String, embedding: Arc<Embedding>) {
+        self.entries
+            .lock()
+            .unpoisoned()
+            .put((model.clone(), text), embedding);
+    }
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Allows every account a number of requests per minute. The minute
+/// starts with the first request of an account.
+pub struct RateLimiter {
+    per_minute: u32,
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request against the account. If the account used up its
+    /// requests, returns how long until it may send the next one.
+    pub fn check(&self, account: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unpoisoned();
+        // forget accounts whose window is over, so the map stays small
+        windows.retain(|_, (start, _)| now.duration_since(*start) < RATE_WINDOW);
+        let (start, count) = windows.entry(account.to_string()).or_insert((now, 0));
+        if *count >= self.per_minute {
+            return Err(RATE_WINDOW - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_and_limit() {
+        let ada = EmbeddingModel::default();
+        let small = EmbeddingModel {
+            model: "text-embedding-3-small".to_string(),
+            dimensions: Some(512),
+            ..Default::default()
+        };
+        let cache = EmbeddingCache::new(1);
+        cache.insert(&ada, "a".to_string(), Arc::new([1.0; 1536]));
+        assert_eq!(1.0, cache.get(&ada, "a").unwrap()[0]);
+        assert!(cache.get(&small, "a").is_none());
+        cache.insert(&ada, "b".to_string(), Arc::new([2.0; 1536]));
+        assert!(cache.get(&ada, "a").is_none());
+
+        let limiter = RateLimiter::new(2);
+        assert!(limiter.check("team-a").is_ok());
+        assert!(limiter.check("team-a").is_ok());
+        let wait = limiter.check("team-a").unwrap_err();
+        assert!(wait <= RATE_WINDOW);
+        assert!(limiter.check("team-b").is_ok());
+    }
+}
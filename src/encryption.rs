@@ -0,0 +1,371 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::secret::Secret;
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// The bytes that sealing adds: a random nonce in front, and the
+/// authentication tag at the end.
+pub const SEAL_OVERHEAD: usize = NONCE_LENGTH + TAG_LENGTH;
+
+/// The plain bytes in every sealed segment of a stream, except for
+/// the last, which may be shorter.
+const SEGMENT_LENGTH: usize = 1 << 20;
+
+/// AES-256-GCM with a key derived from a configured secret. Every
+/// piece of data is sealed on its own with a random nonce, along
+/// with associated data that says where it belongs, so that sealed
+/// pieces can't be swapped around without that being noticed.
+pub struct Cipher(Aes256Gcm);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+impl Cipher {
+    /// The key is the SHA-256 of the secret, so any secret will do,
+    /// but a short one is easily guessed.
+    pub fn new(key: &Secret) -> Self {
+        let key = Sha256::digest(key.expose().as_bytes());
+        Cipher(Aes256Gcm::new(&key))
+    }
+
+    /// `plain`, encrypted and authenticated together with `aad`.
+    pub fn seal(&self, plain: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plain, aad })
+            .expect("plain texts are far below the length AES-GCM can seal");
+        let mut bytes = Vec::with_capacity(NONCE_LENGTH + sealed.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&sealed);
+        bytes
+    }
+
+    /// The plain bytes of what `seal` made with the same `aad`. Data
+    /// that was changed, sealed with another key or for another place
+    /// is refused as invalid.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(invalid("sealed data is too short"));
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LENGTH);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .map_err(|_| invalid("sealed data can't be opened with the configured key"))
+    }
+
+    /// A line of a JSON line file, sealed and base64 encoded so that
+    /// it is still a line.
+    pub fn seal_line(&self, line: &[u8], aad: &[u8]) -> String {
+        BASE64.encode(self.seal(line, aad))
+    }
+
+    pub fn open_line(&self, line: &str, aad: &[u8]) -> io::Result<Vec<u8>> {
+        let sealed = BASE64
+            .decode(line.trim_end())
+            .map_err(|_| invalid("sealed line is not base64"))?;
+        self.open(&sealed, aad)
+    }
+}
+
+/// Associated data of a segment of a stream: what the stream is, its
+/// place in the stream, and whether it is the last, so that a stream
+/// cut off between segments is noticed.
+fn segment_aad(context: &[u8], segment: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(context.len() + 9);
+    aad.extend_from_slice(context);
+    aad.extend_from_slice(&segment.to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// Seals what is written to it in segments, for files like indexes
+/// that are written in one go. Every segment is bound to `context`,
+/// like the name of the file, so that the stream can't pass for
+/// another. `finish` has to be called to write the last segment.
+pub struct SealingWriter<'a, W: Write> {
+    cipher: &'a Cipher,
+    context: &'a [u8],
+    inner: W,
+    buffer: Vec<u8>,
+    segment: u64,
+}
+
+impl<'a, W: Write> SealingWriter<'a, W> {
+    pub fn new(cipher: &'a Cipher, context: &'a [u8], inner: W) -> Self {
+        SealingWriter {
+            cipher,
+            context,
+            inner,
+            buffer: Vec::with_capacity(SEGMENT_LENGTH),
+            segment: 0,
+        }
+    }
+
+    fn write_segment(&mut self, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .seal(&self.buffer, &segment_aad(self.context, self.segment, last));
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        self.segment += 1;
+        Ok(())
+    }
+
+    /// Write the last segment, which may be empty, and hand back the
+    /// inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_segment(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<'a, W: Write> Write for SealingWriter<'a, W> {
+    fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+        let written = data.len();
+        while !data.is_empty() {
+            // a full segment is only written once more data comes,
+            // as the last segment has to be marked as such
+            if self.buffer.len() == SEGMENT_LENGTH {
+                self.write_segment(false)?;
+            }
+            let len = data.len().min(SEGMENT_LENGTH - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads what a `SealingWriter` wrote with the same context, a
+/// segment at a time.
+pub struct OpeningReader<'a, R: Read> {
+    cipher: &'a Cipher,
+    context: &'a [u8],
+    inner: R,
+    segment: u64,
+    /// The sealed segment after the current one, read to tell whether
+    /// the current one is the last.
+    ahead: Option<Vec<u8>>,
+    plain: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<'a, R: Read> OpeningReader<'a, R> {
+    pub fn new(cipher: &'a Cipher, context: &'a [u8], inner: R) -> Self {
+        OpeningReader {
+            cipher,
+            context,
+            inner,
+            segment: 0,
+            ahead: None,
+            plain: Vec::new(),
+            position: 0,
+            done: false,
+        }
+    }
+
+    /// Read a sealed segment, which is shorter than a full one only at
+    /// the end of the stream.
+    fn read_sealed(&mut self) -> io::Result<Vec<u8>> {
+        let mut sealed = vec![0; SEGMENT_LENGTH + SEAL_OVERHEAD];
+        let mut len = 0;
+        while len < sealed.len() {
+            match self.inner.read(&mut sealed[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        sealed.truncate(len);
+        Ok(sealed)
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        let sealed = match self.ahead.take() {
+            Some(sealed) => sealed,
+            None => self.read_sealed()?,
+        };
+        if sealed.is_empty() {
+            return Err(invalid("sealed stream ends before its last segment"));
+        }
+        let next = if sealed.len() == SEGMENT_LENGTH + SEAL_OVERHEAD {
+            self.read_sealed()?
+        } else {
+            Vec::new()
+        };
+        let last = next.is_empty();
+        self.plain = self
+            .cipher
+            .open(&sealed, &segment_aad(self.context, self.segment, last))?;
+        self.position = 0;
+        self.segment += 1;
+        self.done = last;
+        if !last {
+            self.ahead = Some(next);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: Read> Read for OpeningReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_segment()?;
+        }
+        let len = buf.len().min(self.plain.len() - self.position);
+        buf[..len].copy_from_slice(&self.plain[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Write `value` as JSON, sealed in segments bound to `context` when
+/// there is a cipher.
+pub fn write_json<W: Write, T: Serialize>(
+    cipher: Option<&Cipher>,
+    context: &[u8],
+    mut writer: W,
+    value: &T,
+) -> io::Result<()> {
+    match cipher {
+        None => serde_json::to_writer(&mut writer, value)?,
+        Some(cipher) => {
+            let mut sealing = SealingWriter::new(cipher, context, &mut writer);
+            serde_json::to_writer(&mut sealing, value)?;
+            sealing.finish()?;
+        }
+    }
+    writer.flush()
+}
+
+/// Read what `write_json` wrote with the same cipher and context.
+pub fn read_json<R: Read, T: DeserializeOwned>(
+    cipher: Option<&Cipher>,
+    context: &[u8],
+    reader: R,
+) -> io::Result<T> {
+    match cipher {
+        None => serde_json::from_reader(reader),
+        Some(cipher) => serde_json::from_reader(OpeningReader::new(cipher, context, reader)),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Copy a stream that was sealed for `from` to one sealed for `to`.
+pub fn reseal<R: Read, W: Write>(
+    cipher: &Cipher,
+    (from, reader): (&[u8], R),
+    (to, writer): (&[u8], W),
+) -> io::Result<W> {
+    let mut sealing = SealingWriter::new(cipher, to, writer);
+    io::copy(&mut OpeningReader::new(cipher, from, reader), &mut sealing)?;
+    sealing.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher_with(key: &str) -> Cipher {
+        Cipher::new(&serde_json::from_str(&format!("{key:?}")).unwrap())
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let cipher = cipher_with("correct horse battery staple");
+        let sealed = cipher.seal(b"vector", b"7");
+        assert_eq!(6 + SEAL_OVERHEAD, sealed.len());
+        assert_eq!(b"vector".to_vec(), cipher.open(&sealed, b"7").unwrap());
+        // a random nonce makes every sealing different
+        assert_ne!(sealed, cipher.seal(b"vector", b"7"));
+
+        assert!(cipher.open(&sealed, b"8").is_err());
+        assert!(cipher_with("another key").open(&sealed, b"7").is_err());
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LENGTH] ^= 1;
+        assert!(cipher.open(&tampered, b"7").is_err());
+
+        let line = cipher.seal_line(br#"{"vector":3}"#, b"meta");
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            br#"{"vector":3}"#.to_vec(),
+            cipher.open_line(&line, b"meta").unwrap()
+        );
+    }
+
+    #[test]
+    fn seal_streams_in_segments() {
+        let cipher = cipher_with("correct horse battery staple");
+        for len in [0, 10, SEGMENT_LENGTH, 2 * SEGMENT_LENGTH + 5] {
+            let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let mut writer = SealingWriter::new(&cipher, b"index.hnsw", Vec::new());
+            for chunk in plain.chunks(100_000) {
+                writer.write_all(chunk).unwrap();
+            }
+            let sealed = writer.finish().unwrap();
+            let segments = len / SEGMENT_LENGTH + 1;
+            assert_eq!(len + segments * SEAL_OVERHEAD, sealed.len());
+
+            let mut opened = Vec::new();
+            OpeningReader::new(&cipher, b"index.hnsw", &sealed[..])
+                .read_to_end(&mut opened)
+                .unwrap();
+            assert_eq!(plain, opened);
+            // nor does it pass for another stream
+            assert!(OpeningReader::new(&cipher, b"other.hnsw", &sealed[..])
+                .read_to_end(&mut Vec::new())
+                .is_err());
+            let resealed = reseal(
+                &cipher,
+                (b"index.hnsw", &sealed[..]),
+                (b"other.hnsw", Vec::new()),
+            )
+            .unwrap();
+            let mut opened = Vec::new();
+            OpeningReader::new(&cipher, b"other.hnsw", &resealed[..])
+                .read_to_end(&mut opened)
+                .unwrap();
+            assert_eq!(plain, opened);
+
+            if segments > 1 {
+                // cut off after a whole segment
+                let cut = &sealed[..SEGMENT_LENGTH + SEAL_OVERHEAD];
+                let mut opened = Vec::new();
+                assert!(OpeningReader::new(&cipher, b"index.hnsw", cut)
+                    .read_to_end(&mut opened)
+                    .is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::any::Any;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{LockResult, PoisonError};
+
+use thiserror::Error;
+
+use crate::cluster::ShardError;
+use crate::config::InvalidConfig;
+use crate::filter::FilterError;
+use crate::indexer::{IndexError, SearchError};
+use crate::ingestion::IngestionError;
+use crate::openai::EmbeddingError;
+use crate::replication::ReplicationError;
+use crate::webhook::WebhookError;
+
+/// The errors of the library, by kind of failure, so that they can be
+/// told apart. Failures of the storage directory say which domain,
+/// file and operation they concern.
+#[derive(Debug, Error)]
+pub enum VectorlinkError {
+    #[error("could not {operation} {}: {source}", .path.display())]
+    Io {
+        operation: &'static str,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{} is corrupt: {reason}", .path.display())]
+    Corrupt { path: PathBuf, reason: String },
+    #[error("domain {domain}: {source}")]
+    Domain {
+        domain: String,
+        #[source]
+        source: Box<VectorlinkError>,
+    },
+    #[error("domain {0} does not exist")]
+    DomainNotFound(String),
+    #[error("domain {0} already exists")]
+    DomainExists(String),
+    #[error("vector {0} is not stored")]
+    VectorNotFound(usize),
+    #[error("no vector page is free to load into, keep more pages in memory")]
+    NoFreePages,
+    #[error("loading vector page {0} was cancelled")]
+    LoadCancelled(usize),
+    #[error("{0} is not an index name, which is a domain and a commit separated by @")]
+    InvalidIndexName(String),
+    #[error("a store operation panicked: {0}")]
+    Panicked(String),
+    #[error("the {0} file of the domain may be half written by a store operation that panicked")]
+    Poisoned(&'static str),
+    #[error("{}: {reason}", .dir.display())]
+    Encryption { dir: PathBuf, reason: &'static str },
+    #[error("index {name} failed validation: {report}")]
+    InvalidIndex { name: String, report: String },
+    #[error(transparent)]
+    Index(#[from] IndexError),
+    #[error(transparent)]
+    Search(#[from] SearchError),
+    #[error(transparent)]
+    Embedding(#[from] EmbeddingError),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+    #[error(transparent)]
+    Ingestion(#[from] IngestionError),
+    #[error(transparent)]
+    Replication(#[from] ReplicationError),
+    #[error(transparent)]
+    Shard(#[from] ShardError),
+    #[error(transparent)]
+    Webhook(#[from] WebhookError),
+    #[error(transparent)]
+    Config(#[from] InvalidConfig),
+}
+
+pub type Result<T, E = VectorlinkError> = std::result::Result<T, E>;
+
+impl VectorlinkError {
+    /// This error as it happened to the given domain.
+    pub fn in_domain(self, domain: &str) -> Self {
+        VectorlinkError::Domain {
+            domain: domain.to_string(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The message of this error with files named without their
+    /// directory, for clients that shouldn't learn where the storage
+    /// directory is.
+    pub fn without_paths(&self) -> String {
+        match self {
+            VectorlinkError::Io {
+                operation,
+                path,
+                source,
+            } => format!("could not {operation} {}: {source}", file_name(path)),
+            VectorlinkError::Corrupt { path, reason } => {
+                format!("{} is corrupt: {reason}", file_name(path))
+            }
+            VectorlinkError::Domain { domain, source } => {
+                format!("domain {domain}: {}", source.without_paths())
+            }
+            VectorlinkError::Encryption { reason, .. } => reason.to_string(),
+            e => e.to_string(),
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            VectorlinkError::Io { source, .. } => source.kind(),
+            VectorlinkError::Domain { source, .. } => source.kind(),
+            VectorlinkError::Corrupt { .. } | VectorlinkError::InvalidIndex { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            VectorlinkError::DomainNotFound(_) | VectorlinkError::VectorNotFound(_) => {
+                io::ErrorKind::NotFound
+            }
+            VectorlinkError::DomainExists(_) => io::ErrorKind::AlreadyExists,
+            VectorlinkError::Config(_)
+            | VectorlinkError::InvalidIndexName(_)
+            | VectorlinkError::Encryption { .. } => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => "a file".to_string(),
+    }
+}
+
+/// For code that still deals in io errors. The error can be had back
+/// with `get_ref` and `downcast_ref`.
+impl From<VectorlinkError> for io::Error {
+    fn from(e: VectorlinkError) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// Says what was being done to which file when an io error happened.
+pub trait IoContext<T> {
+    fn context(self, operation: &'static str, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn context(self, operation: &'static str, path: &Path) -> Result<T> {
+        self.map_err(|source| VectorlinkError::Io {
+            operation,
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// The message a thread panicked with, if it was a string, as it is
+/// for `panic!` and failed unwraps.
+pub fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "no message".to_string(),
+        },
+    }
+}
+
+/// Takes a lock even if a thread panicked while holding it, so that a
+/// panic while serving one request doesn't fail every later one.
+///
+/// This is only for locks whose data is whole whatever point a panic
+/// happens at: values that are replaced in one assignment, and maps
+/// and caches that are changed by single calls, where a lost update
+/// leaves an entry missing at worst. Locks over a file and the counts
+/// that go with it are taken with `Checked` instead, as a panic in the
+/// middle of a write leaves the two out of step.
+pub trait Unpoisoned<T> {
+    fn unpoisoned(self) -> T;
+}
+
+impl<T> Unpoisoned<T> for LockResult<T> {
+    fn unpoisoned(self) -> T {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Takes a lock, failing with `VectorlinkError::Poisoned` if a thread
+/// panicked while holding it. The data behind the lock is not used
+/// again, as the panic may have left it half changed.
+pub trait Checked<T> {
+    fn checked(self, file: &'static str) -> Result<T>;
+}
+
+impl<T> Checked<T> for LockResult<T> {
+    fn checked(self, file: &'static str) -> Result<T> {
+        self.map_err(|_| VectorlinkError::Poisoned(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_and_kinds() {
+        let path = Path::new("/var/lib/vectorlink/admin%2Fstar_wars.vecs");
+        let missing: io::Result<()> = Err(io::Error::from(io::ErrorKind::NotFound));
+        let error = missing
+            .context("open", path)
+            .unwrap_err()
+            .in_domain("admin/star_wars");
+        assert!(error
+            .to_string()
+            .starts_with("domain admin/star_wars: could not open /var/lib/vectorlink"));
+        assert!(error
+            .without_paths()
+            .starts_with("domain admin/star_wars: could not open admin%2Fstar_wars.vecs: "));
+        let VectorlinkError::Domain { source, .. } = &error else {
+            panic!("expected a domain error");
+        };
+        assert!(matches!(
+            **source,
+            VectorlinkError::Io {
+                operation: "open",
+                ..
+            }
+        ));
+
+        let error = io::Error::from(error);
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+        assert!(error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<VectorlinkError>())
+            .is_some());
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            io::Error::from(VectorlinkError::DomainExists("x".to_string())).kind()
+        );
+    }
+
+    #[test]
+    fn recover_from_panics() {
+        let lock = std::sync::Mutex::new(1);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.lock().unwrap();
+                panic!("while holding the lock");
+            })
+            .join()
+            .unwrap_err();
+        });
+        assert!(lock.is_poisoned());
+        assert!(matches!(
+            lock.lock().checked("vectors"),
+            Err(VectorlinkError::Poisoned("vectors"))
+        ));
+        *lock.lock().unpoisoned() += 1;
+        assert_eq!(2, *lock.lock().unpoisoned());
+
+        let payload = std::panic::catch_unwind(|| panic!("vector {} is missing", 3)).unwrap_err();
+        assert_eq!("vector 3 is missing", panic_message(payload));
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(3)).unwrap_err();
+        assert_eq!("no message", panic_message(payload));
+    }
+}
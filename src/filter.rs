@@ -0,0 +1,355 @@
+use std::cmp::Ordering;
+
+use serde_json::Value;
+use thiserror::Error;
+
+/// A filter over the metadata of a vector, such as
+/// `genre = "scifi" AND (year >= 1977 OR rating IN (4, 5))`.
+///
+/// Fields name keys of the metadata object, with dots reaching into
+/// nested objects. A comparison against a missing field is false.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare {
+        field: String,
+        op: CompareOp,
+        value: Value,
+    },
+    In {
+        field: String,
+        values: Vec<Value>,
+    },
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("Invalid filter at position {position}: {message}")]
+pub struct FilterError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Filter, FilterError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: input.len(),
+        };
+        let filter = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some((position, token)) => Err(FilterError {
+                position,
+                message: format!("unexpected {token:?}"),
+            }),
+        }
+    }
+
+    pub fn matches(&self, metadata: Option<&Value>) -> bool {
+        match self {
+            Filter::Compare { field, op, value } => lookup(metadata, field)
+                .map(|v| compare(v, *op, value))
+                .unwrap_or(false),
+            Filter::In { field, values } => lookup(metadata, field)
+                .map(|v| values.iter().any(|value| compare(v, CompareOp::Eq, value)))
+                .unwrap_or(false),
+            Filter::Not(filter) => !filter.matches(metadata),
+            Filter::And(left, right) => left.matches(metadata) && right.matches(metadata),
+            Filter::Or(left, right) => left.matches(metadata) || right.matches(metadata),
+        }
+    }
+}
+
+/// The value of a field of the metadata, with dots reaching into
+/// nested objects.
+pub fn lookup<'a>(metadata: Option<&'a Value>, field: &str) -> Option<&'a Value> {
+    field
+        .split('.')
+        .try_fold(metadata?, |value, key| value.get(key))
+}
+
+fn compare(left: &Value, op: CompareOp, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64().partial_cmp(&r.as_f64()),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None,
+    };
+    match (op, ordering) {
+        (CompareOp::Ne, None) => true,
+        (_, None) => false,
+        (CompareOp::Eq, Some(o)) => o == Ordering::Equal,
+        (CompareOp::Ne, Some(o)) => o != Ordering::Equal,
+        (CompareOp::Lt, Some(o)) => o == Ordering::Less,
+        (CompareOp::Le, Some(o)) => o != Ordering::Greater,
+        (CompareOp::Gt, Some(o)) => o == Ordering::Greater,
+        (CompareOp::Ge, Some(o)) => o != Ordering::Less,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Op(CompareOp),
+    And,
+    Or,
+    Not,
+    In,
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let error = |message: &str| FilterError {
+            position: start,
+            message: message.to_string(),
+        };
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = match c {
+            '(' | ')' | ',' | '=' => {
+                chars.next();
+                match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    ',' => Token::Comma,
+                    _ => Token::Op(CompareOp::Eq),
+                }
+            }
+            '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if(|(_, c)| *c == '=').is_some();
+                match (c, eq) {
+                    ('!', true) => Token::Op(CompareOp::Ne),
+                    ('<', false) => Token::Op(CompareOp::Lt),
+                    ('<', true) => Token::Op(CompareOp::Le),
+                    ('>', false) => Token::Op(CompareOp::Gt),
+                    ('>', true) => Token::Op(CompareOp::Ge),
+                    _ => return Err(error("expected !=")),
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => return Err(error("unterminated string")),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(error("unterminated string")),
+                    }
+                }
+                Token::Literal(Value::String(s))
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut end = start;
+                while let Some((i, c)) = chars
+                    .next_if(|(_, c)| c.is_ascii_alphanumeric() || matches!(*c, '-' | '+' | '.'))
+                {
+                    end = i + c.len_utf8();
+                }
+                let number: serde_json::Number = input[start..end]
+                    .parse()
+                    .map_err(|_| error("invalid number"))?;
+                Token::Literal(Value::Number(number))
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = start;
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_alphanumeric() || matches!(*c, '_' | '.'))
+                {
+                    end = i + c.len_utf8();
+                }
+                let word = &input[start..end];
+                match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    "IN" => Token::In,
+                    "TRUE" => Token::Literal(Value::Bool(true)),
+                    "FALSE" => Token::Literal(Value::Bool(false)),
+                    "NULL" => Token::Literal(Value::Null),
+                    _ => Token::Ident(word.to_string()),
+                }
+            }
+            _ => return Err(error(&format!("unexpected character {c:?}"))),
+        };
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<(usize, Token)> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), FilterError> {
+        let token = self.peek().ok_or_else(|| FilterError {
+            position: self.end,
+            message: "unexpected end of filter".to_string(),
+        })?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn accept(&mut self, token: &Token) -> bool {
+        if self.peek().map(|(_, t)| &t == token).unwrap_or(false) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), FilterError> {
+        let (position, next) = self.next()?;
+        if next == token {
+            Ok(())
+        } else {
+            Err(FilterError {
+                position,
+                message: format!("expected {token:?} but got {next:?}"),
+            })
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter, FilterError> {
+        let mut filter = self.parse_and()?;
+        while self.accept(&Token::Or) {
+            filter = Filter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, FilterError> {
+        let mut filter = self.parse_unary()?;
+        while self.accept(&Token::And) {
+            filter = Filter::And(Box::new(filter), Box::new(self.parse_unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, FilterError> {
+        if self.accept(&Token::Not) {
+            return Ok(Filter::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.accept(&Token::Open) {
+            let filter = self.parse_or()?;
+            self.expect(Token::Close)?;
+            return Ok(filter);
+        }
+        let field = match self.next()? {
+            (_, Token::Ident(field)) => field,
+            (position, token) => {
+                return Err(FilterError {
+                    position,
+                    message: format!("expected a field but got {token:?}"),
+                })
+            }
+        };
+        match self.next()? {
+            (_, Token::Op(op)) => Ok(Filter::Compare {
+                field,
+                op,
+                value: self.parse_value()?,
+            }),
+            (_, Token::In) => {
+                self.expect(Token::Open)?;
+                let mut values = vec![self.parse_value()?];
+                while self.accept(&Token::Comma) {
+                    values.push(self.parse_value()?);
+                }
+                self.expect(Token::Close)?;
+                Ok(Filter::In { field, values })
+            }
+            (position, token) => Err(FilterError {
+                position,
+                message: format!("expected a comparison but got {token:?}"),
+            }),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, FilterError> {
+        match self.next()? {
+            (_, Token::Literal(value)) => Ok(value),
+            (position, token) => Err(FilterError {
+                position,
+                message: format!("expected a value but got {token:?}"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_and_match() {
+        let metadata =
+            json!({"genre": "scifi", "year": 1977, "rating": {"stars": 4.5}, "draft": false});
+        let matches = |filter: &str| Filter::parse(filter).unwrap().matches(Some(&metadata));
+
+        assert!(matches(r#"genre = "scifi""#));
+        assert!(!matches(r#"genre != "scifi""#));
+        assert!(matches("year >= 1977 AND year < 1980"));
+        assert!(matches("rating.stars > 4"));
+        assert!(matches("year IN (1977, 1980, 1983)"));
+        assert!(!matches("year in (1999)"));
+        assert!(matches(
+            r#"NOT draft = true AND (genre = "drama" OR year <= 1977)"#
+        ));
+        assert!(matches(
+            r#"genre = "drama" OR genre = "scifi" AND year = 1977"#
+        ));
+        // missing fields never match a comparison
+        assert!(!matches("director = null"));
+        assert!(matches("NOT director = null"));
+        assert!(!Filter::parse("year > 1").unwrap().matches(None));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(7, Filter::parse("year > AND").unwrap_err().position);
+        assert!(Filter::parse("year >").is_err());
+        assert!(Filter::parse(r#"genre = "scifi"#).is_err());
+        assert!(Filter::parse("(year = 1").is_err());
+        assert!(Filter::parse("year = 1 year").is_err());
+        assert!(Filter::parse("year IN ()").is_err());
+        assert!(Filter::parse("year ~ 1").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::io::{self, ErrorKind, Write};
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::error;
+use crate::indexer::{search_exact, HnswIndex, Point, SearchError};
+use crate::progress::Progress;
+use crate::vecmath::EMBEDDING_LENGTH;
+use crate::vectors::{Domain, LoadedVec, VectorStore};
+
+/// The exact nearest neighbours of a set of query vectors among the
+/// points of an index. Queries and neighbours are known by their
+/// vector id, which is their position in the vector file of the
+/// domain and in its export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroundTruth {
+    pub queries: Vec<usize>,
+    /// The neighbours of every query with their distance, nearest
+    /// first. A query is its own nearest neighbour.
+    pub neighbors: Vec<Vec<(usize, f32)>>,
+}
+
+/// Pick `n` points of an index whose vectors are not deleted, at
+/// random, by vector id.
+pub fn sample_queries(domain: &Domain, hnsw: &HnswIndex, n: usize, seed: u64) -> Vec<usize> {
+    let live: Vec<usize> = (0..hnsw.layer_len(0))
+        .map(|i| hnsw.feature(i).vec_id())
+        .filter(|v| !domain.is_deleted(*v))
+        .collect();
+    let mut picked: Vec<usize> = rand::seq::index::sample(
+        &mut StdRng::seed_from_u64(seed),
+        live.len(),
+        n.min(live.len()),
+    )
+    .into_iter()
+    .map(|i| live[i])
+    .collect();
+    picked.sort();
+    picked
+}
+
+/// The exact `k` nearest points of an index to every query, by vector
+/// id and distance, nearest first, leaving out the vectors that
+/// `excluded` says are. Every query is compared with every point, in
+/// the batches of [`search_exact`].
+pub fn exact_neighbors(
+    hnsw: &HnswIndex,
+    queries: &[Point],
+    k: usize,
+    excluded: impl Fn(usize) -> bool,
+    progress: &dyn Progress,
+) -> Result<Vec<Vec<(usize, f32)>>, SearchError> {
+    let excluded_points = (0..hnsw.layer_len(0))
+        .filter(|i| excluded(hnsw.feature(*i).vec_id()))
+        .count();
+    progress.stage("searching exactly", Some(queries.len() as u64));
+    let mut neighbors = Vec::with_capacity(queries.len());
+    for query in queries {
+        let nearest = search_exact(query, k + excluded_points, hnsw)?
+            .into_iter()
+            .filter(|result| !excluded(result.vector_id()))
+            .take(k)
+            .map(|result| (result.vector_id(), f32::from_bits(result.distance())))
+            .collect();
+        neighbors.push(nearest);
+        progress.advance(1);
+    }
+    progress.finish();
+    Ok(neighbors)
+}
+
+/// Find the exact `k` nearest neighbours of the given vectors of a
+/// domain among the points of an index of it that are not deleted.
+pub fn compute(
+    store: &VectorStore,
+    domain: &Domain,
+    hnsw: &HnswIndex,
+    queries: Vec<usize>,
+    k: usize,
+    progress: &dyn Progress,
+) -> error::Result<GroundTruth> {
+    let _span = tracing::info_span!(
+        "ground_truth",
+        domain = %domain.name(),
+        queries = queries.len(),
+        k
+    )
+    .entered();
+    let points = queries
+        .iter()
+        .map(|q| {
+            Ok(Point::Mem {
+                vec: Box::new(*store.get_stored_vec(domain, *q)?),
+            })
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    let neighbors = exact_neighbors(hnsw, &points, k, |v| domain.is_deleted(v), progress)?;
+    Ok(GroundTruth { queries, neighbors })
+}
+
+/// Write ground truth in the binary format of big-ann-benchmarks: the
+/// number of queries and `k` as little-endian u32s, then the neighbour
+/// ids of every query as u32s, then their distances as f32s. Queries
+/// with fewer than `k` neighbours are padded with id `u32::MAX` and an
+/// infinite distance.
+pub fn write_ground_truth<W: Write>(
+    truth: &GroundTruth,
+    k: usize,
+    mut writer: W,
+) -> io::Result<()> {
+    writer.write_all(&format_u32(truth.neighbors.len(), "queries")?.to_le_bytes())?;
+    writer.write_all(&format_u32(k, "k")?.to_le_bytes())?;
+    let padded = |neighbors: &Vec<(usize, f32)>| {
+        let padding = std::iter::repeat((u32::MAX as usize, f32::INFINITY));
+        neighbors.clone().into_iter().chain(padding).take(k)
+    };
+    for neighbors in &truth.neighbors {
+        for (id, _) in padded(neighbors) {
+            writer.write_all(&format_u32(id, "vector id")?.to_le_bytes())?;
+        }
+    }
+    for neighbors in &truth.neighbors {
+        for (_, distance) in padded(neighbors) {
+            writer.write_all(&distance.to_le_bytes())?;
+        }
+    }
+    writer.flush()
+}
+
+/// A number as the u32 of the file format, which holds the ids of
+/// domains of up to 2^32 - 1 vectors.
+fn format_u32(n: usize, what: &str) -> io::Result<u32> {
+    u32::try_from(n).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{what} {n} does not fit the 32 bits of the format"),
+        )
+    })
+}
+
+/// Write the query vectors in the `.fbin` format of big-ann-benchmarks:
+/// the number of vectors and their dimension as little-endian u32s,
+/// then the vectors as f32s.
+pub fn write_queries<W: Write>(vecs: &[LoadedVec], mut writer: W) -> io::Result<()> {
+    writer.write_all(&(vecs.len() as u32).to_le_bytes())?;
+    writer.write_all(&(EMBEDDING_LENGTH as u32).to_le_bytes())?;
+    for vec in vecs {
+        for f in vec.iter() {
+            writer.write_all(&f.to_le_bytes())?;
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::{new_index, start_indexing_from_operations, PointOperation};
+    use crate::progress::NoProgress;
+    use crate::vecmath::{normalized_cosine_distance, random_normalized_embedding};
+
+    #[test]
+    fn agree_with_exact_search() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 100);
+        let domain = store.get_domain("admin/truth").unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let vecs: Vec<_> = (0..50)
+            .map(|_| random_normalized_embedding(&mut rng))
+            .collect();
+        let ids = store.add_vecs(&domain, vecs.iter()).unwrap();
+        let operations: Vec<_> = ids
+            .iter()
+            .map(|id| PointOperation::Insert {
+                point: Point::Stored {
+                    id: id.to_string(),
+                    vec: store.get_stored_vec(&domain, *id).unwrap(),
+                },
+            })
+            .collect();
+        let hnsw = start_indexing_from_operations(new_index(Some(42)), operations).unwrap();
+        // deleted after indexing, so the index still holds it
+        domain.add_tombstones(&[ids[3]]).unwrap();
+
+        let queries = sample_queries(&domain, &hnsw, 5, 7);
+        assert_eq!(5, queries.len());
+        assert!(!queries.contains(&ids[3]));
+        let truth = compute(&store, &domain, &hnsw, queries.clone(), 4, &NoProgress).unwrap();
+        assert_eq!(5, truth.neighbors.len());
+
+        for (query, neighbors) in queries.iter().zip(&truth.neighbors) {
+            assert_eq!(*query, neighbors[0].0);
+            assert!(neighbors[0].1 < 1e-6);
+            let mut compared: Vec<(u32, usize)> = ids
+                .iter()
+                .filter(|id| **id != ids[3])
+                .map(|id| {
+                    let distance = normalized_cosine_distance(&vecs[*query], &vecs[*id]);
+                    (distance.to_bits(), *id)
+                })
+                .collect();
+            compared.sort();
+            let exact: Vec<usize> = compared.iter().take(4).map(|(_, id)| *id).collect();
+            let found: Vec<usize> = neighbors.iter().map(|(id, _)| *id).collect();
+            assert_eq!(exact, found);
+        }
+
+        let mut bytes = Vec::new();
+        write_ground_truth(&truth, 6, &mut bytes).unwrap();
+        assert_eq!(8 + 5 * 6 * 8, bytes.len());
+        assert_eq!(5, u32::from_le_bytes(bytes[0..4].try_into().unwrap()));
+        assert_eq!(6, u32::from_le_bytes(bytes[4..8].try_into().unwrap()));
+        assert_eq!(
+            queries[0] as u32,
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap())
+        );
+        assert_eq!(
+            u32::MAX,
+            u32::from_le_bytes(bytes[28..32].try_into().unwrap())
+        );
+
+        let query_vecs: Vec<_> = queries
+            .iter()
+            .map(|q| store.get_stored_vec(&domain, *q).unwrap())
+            .collect();
+        let mut bytes = Vec::new();
+        write_queries(&query_vecs, &mut bytes).unwrap();
+        assert_eq!(8 + 5 * EMBEDDING_LENGTH * 4, bytes.len());
+    }
+}
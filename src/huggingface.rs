@@ -0,0 +1,179 @@
+use futures::future::BoxFuture;
+use reqwest::{header, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::openai::{
+    self, execute_with_retries, padded_embedding, EmbeddingError, EmbeddingLimits,
+    EmbeddingProvider,
+};
+use crate::vecmath::Embedding;
+
+/// Texts sent to an inference endpoint in one request.
+const BATCH_SIZE: usize = 32;
+
+#[derive(Serialize)]
+struct FeatureExtractionRequest<'a> {
+    inputs: &'a [String],
+    options: FeatureExtractionOptions,
+}
+
+#[derive(Serialize)]
+struct FeatureExtractionOptions {
+    /// Wait for a model that is not loaded yet, instead of failing
+    /// with 503.
+    wait_for_model: bool,
+}
+
+/// Sentence models answer with one vector per text, other models with
+/// one vector per token.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum FeatureExtractionResponse {
+    Sentences(Vec<Vec<f32>>),
+    Tokens(Vec<Vec<Vec<f32>>>),
+}
+
+/// The feature extraction endpoint of a model on the Inference API.
+pub fn inference_api_url(model: &str) -> Result<Url, EmbeddingError> {
+    Url::parse(&format!(
+        "https://api-inference.huggingface.co/pipeline/feature-extraction/{model}"
+    ))
+    .map_err(|e| EmbeddingError::BadEndpoint(e.to_string()))
+}
+
+/// The mean of the token vectors of a text.
+fn mean_pool(tokens: &[Vec<f32>]) -> Vec<f32> {
+    let mut mean = vec![0.0; tokens.first().map(Vec::len).unwrap_or(0)];
+    for token in tokens {
+        for (sum, x) in mean.iter_mut().zip(token) {
+            *sum += x;
+        }
+    }
+    for sum in mean.iter_mut() {
+        *sum /= tokens.len() as f32;
+    }
+    mean
+}
+
+/// Embeds with a feature extraction endpoint.
+pub struct HuggingFaceEmbedder {
+    url: Url,
+    model: String,
+    api_key: String,
+    dimension: usize,
+}
+
+impl HuggingFaceEmbedder {
+    pub fn new(url: Url, model: &str, api_key: &str, dimension: usize) -> Self {
+        HuggingFaceEmbedder {
+            url,
+            model: model.to_string(),
+            api_key: api_key.to_string(),
+            dimension,
+        }
+    }
+}
+
+impl EmbeddingProvider for HuggingFaceEmbedder {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn limits(&self) -> EmbeddingLimits {
+        EmbeddingLimits {
+            batch_size: BATCH_SIZE,
+            concurrency: openai::concurrency(),
+        }
+    }
+
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<Embedding>, EmbeddingError>> {
+        Box::pin(embed_batch(&self.url, &self.api_key, self.dimension, texts))
+    }
+}
+
+async fn embed_batch(
+    url: &Url,
+    api_key: &str,
+    dimension: usize,
+    batch: &[String],
+) -> Result<Vec<Embedding>, EmbeddingError> {
+    let request = openai::client()
+        .post(url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, format!("Bearer {api_key}"))
+        .body(serde_json::to_vec(&FeatureExtractionRequest {
+            inputs: batch,
+            options: FeatureExtractionOptions {
+                wait_for_model: true,
+            },
+        })?)
+        .build()?;
+    let response_bytes = execute_with_retries(request).await?;
+    embeddings(
+        serde_json::from_slice(&response_bytes)?,
+        batch.len(),
+        dimension,
+    )
+}
+
+/// The embeddings of a response to `expected` texts, one per text.
+fn embeddings(
+    response: FeatureExtractionResponse,
+    expected: usize,
+    dimension: usize,
+) -> Result<Vec<Embedding>, EmbeddingError> {
+    let vectors = match response {
+        FeatureExtractionResponse::Sentences(vectors) => vectors,
+        FeatureExtractionResponse::Tokens(texts) => {
+            texts.iter().map(|tokens| mean_pool(tokens)).collect()
+        }
+    };
+    if vectors.len() != expected {
+        return Err(EmbeddingError::WrongCount {
+            expected,
+            got: vectors.len(),
+        });
+    }
+    vectors
+        .iter()
+        .map(|vector| padded_embedding(vector, dimension))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentence_and_token_responses() {
+        let sentences: FeatureExtractionResponse =
+            serde_json::from_str("[[0.5, 1.0, -1.0], [0.0, 0.0, 1.0]]").unwrap();
+        assert!(matches!(sentences, FeatureExtractionResponse::Sentences(v) if v.len() == 2));
+        let tokens: FeatureExtractionResponse =
+            serde_json::from_str("[[[1.0, 2.0], [3.0, 4.0]]]").unwrap();
+        let FeatureExtractionResponse::Tokens(texts) = tokens else {
+            panic!("expected token vectors");
+        };
+        assert_eq!(vec![2.0, 3.0], mean_pool(&texts[0]));
+    }
+
+    #[test]
+    fn one_embedding_per_text() {
+        let response = || serde_json::from_str("[[0.5, 1.0], [0.0, 1.0]]").unwrap();
+        assert_eq!(2, embeddings(response(), 2, 2).unwrap().len());
+        assert!(matches!(
+            embeddings(response(), 3, 2),
+            Err(EmbeddingError::WrongCount {
+                expected: 3,
+                got: 2
+            })
+        ));
+    }
+}
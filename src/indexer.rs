@@ -1,25 +1,140 @@
 #![allow(unused, dead_code)]
 use crate::{
-    openai::{embeddings_for, EmbeddingError},
+    config::ThreadsConfig,
+    encryption::{self, Cipher},
+    error::{self, IoContext, VectorlinkError},
+    kmeans,
+    lexical::LexicalIndex,
+    metrics::{self, Counter, Histogram},
+    openai::{embed_texts, EmbeddingError, EmbeddingProvider},
+    progress::{NoProgress, Progress},
     server::Operation,
+    sparse::{SparseIndex, SparseVector},
     vecmath::{self, Embedding},
-    vectors::{Domain, LoadedVec, VectorStore},
+    vectors::{Clustering, Domain, LoadedVec, VectorStore},
 };
 use hnsw::{Hnsw, Searcher};
+use lazy_static::lazy_static;
+use rand::SeedableRng;
 use rand_pcg::Lcg128Xsl64;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use space::{Metric, Neighbor};
+use std::cell::Cell;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::convert::Infallible;
 use std::fs::File;
+use std::io::Write;
 use std::{
     io,
     iter::{self, zip},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 use thiserror::Error;
+use tracing::Instrument;
 use urlencoding::{decode, encode};
 
-pub type HnswIndex = Hnsw<OpenAI, Point, Lcg128Xsl64, 24, 48>;
-pub type HnswStorageIndex = Hnsw<OpenAI, IndexPoint, Lcg128Xsl64, 24, 48>;
+// Maximum number of neighbours of a node in the upper layers and in
+// the bottom layer of the graph respectively.
+pub const M: usize = 24;
+pub const M0: usize = 48;
+
+pub type HnswIndex = Hnsw<OpenAI, Point, Lcg128Xsl64, M, M0>;
+pub type HnswStorageIndex = Hnsw<OpenAI, IndexPoint, Lcg128Xsl64, M, M0>;
+
+/// Create an empty index. The level of every inserted node is drawn
+/// from the index's random generator, so giving a seed makes a build
+/// from the same operations reproducible.
+pub fn new_index(seed: Option<u64>) -> HnswIndex {
+    match seed {
+        Some(seed) => Hnsw::new_prng(OpenAI, Lcg128Xsl64::seed_from_u64(seed)),
+        None => Hnsw::new(OpenAI),
+    }
+}
+
+/// Run all parallel work of this process on a single thread. Combined
+/// with a seed, this makes index builds fully deterministic.
+pub fn use_single_thread() -> Result<(), rayon::ThreadPoolBuildError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build_global()?;
+    configure_thread_pools(&ThreadsConfig {
+        build: Some(1),
+        search: Some(1),
+        build_nice: 0,
+    })
+}
+
+struct ThreadPools {
+    build: rayon::ThreadPool,
+    search: rayon::ThreadPool,
+}
+
+static THREAD_POOLS: OnceLock<ThreadPools> = OnceLock::new();
+
+fn create_thread_pools(config: &ThreadsConfig) -> Result<ThreadPools, rayon::ThreadPoolBuildError> {
+    let nice = config.build_nice;
+    let build = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.build.unwrap_or(0))
+        .thread_name(|i| format!("vectorlink-build-{i}"))
+        .start_handler(move |_| lower_priority(nice))
+        .build()?;
+    let search = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.search.unwrap_or(0))
+        .thread_name(|i| format!("vectorlink-search-{i}"))
+        .build()?;
+    Ok(ThreadPools { build, search })
+}
+
+#[cfg(target_os = "linux")]
+fn lower_priority(nice: i32) {
+    if nice == 0 {
+        return;
+    }
+    // On Linux, this sets the priority of the calling thread rather
+    // than of the whole process.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        tracing::warn!(
+            nice,
+            error = %io::Error::last_os_error(),
+            "could not lower the priority of a build thread"
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority(_nice: i32) {}
+
+/// Size the pools for index builds and searches. This has to happen
+/// before either is used, after which the pools can't change. Pools
+/// that are used without being configured get a thread per core.
+pub fn configure_thread_pools(config: &ThreadsConfig) -> Result<(), rayon::ThreadPoolBuildError> {
+    let pools = create_thread_pools(config)?;
+    if THREAD_POOLS.set(pools).is_err() {
+        tracing::warn!("thread pools were already in use, keeping their configuration");
+    }
+    Ok(())
+}
+
+fn thread_pools() -> &'static ThreadPools {
+    THREAD_POOLS.get_or_init(|| {
+        create_thread_pools(&ThreadsConfig::default()).expect("could not start thread pools")
+    })
+}
+
+/// The pool for inserting points, training centroids and other work
+/// over a whole index.
+pub fn build_pool() -> &'static rayon::ThreadPool {
+    &thread_pools().build
+}
+
+/// The pool for searches that are spread over several threads.
+pub fn search_pool() -> &'static rayon::ThreadPool {
+    &thread_pools().search
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Point {
@@ -41,14 +156,15 @@ impl Point {
         }
     }
 
-    fn vec_id(&self) -> usize {
+    /// The id of the point's vector in the domain's vector file.
+    pub fn vec_id(&self) -> usize {
         match self {
             Point::Stored { id, vec } => vec.id(),
             Point::Mem { vec } => panic!("You can not get the vector id of a memory point"),
         }
     }
 
-    fn vec(&self) -> &Embedding {
+    pub fn vec(&self) -> &Embedding {
         match self {
             Point::Stored { id: _, vec } => vec,
             Point::Mem { vec } => vec,
@@ -56,12 +172,46 @@ impl Point {
     }
 }
 
+lazy_static! {
+    static ref SEARCHES: Arc<Counter> =
+        metrics::registry().counter("vectorlink_searches_total", "Approximate searches.");
+    static ref SEARCH_SECONDS: Arc<Histogram> = metrics::registry().histogram(
+        "vectorlink_search_seconds",
+        "Time taken by approximate searches.",
+        metrics::SECONDS
+    );
+    static ref SEARCH_DISTANCES: Arc<Histogram> = metrics::registry().histogram(
+        "vectorlink_search_distances",
+        "Distances computed by an approximate search, which grows with the hops it takes.",
+        metrics::COUNTS
+    );
+    static ref POINTS_INSERTED: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_points_inserted_total",
+        "Points inserted into HNSW indexes."
+    );
+    static ref CENTROID_TRAINING_POINTS: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_centroid_training_points_total",
+        "Points assigned to centroids while training IVF indexes, over all iterations."
+    );
+    static ref CENTROID_TRAINING_SECONDS: Arc<Histogram> = metrics::registry().histogram(
+        "vectorlink_centroid_training_seconds",
+        "Time taken to train the centroids of IVF indexes.",
+        metrics::SECONDS
+    );
+}
+
+thread_local! {
+    // Distances computed on this thread, to count those of a search.
+    static DISTANCES: Cell<u64> = Cell::new(0);
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OpenAI;
 
 impl Metric<Point> for OpenAI {
     type Unit = u32;
     fn distance(&self, p1: &Point, p2: &Point) -> u32 {
+        DISTANCES.with(|d| d.set(d.get() + 1));
         let a = p1.vec();
         let b = p2.vec();
         let f = vecmath::normalized_cosine_distance(a, b);
@@ -88,35 +238,115 @@ enum Op {
     Changed,
 }
 
+/// Store vectors that were embedded elsewhere, and turn them into
+/// insert operations. Records are `(id, vector, metadata)`. The
+/// metadata is kept for filtering, and a `document` string in it is
+/// recorded as for indexing operations.
+pub fn records_to_point_operations(
+    domain: &Domain,
+    vector_store: &VectorStore,
+    records: Vec<(String, Embedding, Option<serde_json::Value>)>,
+) -> Result<Vec<PointOperation>, IndexError> {
+    let _span = tracing::debug_span!(
+        "store_records",
+        domain = %domain.name(),
+        records = records.len()
+    )
+    .entered();
+    let loaded_vecs: Vec<LoadedVec> =
+        vector_store.add_and_load_vecs(domain, records.iter().map(|(_, vec, _)| vec))?;
+    let metadata: Vec<(usize, serde_json::Value)> = zip(records.iter(), loaded_vecs.iter())
+        .filter_map(|((_, _, metadata), vec)| metadata.clone().map(|m| (vec.id(), m)))
+        .collect();
+    let documents: Vec<(usize, String)> = metadata
+        .iter()
+        .filter_map(|(vec, m)| m.get("document")?.as_str().map(|d| (*vec, d.to_string())))
+        .collect();
+    if !documents.is_empty() {
+        domain.add_documents(&documents)?;
+    }
+    if !metadata.is_empty() {
+        domain.add_metadata(&metadata)?;
+    }
+    Ok(zip(records, loaded_vecs)
+        .map(|((id, _, _), vec)| PointOperation::Insert {
+            point: Point::Stored { vec, id },
+        })
+        .collect())
+}
+
+/// Refuse to mix the embeddings of another model, or of another
+/// dimension, into a domain that records its model. This only looks
+/// at the embedder's settings, so a job checks once before it embeds
+/// anything.
+pub fn check_embedder(domain: &Domain, embedder: &dyn EmbeddingProvider) -> Result<(), IndexError> {
+    let Some(recorded) = domain.embedding_model() else {
+        return Ok(());
+    };
+    let dimension = domain.embedding_dimension();
+    if recorded.model != embedder.model_name() || dimension != Some(embedder.dimension()) {
+        return Err(IndexError::ModelMismatch(format!(
+            "domain {} holds embeddings of {} with {} dimensions, not of {} with {}",
+            domain.name(),
+            recorded.model,
+            dimension.map_or("unknown".to_string(), |d| d.to_string()),
+            embedder.model_name(),
+            embedder.dimension()
+        )));
+    }
+    Ok(())
+}
+
 pub async fn operations_to_point_operations(
     domain: &Domain,
     vector_store: &VectorStore,
-    structs: Vec<Result<Operation, std::io::Error>>,
-    key: &str,
+    ops: Vec<Operation>,
+    embedder: &dyn EmbeddingProvider,
 ) -> Result<Vec<PointOperation>, IndexError> {
-    // Should not unwrap here -
-    let ops: Vec<Operation> = structs.into_iter().collect::<Result<Vec<_>, _>>()?;
-    let tuples: Vec<(Op, String, String)> = ops
+    let span = tracing::debug_span!(
+        "embed_operations",
+        domain = %domain.name(),
+        operations = ops.len(),
+        model = embedder.model_name()
+    );
+    let start = Instant::now();
+    let tuples: Vec<(Op, String, String, Option<String>)> = ops
         .iter()
         .flat_map(|o| match o {
-            Operation::Inserted { string, id } => Some((Op::Insert, string.into(), id.into())),
-            Operation::Changed { string, id } => Some((Op::Changed, string.into(), id.into())),
+            Operation::Inserted {
+                string,
+                id,
+                document,
+            } => Some((Op::Insert, string.into(), id.into(), document.clone())),
+            Operation::Changed {
+                string,
+                id,
+                document,
+            } => Some((Op::Changed, string.into(), id.into(), document.clone())),
             Operation::Deleted { id: _ } => None,
             Operation::Error { message } => {
-                eprintln!("{}", message);
+                tracing::warn!(error = %message, "content endpoint reported an error");
                 None
             }
         })
         .collect();
-    let strings: Vec<String> = tuples.iter().map(|(_, s, _)| s.to_string()).collect();
+    let strings: Vec<String> = tuples.iter().map(|(_, s, _, _)| s.to_string()).collect();
     let vecs: Vec<Embedding> = if strings.is_empty() {
         Vec::new()
     } else {
-        embeddings_for(key, &strings).await?
+        embed_texts(embedder, &strings)
+            .instrument(span.clone())
+            .await?
     };
     let loaded_vecs: Vec<LoadedVec> = vector_store.add_and_load_vecs(&domain, vecs.iter())?;
+    let documents: Vec<(usize, String)> = zip(tuples.iter(), loaded_vecs.iter())
+        .filter_map(|((_, _, _, document), vec)| document.clone().map(|d| (vec.id(), d)))
+        .collect();
+    if !documents.is_empty() {
+        domain.add_documents(&documents)?;
+    }
     let mut new_ops: Vec<PointOperation> = zip(tuples, loaded_vecs)
-        .map(|((op, _, id), vec)| match op {
+        .map(|((op, _, id, _), vec)| match op {
             Op::Insert => PointOperation::Insert {
                 point: Point::Stored { vec, id },
             },
@@ -133,6 +363,13 @@ pub async fn operations_to_point_operations(
         })
         .collect();
     new_ops.append(&mut delete_ops);
+    span.in_scope(|| {
+        tracing::debug!(
+            vectors = vecs.len(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "embedded operations"
+        )
+    });
     Ok(new_ops)
 }
 
@@ -150,6 +387,18 @@ pub enum IndexError {
     IoError(#[from] std::io::Error),
     #[error("Embedding error: {0:?}")]
     EmbeddingError(#[from] EmbeddingError),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Embedding model mismatch: {0}")]
+    ModelMismatch(String),
+    #[error("{0}")]
+    Store(Box<VectorlinkError>),
+}
+
+impl From<VectorlinkError> for IndexError {
+    fn from(e: VectorlinkError) -> Self {
+        IndexError::Store(Box::new(e))
+    }
 }
 
 /*
@@ -157,21 +406,68 @@ pub fn serialize_index(domain: Domain, hnsw: HnswIndex) -> io::Result<()> {}
  */
 
 pub fn start_indexing_from_operations(
+    hnsw: HnswIndex,
+    operations: Vec<PointOperation>,
+) -> Result<HnswIndex, io::Error> {
+    start_indexing_with_progress(hnsw, operations, &NoProgress)
+}
+
+/// An HNSW can only grow, changes to it are made by building a new
+/// one. Refuse those before anything is inserted, so that the index
+/// isn't given up for operations that can't be done.
+pub fn check_insertions(operations: &[PointOperation]) -> io::Result<()> {
+    for operation in operations.iter() {
+        let refused = match operation {
+            PointOperation::Insert { .. } => continue,
+            PointOperation::Replace { .. } => "replaced",
+            PointOperation::Delete { .. } => "deleted",
+        };
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("points of an HNSW index can't be {refused}, only inserted"),
+        ));
+    }
+    Ok(())
+}
+
+// Points inserted between progress reports.
+const PROGRESS_STEP: usize = 1000;
+
+/// Like [`start_indexing_from_operations`], reporting the operations
+/// done to the current stage of `progress`.
+pub fn start_indexing_with_progress(
     mut hnsw: HnswIndex,
     operations: Vec<PointOperation>,
+    progress: &dyn Progress,
 ) -> Result<HnswIndex, io::Error> {
-    let mut searcher = Searcher::default();
-    for operation in operations {
-        match operation {
-            PointOperation::Insert { point } => {
-                hnsw.insert(point.clone(), &mut searcher);
+    let _span = tracing::debug_span!(
+        "insert_points",
+        points = hnsw.layer_len(0),
+        operations = operations.len()
+    )
+    .entered();
+    check_insertions(&operations)?;
+    let start = Instant::now();
+    let hnsw = build_pool().install(move || {
+        let mut searcher = Searcher::default();
+        let total = operations.len();
+        for (i, operation) in operations.into_iter().enumerate() {
+            if let PointOperation::Insert { point } = operation {
+                hnsw.insert(point, &mut searcher);
+            }
+            if (i + 1) % PROGRESS_STEP == 0 {
+                progress.advance(PROGRESS_STEP as u64);
             }
-            PointOperation::Replace { point: _ } => todo!(),
-            PointOperation::Delete { id: _ } => todo!(),
         }
-    }
-    // Put this index somewhere!
-    //todo!()
+        progress.advance((total % PROGRESS_STEP) as u64);
+        POINTS_INSERTED.add(total as u64);
+        hnsw
+    });
+    tracing::debug!(
+        points = hnsw.layer_len(0),
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "inserted points"
+    );
     Ok(hnsw)
 }
 
@@ -179,6 +475,48 @@ pub fn start_indexing_from_operations(
 pub enum SearchError {
     #[error("Search failed for unknown reason")]
     SearchFailed,
+    #[error("Search was cancelled")]
+    Cancelled,
+}
+
+// How many candidates a scan looks at between checks for cancellation.
+pub const SCAN_BATCH: usize = 4096;
+
+/// Lets a long search or scan be stopped from the outside, or once a
+/// deadline has passed. Scans check it between batches of candidates.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            cancelled: Default::default(),
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .map(|deadline| Instant::now() >= deadline)
+                .unwrap_or(false)
+    }
+
+    pub fn check(&self) -> Result<(), SearchError> {
+        if self.is_cancelled() {
+            Err(SearchError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -200,12 +538,34 @@ impl PointQuery {
     pub fn distance(&self) -> u32 {
         self.distance
     }
+
+    /// The id of the result's vector in the domain's vector file.
+    pub fn vector_id(&self) -> usize {
+        self.point.vec_id()
+    }
+}
+
+/// The beam width used for searches that don't specify one.
+pub const DEFAULT_EF: usize = 100;
+
+pub fn search(p: &Point, num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQuery>, SearchError> {
+    search_with_ef(p, num, DEFAULT_EF, hnsw)
 }
 
-pub fn search(p: &Point, mut num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQuery>, SearchError> {
+/// Search with an explicit beam width. A larger `ef` visits more of
+/// the graph, trading latency for recall. It is never allowed to drop
+/// below `num`, as the search could then not fill the result list.
+pub fn search_with_ef(
+    p: &Point,
+    mut num: usize,
+    ef: usize,
+    hnsw: &HnswIndex,
+) -> Result<Vec<PointQuery>, SearchError> {
     // We need to set the number correctly
     // to make sure we don't go out of bounds
     let layer_len = hnsw.layer_len(0);
+    let _span = tracing::debug_span!("search", k = num, ef, points = layer_len).entered();
+    let start = Instant::now();
     if layer_len < num {
         num = layer_len;
     }
@@ -216,8 +576,22 @@ pub fn search(p: &Point, mut num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQu
     .take(num)
     .collect();
     let mut searcher = Searcher::default();
-    let ef = num.max(100);
-    hnsw.nearest(p, ef, &mut searcher, &mut output);
+    let ef = num.max(ef);
+    // On the search pool even when it is a single search, so that an
+    // index build can't take its CPU. Searches that are part of work
+    // on a pool already, like a batch or the statistics of an index,
+    // stay on that pool. Distances are counted on the thread that
+    // computes them.
+    let mut nearest = || {
+        let distances_before = DISTANCES.with(|d| d.get());
+        hnsw.nearest(p, ef, &mut searcher, &mut output);
+        SEARCH_DISTANCES.observe((DISTANCES.with(|d| d.get()) - distances_before) as f64);
+    };
+    if rayon::current_thread_index().is_some() {
+        nearest();
+    } else {
+        search_pool().install(nearest);
+    }
     let mut points = Vec::with_capacity(num);
     for elt in output {
         points.push(PointQuery {
@@ -226,117 +600,2349 @@ pub fn search(p: &Point, mut num: usize, hnsw: &HnswIndex) -> Result<Vec<PointQu
             distance: elt.distance,
         })
     }
+    SEARCHES.inc();
+    SEARCH_SECONDS.observe(start.elapsed().as_secs_f64());
+    tracing::debug!(elapsed_us = start.elapsed().as_micros() as u64, "searched");
     Ok(points)
 }
 
-pub fn serialize_index(mut path: PathBuf, name: &str, hnsw: HnswIndex) -> io::Result<()> {
-    //let name = encode(name);
-    path.push(format!("{name}.hnsw"));
-    let write_file = File::options().write(true).create(true).open(&path)?;
+/// Return the page of `num` results that follows the result with
+/// distance `last_distance` and external id `last_id`. Results are
+/// ordered by distance, with ties broken by external id, so paging
+/// is deterministic as long as the index doesn't change.
+pub fn search_after(
+    p: &Point,
+    num: usize,
+    last_distance: f32,
+    last_id: &str,
+    ef: usize,
+    hnsw: &HnswIndex,
+) -> Result<Vec<PointQuery>, SearchError> {
+    search_after_filtered(
+        p,
+        num,
+        Some((last_distance, last_id)),
+        ef,
+        hnsw,
+        |_| true,
+        &Cancellation::default(),
+    )
+}
 
-    let hnsw = hnsw.transform_features(|t| IndexPoint {
-        id: t.id().to_string(),
-        index: t.vec_id(),
-    });
-    serde_json::to_writer(write_file, &hnsw)?;
-    Ok(())
+/// Like [`search_after`], but only returns results for which `keep`
+/// holds. Without a cursor, this returns the first page.
+pub fn search_after_filtered(
+    p: &Point,
+    num: usize,
+    after: Option<(f32, &str)>,
+    ef: usize,
+    hnsw: &HnswIndex,
+    keep: impl Fn(&PointQuery) -> bool,
+    cancel: &Cancellation,
+) -> Result<Vec<PointQuery>, SearchError> {
+    if num == 0 {
+        return Ok(Vec::new());
+    }
+    let len = hnsw.layer_len(0);
+    let cursor = after.map(|(distance, id)| (distance.to_bits(), id));
+    // We don't know how many results precede the cursor, so keep
+    // fetching more until the page is full or the index is exhausted.
+    let mut fetch = num.saturating_mul(2);
+    loop {
+        cancel.check()?;
+        let mut results = search_with_ef(p, fetch, ef.max(fetch), hnsw)?;
+        results.sort_by(|a, b| (a.distance, a.id()).cmp(&(b.distance, b.id())));
+        let page: Vec<_> = results
+            .into_iter()
+            .filter(|r| cursor.map(|c| (r.distance, r.id()) > c).unwrap_or(true))
+            .filter(&keep)
+            .take(num)
+            .collect();
+        if page.len() == num || fetch >= len {
+            return Ok(page);
+        }
+        fetch = fetch.saturating_mul(2);
+    }
 }
 
-pub fn create_index_name(domain: &str, commit: &str) -> String {
-    let domain = encode(domain);
-    format!("{}@{}", domain, commit)
+/// Like [`search_after_filtered`], but compares `p` with every point
+/// for which `among` holds, rather than searching the graph. When few
+/// points can pass a filter, this is both faster and exact, as the
+/// graph search would have to widen until it found them all. Results
+/// are ordered the same way, so cursors carry over.
+pub fn search_after_among(
+    p: &Point,
+    num: usize,
+    after: Option<(f32, &str)>,
+    hnsw: &HnswIndex,
+    among: impl Fn(&Point) -> bool,
+    keep: impl Fn(&PointQuery) -> bool,
+    cancel: &Cancellation,
+) -> Result<Vec<PointQuery>, SearchError> {
+    let len = hnsw.layer_len(0);
+    let _span = tracing::debug_span!("search_among", k = num, points = len).entered();
+    let cursor = after.map(|(distance, id)| (distance.to_bits(), id));
+    let mut results = Vec::new();
+    for start in (0..len).step_by(SCAN_BATCH) {
+        cancel.check()?;
+        for id in start..(start + SCAN_BATCH).min(len) {
+            let point = hnsw.feature(id);
+            if !among(point) {
+                continue;
+            }
+            let result = PointQuery {
+                id,
+                point: point.clone(),
+                distance: OpenAI.distance(p, point),
+            };
+            if cursor
+                .map(|c| (result.distance, result.id()) > c)
+                .unwrap_or(true)
+                && keep(&result)
+            {
+                results.push(result);
+            }
+        }
+    }
+    results.sort_by(|a, b| (a.distance, a.id()).cmp(&(b.distance, b.id())));
+    results.truncate(num);
+    Ok(results)
 }
 
-pub fn parse_index_name(name: &str) -> (String, String) {
-    let (domain, commit) = name.split_once('@').unwrap();
-    let domain = decode(domain).unwrap();
-    (domain.to_string(), commit.to_string())
+/// Return the `num` nearest results for which `keep` holds. Like
+/// [`search_after`], this keeps widening the search until enough
+/// results pass the filter or the index is exhausted.
+pub fn search_filtered(
+    p: &Point,
+    num: usize,
+    ef: usize,
+    hnsw: &HnswIndex,
+    keep: impl Fn(&PointQuery) -> bool,
+) -> Result<Vec<PointQuery>, SearchError> {
+    if num == 0 {
+        return Ok(Vec::new());
+    }
+    let len = hnsw.layer_len(0);
+    let mut fetch = num;
+    loop {
+        let results: Vec<_> = search_with_ef(p, fetch, ef.max(fetch), hnsw)?
+            .into_iter()
+            .filter(&keep)
+            .take(num)
+            .collect();
+        if results.len() == num || fetch >= len {
+            return Ok(results);
+        }
+        fetch = fetch.saturating_mul(2);
+    }
 }
 
-pub fn deserialize_index(
-    path: &mut PathBuf,
-    name: &str,
-    vector_store: &VectorStore,
-) -> io::Result<HnswIndex> {
-    path.push(format!("{name}.hnsw"));
-    let (domain, _) = parse_index_name(name);
-    let read_file = File::options().read(true).open(&path)?;
-    let hnsw: HnswStorageIndex = serde_json::from_reader(read_file).unwrap();
-    let domain = vector_store.get_domain(&domain)?;
-    let hnsw = hnsw.transform_features(|t| Point::Stored {
-        id: t.id,
-        vec: vector_store.get_vec(&domain, t.index).unwrap().unwrap(),
-    });
-    Ok(hnsw)
+/// Search for the `num` nearest neighbours of every query, spreading
+/// the queries over the search pool. All queries share the same beam
+/// width. The results are returned in the same order as the queries.
+pub fn search_batch(
+    queries: &[Point],
+    num: usize,
+    ef: usize,
+    hnsw: &HnswIndex,
+) -> Result<Vec<Vec<PointQuery>>, SearchError> {
+    search_pool().install(|| {
+        queries
+            .par_iter()
+            .map(|q| search_with_ef(q, num, ef, hnsw))
+            .collect()
+    })
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::vectors::VectorStore;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Aggregation {
+    /// Score a document by its best matching chunk.
+    Max,
+    /// Score a document by the mean distance of its matching chunks.
+    Mean,
+}
 
-    use super::*;
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DocumentQuery {
+    id: String,
+    distance: f32,
+    chunks: usize,
+}
 
-    #[test]
-    fn low_dimensional_search() {
-        let tempdir = tempfile::tempdir().unwrap();
-        let path = tempdir.path();
-        let store = VectorStore::new(path, 2);
+impl DocumentQuery {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
 
-        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536], [0.0; 1536]]
-            .into_iter()
-            .collect();
-        vector_block[0][0] = 1.0;
-        vector_block[1][1] = 1.0;
-        vector_block[2][0] = -1.0;
-        vector_block[3][1] = -1.0;
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+}
 
-        let domain = store.get_domain("foo").unwrap();
-        let ids = store.add_vecs(&domain, vector_block.iter()).unwrap();
-        let e1 = store.get_vec(&domain, ids[0]).unwrap().unwrap();
-        let e2 = store.get_vec(&domain, ids[1]).unwrap().unwrap();
-        let e3 = store.get_vec(&domain, ids[2]).unwrap().unwrap();
-        let e4 = store.get_vec(&domain, ids[3]).unwrap().unwrap();
+// How many chunks to retrieve per requested document.
+const CHUNKS_PER_DOCUMENT: usize = 4;
 
-        let operations: Vec<_> = [
-            PointOperation::Insert {
-                point: Point::Stored {
-                    id: "Point/1".to_string(),
-                    vec: e1.clone(),
-                },
-            },
-            PointOperation::Insert {
-                point: Point::Stored {
-                    id: "Point/2".to_string(),
-                    vec: e2.clone(),
-                },
-            },
-            PointOperation::Insert {
-                point: Point::Stored {
-                    id: "Point/3".to_string(),
-                    vec: e3,
-                },
-            },
-            PointOperation::Insert {
-                point: Point::Stored {
-                    id: "Point/4".to_string(),
-                    vec: e4,
-                },
+/// Search for the `num` nearest documents, where a document may be
+/// made up of several chunk vectors. The chunk results are grouped by
+/// the document the domain records for their vector, with vectors that
+/// are not part of any document standing on their own. Only the chunks
+/// found by the search take part in the aggregation.
+pub fn search_documents(
+    p: &Point,
+    num: usize,
+    ef: usize,
+    aggregation: Aggregation,
+    domain: &Domain,
+    hnsw: &HnswIndex,
+) -> Result<Vec<DocumentQuery>, SearchError> {
+    let results = search_with_ef(p, num.saturating_mul(CHUNKS_PER_DOCUMENT), ef, hnsw)?;
+    // document id -> (best distance, sum of distances, number of chunks)
+    let mut documents: HashMap<String, (f32, f32, usize)> = HashMap::new();
+    for result in results {
+        if domain.is_deleted(result.point.vec_id()) {
+            continue;
+        }
+        let distance = f32::from_bits(result.distance());
+        let document = domain
+            .document(result.point.vec_id())
+            .unwrap_or_else(|| result.id().to_string());
+        let entry = documents.entry(document).or_insert((f32::MAX, 0.0, 0));
+        entry.0 = entry.0.min(distance);
+        entry.1 += distance;
+        entry.2 += 1;
+    }
+    let mut documents: Vec<DocumentQuery> = documents
+        .into_iter()
+        .map(|(id, (best, sum, chunks))| DocumentQuery {
+            id,
+            distance: match aggregation {
+                Aggregation::Max => best,
+                Aggregation::Mean => sum / chunks as f32,
             },
-        ]
+            chunks,
+        })
+        .collect();
+    documents.sort_by(|a, b| {
+        a.distance
+            .total_cmp(&b.distance)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    documents.truncate(num);
+    Ok(documents)
+}
+
+/// How the vector and the keyword rankings of a hybrid search are
+/// combined.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Fusion {
+    /// Reciprocal rank fusion. A point scores `1 / (k + rank)` for
+    /// every ranking it is in, so only ranks matter.
+    Rrf {
+        #[serde(default = "default_rrf_k")]
+        k: f32,
+    },
+    /// The similarity to the query vector and the BM25 score, scaled
+    /// to the best keyword match, weighted by `vector_weight` and the
+    /// rest.
+    Weighted {
+        #[serde(default = "default_vector_weight")]
+        vector_weight: f32,
+    },
+}
+
+fn default_rrf_k() -> f32 {
+    60.0
+}
+
+fn default_vector_weight() -> f32 {
+    0.5
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::Rrf { k: default_rrf_k() }
+    }
+}
+
+impl Fusion {
+    /// What is wrong with this fusion, if anything. The first rank
+    /// scores `1 / (k + 1)`, so `k` has to be more than -1, and the
+    /// weight of the vector part is a share.
+    pub fn problem(&self) -> Option<String> {
+        match *self {
+            Fusion::Rrf { k } if !(k > -1.0 && k.is_finite()) => Some(format!(
+                "the k of rrf fusion has to be more than -1, not {k}"
+            )),
+            Fusion::Weighted { vector_weight } if !(0.0..=1.0).contains(&vector_weight) => {
+                Some(format!(
+                    "the vector_weight of weighted fusion goes from 0 to 1, not {vector_weight}"
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HybridQuery {
+    point: PointQuery,
+    score: f32,
+}
+
+impl HybridQuery {
+    /// The point, with its distance to the query vector.
+    pub fn point(&self) -> &PointQuery {
+        &self.point
+    }
+
+    /// The fused score, higher is better.
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+}
+
+// The least number of candidates each ranking of a hybrid search
+// contributes, so that points ranked low by one ranking and high by
+// the other are found.
+const HYBRID_CANDIDATES: usize = 100;
+
+/// What a hybrid search ranks points by, next to their distance to
+/// the query vector.
+pub enum Terms<'a> {
+    /// The BM25 score of their text for some keywords.
+    Keywords(&'a str, &'a LexicalIndex),
+    /// The dot product of their sparse vector with a sparse query.
+    Sparse(&'a SparseVector, &'a SparseIndex),
+}
+
+/// Search for the `num` points that rank best by both their distance
+/// to `p` and their score for `terms`, combined by `fusion`.
+/// Candidates come from an approximate search and from the index of
+/// the terms, and `keep` applies to both.
+#[allow(clippy::too_many_arguments)]
+pub fn search_hybrid(
+    p: &Point,
+    terms: Terms,
+    num: usize,
+    ef: usize,
+    hnsw: &HnswIndex,
+    fusion: Fusion,
+    keep: impl Fn(&PointQuery) -> bool,
+    cancel: &Cancellation,
+) -> Result<Vec<HybridQuery>, SearchError> {
+    let candidates = num.max(HYBRID_CANDIDATES);
+    let by_vector = search_after_filtered(p, candidates, None, ef, hnsw, &keep, cancel)?;
+    cancel.check()?;
+    let in_index = |i: usize| i < hnsw.layer_len(0);
+    let by_terms = match terms {
+        Terms::Keywords(keywords, lexical) => lexical.search(keywords, usize::MAX, in_index),
+        Terms::Sparse(query, sparse) => sparse.search(query, usize::MAX, in_index),
+    };
+    let by_terms: Vec<(PointQuery, f32)> = by_terms
         .into_iter()
+        .map(|(i, score)| {
+            let point = hnsw.feature(i).clone();
+            let query = PointQuery {
+                id: i,
+                distance: OpenAI.distance(p, &point),
+                point,
+            };
+            (query, score)
+        })
+        .filter(|(query, _)| keep(query))
+        .take(candidates)
         .collect();
-        let hnsw = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
-        let mut candidate_vec: Embedding = [0.0; 1536];
-        candidate_vec[0] = 0.707;
-        candidate_vec[1] = 0.707;
 
-        let p = Point::Mem {
-            vec: Box::new(candidate_vec),
+    let best_term_score = by_terms.first().map(|(_, score)| *score).unwrap_or(0.0);
+    let mut fused: HashMap<usize, HybridQuery> = HashMap::new();
+    let mut add = |query: &PointQuery, score: f32| {
+        fused
+            .entry(query.id)
+            .or_insert_with(|| HybridQuery {
+                point: query.clone(),
+                score: 0.0,
+            })
+            .score += score;
+    };
+    match fusion {
+        Fusion::Rrf { k } => {
+            for (rank, query) in by_vector.iter().enumerate() {
+                add(query, 1.0 / (k + rank as f32 + 1.0));
+            }
+            for (rank, (query, _)) in by_terms.iter().enumerate() {
+                add(query, 1.0 / (k + rank as f32 + 1.0));
+            }
+        }
+        Fusion::Weighted { vector_weight } => {
+            // Every candidate has a distance, so every candidate gets
+            // its vector part, whichever ranking it came from.
+            let similarity = |query: &PointQuery| 1.0 - f32::from_bits(query.distance);
+            for query in by_vector.iter() {
+                add(query, 0.0);
+            }
+            for (query, score) in by_terms.iter() {
+                add(query, (1.0 - vector_weight) * score / best_term_score);
+            }
+            for query in fused.values_mut() {
+                query.score += vector_weight * similarity(&query.point);
+            }
+        }
+    }
+
+    let mut fused: Vec<HybridQuery> = fused.into_values().collect();
+    fused.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.point.distance.cmp(&b.point.distance))
+            .then_with(|| a.point.id().cmp(b.point.id()))
+    });
+    fused.truncate(num);
+    Ok(fused)
+}
+
+/// Maximal marginal relevance, which reranks the nearest results of a
+/// search so that they are not all alike.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mmr {
+    /// How much relevance counts against diversity, from 0 to 1. At 1
+    /// results stay in order of distance.
+    #[serde(default = "default_mmr_lambda")]
+    pub lambda: f32,
+    /// How many of the nearest results to choose from. Defaults to
+    /// four times as many as are returned.
+    #[serde(default)]
+    pub candidates: Option<usize>,
+}
+
+fn default_mmr_lambda() -> f32 {
+    0.5
+}
+
+/// The most of the nearest results that a search reranks, unless it
+/// returns more than that.
+pub const MAX_RERANK_CANDIDATES: usize = 10_000;
+
+impl Mmr {
+    pub fn candidates(&self, num: usize) -> usize {
+        self.candidates
+            .unwrap_or(num.saturating_mul(4))
+            .min(MAX_RERANK_CANDIDATES)
+            .max(num)
+    }
+}
+
+/// Pick `num` of the candidates for `p`, each time the one for which
+/// `lambda` times its distance to `p` is least, less the rest times its
+/// distance to the nearest candidate picked before. Distances come
+/// from `distance`, so this works for any metric.
+pub fn rerank_mmr(
+    p: &Point,
+    candidates: Vec<PointQuery>,
+    num: usize,
+    lambda: f32,
+    distance: impl Fn(&Point, &Point) -> f32,
+) -> Vec<PointQuery> {
+    let relevance: Vec<f32> = candidates.iter().map(|c| distance(p, &c.point)).collect();
+    // the distance of every candidate to the nearest one picked
+    let mut nearest_picked: Vec<Option<f32>> = vec![None; candidates.len()];
+    let mut picked: Vec<usize> = Vec::with_capacity(num.min(candidates.len()));
+    while picked.len() < num {
+        let best = (0..candidates.len())
+            .filter(|i| !picked.contains(i))
+            .map(|i| {
+                let diversity = nearest_picked[i].unwrap_or(0.0);
+                (i, lambda * relevance[i] - (1.0 - lambda) * diversity)
+            })
+            .min_by(|(a, a_score), (b, b_score)| a_score.total_cmp(b_score).then(a.cmp(b)));
+        let Some((best, _)) = best else {
+            break;
         };
-        let points = search(&p, 4, &hnsw).unwrap();
-        let p1 = &points[0];
-        let p2 = &points[1];
-        assert_eq!(*p1.point.vec(), *e1);
-        assert_eq!(*p2.point.vec(), *e2);
+        picked.push(best);
+        for (i, candidate) in candidates.iter().enumerate() {
+            let d = distance(&candidates[best].point, &candidate.point);
+            nearest_picked[i] = Some(nearest_picked[i].map_or(d, |n| n.min(d)));
+        }
+    }
+    let mut candidates: Vec<Option<PointQuery>> = candidates.into_iter().map(Some).collect();
+    picked
+        .into_iter()
+        .filter_map(|i| candidates[i].take())
+        .collect()
+}
+
+/// Exhaustively compare `p` against every point in the index,
+/// returning the exact `num` nearest neighbours. This ignores the
+/// graph structure entirely, which makes it suitable for small
+/// domains and for validating the recall of the approximate search.
+pub fn search_exact(
+    p: &Point,
+    num: usize,
+    hnsw: &HnswIndex,
+) -> Result<Vec<PointQuery>, SearchError> {
+    search_exact_cancellable(p, num, hnsw, &Cancellation::default())
+}
+
+/// Like [`search_exact`], but gives up between batches of candidates
+/// once `cancel` fires.
+pub fn search_exact_cancellable(
+    p: &Point,
+    num: usize,
+    hnsw: &HnswIndex,
+    cancel: &Cancellation,
+) -> Result<Vec<PointQuery>, SearchError> {
+    let len = hnsw.layer_len(0);
+    let _span = tracing::debug_span!("search_exact", k = num, points = len).entered();
+    let start = Instant::now();
+    let mut distances: Vec<(usize, u32)> = Vec::with_capacity(len);
+    search_pool().install(|| {
+        for start in (0..len).step_by(SCAN_BATCH) {
+            cancel.check()?;
+            let end = (start + SCAN_BATCH).min(len);
+            distances.par_extend(
+                (start..end)
+                    .into_par_iter()
+                    .map(|i| (i, OpenAI.distance(p, hnsw.feature(i)))),
+            );
+        }
+        distances.par_sort_unstable_by_key(|(i, distance)| (*distance, *i));
+        Ok::<_, SearchError>(())
+    })?;
+    distances.truncate(num);
+    tracing::debug!(
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "searched exactly"
+    );
+    Ok(distances
+        .into_iter()
+        .map(|(id, distance)| PointQuery {
+            id,
+            point: hnsw.feature(id).clone(),
+            distance,
+        })
+        .collect())
+}
+
+/// Find the `k` nearest neighbours of every point in the index,
+/// excluding the point itself. The outer vector is indexed by internal
+/// id, and each neighbour is an internal id paired with its distance.
+pub fn knn_graph(
+    hnsw: &HnswIndex,
+    k: usize,
+    ef: usize,
+    progress: &dyn Progress,
+) -> Vec<Vec<(usize, f32)>> {
+    progress.stage("searching neighbours", Some(hnsw.layer_len(0) as u64));
+    build_pool().install(|| {
+        (0..hnsw.layer_len(0))
+            .into_par_iter()
+            .map(|i| {
+                let neighbours = search_with_ef(hnsw.feature(i), k + 1, ef, hnsw)
+                    .map(|results| {
+                        results
+                            .into_iter()
+                            .filter(|r| r.internal_id() != i)
+                            .take(k)
+                            .map(|r| (r.internal_id(), f32::from_bits(r.distance())))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                progress.advance(1);
+                neighbours
+            })
+            .collect()
+    })
+}
+
+#[derive(Serialize)]
+struct KnnGraphNeighbor<'a> {
+    id: &'a str,
+    distance: f32,
+}
+
+#[derive(Serialize)]
+struct KnnGraphLine<'a> {
+    id: &'a str,
+    neighbors: Vec<KnnGraphNeighbor<'a>>,
+}
+
+/// Write a kNN graph as JSON lines, one line per point, using external ids.
+pub fn write_knn_graph_jsonl<W: Write>(
+    hnsw: &HnswIndex,
+    graph: &[Vec<(usize, f32)>],
+    mut writer: W,
+) -> io::Result<()> {
+    for (i, neighbors) in graph.iter().enumerate() {
+        let line = KnnGraphLine {
+            id: hnsw.feature(i).id(),
+            neighbors: neighbors
+                .iter()
+                .map(|(j, distance)| KnnGraphNeighbor {
+                    id: hnsw.feature(*j).id(),
+                    distance: *distance,
+                })
+                .collect(),
+        };
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+/// Write a kNN graph in a compact little-endian binary format. The
+/// file starts with the number of points as a u64. Then, for every
+/// point in internal id order, follows the number of neighbours as a
+/// u32 and that many pairs of a u64 internal id and an f32 distance.
+/// Internal ids match the line order of the JSON lines format.
+pub fn write_knn_graph_binary<W: Write>(
+    graph: &[Vec<(usize, f32)>],
+    mut writer: W,
+) -> io::Result<()> {
+    writer.write_all(&(graph.len() as u64).to_le_bytes())?;
+    for neighbors in graph {
+        writer.write_all(&(neighbors.len() as u32).to_le_bytes())?;
+        for (j, distance) in neighbors {
+            writer.write_all(&(*j as u64).to_le_bytes())?;
+            writer.write_all(&distance.to_le_bytes())?;
+        }
+    }
+    writer.flush()
+}
+
+/// What a point is compared with to tell whether it is an outlier.
+pub enum OutlierMethod<'a> {
+    /// Its `k` nearest neighbours, found with a search of width `ef`.
+    /// The score is the mean distance to them.
+    Neighbors { k: usize, ef: usize },
+    /// The centroid of its cluster, or the nearest centroid for points
+    /// added after the clustering. The score is the distance to it.
+    Centroid(&'a Clustering),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Outlier {
+    pub id: String,
+    pub score: f32,
+}
+
+/// The points of the index scoring above `threshold`, highest score
+/// first. Vectors far from everything else are mostly garbage, like
+/// embeddings of empty or truncated texts, or vectors of the wrong
+/// model. Points deleted from the domain are neither scored nor
+/// counted as neighbours.
+pub fn find_outliers(
+    hnsw: &HnswIndex,
+    domain: &Domain,
+    method: &OutlierMethod,
+    threshold: f32,
+    progress: &dyn Progress,
+) -> Result<Vec<Outlier>, SearchError> {
+    let len = hnsw.layer_len(0);
+    progress.stage("scoring points", Some(len as u64));
+    let scored: Vec<Option<(usize, f32)>> = build_pool().install(|| {
+        (0..len)
+            .into_par_iter()
+            .map(|i| {
+                let point = hnsw.feature(i);
+                if domain.is_deleted(point.vec_id()) {
+                    progress.advance(1);
+                    return Ok(None);
+                }
+                let score = match method {
+                    OutlierMethod::Neighbors { k, ef } => {
+                        let distances: Vec<f32> = search_with_ef(point, k + 1, *ef, hnsw)?
+                            .into_iter()
+                            .filter(|r| r.internal_id() != i && !domain.is_deleted(r.vector_id()))
+                            .take(*k)
+                            .map(|r| f32::from_bits(r.distance()))
+                            .collect();
+                        (!distances.is_empty())
+                            .then(|| distances.iter().sum::<f32>() / distances.len() as f32)
+                    }
+                    OutlierMethod::Centroid(clustering) => match clustering
+                        .assignments
+                        .get(point.vec_id())
+                        .copied()
+                        .flatten()
+                    {
+                        Some(cluster) => Some(vecmath::normalized_cosine_distance(
+                            &clustering.centroids[cluster as usize],
+                            point.vec(),
+                        )),
+                        None if clustering.centroids.is_empty() => None,
+                        None => {
+                            Some(kmeans::nearest_centroid(&clustering.centroids, point.vec()).1)
+                        }
+                    },
+                };
+                progress.advance(1);
+                Ok(score
+                    .filter(|score| *score > threshold)
+                    .map(|score| (i, score)))
+            })
+            .collect::<Result<_, SearchError>>()
+    })?;
+    let mut outliers: Vec<(usize, f32)> = scored.into_iter().flatten().collect();
+    outliers.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+    Ok(outliers
+        .into_iter()
+        .map(|(i, score)| Outlier {
+            id: hnsw.feature(i).id().to_string(),
+            score,
+        })
+        .collect())
+}
+
+/// Find the pairs of points of an index that are closer than
+/// `threshold`, as their internal ids with the lower one first. Every
+/// point is only compared with its nearest neighbours.
+pub fn find_duplicates(
+    hnsw: &HnswIndex,
+    domain: &Domain,
+    threshold: f32,
+    cancel: &Cancellation,
+    progress: &dyn Progress,
+) -> Result<Vec<(usize, usize)>, SearchError> {
+    let len = hnsw.layer_len(0);
+    progress.stage("searching for duplicates", Some(len as u64));
+    let mut duplicates = Vec::new();
+    for start in (0..len).step_by(SCAN_BATCH) {
+        cancel.check()?;
+        let end = (start + SCAN_BATCH).min(len);
+        for i in start..end {
+            let point = hnsw.feature(i);
+            if domain.is_deleted(point.vec_id()) {
+                continue;
+            }
+            for result in search(point, 2, hnsw)? {
+                if result.internal_id() > i
+                    && f32::from_bits(result.distance()) < threshold
+                    && !domain.is_deleted(result.vector_id())
+                {
+                    duplicates.push((i, result.internal_id()));
+                }
+            }
+        }
+        progress.advance((end - start) as u64);
+    }
+    Ok(duplicates)
+}
+
+// How many results a node's search for its own vector may return
+// before we consider that node unreachable.
+const REACHABILITY_PROBE: usize = 10;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct IndexStatistics {
+    /// Number of nodes in each layer, starting at the bottom layer.
+    layer_sizes: Vec<usize>,
+    /// The smallest out-degree of a node in each layer.
+    min_neighbors: Vec<usize>,
+    /// The mean out-degree of the nodes in each layer.
+    mean_neighbors: Vec<f64>,
+    /// The largest out-degree of a node in each layer.
+    max_neighbors: Vec<usize>,
+    /// Nodes that a search for their own vector does not find.
+    unreachable: usize,
+    /// Rough estimate of the memory used by the graph and the vectors
+    /// it points at, in bytes.
+    estimated_memory: usize,
+}
+
+impl IndexStatistics {
+    pub fn unreachable(&self) -> usize {
+        self.unreachable
+    }
+
+    pub fn estimated_memory(&self) -> usize {
+        self.estimated_memory
+    }
+}
+
+/// Collect statistics about the shape of an index. Finding
+/// unreachable nodes requires a search per node, so this is about as
+/// expensive as a duplicate scan.
+pub fn index_statistics(hnsw: &HnswIndex, progress: &dyn Progress) -> IndexStatistics {
+    let layer_sizes: Vec<usize> = (0..hnsw.layers()).map(|l| hnsw.layer_len(l)).collect();
+    let links = GraphLinks::of(&hnsw.clone().transform_features(|_| ()));
+    let mut min_neighbors = Vec::new();
+    let mut mean_neighbors = Vec::new();
+    let mut max_neighbors = Vec::new();
+    for nodes in links.layers() {
+        let counts = nodes.iter().map(|list| list.len());
+        min_neighbors.push(counts.clone().min().unwrap_or(0));
+        max_neighbors.push(counts.clone().max().unwrap_or(0));
+        mean_neighbors.push(counts.sum::<usize>() as f64 / nodes.len().max(1) as f64);
+    }
+    let len = hnsw.layer_len(0);
+    progress.stage("checking reachability", Some(len as u64));
+    let unreachable = build_pool().install(|| {
+        (0..len)
+            .into_par_iter()
+            .filter(|&i| {
+                let unreachable = search(hnsw.feature(i), REACHABILITY_PROBE, hnsw)
+                    .map(|results| !results.iter().any(|r| r.internal_id() == i))
+                    .unwrap_or(true);
+                progress.advance(1);
+                unreachable
+            })
+            .count()
+    });
+
+    let ids: usize = (0..len).map(|i| hnsw.feature(i).id().len()).sum();
+    let upper_layers: usize = layer_sizes.iter().skip(1).sum();
+    let estimated_memory = len * (std::mem::size_of::<Point>() + vecmath::EMBEDDING_BYTE_LENGTH)
+        + ids
+        + graph_memory(len, upper_layers, M, M0);
+
+    IndexStatistics {
+        layer_sizes,
+        min_neighbors,
+        mean_neighbors,
+        max_neighbors,
+        unreachable,
+        estimated_memory,
+    }
+}
+
+fn graph_memory(len: usize, upper_layers: usize, m: usize, m0: usize) -> usize {
+    len * m0 * std::mem::size_of::<usize>() + upper_layers * (m + 2) * std::mem::size_of::<usize>()
+}
+
+// Ids are usually IRIs. This is a guess for when we don't know them yet.
+const ESTIMATED_ID_LENGTH: usize = 64;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// Peak bytes needed while building and serializing an index.
+    build: usize,
+    /// Bytes needed to keep the index loaded for searching.
+    serving: usize,
+}
+
+impl MemoryEstimate {
+    pub fn build(&self) -> usize {
+        self.build
+    }
+
+    pub fn serving(&self) -> usize {
+        self.serving
+    }
+}
+
+/// Predict how much memory building and serving an index will take,
+/// without loading anything. This uses the same accounting as
+/// [`index_statistics`], with the number of upper layer nodes taken
+/// from the expected level distribution. Vectors are counted with f32
+/// components, as the vector store keeps them.
+pub fn estimate_memory(
+    num_vectors: usize,
+    dimension: usize,
+    m: usize,
+    m0: usize,
+) -> MemoryEstimate {
+    let vectors = num_vectors * dimension * std::mem::size_of::<f32>();
+    let points = num_vectors * (std::mem::size_of::<Point>() + ESTIMATED_ID_LENGTH);
+    // a node reaches layer l with probability 1/m^l
+    let upper_layers = num_vectors / m.max(2).saturating_sub(1);
+    let graph = graph_memory(num_vectors, upper_layers, m, m0);
+
+    let serving = vectors + points + graph;
+    // While building, the operation list holds a second copy of the
+    // points, and serializing makes a copy of the graph with storage
+    // features.
+    let build = serving
+        + points
+        + graph
+        + num_vectors * (std::mem::size_of::<IndexPoint>() + ESTIMATED_ID_LENGTH);
+
+    MemoryEstimate { build, serving }
+}
+
+/// An inverted file index. Points are partitioned over a set of
+/// centroids found with spherical k-means, and a search only scans the
+/// lists belonging to the `nprobe` centroids nearest to the query.
+/// Unlike the HNSW, inserts and deletes are cheap, and memory use is
+/// just the points plus the centroids.
+///
+/// This only lives in memory: it isn't serialized, loaded or chosen
+/// for a domain, which are all indexed with an HNSW.
+#[derive(Clone, Debug)]
+pub struct IvfIndex {
+    centroids: Vec<Embedding>,
+    lists: Vec<Vec<(usize, Point)>>,
+    next_id: usize,
+}
+
+impl IvfIndex {
+    /// Train `num_lists` centroids over the given sample of points. The
+    /// initial centroids are picked at random, using `seed` if given.
+    /// Every iteration is a step of `progress`.
+    pub fn train(
+        sample: &[Point],
+        num_lists: usize,
+        iterations: usize,
+        seed: Option<u64>,
+        progress: &dyn Progress,
+    ) -> Self {
+        let num_lists = num_lists.min(sample.len()).max(1);
+        let _span = tracing::info_span!(
+            "train_ivf",
+            sample = sample.len(),
+            lists = num_lists,
+            iterations
+        )
+        .entered();
+        let start = Instant::now();
+        let centroids: Vec<Embedding> = if sample.is_empty() {
+            vec![vecmath::empty_embedding()]
+        } else {
+            let indices = match seed {
+                Some(seed) => rand::seq::index::sample(
+                    &mut Lcg128Xsl64::seed_from_u64(seed),
+                    sample.len(),
+                    num_lists,
+                ),
+                None => rand::seq::index::sample(&mut rand::thread_rng(), sample.len(), num_lists),
+            };
+            indices.into_iter().map(|i| *sample[i].vec()).collect()
+        };
+
+        progress.stage("training centroids", Some(iterations as u64));
+        let vecs: Vec<&Embedding> = sample.iter().map(|p| p.vec()).collect();
+        let centroids = kmeans::train(centroids, iterations, progress, |f| {
+            f(&vecs);
+            Ok::<_, Infallible>(())
+        })
+        .unwrap_or_else(|never| match never {});
+
+        CENTROID_TRAINING_POINTS.add((sample.len() * iterations) as u64);
+        CENTROID_TRAINING_SECONDS.observe(start.elapsed().as_secs_f64());
+        tracing::info!(
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "trained centroids"
+        );
+        let lists = vec![Vec::new(); centroids.len()];
+        Self {
+            centroids,
+            lists,
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(|l| l.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn num_lists(&self) -> usize {
+        self.centroids.len()
+    }
+
+    pub fn insert(&mut self, point: Point) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (list, _) = kmeans::nearest_centroid(&self.centroids, point.vec());
+        self.lists[list].push((id, point));
+
+        id
+    }
+
+    /// Remove all points with the given external id, returning whether
+    /// anything was removed.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let mut removed = false;
+        for list in self.lists.iter_mut() {
+            let len = list.len();
+            list.retain(|(_, p)| p.id() != id);
+            removed |= list.len() != len;
+        }
+
+        removed
+    }
+
+    pub fn search(&self, p: &Point, num: usize, nprobe: usize) -> Vec<PointQuery> {
+        let mut lists: Vec<(usize, u32)> = self
+            .centroids
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i, vecmath::normalized_cosine_distance(c, p.vec()).to_bits()))
+            .collect();
+        lists.sort_unstable_by_key(|(i, distance)| (*distance, *i));
+        lists.truncate(nprobe.max(1));
+
+        let mut candidates: Vec<(u32, usize, &Point)> = search_pool().install(|| {
+            let mut candidates: Vec<_> = lists
+                .par_iter()
+                .flat_map(|(list, _)| self.lists[*list].par_iter())
+                .map(|(id, point)| (OpenAI.distance(p, point), *id, point))
+                .collect();
+            candidates.par_sort_unstable_by_key(|(distance, id, _)| (*distance, *id));
+            candidates
+        });
+        candidates.truncate(num);
+        candidates
+            .into_iter()
+            .map(|(distance, id, point)| PointQuery {
+                id,
+                point: point.clone(),
+                distance,
+            })
+            .collect()
+    }
+}
+
+pub fn start_ivf_indexing_from_operations(
+    mut ivf: IvfIndex,
+    operations: Vec<PointOperation>,
+) -> Result<IvfIndex, io::Error> {
+    let _span = tracing::debug_span!(
+        "insert_ivf_points",
+        points = ivf.len(),
+        operations = operations.len()
+    )
+    .entered();
+    for operation in operations {
+        match operation {
+            PointOperation::Insert { point } => {
+                ivf.insert(point);
+            }
+            PointOperation::Replace { point } => {
+                ivf.remove(point.id());
+                ivf.insert(point);
+            }
+            PointOperation::Delete { id } => {
+                ivf.remove(&id);
+            }
+        }
+    }
+    Ok(ivf)
+}
+
+/// Write an index to `{name}.hnsw` in `path`, sealed with `cipher` if
+/// there is one. It is written to a temporary file first, so an index
+/// file on disk is always complete.
+pub fn serialize_index(
+    mut path: PathBuf,
+    name: &str,
+    hnsw: HnswIndex,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let _span =
+        tracing::info_span!("write_index", index = name, points = hnsw.layer_len(0)).entered();
+    let start = Instant::now();
+    //let name = encode(name);
+    path.push(format!("{name}.hnsw"));
+    let mut tmp_path = path.clone();
+    tmp_path.set_extension("hnsw.tmp");
+    let write_file = File::create(&tmp_path)?;
+
+    let hnsw = hnsw.transform_features(|t| IndexPoint {
+        id: t.id().to_string(),
+        index: t.vec_id(),
+    });
+    encryption::write_json(cipher, index_aad(name).as_bytes(), &write_file, &hnsw)?;
+    write_file.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+    tracing::info!(
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "wrote index"
+    );
+    Ok(())
+}
+
+pub fn create_index_name(domain: &str, commit: &str) -> String {
+    let domain = encode(domain);
+    format!("{}@{}", domain, commit)
+}
+
+pub fn parse_index_name(name: &str) -> error::Result<(String, String)> {
+    let invalid = || VectorlinkError::InvalidIndexName(name.to_string());
+    let (domain, commit) = name.split_once('@').ok_or_else(invalid)?;
+    let domain = decode(domain).map_err(|_| invalid())?;
+    Ok((domain.to_string(), commit.to_string()))
+}
+
+/// Touch the vectors of every point in the index, starting at the top
+/// layer, so that the pages backing them are resident before the first
+/// search needs them. Returns the number of points visited.
+pub fn warm_up_index(hnsw: &HnswIndex) -> usize {
+    let _span = tracing::info_span!("warm_up_index", points = hnsw.layer_len(0)).entered();
+    let start = Instant::now();
+    let mut sum = 0.0;
+    for layer in (0..hnsw.layers()).rev() {
+        for i in 0..hnsw.layer_len(layer) {
+            let vec = hnsw.layer_feature(layer, i).vec();
+            // one read per 4K page of the embedding is enough
+            for f in vec.iter().step_by(1024) {
+                sum += f;
+            }
+        }
+    }
+    std::hint::black_box(sum);
+    tracing::info!(
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "warmed up index"
+    );
+
+    hnsw.layer_len(0)
+}
+
+/// Split a `domain@commit` string as given on the command line into an
+/// index name.
+pub fn index_name_from_spec(spec: &str) -> Option<String> {
+    let (domain, commit) = spec.rsplit_once('@')?;
+    Some(create_index_name(domain, commit))
+}
+
+fn active_index_path(dir: &Path, domain: &str) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push(format!("{}.active", encode(domain)));
+    path
+}
+
+/// The commit whose index is served by default for a domain, if any.
+pub fn read_active_commit(dir: &Path, domain: &str) -> io::Result<Option<String>> {
+    match std::fs::read_to_string(active_index_path(dir, domain)) {
+        Ok(commit) => Ok(Some(commit.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mark the index for `commit` as the active one for a domain. The
+/// marker is written to a temporary file and then renamed over the
+/// old one, so a crash never leaves a half-written marker behind.
+pub fn write_active_commit(dir: &Path, domain: &str, commit: &str) -> io::Result<()> {
+    let path = active_index_path(dir, domain);
+    let mut tmp_path = path.clone();
+    tmp_path.set_extension("active.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(commit.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
+
+/// All commits for which an index of the domain exists on disk.
+pub fn list_index_versions(dir: &Path, domain: &str) -> io::Result<Vec<String>> {
+    let prefix = format!("{}@", encode(domain));
+    let mut versions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        if let Some(commit) = file_name
+            .strip_prefix(&prefix)
+            .and_then(|n| n.strip_suffix(".hnsw"))
+        {
+            versions.push(commit.to_string());
+        }
+    }
+    versions.sort();
+    Ok(versions)
+}
+
+fn index_path(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push(format!("{name}.hnsw"));
+    path
+}
+
+/// Delete all indexes of a domain and its active marker.
+pub fn remove_index_versions(dir: &Path, domain: &str) -> io::Result<()> {
+    for commit in list_index_versions(dir, domain)? {
+        std::fs::remove_file(index_path(dir, &create_index_name(domain, &commit)))?;
+    }
+    match std::fs::remove_file(active_index_path(dir, domain)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Copy all indexes of a domain, and its active marker, to another
+/// domain. This is only meaningful if the target domain holds the
+/// same vectors under the same ids, as after
+/// [`VectorStore::copy_domain`]. Sealed indexes are sealed again for
+/// their new name.
+pub fn copy_index_versions(
+    dir: &Path,
+    source: &str,
+    target: &str,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    for commit in list_index_versions(dir, source)? {
+        let source_name = create_index_name(source, &commit);
+        let target_name = create_index_name(target, &commit);
+        let source_path = index_path(dir, &source_name);
+        let target_path = index_path(dir, &target_name);
+        match cipher {
+            None => {
+                std::fs::copy(source_path, target_path)?;
+            }
+            // sealed indexes are bound to their name
+            Some(cipher) => {
+                let file = encryption::reseal(
+                    cipher,
+                    (index_aad(&source_name).as_bytes(), File::open(source_path)?),
+                    (
+                        index_aad(&target_name).as_bytes(),
+                        File::create(target_path)?,
+                    ),
+                )?;
+                file.sync_all()?;
+            }
+        }
+    }
+    if let Some(commit) = read_active_commit(dir, source)? {
+        write_active_commit(dir, target, &commit)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    num_points: usize,
+    num_vecs: usize,
+    /// Points whose vector lies beyond the end of the domain.
+    missing_vectors: Vec<usize>,
+    /// External ids that occur more than once in the index.
+    duplicate_ids: Vec<String>,
+    /// Nodes, as (layer, node), with a neighbor beyond their layer.
+    dangling_neighbors: Vec<(usize, usize)>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.is_loadable() && self.duplicate_ids.is_empty()
+    }
+
+    /// Whether the index can be loaded at all. Duplicate ids are only
+    /// suspicious, but a point without a vector can't be resolved, and
+    /// a search that follows a dangling neighbor would go out of bounds.
+    pub fn is_loadable(&self) -> bool {
+        self.missing_vectors.is_empty() && self.dangling_neighbors.is_empty()
+    }
+}
+
+// The neighbor lists as the index serializes them. Hnsw doesn't
+// expose its graph, so this is how we get at it. Unused slots hold
+// usize::MAX.
+#[derive(Deserialize)]
+struct GraphLinks {
+    zero: Vec<NeighborList>,
+    layers: Vec<Vec<LayerNode>>,
+}
+
+#[derive(Deserialize)]
+struct NeighborList {
+    neighbors: Vec<usize>,
+}
+
+impl NeighborList {
+    fn len(&self) -> usize {
+        self.neighbors.iter().filter(|&&n| n != usize::MAX).count()
+    }
+}
+
+#[derive(Deserialize)]
+struct LayerNode {
+    neighbors: NeighborList,
+}
+
+impl GraphLinks {
+    fn of<T: Serialize>(hnsw: &Hnsw<OpenAI, T, Lcg128Xsl64, M, M0>) -> Self {
+        serde_json::to_value(hnsw)
+            .and_then(GraphLinks::deserialize)
+            .expect("a deserialized index serializes again")
+    }
+
+    /// The neighbor lists of each layer, starting at the bottom layer.
+    fn layers(&self) -> Vec<Vec<&NeighborList>> {
+        let mut layers: Vec<Vec<&NeighborList>> = vec![self.zero.iter().collect()];
+        layers.extend(
+            self.layers
+                .iter()
+                .map(|layer| layer.iter().map(|node| &node.neighbors).collect()),
+        );
+        layers
+    }
+
+    /// Nodes, as (layer, node), with a neighbor that isn't in their
+    /// layer.
+    fn dangling(&self) -> Vec<(usize, usize)> {
+        let mut dangling = Vec::new();
+        for (layer, nodes) in self.layers().iter().enumerate() {
+            for (node, list) in nodes.iter().enumerate() {
+                if list
+                    .neighbors
+                    .iter()
+                    .any(|&n| n != usize::MAX && n >= nodes.len())
+                {
+                    dangling.push((layer, node));
+                }
+            }
+        }
+        dangling
+    }
+}
+
+/// Check a serialized index against the domain it points into.
+pub fn validate_index(hnsw: &HnswStorageIndex, domain: &Domain) -> ValidationReport {
+    let num_points = hnsw.layer_len(0);
+    let num_vecs = domain.num_vecs();
+    let mut seen = HashSet::with_capacity(num_points);
+    let mut missing_vectors = Vec::new();
+    let mut duplicate_ids = Vec::new();
+    for i in 0..num_points {
+        let point = hnsw.feature(i);
+        if point.index >= num_vecs {
+            missing_vectors.push(i);
+        }
+        if !seen.insert(point.id.as_str()) {
+            duplicate_ids.push(point.id.clone());
+        }
+    }
+
+    ValidationReport {
+        num_points,
+        num_vecs,
+        missing_vectors,
+        duplicate_ids,
+        dangling_neighbors: GraphLinks::of(hnsw).dangling(),
+    }
+}
+
+pub fn read_storage_index(
+    path: &Path,
+    name: &str,
+    cipher: Option<&Cipher>,
+) -> io::Result<HnswStorageIndex> {
+    let mut path = path.to_path_buf();
+    path.push(format!("{name}.hnsw"));
+    let read_file = File::options().read(true).open(&path)?;
+    encryption::read_json(cipher, index_aad(name).as_bytes(), read_file)
+}
+
+/// What a sealed index is bound to: its file name, which names the
+/// domain and commit, so that it can't pass for another index.
+fn index_aad(name: &str) -> String {
+    format!("{name}.hnsw")
+}
+
+/// Load an index and resolve its points against the vector store. The
+/// index is validated first. A strict load refuses any index with
+/// problems, otherwise only problems that make the index unusable
+/// are fatal, and the rest are logged.
+pub fn deserialize_index(
+    path: &mut PathBuf,
+    name: &str,
+    vector_store: &VectorStore,
+    strict: bool,
+) -> error::Result<HnswIndex> {
+    let _span = tracing::info_span!("load_index", index = name, strict).entered();
+    let start = Instant::now();
+    let (domain, _) = parse_index_name(name)?;
+    let hnsw = read_storage_index(path, name, vector_store.cipher())
+        .context("read", &path.join(format!("{name}.hnsw")))?;
+    let domain = vector_store.get_domain(&domain)?;
+    let report = validate_index(&hnsw, &domain);
+    if !report.is_loadable() || (strict && !report.is_valid()) {
+        return Err(VectorlinkError::InvalidIndex {
+            name: name.to_string(),
+            report: format!("{report:?}"),
+        });
+    } else if !report.is_valid() {
+        tracing::warn!(index = name, report = ?report, "index has problems");
+    }
+    // Load the vectors up front, so that one that can't be loaded
+    // fails the load rather than panicking halfway through.
+    let mut vecs = HashMap::new();
+    for i in 0..hnsw.layer_len(0) {
+        let index = hnsw.feature(i).index;
+        if let Entry::Vacant(entry) = vecs.entry(index) {
+            entry.insert(vector_store.get_stored_vec(&domain, index)?);
+        }
+    }
+    let hnsw = hnsw.transform_features(|t| Point::Stored {
+        id: t.id,
+        vec: vecs[&t.index].clone(),
+    });
+    tracing::info!(
+        points = hnsw.layer_len(0),
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "loaded index"
+    );
+    Ok(hnsw)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vectors::VectorStore;
+
+    use super::*;
+
+    /// Store `vectors` in a domain `foo` of `store`, and index them as
+    /// the points `Point/0`, `Point/1` and so on.
+    fn index_of(store: &VectorStore, vectors: &[Embedding]) -> (Arc<Domain>, HnswIndex) {
+        let domain = store.get_domain("foo").unwrap();
+        let operations: Vec<_> = store
+            .add_and_load_vecs(&domain, vectors.iter())
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(i, vec)| PointOperation::Insert {
+                point: Point::Stored {
+                    id: format!("Point/{i}"),
+                    vec,
+                },
+            })
+            .collect();
+        let hnsw = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
+        (domain, hnsw)
+    }
+
+    #[test]
+    fn low_dimensional_search() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][0] = -1.0;
+        vector_block[3][1] = -1.0;
+
+        let domain = store.get_domain("foo").unwrap();
+        let ids = store.add_vecs(&domain, vector_block.iter()).unwrap();
+        let e1 = store.get_vec(&domain, ids[0]).unwrap().unwrap();
+        let e2 = store.get_vec(&domain, ids[1]).unwrap().unwrap();
+        let e3 = store.get_vec(&domain, ids[2]).unwrap().unwrap();
+        let e4 = store.get_vec(&domain, ids[3]).unwrap().unwrap();
+
+        let operations: Vec<_> = [
+            PointOperation::Insert {
+                point: Point::Stored {
+                    id: "Point/1".to_string(),
+                    vec: e1.clone(),
+                },
+            },
+            PointOperation::Insert {
+                point: Point::Stored {
+                    id: "Point/2".to_string(),
+                    vec: e2.clone(),
+                },
+            },
+            PointOperation::Insert {
+                point: Point::Stored {
+                    id: "Point/3".to_string(),
+                    vec: e3,
+                },
+            },
+            PointOperation::Insert {
+                point: Point::Stored {
+                    id: "Point/4".to_string(),
+                    vec: e4,
+                },
+            },
+        ]
+        .into_iter()
+        .collect();
+        let hnsw = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
+        let mut candidate_vec: Embedding = [0.0; 1536];
+        candidate_vec[0] = 0.707;
+        candidate_vec[1] = 0.707;
+
+        let p = Point::Mem {
+            vec: Box::new(candidate_vec),
+        };
+        let points = search(&p, 4, &hnsw).unwrap();
+        let p1 = &points[0];
+        let p2 = &points[1];
+        assert_eq!(*p1.point.vec(), *e1);
+        assert_eq!(*p2.point.vec(), *e2);
+    }
+
+    #[test]
+    fn exact_search_agrees_with_hnsw() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][0] = -1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+        let mut candidate_vec: Embedding = [0.0; 1536];
+        candidate_vec[0] = 0.8;
+        candidate_vec[1] = 0.6;
+
+        let p = Point::Mem {
+            vec: Box::new(candidate_vec),
+        };
+        let exact = search_exact(&p, 2, &hnsw).unwrap();
+        let approximate = search(&p, 2, &hnsw).unwrap();
+        assert_eq!(2, exact.len());
+        assert_eq!("Point/0", exact[0].id());
+        assert_eq!("Point/1", exact[1].id());
+        assert_eq!(exact, approximate);
+
+        let cancel = Cancellation::default();
+        cancel.cancel();
+        assert!(matches!(
+            search_exact_cancellable(&p, 2, &hnsw, &cancel),
+            Err(SearchError::Cancelled)
+        ));
+        let expired = Cancellation::with_timeout(Duration::ZERO);
+        assert!(expired.is_cancelled());
+        assert!(!Cancellation::with_timeout(Duration::from_secs(60)).is_cancelled());
+    }
+
+    #[test]
+    fn ivf_search_and_delete() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][0] = -1.0;
+        vector_block[3][1] = -1.0;
+
+        let domain = store.get_domain("foo").unwrap();
+        let points: Vec<Point> = store
+            .add_and_load_vecs(&domain, vector_block.iter())
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(i, vec)| Point::Stored {
+                id: format!("Point/{}", i + 1),
+                vec,
+            })
+            .collect();
+        let ivf = IvfIndex::train(&points, 2, 5, None, &NoProgress);
+        assert_eq!(2, ivf.num_lists());
+        let operations = points
+            .into_iter()
+            .map(|point| PointOperation::Insert { point })
+            .collect();
+        let mut ivf = start_ivf_indexing_from_operations(ivf, operations).unwrap();
+        assert_eq!(4, ivf.len());
+
+        let mut candidate_vec: Embedding = [0.0; 1536];
+        candidate_vec[0] = 0.8;
+        candidate_vec[1] = 0.6;
+        let p = Point::Mem {
+            vec: Box::new(candidate_vec),
+        };
+        let results = ivf.search(&p, 2, 2);
+        assert_eq!("Point/1", results[0].id());
+        assert_eq!("Point/2", results[1].id());
+
+        ivf = start_ivf_indexing_from_operations(
+            ivf,
+            vec![PointOperation::Delete {
+                id: "Point/1".to_string(),
+            }],
+        )
+        .unwrap();
+        assert_eq!(3, ivf.len());
+        let results = ivf.search(&p, 1, 2);
+        assert_eq!("Point/2", results[0].id());
+    }
+
+    #[test]
+    fn batch_search_keeps_query_order() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][2] = 1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+
+        let queries: Vec<Point> = vector_block
+            .iter()
+            .rev()
+            .map(|v| Point::Mem { vec: Box::new(*v) })
+            .collect();
+        let results = search_batch(&queries, 1, DEFAULT_EF, &hnsw).unwrap();
+        let ids: Vec<&str> = results.iter().map(|r| r[0].id()).collect();
+        assert_eq!(vec!["Point/2", "Point/1", "Point/0"], ids);
+        for (query, result) in queries.iter().zip(results.iter()) {
+            assert_eq!(*result, search(query, 1, &hnsw).unwrap());
+        }
+    }
+
+    #[test]
+    fn small_ef_still_fills_results() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][2] = 1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+
+        let p = Point::Mem {
+            vec: Box::new(vector_block[1]),
+        };
+        let results = search_with_ef(&p, 3, 1, &hnsw).unwrap();
+        assert_eq!(3, results.len());
+        assert_eq!("Point/1", results[0].id());
+    }
+
+    #[test]
+    fn statistics_for_small_index() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][2] = 1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+
+        let statistics = index_statistics(&hnsw, &NoProgress);
+        assert_eq!(3, statistics.layer_sizes[0]);
+        assert_eq!(2, statistics.min_neighbors[0]);
+        assert_eq!(2.0, statistics.mean_neighbors[0]);
+        assert_eq!(2, statistics.max_neighbors[0]);
+        assert_eq!(0, statistics.unreachable());
+        assert!(statistics.estimated_memory() > 3 * vecmath::EMBEDDING_BYTE_LENGTH);
+    }
+
+    #[test]
+    fn validate_serialized_index() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536]].into_iter().collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+
+        let domain = store.get_domain("foo").unwrap();
+        let operations: Vec<_> = store
+            .add_and_load_vecs(&domain, vector_block.iter())
+            .unwrap()
+            .into_iter()
+            .map(|vec| PointOperation::Insert {
+                point: Point::Stored {
+                    id: "Point/1".to_string(),
+                    vec,
+                },
+            })
+            .collect();
+        let hnsw = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
+        let name = create_index_name("foo", "commit");
+        serialize_index(path.to_path_buf(), &name, hnsw, None).unwrap();
+
+        let storage_index = read_storage_index(path, &name, None).unwrap();
+        let report = validate_index(&storage_index, &domain);
+        assert!(report.is_loadable());
+        assert!(!report.is_valid());
+        assert_eq!(vec!["Point/1".to_string()], report.duplicate_ids);
+
+        assert!(deserialize_index(&mut path.to_path_buf(), &name, &store, false).is_ok());
+        assert!(matches!(
+            deserialize_index(&mut path.to_path_buf(), &name, &store, true),
+            Err(VectorlinkError::InvalidIndex { .. })
+        ));
+
+        let other_domain = store.get_domain("bar").unwrap();
+        let report = validate_index(&storage_index, &other_domain);
+        assert!(!report.is_loadable());
+        assert_eq!(vec![0, 1], report.missing_vectors);
+    }
+
+    #[test]
+    fn refuse_dangling_neighbors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let mut vectors: Vec<Embedding> = vec![[0.0; 1536]; 3];
+        for (i, vector) in vectors.iter_mut().enumerate() {
+            vector[i] = 1.0;
+        }
+        let (domain, hnsw) = index_of(&store, &vectors);
+        let storage_index = hnsw.transform_features(|p| IndexPoint {
+            id: p.id().to_string(),
+            index: p.vec_id(),
+        });
+        assert!(validate_index(&storage_index, &domain).is_valid());
+
+        let mut value = serde_json::to_value(&storage_index).unwrap();
+        value["zero"][1]["neighbors"][0] = 3.into();
+        let corrupted: HnswStorageIndex = serde_json::from_value(value).unwrap();
+        let report = validate_index(&corrupted, &domain);
+        assert!(!report.is_loadable());
+        assert_eq!(vec![(0, 1)], report.dangling_neighbors);
+    }
+
+    #[test]
+    fn knn_graph_excludes_self() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][0] = 0.8;
+        vector_block[1][1] = 0.6;
+        vector_block[2][1] = 1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+
+        let graph = knn_graph(&hnsw, 1, DEFAULT_EF, &NoProgress);
+        assert_eq!(3, graph.len());
+        for (i, neighbors) in graph.iter().enumerate() {
+            assert_eq!(1, neighbors.len());
+            assert_ne!(i, neighbors[0].0);
+        }
+        assert_eq!("Point/1", hnsw.feature(graph[0][0].0).id());
+
+        let mut jsonl = Vec::new();
+        write_knn_graph_jsonl(&hnsw, &graph, &mut jsonl).unwrap();
+        assert_eq!(3, jsonl.iter().filter(|b| **b == b'\n').count());
+
+        let mut binary = Vec::new();
+        write_knn_graph_binary(&graph, &mut binary).unwrap();
+        assert_eq!(8 + 3 * (4 + 12), binary.len());
+    }
+
+    #[test]
+    fn paging_visits_every_point_once() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][0] = -1.0;
+        // this is a duplicate of the first vector, to test tie breaking
+        vector_block[3][0] = 1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+
+        let p = Point::Mem {
+            vec: Box::new(vector_block[0]),
+        };
+        let first = search_after(&p, 1, 0.0, "", DEFAULT_EF, &hnsw).unwrap();
+        let mut ids: Vec<String> = first.iter().map(|r| r.id().to_string()).collect();
+        let mut last = first[0].clone();
+        loop {
+            let page = search_after(
+                &p,
+                1,
+                f32::from_bits(last.distance()),
+                last.id(),
+                DEFAULT_EF,
+                &hnsw,
+            )
+            .unwrap();
+            if page.is_empty() {
+                break;
+            }
+            ids.push(page[0].id().to_string());
+            last = page[0].clone();
+        }
+        assert_eq!(vec!["Point/0", "Point/3", "Point/1", "Point/2"], ids);
+    }
+
+    #[test]
+    fn switch_active_version() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+
+        assert_eq!(None, read_active_commit(path, "admin/foo").unwrap());
+        write_active_commit(path, "admin/foo", "commit1").unwrap();
+        assert_eq!(
+            Some("commit1".to_string()),
+            read_active_commit(path, "admin/foo").unwrap()
+        );
+        write_active_commit(path, "admin/foo", "commit2").unwrap();
+        assert_eq!(
+            Some("commit2".to_string()),
+            read_active_commit(path, "admin/foo").unwrap()
+        );
+
+        for commit in ["commit2", "commit1"] {
+            let name = create_index_name("admin/foo", commit);
+            serialize_index(path.to_path_buf(), &name, Hnsw::new(OpenAI), None).unwrap();
+        }
+        let name = create_index_name("admin/bar", "commit3");
+        serialize_index(path.to_path_buf(), &name, Hnsw::new(OpenAI), None).unwrap();
+        assert_eq!(
+            vec!["commit1".to_string(), "commit2".to_string()],
+            list_index_versions(path, "admin/foo").unwrap()
+        );
+    }
+
+    #[test]
+    fn aggregate_chunks_per_document() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][0] = 0.6;
+        vector_block[2][1] = 0.8;
+
+        let domain = store.get_domain("foo").unwrap();
+        let vecs = store
+            .add_and_load_vecs(&domain, vector_block.iter())
+            .unwrap();
+        // the first two vectors are chunks of the same document
+        domain
+            .add_documents(&[
+                (vecs[0].id(), "Doc/1".to_string()),
+                (vecs[1].id(), "Doc/1".to_string()),
+            ])
+            .unwrap();
+        let operations: Vec<_> = vecs
+            .into_iter()
+            .enumerate()
+            .map(|(i, vec)| PointOperation::Insert {
+                point: Point::Stored {
+                    id: format!("Chunk/{i}"),
+                    vec,
+                },
+            })
+            .collect();
+        let hnsw = start_indexing_from_operations(Hnsw::new(OpenAI), operations).unwrap();
+
+        let p = Point::Mem {
+            vec: Box::new(vector_block[0]),
+        };
+        let max = search_documents(&p, 2, DEFAULT_EF, Aggregation::Max, &domain, &hnsw).unwrap();
+        assert_eq!(2, max.len());
+        assert_eq!("Doc/1", max[0].id());
+        assert_eq!(2, max[0].chunks);
+        assert_eq!("Chunk/2", max[1].id());
+
+        let mean = search_documents(&p, 2, DEFAULT_EF, Aggregation::Mean, &domain, &hnsw).unwrap();
+        assert_eq!("Chunk/2", mean[0].id());
+        assert_eq!("Doc/1", mean[1].id());
+    }
+
+    #[test]
+    fn warm_up_visits_every_point() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let mut vector_block: Vec<Embedding> = [[0.0; 1536], [0.0; 1536], [0.0; 1536]]
+            .into_iter()
+            .collect();
+        vector_block[0][0] = 1.0;
+        vector_block[1][1] = 1.0;
+        vector_block[2][2] = 1.0;
+
+        let (_, hnsw) = index_of(&store, &vector_block);
+        assert_eq!(3, warm_up_index(&hnsw));
+        assert_eq!(
+            Some(create_index_name("admin/foo", "commit")),
+            index_name_from_spec("admin/foo@commit")
+        );
+        assert_eq!(None, index_name_from_spec("admin/foo"));
+    }
+
+    #[test]
+    fn memory_estimate_scales() {
+        let small = estimate_memory(1000, 1536, M, M0);
+        let large = estimate_memory(2000, 1536, M, M0);
+        let narrow = estimate_memory(1000, 768, M, M0);
+        assert!(small.serving() >= 1000 * vecmath::EMBEDDING_BYTE_LENGTH);
+        assert!(small.build() > small.serving());
+        assert!(large.serving() > small.serving());
+        assert_eq!(
+            small.serving() - narrow.serving(),
+            1000 * 768 * std::mem::size_of::<f32>()
+        );
+    }
+
+    #[test]
+    fn seeded_builds_are_reproducible() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let vector_block: Vec<Embedding> = (0..100)
+            .map(|i| {
+                let mut v = [0.0; 1536];
+                v[i % 1536] = 1.0;
+                v[(i * 7 + 1) % 1536] = 0.5;
+                vecmath::normalize_vec(&mut v);
+                v
+            })
+            .collect();
+        let domain = store.get_domain("foo").unwrap();
+        let points: Vec<Point> = store
+            .add_and_load_vecs(&domain, vector_block.iter())
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(i, vec)| Point::Stored {
+                id: format!("Point/{i}"),
+                vec,
+            })
+            .collect();
+        let build = |seed| {
+            let operations = points
+                .iter()
+                .cloned()
+                .map(|point| PointOperation::Insert { point })
+                .collect();
+            start_indexing_from_operations(new_index(Some(seed)), operations).unwrap()
+        };
+        let first = build(42);
+        let second = build(42);
+        let layers = |hnsw: &HnswIndex| -> Vec<usize> {
+            (0..hnsw.layers()).map(|l| hnsw.layer_len(l)).collect()
+        };
+        assert_eq!(layers(&first), layers(&second));
+        let query = points[3].clone();
+        let ids = |hnsw: &HnswIndex| -> Vec<String> {
+            search(&query, 10, hnsw)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.id().to_string())
+                .collect()
+        };
+        assert_eq!(ids(&first), ids(&second));
+
+        let centroids = |seed| IvfIndex::train(&points, 8, 3, Some(seed), &NoProgress).centroids;
+        assert_eq!(centroids(7), centroids(7));
+    }
+
+    #[test]
+    fn filtered_search_fills_results() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 2);
+
+        let vector_block: Vec<Embedding> = (0..20)
+            .map(|i| {
+                let mut v = [0.0; 1536];
+                v[i] = 1.0;
+                v[1535] = 1.0;
+                vecmath::normalize_vec(&mut v);
+                v
+            })
+            .collect();
+        let (_, hnsw) = index_of(&store, &vector_block);
+
+        let mut query: Embedding = [0.0; 1536];
+        query[0] = 1.0;
+        let p = Point::Mem {
+            vec: Box::new(query),
+        };
+        let results = search_filtered(&p, 5, 1, &hnsw, |r| r.internal_id() % 2 == 1).unwrap();
+        assert_eq!(5, results.len());
+        assert!(results.iter().all(|r| r.internal_id() % 2 == 1));
+
+        let cancel = Cancellation::default();
+        let odd = |r: &PointQuery| r.internal_id() % 2 == 1;
+        let first = search_after_filtered(&p, 3, None, 1, &hnsw, odd, &cancel).unwrap();
+        let last = first.last().unwrap();
+        let after = (f32::from_bits(last.distance()), last.id());
+        let second = search_after_filtered(&p, 3, Some(after), 1, &hnsw, odd, &cancel).unwrap();
+        assert_eq!(3, second.len());
+        assert!(second.iter().all(|r| r.internal_id() % 2 == 1));
+        assert!(second
+            .iter()
+            .all(|r| first.iter().all(|f| f.id() != r.id())));
+
+        let internal_ids = |results: &[PointQuery]| -> Vec<usize> {
+            results.iter().map(|r| r.internal_id()).collect()
+        };
+        let mut exact: Vec<_> = search_exact(&p, hnsw.layer_len(0), &hnsw)
+            .unwrap()
+            .into_iter()
+            .filter(odd)
+            .collect();
+        // most points are as far away, and ties go by external id
+        exact.sort_by(|a, b| (a.distance(), a.id()).cmp(&(b.distance(), b.id())));
+        let among = search_after_among(&p, 3, None, &hnsw, |_| true, odd, &cancel).unwrap();
+        assert_eq!(internal_ids(&exact[..3]), internal_ids(&among));
+        let last = among.last().unwrap();
+        let after = (f32::from_bits(last.distance()), last.id());
+        let next = search_after_among(&p, 3, Some(after), &hnsw, |_| true, odd, &cancel).unwrap();
+        assert_eq!(internal_ids(&exact[3..6]), internal_ids(&next));
+        let nearest = exact[0].id().to_string();
+        let without = |point: &Point| point.id() != nearest;
+        let among = search_after_among(&p, 3, None, &hnsw, without, odd, &cancel).unwrap();
+        assert_eq!(internal_ids(&exact[1..4]), internal_ids(&among));
+    }
+
+    #[test]
+    fn records_become_inserts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let mut vec: Embedding = [0.0; 1536];
+        vec[0] = 1.0;
+        let records = vec![
+            (
+                "Point/1".to_string(),
+                vec,
+                Some(serde_json::json!({"document": "first", "year": 1977})),
+            ),
+            ("Point/2".to_string(), vec, None),
+        ];
+        let operations = records_to_point_operations(&domain, &store, records).unwrap();
+        assert_eq!(2, operations.len());
+        assert_eq!(2, domain.num_vecs());
+        let hnsw = start_indexing_from_operations(new_index(None), operations).unwrap();
+        assert_eq!(2, hnsw.len());
+        assert_eq!(Some("first".to_string()), domain.document(0));
+        assert_eq!(Some(1977), domain.metadata(0).unwrap()["year"].as_i64());
+        assert_eq!(None, domain.metadata(1));
+    }
+
+    #[test]
+    fn refuse_other_models() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("foo").unwrap();
+        let check = |embedder: &dyn EmbeddingProvider| check_embedder(&domain, embedder);
+        let small = crate::openai::EmbeddingModel {
+            model: "text-embedding-3-small".to_string(),
+            dimensions: Some(512),
+            provider: crate::openai::Provider::Mock,
+            ..Default::default()
+        };
+        let ada = crate::openai::EmbeddingModel {
+            provider: crate::openai::Provider::Mock,
+            ..Default::default()
+        };
+        // a domain without a recorded model takes any
+        assert!(check(&*ada.embedder("").unwrap()).is_ok());
+        domain.set_embedding_model(&small).unwrap();
+        assert!(check(&*small.embedder("").unwrap()).is_ok());
+        assert!(matches!(
+            check(&*ada.embedder("").unwrap()),
+            Err(IndexError::ModelMismatch(_))
+        ));
+        let larger = crate::openai::EmbeddingModel {
+            dimensions: Some(1024),
+            ..small.clone()
+        };
+        assert!(matches!(
+            check(&*larger.embedder("").unwrap()),
+            Err(IndexError::ModelMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn refuse_what_an_hnsw_cant_do() {
+        let operations = vec![PointOperation::Delete {
+            id: "Point/0".to_string(),
+        }];
+        let error = start_indexing_from_operations(Hnsw::new(OpenAI), operations)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::Unsupported, error.kind());
+
+        assert_eq!(
+            ("admin/foo".to_string(), "commit".to_string()),
+            parse_index_name(&create_index_name("admin/foo", "commit")).unwrap()
+        );
+        assert!(matches!(
+            parse_index_name("admin%2Ffoo"),
+            Err(VectorlinkError::InvalidIndexName(_))
+        ));
+    }
+
+    #[test]
+    fn fuse_vector_and_keyword_rankings() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let mut vector_block: Vec<Embedding> = vec![[0.0; 1536]; 3];
+        vector_block[0][0] = 1.0;
+        vector_block[1][0] = 0.8;
+        vector_block[1][1] = 0.6;
+        vector_block[2][1] = 1.0;
+        let (_, hnsw) = index_of(&store, &vector_block);
+        let texts = [
+            "a desert planet",
+            "hoth, hoth and hoth",
+            "the ice planet hoth",
+        ];
+        let lexical = LexicalIndex::build(
+            &Default::default(),
+            (0..hnsw.layer_len(0))
+                .map(|i| texts[hnsw.feature(i).id()[6..].parse::<usize>().unwrap()].to_string()),
+        );
+
+        let p = Point::Mem {
+            vec: Box::new(vector_block[0]),
+        };
+        let search = |fusion| {
+            search_hybrid(
+                &p,
+                Terms::Keywords("hoth", &lexical),
+                3,
+                DEFAULT_EF,
+                &hnsw,
+                fusion,
+                |_| true,
+                &Cancellation::default(),
+            )
+            .unwrap()
+        };
+        let ids = |results: Vec<HybridQuery>| -> Vec<String> {
+            results.iter().map(|r| r.point().id().to_string()).collect()
+        };
+        // second by vector and first by keywords beats first by only
+        // one of them
+        let rrf = search(Fusion::default());
+        assert_eq!(vec!["Point/1", "Point/2", "Point/0"], ids(rrf.clone()));
+        assert!((rrf[0].score() - (1.0 / 61.0 + 1.0 / 62.0)).abs() < 1e-6);
+        assert_eq!(
+            vec!["Point/0", "Point/1", "Point/2"],
+            ids(search(Fusion::Weighted { vector_weight: 1.0 }))
+        );
+        let keywords_only = search(Fusion::Weighted { vector_weight: 0.0 });
+        assert_eq!("Point/1", keywords_only[0].point().id());
+        assert_eq!(1.0, keywords_only[0].score());
+        let kept = search_hybrid(
+            &p,
+            Terms::Keywords("hoth", &lexical),
+            3,
+            DEFAULT_EF,
+            &hnsw,
+            Fusion::default(),
+            |r| r.id() != "Point/1",
+            &Cancellation::default(),
+        )
+        .unwrap();
+        assert_eq!(vec!["Point/2", "Point/0"], ids(kept));
+
+        // sparse vectors rank like keywords do
+        let sparse_vectors = [
+            None,
+            Some(SparseVector::new(vec![5], vec![1.0]).unwrap()),
+            Some(SparseVector::new(vec![5], vec![2.0]).unwrap()),
+        ];
+        let sparse =
+            SparseIndex::build((0..hnsw.layer_len(0)).map(|i| {
+                sparse_vectors[hnsw.feature(i).id()[6..].parse::<usize>().unwrap()].as_ref()
+            }));
+        let query = SparseVector::new(vec![5], vec![1.0]).unwrap();
+        let results = search_hybrid(
+            &p,
+            Terms::Sparse(&query, &sparse),
+            3,
+            DEFAULT_EF,
+            &hnsw,
+            Fusion::default(),
+            |_| true,
+            &Cancellation::default(),
+        )
+        .unwrap();
+        assert_eq!(vec!["Point/2", "Point/1", "Point/0"], ids(results));
+    }
+
+    #[test]
+    fn refuse_unusable_fusions() {
+        assert_eq!(None, Fusion::default().problem());
+        assert_eq!(None, Fusion::Rrf { k: 0.0 }.problem());
+        assert!(Fusion::Rrf { k: -1.0 }.problem().is_some());
+        assert!(Fusion::Rrf { k: f32::NAN }.problem().is_some());
+        assert_eq!(None, Fusion::Weighted { vector_weight: 1.0 }.problem());
+        assert!(Fusion::Weighted { vector_weight: 1.5 }.problem().is_some());
+        assert!(Fusion::Weighted {
+            vector_weight: -0.1
+        }
+        .problem()
+        .is_some());
+    }
+
+    #[test]
+    fn flag_far_points() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+
+        // four points close together, and one at right angles to them
+        let mut vector_block = vec![vecmath::empty_embedding(); 5];
+        for (i, vec) in vector_block.iter_mut().enumerate().take(4) {
+            vec[0] = 1.0;
+            vec[1] = i as f32 * 0.05;
+            vecmath::normalize_vec(vec);
+        }
+        vector_block[4][2] = 1.0;
+
+        let (domain, hnsw) = index_of(&store, &vector_block);
+
+        let method = OutlierMethod::Neighbors {
+            k: 2,
+            ef: DEFAULT_EF,
+        };
+        let outliers = find_outliers(&hnsw, &domain, &method, 0.25, &NoProgress).unwrap();
+        assert_eq!(1, outliers.len());
+        assert_eq!("Point/4", outliers[0].id);
+        assert!((outliers[0].score - 0.5).abs() < 0.01);
+        let all = find_outliers(&hnsw, &domain, &method, -1.0, &NoProgress).unwrap();
+        assert_eq!(5, all.len());
+
+        let clustering = Clustering {
+            params: Default::default(),
+            centroids: vec![vector_block[0]],
+            assignments: vec![Some(0); 4],
+        };
+        let outliers = find_outliers(
+            &hnsw,
+            &domain,
+            &OutlierMethod::Centroid(&clustering),
+            0.25,
+            &NoProgress,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["Point/4"],
+            outliers.iter().map(|o| &o.id).collect::<Vec<_>>()
+        );
+
+        // deleted points are not outliers any more
+        domain.add_tombstones(&[hnsw.feature(4).vec_id()]).unwrap();
+        let outliers = find_outliers(&hnsw, &domain, &method, 0.25, &NoProgress).unwrap();
+        assert!(outliers.is_empty());
+        assert_eq!(
+            4,
+            find_outliers(&hnsw, &domain, &method, -1.0, &NoProgress)
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn find_close_pairs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+
+        // two pairs of nearly the same vector, far from each other
+        let mut vector_block = vec![vecmath::empty_embedding(); 4];
+        vector_block[0][0] = 1.0;
+        vector_block[1][0] = 1.0;
+        vector_block[1][1] = 0.001;
+        vector_block[2][2] = 1.0;
+        vector_block[3][2] = 1.0;
+        vector_block[3][3] = 0.001;
+        for vec in vector_block.iter_mut() {
+            vecmath::normalize_vec(vec);
+        }
+        let (domain, hnsw) = index_of(&store, &vector_block);
+
+        let (progress, mut events) = crate::progress::channel();
+        let mut pairs: Vec<_> =
+            find_duplicates(&hnsw, &domain, 0.01, &Cancellation::default(), &progress)
+                .unwrap()
+                .into_iter()
+                .map(|(a, b)| {
+                    (
+                        hnsw.feature(a).id().to_string(),
+                        hnsw.feature(b).id().to_string(),
+                    )
+                })
+                .map(|(a, b)| if a < b { (a, b) } else { (b, a) })
+                .collect();
+        pairs.sort();
+        assert_eq!(
+            vec![
+                ("Point/0".to_string(), "Point/1".to_string()),
+                ("Point/2".to_string(), "Point/3".to_string())
+            ],
+            pairs
+        );
+        let mut advanced = 0;
+        while let Ok(event) = events.try_recv() {
+            if let crate::progress::ProgressEvent::Advance(steps) = event {
+                advanced += steps;
+            }
+        }
+        assert_eq!(4, advanced);
+
+        domain.add_tombstones(&[hnsw.feature(0).vec_id()]).unwrap();
+        assert_eq!(
+            1,
+            find_duplicates(&hnsw, &domain, 0.01, &Cancellation::default(), &NoProgress)
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn mmr_spreads_results() {
+        let vec = |x: f32, y: f32| {
+            let mut vec = vecmath::empty_embedding();
+            vec[0] = x;
+            vec[1] = y;
+            vecmath::normalize_vec(&mut vec);
+            Point::Mem { vec: Box::new(vec) }
+        };
+        let query = vec(1.0, 0.0);
+        let distance = |a: &Point, b: &Point| f32::from_bits(OpenAI.distance(a, b));
+        // two nearly the same points near the query, and one further
+        // off in another direction
+        let candidates: Vec<PointQuery> = [vec(1.0, 0.1), vec(1.0, 0.11), vec(1.0, -0.5)]
+            .into_iter()
+            .enumerate()
+            .map(|(id, point)| PointQuery {
+                id,
+                distance: OpenAI.distance(&query, &point),
+                point,
+            })
+            .collect();
+        let order = |lambda| -> Vec<usize> {
+            rerank_mmr(&query, candidates.clone(), 3, lambda, distance)
+                .iter()
+                .map(|r| r.internal_id())
+                .collect()
+        };
+        assert_eq!(vec![0, 1, 2], order(1.0));
+        assert_eq!(vec![0, 2, 1], order(0.5));
+        assert_eq!(
+            1,
+            rerank_mmr(&query, candidates.clone(), 1, 0.5, distance).len()
+        );
+        assert_eq!(
+            3,
+            rerank_mmr(&query, candidates.clone(), 10, 0.5, distance).len()
+        );
     }
 }
@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::IngestionConfig;
+
+/// Bounds on the ingestion work a server takes on at once. Work beyond
+/// them is refused right away, so that clients back off instead of
+/// the server buffering it.
+pub struct IngestionLimits {
+    uploads: Arc<Semaphore>,
+    max_uploads: usize,
+    jobs: Arc<Semaphore>,
+    max_running_jobs: usize,
+    /// Jobs that are waiting or running.
+    jobs_queued: AtomicUsize,
+    max_queued_jobs: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum IngestionError {
+    #[error("Too many uploads are running, retry later")]
+    TooManyUploads,
+    #[error("The index job queue is full, retry later")]
+    QueueFull,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IngestionStatistics {
+    uploads_running: usize,
+    max_uploads: usize,
+    jobs_running: usize,
+    jobs_waiting: usize,
+    max_running_jobs: usize,
+    max_queued_jobs: usize,
+}
+
+/// A job's place in the queue, given up when it is dropped.
+pub struct QueuedJob {
+    limits: Arc<IngestionLimits>,
+}
+
+impl QueuedJob {
+    /// Wait until fewer than the maximum number of jobs are running.
+    /// The job counts as running for as long as the permit is held.
+    pub async fn start(&self) -> OwnedSemaphorePermit {
+        self.limits
+            .jobs
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the job semaphore is never closed")
+    }
+}
+
+impl Drop for QueuedJob {
+    fn drop(&mut self) {
+        self.limits.jobs_queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl IngestionLimits {
+    pub fn new(config: &IngestionConfig) -> Self {
+        IngestionLimits {
+            uploads: Arc::new(Semaphore::new(config.max_uploads)),
+            max_uploads: config.max_uploads,
+            jobs: Arc::new(Semaphore::new(config.max_running_jobs)),
+            max_running_jobs: config.max_running_jobs,
+            jobs_queued: AtomicUsize::new(0),
+            max_queued_jobs: config.max_queued_jobs,
+        }
+    }
+
+    /// Admit an upload for as long as the permit is held.
+    pub fn try_upload(&self) -> Result<OwnedSemaphorePermit, IngestionError> {
+        self.uploads
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| IngestionError::TooManyUploads)
+    }
+
+    /// Take a place in the job queue.
+    pub fn try_queue_job(self: &Arc<Self>) -> Result<QueuedJob, IngestionError> {
+        self.jobs_queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < self.max_queued_jobs).then_some(queued + 1)
+            })
+            .map_err(|_| IngestionError::QueueFull)?;
+        Ok(QueuedJob {
+            limits: self.clone(),
+        })
+    }
+
+    pub fn statistics(&self) -> IngestionStatistics {
+        let jobs_running = self.max_running_jobs - self.jobs.available_permits();
+        IngestionStatistics {
+            uploads_running: self.max_uploads - self.uploads.available_permits(),
+            max_uploads: self.max_uploads,
+            jobs_running,
+            jobs_waiting: self
+                .jobs_queued
+                .load(Ordering::Acquire)
+                .saturating_sub(jobs_running),
+            max_running_jobs: self.max_running_jobs,
+            max_queued_jobs: self.max_queued_jobs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_beyond_limits() {
+        let limits = Arc::new(IngestionLimits::new(&IngestionConfig {
+            max_uploads: 1,
+            max_running_jobs: 1,
+            max_queued_jobs: 2,
+        }));
+        let upload = limits.try_upload().unwrap();
+        assert!(limits.try_upload().is_err());
+        assert_eq!(1, limits.statistics().uploads_running);
+        drop(upload);
+        assert!(limits.try_upload().is_ok());
+
+        let first = limits.try_queue_job().unwrap();
+        let second = limits.try_queue_job().unwrap();
+        assert!(matches!(
+            limits.try_queue_job(),
+            Err(IngestionError::QueueFull)
+        ));
+        assert_eq!(2, limits.statistics().jobs_waiting);
+        drop(first);
+        assert!(limits.try_queue_job().is_ok());
+        drop(second);
+        assert_eq!(0, limits.statistics().jobs_waiting);
+    }
+}
@@ -0,0 +1,102 @@
+use rayon::prelude::*;
+
+use crate::indexer::build_pool;
+use crate::progress::Progress;
+use crate::vecmath::{self, Embedding};
+
+/// The centroid nearest to a vector by cosine distance, with the
+/// distance. There has to be at least one centroid.
+pub fn nearest_centroid(centroids: &[Embedding], vec: &Embedding) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|c| vecmath::normalized_cosine_distance(c, vec))
+        .enumerate()
+        .min_by_key(|(_, distance)| distance.to_bits())
+        .unwrap_or((0, 0.0))
+}
+
+/// Spherical k-means, starting from the given centroids. Every
+/// iteration assigns each vector to its nearest centroid, and moves
+/// each centroid to the normalized mean of its vectors. A centroid
+/// that is left without vectors stays where it is.
+///
+/// `pass` goes over all vectors each time it is called, handing them
+/// to its argument in batches. Vectors can so be streamed from disk
+/// rather than held in memory. Every iteration is a step of
+/// `progress`.
+pub fn train<E>(
+    mut centroids: Vec<Embedding>,
+    iterations: usize,
+    progress: &dyn Progress,
+    mut pass: impl FnMut(&mut dyn FnMut(&[&Embedding])) -> Result<(), E>,
+) -> Result<Vec<Embedding>, E> {
+    if centroids.is_empty() {
+        return Ok(centroids);
+    }
+    for _ in 0..iterations {
+        let mut sums = vec![vecmath::empty_embedding(); centroids.len()];
+        let mut counts = vec![0_usize; centroids.len()];
+        pass(&mut |batch| {
+            let assignments: Vec<usize> = build_pool().install(|| {
+                batch
+                    .par_iter()
+                    .map(|vec| nearest_centroid(&centroids, vec).0)
+                    .collect()
+            });
+            for (vec, c) in batch.iter().zip(assignments) {
+                for (sum, f) in sums[c].iter_mut().zip(vec.iter()) {
+                    *sum += f;
+                }
+                counts[c] += 1;
+            }
+        })?;
+        for ((centroid, mut sum), count) in centroids.iter_mut().zip(sums).zip(counts) {
+            if count != 0 {
+                vecmath::normalize_vec(&mut sum);
+                *centroid = sum;
+            }
+        }
+        progress.advance(1);
+    }
+
+    Ok(centroids)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn find_two_groups() {
+        let mut vecs = vec![vecmath::empty_embedding(); 6];
+        for (i, vec) in vecs.iter_mut().enumerate() {
+            // three around the first axis, three around the second
+            vec[i / 3] = 1.0;
+            vec[2] = i as f32 * 0.01;
+            vecmath::normalize_vec(vec);
+        }
+        let refs: Vec<&Embedding> = vecs.iter().collect();
+        let mut passes = 0;
+        let centroids = train(vec![vecs[0], vecs[1]], 3, &NoProgress, |f| {
+            passes += 1;
+            // in two batches, like a stream would
+            f(&refs[..4]);
+            f(&refs[4..]);
+            Ok::<_, Infallible>(())
+        })
+        .unwrap();
+        assert_eq!(3, passes);
+        let clusters: Vec<usize> = vecs
+            .iter()
+            .map(|vec| nearest_centroid(&centroids, vec).0)
+            .collect();
+        assert_eq!(clusters[0], clusters[1]);
+        assert_eq!(clusters[0], clusters[2]);
+        assert_eq!(clusters[3], clusters[4]);
+        assert_eq!(clusters[3], clusters[5]);
+        assert_ne!(clusters[0], clusters[3]);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Which text of a domain its lexical index covers, and how BM25
+/// weighs it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LexicalConfig {
+    /// Metadata fields whose text is indexed. Arrays of strings are
+    /// indexed as one text.
+    #[serde(default = "default_fields")]
+    pub fields: Vec<String>,
+    /// How quickly repeating a term stops adding to the score.
+    #[serde(default = "default_k1")]
+    pub k1: f32,
+    /// How much longer texts are penalized, from 0 to 1.
+    #[serde(default = "default_b")]
+    pub b: f32,
+}
+
+fn default_fields() -> Vec<String> {
+    vec!["text".to_string()]
+}
+
+fn default_k1() -> f32 {
+    1.2
+}
+
+fn default_b() -> f32 {
+    0.75
+}
+
+impl Default for LexicalConfig {
+    fn default() -> Self {
+        LexicalConfig {
+            fields: default_fields(),
+            k1: default_k1(),
+            b: default_b(),
+        }
+    }
+}
+
+/// Lowercased runs of letters and digits.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+}
+
+/// The text of the given fields of a point's metadata, joined by
+/// spaces.
+pub fn metadata_text(metadata: Option<&serde_json::Value>, fields: &[String]) -> String {
+    let mut text = String::new();
+    let Some(metadata) = metadata else {
+        return text;
+    };
+    let mut push = |value: &serde_json::Value| {
+        if let Some(s) = value.as_str() {
+            text.push_str(s);
+            text.push(' ');
+        }
+    };
+    for field in fields {
+        match metadata.get(field) {
+            Some(serde_json::Value::Array(values)) => values.iter().for_each(&mut push),
+            Some(value) => push(value),
+            None => {}
+        }
+    }
+    text
+}
+
+/// A BM25 index over one text per point of an index. Points are
+/// known by their position in the index, like the nodes of the HNSW.
+pub struct LexicalIndex {
+    config: LexicalConfig,
+    // the points each term occurs in, with the number of occurrences
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    lengths: Vec<u32>,
+    average_length: f32,
+}
+
+impl LexicalIndex {
+    pub fn build(config: &LexicalConfig, texts: impl Iterator<Item = String>) -> Self {
+        let mut postings: HashMap<String, Vec<(usize, u32)>> = HashMap::new();
+        let mut lengths = Vec::new();
+        for (point, text) in texts.enumerate() {
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            let mut length = 0;
+            for token in tokenize(&text) {
+                *counts.entry(token).or_default() += 1;
+                length += 1;
+            }
+            for (term, count) in counts {
+                postings.entry(term).or_default().push((point, count));
+            }
+            lengths.push(length);
+        }
+        let total: u64 = lengths.iter().map(|l| *l as u64).sum();
+        let average_length = if lengths.is_empty() {
+            0.0
+        } else {
+            total as f32 / lengths.len() as f32
+        };
+        LexicalIndex {
+            config: config.clone(),
+            postings,
+            lengths,
+            average_length,
+        }
+    }
+
+    /// Whether this index was built with the given configuration.
+    pub fn is_built_with(&self, config: &LexicalConfig) -> bool {
+        self.config == *config
+    }
+
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    /// The `k` points scoring highest for the terms of `query`, with
+    /// their score, best first. Points that `keep` refuses are left
+    /// out, and so are points matching none of the terms.
+    pub fn search(&self, query: &str, k: usize, keep: impl Fn(usize) -> bool) -> Vec<(usize, f32)> {
+        let n = self.lengths.len() as f32;
+        let LexicalConfig { k1, b, .. } = self.config;
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort();
+        terms.dedup();
+        for term in terms {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let df = postings.len() as f32;
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for (point, count) in postings {
+                let tf = *count as f32;
+                let length = self.lengths[*point] as f32;
+                let norm = if self.average_length > 0.0 {
+                    1.0 - b + b * length / self.average_length
+                } else {
+                    1.0
+                };
+                *scores.entry(*point).or_default() += idf * tf * (k1 + 1.0) / (tf + k1 * norm);
+            }
+        }
+        let mut scores: Vec<(usize, f32)> = scores
+            .into_iter()
+            .filter(|(point, _)| keep(*point))
+            .collect();
+        scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        scores.truncate(k);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rank_by_bm25() {
+        let texts = [
+            "The Millennium Falcon is a fast ship",
+            "Han Solo flies the Millennium Falcon",
+            "A small green jedi master",
+            "Ships, ships and more ships",
+        ];
+        let index = LexicalIndex::build(
+            &LexicalConfig::default(),
+            texts.iter().map(|t| t.to_string()),
+        );
+        assert_eq!(4, index.len());
+
+        let results = index.search("millennium falcon", 10, |_| true);
+        let points: Vec<usize> = results.iter().map(|(p, _)| *p).collect();
+        assert_eq!(2, points.len());
+        assert!(points.contains(&0) && points.contains(&1));
+
+        // repeating a term counts, but less and less
+        let results = index.search("ship ships", 10, |_| true);
+        assert_eq!(3, results[0].0);
+        assert_eq!(0, results[1].0);
+
+        assert!(index.search("jedi", 10, |p| p != 2).is_empty());
+        assert!(index.search("wookiee", 10, |_| true).is_empty());
+    }
+
+    #[test]
+    fn text_of_fields() {
+        let metadata = json!({
+            "title": "Star Wars",
+            "tags": ["space", "opera", 3],
+            "year": 1977,
+        });
+        let fields = vec!["title".to_string(), "tags".to_string(), "year".to_string()];
+        let text = metadata_text(Some(&metadata), &fields);
+        assert_eq!(
+            vec!["star", "wars", "space", "opera"],
+            tokenize(&text).collect::<Vec<_>>()
+        );
+        assert_eq!("", metadata_text(None, &fields));
+    }
+}
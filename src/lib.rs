@@ -1,5 +1,36 @@
+pub mod arithmetic;
+pub mod audit;
+pub mod bench;
+pub mod bitmap;
+pub mod cache;
+pub mod clip;
+pub mod cluster;
+pub mod compression;
+pub mod config;
+pub mod deadletter;
+pub mod embed;
+pub mod encryption;
+pub mod error;
+pub mod filter;
+pub mod groundtruth;
+pub mod huggingface;
 pub mod indexer;
+pub mod ingestion;
+pub mod kmeans;
+pub mod lexical;
+#[cfg(feature = "local-embeddings")]
+pub mod local;
+pub mod metrics;
+pub mod mock;
 pub mod openai;
+pub mod progress;
+pub mod replication;
+pub mod scoring;
+pub mod secret;
 pub mod server;
+pub mod sparse;
+pub mod tls;
+pub mod usage;
 pub mod vecmath;
 pub mod vectors;
+pub mod webhook;
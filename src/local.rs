@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use tokenizers::{PaddingParams, Tokenizer};
+
+use crate::error::Unpoisoned;
+use crate::openai::{padded_embedding, EmbeddingError, EmbeddingLimits, EmbeddingProvider};
+use crate::vecmath::Embedding;
+
+/// Texts run through the model at once.
+const BATCH_SIZE: usize = 32;
+
+/// A BERT sentence model, like bge or all-MiniLM, loaded from a
+/// directory holding its `config.json`, `tokenizer.json` and
+/// `model.safetensors`.
+struct LocalModel {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+fn model_error<E: std::fmt::Display>(path: &Path) -> impl FnOnce(E) -> EmbeddingError + '_ {
+    move |e| EmbeddingError::LocalModel(format!("{}: {e}", path.display()))
+}
+
+impl LocalModel {
+    fn load(path: &Path) -> Result<Self, EmbeddingError> {
+        let device = Device::Cpu;
+        let config =
+            std::fs::read_to_string(path.join("config.json")).map_err(model_error(path))?;
+        let config: Config = serde_json::from_str(&config)?;
+        let mut tokenizer =
+            Tokenizer::from_file(path.join("tokenizer.json")).map_err(model_error(path))?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        let weights = path.join("model.safetensors");
+        // the weights file must not change while it is mapped
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DTYPE, &device) }
+            .map_err(model_error(path))?;
+        let model = BertModel::load(vb, &config).map_err(model_error(path))?;
+        Ok(LocalModel {
+            model,
+            tokenizer,
+            device,
+        })
+    }
+
+    /// The mean of the token vectors of every text, leaving out the
+    /// padding.
+    fn embed(&self, path: &Path, strings: &[String]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(strings.to_vec(), true)
+            .map_err(model_error(path))?;
+        let run = || -> candle_core::Result<Vec<Vec<f32>>> {
+            let stack = |rows: Vec<&[u32]>| -> candle_core::Result<Tensor> {
+                let rows = rows
+                    .into_iter()
+                    .map(|row| Tensor::new(row, &self.device))
+                    .collect::<candle_core::Result<Vec<_>>>()?;
+                Tensor::stack(&rows, 0)
+            };
+            let token_ids = stack(encodings.iter().map(|e| e.get_ids()).collect())?;
+            let mask = stack(encodings.iter().map(|e| e.get_attention_mask()).collect())?
+                .to_dtype(DTYPE)?
+                .unsqueeze(2)?;
+            let token_type_ids = token_ids.zeros_like()?;
+            let output = self.model.forward(&token_ids, &token_type_ids)?;
+            let sum = output.broadcast_mul(&mask)?.sum(1)?;
+            sum.broadcast_div(&mask.sum(1)?)?.to_vec2::<f32>()
+        };
+        run().map_err(model_error(path))
+    }
+}
+
+lazy_static! {
+    static ref MODELS: Mutex<HashMap<PathBuf, Arc<LocalModel>>> = Mutex::new(HashMap::new());
+}
+
+fn model(path: &Path) -> Result<Arc<LocalModel>, EmbeddingError> {
+    if let Some(model) = MODELS.lock().unpoisoned().get(path) {
+        return Ok(model.clone());
+    }
+    let model = Arc::new(LocalModel::load(path)?);
+    MODELS
+        .lock()
+        .unpoisoned()
+        .insert(path.to_path_buf(), model.clone());
+    Ok(model)
+}
+
+/// Embeds with the model in a directory, on a blocking thread. The
+/// model is loaded on first use and kept.
+pub struct LocalEmbedder {
+    path: PathBuf,
+    model: String,
+    dimension: usize,
+}
+
+impl LocalEmbedder {
+    pub fn new(path: &str, model: &str, dimension: usize) -> Self {
+        LocalEmbedder {
+            path: PathBuf::from(path),
+            model: model.to_string(),
+            dimension,
+        }
+    }
+}
+
+impl EmbeddingProvider for LocalEmbedder {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// The model uses every core for a batch already.
+    fn limits(&self) -> EmbeddingLimits {
+        EmbeddingLimits {
+            batch_size: BATCH_SIZE,
+            concurrency: 1,
+        }
+    }
+
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<Embedding>, EmbeddingError>> {
+        let path = self.path.clone();
+        let dimension = self.dimension;
+        let texts = texts.to_vec();
+        Box::pin(async move {
+            let error_path = path.clone();
+            tokio::task::spawn_blocking(move || {
+                let model = model(&path)?;
+                model
+                    .embed(&path, &texts)?
+                    .iter()
+                    .map(|vector| padded_embedding(vector, dimension))
+                    .collect()
+            })
+            .await
+            .map_err(model_error(&error_path))?
+        })
+    }
+}
@@ -1,13 +1,20 @@
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use clap::CommandFactory;
 use clap::{Parser, Subcommand, ValueEnum};
-use hnsw::Hnsw;
+use config::{CommandLine, Config, IndexConfig, ServerConfig, DEFAULT_LOG_LEVEL};
 use indexer::serialize_index;
-use indexer::start_indexing_from_operations;
+use indexer::start_indexing_with_progress;
 use indexer::Point;
-use indexer::{operations_to_point_operations, OpenAI};
+use indexer::{check_embedder, operations_to_point_operations, OpenAI};
+use indexer::{configure_thread_pools, estimate_memory, new_index, use_single_thread, M, M0};
+use indexer::{deserialize_index, knn_graph, write_knn_graph_binary, write_knn_graph_jsonl};
+use indexer::{find_duplicates, find_outliers, Cancellation, OutlierMethod};
+use indexer::{index_name_from_spec, read_storage_index, validate_index, DEFAULT_EF};
+use indexer::{index_statistics, read_active_commit, search_with_ef, PointOperation};
+use openai::EmbeddingModel;
+use progress::Progress;
 use server::Operation;
 use space::Metric;
 use std::fs::File;
@@ -17,11 +24,43 @@ use {
     vecmath::empty_embedding,
     vectors::VectorStore,
 };
+mod arithmetic;
+mod audit;
+mod bench;
+mod bitmap;
+mod cache;
+mod clip;
+mod cluster;
+mod compression;
+mod config;
+mod deadletter;
+mod embed;
+mod encryption;
+mod error;
+mod filter;
+mod groundtruth;
+mod huggingface;
 mod indexer;
+mod ingestion;
+mod kmeans;
+mod lexical;
+#[cfg(feature = "local-embeddings")]
+mod local;
+mod metrics;
+mod mock;
 mod openai;
+mod progress;
+mod replication;
+mod scoring;
+mod secret;
 mod server;
+mod sparse;
+mod tls;
+mod usage;
 mod vecmath;
 mod vectors;
+mod webhook;
+use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 
 #[derive(Parser, Debug)]
@@ -34,30 +73,208 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Serve {
+        #[command(flatten)]
+        server: ServerArgs,
+        /// Run index builds on a single thread
+        #[arg(long)]
+        deterministic: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
+        /// Format of the request logs. Levels are set with RUST_LOG
+        #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+    },
+    /// Work with the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Embed the operations of a file and index them at a commit
+    #[command(alias = "load")]
+    Ingest {
         #[arg(short, long)]
-        content_endpoint: Option<String>,
+        key: Option<String>,
         #[arg(short, long)]
-        user_forward_header: Option<String>,
+        commit: String,
+        #[arg(long)]
+        domain: String,
         #[arg(short, long)]
-        directory: String,
-        #[arg(short, long, default_value_t = 8080)]
-        port: u16,
-        #[arg(short, long, default_value_t = 10000)]
-        size: usize,
+        directory: Option<String>,
+        #[arg(short, long)]
+        input: String,
+        #[arg(short, long)]
+        size: Option<usize>,
+        /// Seed for the random generator of the index
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Run the build on a single thread
+        #[arg(long)]
+        deterministic: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
     },
-    Load {
+    /// Build the index of a commit anew from the points of the index of
+    /// another commit, like after changing the seed
+    BuildIndex {
+        #[arg(short, long)]
+        commit: String,
+        /// The commit whose index holds the points
+        #[arg(long)]
+        from: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        size: Option<usize>,
+        /// Seed for the random generator of the index
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Run the build on a single thread
+        #[arg(long)]
+        deterministic: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Search the index of a commit, or of the active commit, for a text
+    Search {
         #[arg(short, long)]
         key: Option<String>,
+        #[arg(short, long)]
+        commit: Option<String>,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        query: String,
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
+        /// Search beam width [default: from the configuration, or 100]
+        #[arg(long)]
+        ef: Option<usize>,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        size: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Take, list and restore snapshots of a storage directory
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Measure distances, index insertion and search on the vectors of
+    /// a domain, and print the results as JSON
+    Bench {
+        #[arg(long)]
+        domain: String,
+        /// Search the index of this commit rather than one built from
+        /// the sample
+        #[arg(short, long)]
+        commit: Option<String>,
+        /// Vectors of the domain to measure with
+        #[arg(long, default_value_t = 1000)]
+        sample: usize,
+        /// Searches to time
+        #[arg(long, default_value_t = 100)]
+        queries: usize,
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
+        #[arg(long)]
+        ef: Option<usize>,
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        size: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Find the exact nearest neighbours of a sample of the vectors of
+    /// a domain, and write them as ground truth in the binary format
+    /// of big-ann-benchmarks
+    GroundTruth {
+        #[arg(long)]
+        domain: String,
+        /// The commit whose index holds the points to search
+        #[arg(short, long)]
+        commit: String,
+        /// Vectors of the domain to sample as queries
+        #[arg(long, default_value_t = 1000)]
+        queries: usize,
+        #[arg(short, long, default_value_t = 100)]
+        k: usize,
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+        #[arg(short, long)]
+        output: String,
+        /// Also write the query vectors here, in the .fbin format
+        #[arg(long)]
+        queries_output: Option<String>,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        size: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Statistics about the shape of the index of a commit
+    Stats {
         #[arg(short, long)]
         commit: String,
         #[arg(long)]
         domain: String,
         #[arg(short, long)]
-        directory: String,
+        directory: Option<String>,
         #[arg(short, long)]
-        input: String,
-        #[arg(short, long, default_value_t = 10000)]
-        size: usize,
+        size: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Find the points of the index of a commit that are far from the
+    /// rest, and print their ids and scores as JSON, highest first
+    Outliers {
+        #[arg(short, long)]
+        commit: String,
+        #[arg(long)]
+        domain: String,
+        /// Points scoring above this are outliers. Distances go from 0
+        /// for the same direction to 1 for opposite ones
+        #[arg(short, long)]
+        threshold: f32,
+        /// Score points by their mean distance to this many neighbours
+        #[arg(short, long, default_value_t = 10)]
+        k: usize,
+        #[arg(long)]
+        ef: Option<usize>,
+        /// Score points by their distance to the centroid of their
+        /// cluster in the saved clustering of the domain instead
+        #[arg(long)]
+        clusters: bool,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        size: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Find the pairs of points of the index of a commit that are
+    /// nearly the same, and print their ids as JSON
+    Duplicates {
+        #[arg(short, long)]
+        commit: String,
+        #[arg(long)]
+        domain: String,
+        /// Points closer than this are duplicates
+        #[arg(short, long)]
+        threshold: f32,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        size: Option<usize>,
+        #[command(flatten)]
+        config: ConfigArgs,
     },
     Embed {
         #[arg(short, long)]
@@ -87,6 +304,268 @@ enum Commands {
         #[arg(short, long)]
         key: Option<String>,
     },
+    KnnGraph {
+        #[arg(short, long)]
+        commit: String,
+        #[arg(long)]
+        domain: String,
+        #[arg(short, long)]
+        directory: String,
+        #[arg(short, long)]
+        output: String,
+        #[arg(short, long, default_value_t = 10)]
+        k: usize,
+        #[arg(long, value_enum, default_value_t = GraphFormat::Jsonl)]
+        format: GraphFormat,
+        #[arg(short, long, default_value_t = 10000)]
+        size: usize,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Check the configuration, and the index of a commit if one is
+    /// given
+    Validate {
+        #[arg(short, long, requires = "domain")]
+        commit: Option<String>,
+        #[arg(long, requires = "commit")]
+        domain: Option<String>,
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Estimate the memory needed to build and serve an index
+    Estimate {
+        #[arg(short, long)]
+        vectors: usize,
+        #[arg(long, default_value_t = 1536)]
+        dimension: usize,
+        #[arg(short, long, default_value_t = M)]
+        m: usize,
+        #[arg(long, default_value_t = M0)]
+        m0: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the configuration that serve would run with, given the
+    /// same arguments, after the defaults, the file and the
+    /// environment. Secrets are redacted
+    Show {
+        #[command(flatten)]
+        server: ServerArgs,
+        #[command(flatten)]
+        config: ConfigArgs,
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Copy the domains and indexes of a storage directory, as they are
+    /// right now, to another directory, with their checksums
+    Create {
+        #[arg(short, long)]
+        directory: Option<String>,
+        #[arg(short, long)]
+        output: String,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// List the snapshots in a directory, oldest first
+    List {
+        /// The directory the snapshots were created in
+        snapshots: String,
+    },
+    /// Verify a snapshot and replace the contents of a storage
+    /// directory with it. The server must not be running
+    Restore {
+        /// The directory of the snapshot
+        snapshot: String,
+        #[arg(short, long)]
+        directory: Option<String>,
+        /// Restore even if the storage directory already has domains,
+        /// which are removed
+        #[arg(long)]
+        force: bool,
+        /// Only verify the snapshot
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// Settings of the server that take precedence over the
+/// configuration.
+#[derive(clap::Args, Debug)]
+struct ServerArgs {
+    #[arg(short, long)]
+    content_endpoint: Option<String>,
+    #[arg(short, long)]
+    user_forward_header: Option<String>,
+    /// Storage directory, unless given in the configuration file
+    #[arg(short, long)]
+    directory: Option<String>,
+    /// Port to listen on [default: 8080]
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Vector pages kept in memory [default: 10000]
+    #[arg(short, long)]
+    size: Option<usize>,
+    /// Refuse to load indexes that fail validation
+    #[arg(long)]
+    strict: bool,
+    /// Load and warm up the index for a domain@commit before serving
+    #[arg(long)]
+    preload: Vec<String>,
+    /// Seed for the random generator of new indexes
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl ServerArgs {
+    fn command_line(self) -> CommandLine {
+        CommandLine {
+            server: ServerConfig {
+                port: self.port,
+                directory: self.directory,
+                content_endpoint: content_endpoint_or_env(self.content_endpoint),
+                user_forward_header: user_forward_header_or_env(self.user_forward_header),
+            },
+            index: IndexConfig {
+                size: self.size,
+                strict: self.strict,
+                preload: self.preload,
+                seed: self.seed,
+            },
+        }
+    }
+}
+
+/// The configuration a command reads its defaults from.
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    /// Path to a TOML or JSON configuration file
+    #[arg(long)]
+    config: Option<String>,
+    /// Profile of the configuration file to use
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+impl ConfigArgs {
+    /// The path and contents of the configuration, if there is a
+    /// file, and otherwise the defaults with the overrides of the
+    /// environment.
+    fn load(self) -> io::Result<(Option<PathBuf>, Config)> {
+        let config_path = config_or_env(self.config).map(PathBuf::from);
+        let profile = profile_or_env(self.profile);
+        let config = match (&config_path, &profile) {
+            (Some(path), _) => Config::load(path, profile.as_deref())?,
+            (None, None) => Config::from_env()?,
+            (None, Some(profile)) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("profile {profile} given without a configuration file"),
+                ))
+            }
+        };
+        Ok((config_path, config))
+    }
+}
+
+fn directory_or_config(d: Option<String>, config: &Config) -> io::Result<String> {
+    d.or_else(|| config.server.directory.clone())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no storage directory given"))
+}
+
+fn size_or_config(s: Option<usize>, config: &Config) -> usize {
+    s.or(config.index.size).unwrap_or(10000)
+}
+
+/// The vector store in `dirpath`, encrypted with the configured key if
+/// there is one.
+fn open_store(dirpath: &Path, num_bufs: usize, config: &Config) -> io::Result<VectorStore> {
+    let store = VectorStore::new(dirpath, num_bufs);
+    let store = match &config.encryption {
+        Some(encryption) => store.encrypted(&encryption.key)?,
+        None => store,
+    };
+    store.check_key()?;
+    Ok(store)
+}
+
+/// The key for the model, from the arguments, the environment or the
+/// configuration. Models that need no key get an empty one.
+fn key_or_config(k: Option<String>, config: &Config, model: &EmbeddingModel) -> String {
+    let key = k.or_else(|| std::env::var("OPENAI_KEY").ok()).or_else(|| {
+        config
+            .embedding_api_key
+            .as_ref()
+            .map(|key| key.expose().to_string())
+    });
+    if key.is_none() && !model.provider.needs_key() {
+        return String::new();
+    }
+    key_or_env(key)
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Log at the level of `RUST_LOG`, or else of the configuration.
+/// Reloads of the configuration can change the level later.
+fn init_tracing(format: LogFormat, log_level: Option<&str>) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::try_new(log_level.unwrap_or(DEFAULT_LOG_LEVEL))
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(DEFAULT_LOG_LEVEL))
+    });
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Text => {
+            let subscriber = subscriber.with_filter_reloading();
+            let handle = subscriber.reload_handle();
+            server::set_log_filter_reloader(Box::new(move |directives| {
+                let filter = tracing_subscriber::EnvFilter::try_new(directives)
+                    .map_err(|e| e.to_string())?;
+                handle.reload(filter).map_err(|e| e.to_string())
+            }));
+            subscriber.init()
+        }
+        LogFormat::Json => {
+            let subscriber = subscriber
+                .json()
+                .with_current_span(true)
+                .with_filter_reloading();
+            let handle = subscriber.reload_handle();
+            server::set_log_filter_reloader(Box::new(move |directives| {
+                let filter = tracing_subscriber::EnvFilter::try_new(directives)
+                    .map_err(|e| e.to_string())?;
+                handle.reload(filter).map_err(|e| e.to_string())
+            }));
+            subscriber.init()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum GraphFormat {
+    Jsonl,
+    Binary,
 }
 
 #[derive(Clone, Copy, Debug, ValueEnum)]
@@ -96,6 +575,17 @@ enum DistanceVariant {
     Scalar,
 }
 
+/// A progress bar on stderr. It is only drawn when stderr is a
+/// terminal.
+fn progress_bar() -> ProgressBar {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template("{msg} [{elapsed}] {wide_bar} {pos}/{len}")
+            .expect("progress bar template is valid"),
+    );
+    bar
+}
+
 fn key_or_env(k: Option<String>) -> String {
     let result = k.or_else(|| std::env::var("OPENAI_KEY").ok());
     if result.is_none() {
@@ -112,8 +602,16 @@ fn content_endpoint_or_env(c: Option<String>) -> Option<String> {
     c.or_else(|| std::env::var("TERMINUSDB_CONTENT_ENDPOINT").ok())
 }
 
-fn user_forward_header_or_env(c: Option<String>) -> String {
-    c.unwrap_or_else(|| std::env::var("TERMINUSDB_USER_FORWARD_HEADER").unwrap())
+fn config_or_env(c: Option<String>) -> Option<String> {
+    c.or_else(|| std::env::var("VECTORLINK_CONFIG").ok())
+}
+
+fn profile_or_env(p: Option<String>) -> Option<String> {
+    p.or_else(|| std::env::var("VECTORLINK_PROFILE").ok())
+}
+
+fn user_forward_header_or_env(c: Option<String>) -> Option<String> {
+    c.or_else(|| std::env::var("TERMINUSDB_USER_FORWARD_HEADER").ok())
 }
 
 #[tokio::main]
@@ -121,21 +619,74 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
     match args.command {
         Commands::Serve {
-            content_endpoint,
-            user_forward_header,
-            directory,
-            port,
-            size,
+            server,
+            deterministic,
+            config,
+            log_format,
         } => {
+            let (config_path, mut config) = config.load()?;
+            config.apply_command_line(server.command_line());
+            config.validate()?;
+            init_tracing(log_format, config.log_level.as_deref());
+            if deterministic {
+                use_single_thread()?;
+            } else {
+                configure_thread_pools(&config.threads)?;
+            }
+            let directory = directory_or_config(None, &config)?;
+            let user_forward_header =
+                config.server.user_forward_header.clone().ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "no user forward header given")
+                })?;
+            let content_endpoint = config.server.content_endpoint.clone();
+            let port = config.server.port.unwrap_or(8080);
+            let size = size_or_config(None, &config);
+            let strict = config.index.strict;
+            let seed = config.index.seed;
+            let preload = config
+                .index
+                .preload
+                .iter()
+                .map(|spec| {
+                    index_name_from_spec(spec).ok_or_else(|| {
+                        io::Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("expected domain@commit but got {spec}"),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
             server::serve(
                 directory,
-                user_forward_header_or_env(user_forward_header),
+                user_forward_header,
                 port,
                 size,
-                content_endpoint_or_env(content_endpoint),
+                content_endpoint,
+                strict,
+                preload,
+                seed,
+                config,
+                config_path,
             )
             .await?
         }
+        Commands::Config {
+            command:
+                ConfigCommand::Show {
+                    server,
+                    config,
+                    format,
+                },
+        } => {
+            let (_, mut config) = config.load()?;
+            config.apply_command_line(server.command_line());
+            let shown = match format {
+                ConfigFormat::Toml => toml::to_string(&config)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+                ConfigFormat::Json => serde_json::to_string_pretty(&config)?,
+            };
+            println!("{shown}");
+        }
         Commands::Embed { key, string } => {
             let v: Vec<[f32; 1536]> = openai::embeddings_for(&key_or_env(key), &[string]).await?;
             eprintln!("{:?}", v);
@@ -188,18 +739,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             let distance = vecmath::normalized_cosine_distance(&v[3], &calculated);
             eprintln!("{}", distance);
         }
-        Commands::Load {
+        Commands::Ingest {
             key,
             domain,
             commit,
             directory,
             input,
             size,
+            seed,
+            deterministic,
+            config,
         } => {
+            let (_, config) = config.load()?;
+            if deterministic {
+                use_single_thread()?;
+            }
+            let directory = directory_or_config(directory, &config)?;
             let path = Path::new(&input);
             let dirpath = Path::new(&directory);
-            let mut hnsw: HnswIndex = Hnsw::new(OpenAI);
-            let store = VectorStore::new(dirpath, size);
+            let mut hnsw: HnswIndex = new_index(seed.or(config.index.seed));
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
             let resolved_domain = store.get_domain(&domain)?;
 
             let f = File::options().read(true).open(path)?;
@@ -213,15 +772,423 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 })
                 .chunks(100);
 
-            let key = key_or_env(key);
+            let model = resolved_domain
+                .embedding_model()
+                .or_else(|| config.embedding_model.clone())
+                .unwrap_or_default();
+            let embedder = model.embedder(&key_or_config(key, &config, &model))?;
+            check_embedder(&resolved_domain, &*embedder)?;
+            let bar = progress_bar();
+            bar.stage("indexing", None);
             for structs in opstream {
-                let structs: Vec<_> = structs.collect();
+                let ops = structs.collect::<Result<Vec<_>, _>>()?;
                 let new_ops =
-                    operations_to_point_operations(&resolved_domain, &store, structs, &key).await?;
-                hnsw = start_indexing_from_operations(hnsw, new_ops).unwrap();
+                    operations_to_point_operations(&resolved_domain, &store, ops, &*embedder)
+                        .await?;
+                hnsw = start_indexing_with_progress(hnsw, new_ops, &bar).unwrap();
+            }
+            bar.finish();
+            let index_id = create_index_name(&domain, &commit);
+            serialize_index(
+                dirpath.to_path_buf(),
+                &index_id,
+                hnsw.clone(),
+                store.cipher(),
+            )
+            .unwrap();
+        }
+        Commands::BuildIndex {
+            commit,
+            from,
+            domain,
+            directory,
+            size,
+            seed,
+            deterministic,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            if deterministic {
+                use_single_thread()?;
+            }
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let source = deserialize_index(
+                &mut dirpath.to_path_buf(),
+                &create_index_name(&domain, &from),
+                &store,
+                config.index.strict,
+            )?;
+            let operations = (0..source.layer_len(0))
+                .map(|i| PointOperation::Insert {
+                    point: source.feature(i).clone(),
+                })
+                .collect::<Vec<_>>();
+            let bar = progress_bar();
+            bar.stage("inserting", Some(operations.len() as u64));
+            let hnsw = start_indexing_with_progress(
+                new_index(seed.or(config.index.seed)),
+                operations,
+                &bar,
+            )?;
+            bar.finish();
+            let index_id = create_index_name(&domain, &commit);
+            serialize_index(dirpath.to_path_buf(), &index_id, hnsw, store.cipher())?;
+            eprintln!("built index {index_id}");
+        }
+        Commands::Search {
+            key,
+            commit,
+            domain,
+            query,
+            count,
+            ef,
+            directory,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let commit = match commit {
+                Some(commit) => commit,
+                None => read_active_commit(dirpath, &domain)?.ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("domain {domain} has no active commit, give a --commit"),
+                    )
+                })?,
+            };
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let model = store
+                .get_domain(&domain)?
+                .embedding_model()
+                .or_else(|| config.embedding_model.clone())
+                .unwrap_or_default();
+            let hnsw = deserialize_index(
+                &mut dirpath.to_path_buf(),
+                &create_index_name(&domain, &commit),
+                &store,
+                config.index.strict,
+            )?;
+            let key = key_or_config(key, &config, &model);
+            let embedding = openai::embeddings_for_model(&key, &model, &[query]).await?[0];
+            let point = Point::Mem {
+                vec: Box::new(embedding),
+            };
+            let ef = ef.or(config.default_ef).unwrap_or(DEFAULT_EF);
+            for result in search_with_ef(&point, count, ef, &hnsw)? {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "id": result.id(),
+                        "distance": f32::from_bits(result.distance()),
+                    })
+                );
+            }
+        }
+        Commands::Snapshot {
+            command:
+                SnapshotCommand::Create {
+                    directory,
+                    output,
+                    config,
+                },
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, 0, &config)?;
+            let info = replication::snapshot(dirpath, &store, Path::new(&output))?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+        Commands::Snapshot {
+            command: SnapshotCommand::List { snapshots },
+        } => {
+            for (path, info) in replication::list_snapshots(Path::new(&snapshots))? {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "path": path,
+                        "created": info.created,
+                        "format_version": info.format_version,
+                        "files": info.manifest.files.len(),
+                        "bytes": info.size(),
+                        "active": info.manifest.active,
+                    })
+                );
+            }
+        }
+        Commands::Snapshot {
+            command:
+                SnapshotCommand::Restore {
+                    snapshot,
+                    directory,
+                    force,
+                    dry_run,
+                    config,
+                },
+        } => {
+            let snapshot = Path::new(&snapshot);
+            let info = if dry_run {
+                replication::verify_snapshot(snapshot)?
+            } else {
+                let (_, config) = config.load()?;
+                let directory = directory_or_config(directory, &config)?;
+                replication::restore_snapshot(snapshot, Path::new(&directory), force)?
+            };
+            eprintln!(
+                "{} {} files of the snapshot of {}",
+                if dry_run { "verified" } else { "restored" },
+                info.manifest.files.len(),
+                info.created
+            );
+        }
+        Commands::Bench {
+            domain,
+            commit,
+            sample,
+            queries,
+            count,
+            ef,
+            seed,
+            directory,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let bench_domain = store.get_existing_domain(&domain)?;
+            let index = commit
+                .map(|commit| {
+                    let index_id = create_index_name(&domain, &commit);
+                    let hnsw = deserialize_index(
+                        &mut dirpath.to_path_buf(),
+                        &index_id,
+                        &store,
+                        config.index.strict,
+                    )?;
+                    Ok::<_, error::VectorlinkError>((index_id, hnsw))
+                })
+                .transpose()?;
+            let options = bench::BenchOptions {
+                sample,
+                queries,
+                k: count,
+                ef: ef.or(config.default_ef).unwrap_or(DEFAULT_EF),
+                seed,
+            };
+            let bar = progress_bar();
+            let report = bench::run(
+                &store,
+                &bench_domain,
+                index.as_ref().map(|(name, hnsw)| (name.clone(), hnsw)),
+                &options,
+                &bar,
+            )?;
+            bar.finish();
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Commands::GroundTruth {
+            domain,
+            commit,
+            queries,
+            k,
+            seed,
+            output,
+            queries_output,
+            directory,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let truth_domain = store.get_existing_domain(&domain)?;
+            let hnsw = deserialize_index(
+                &mut dirpath.to_path_buf(),
+                &create_index_name(&domain, &commit),
+                &store,
+                config.index.strict,
+            )?;
+            let domain = truth_domain;
+            let queries = groundtruth::sample_queries(&domain, &hnsw, queries, seed);
+            let bar = progress_bar();
+            let truth = groundtruth::compute(&store, &domain, &hnsw, queries, k, &bar)?;
+            let writer = io::BufWriter::new(File::create(output)?);
+            groundtruth::write_ground_truth(&truth, k, writer)?;
+            if let Some(queries_output) = queries_output {
+                let vecs = truth
+                    .queries
+                    .iter()
+                    .map(|q| store.get_stored_vec(&domain, *q))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let writer = io::BufWriter::new(File::create(queries_output)?);
+                groundtruth::write_queries(&vecs, writer)?;
             }
+        }
+        Commands::Stats {
+            commit,
+            domain,
+            directory,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let hnsw = deserialize_index(
+                &mut dirpath.to_path_buf(),
+                &create_index_name(&domain, &commit),
+                &store,
+                config.index.strict,
+            )?;
+            let bar = progress_bar();
+            let statistics = index_statistics(&hnsw, &bar);
+            bar.finish();
+            println!("{}", serde_json::to_string_pretty(&statistics)?);
+        }
+        Commands::Outliers {
+            commit,
+            domain,
+            threshold,
+            k,
+            ef,
+            clusters,
+            directory,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let hnsw = deserialize_index(
+                &mut dirpath.to_path_buf(),
+                &create_index_name(&domain, &commit),
+                &store,
+                config.index.strict,
+            )?;
+            let outlier_domain = store.get_existing_domain(&domain)?;
+            let clustering = if clusters {
+                match outlier_domain.clustering()? {
+                    Some(clustering) => Some(clustering),
+                    None => {
+                        eprintln!("Error: domain {domain} has not been clustered");
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let method = match &clustering {
+                Some(clustering) => OutlierMethod::Centroid(clustering),
+                None => OutlierMethod::Neighbors {
+                    k,
+                    ef: ef.or(config.default_ef).unwrap_or(DEFAULT_EF).max(k + 1),
+                },
+            };
+            let bar = progress_bar();
+            let outliers = find_outliers(&hnsw, &outlier_domain, &method, threshold, &bar)?;
+            bar.finish();
+            println!("{}", serde_json::to_string_pretty(&outliers)?);
+        }
+        Commands::Duplicates {
+            commit,
+            domain,
+            threshold,
+            directory,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let directory = directory_or_config(directory, &config)?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size_or_config(size, &config), &config)?;
+            let hnsw = deserialize_index(
+                &mut dirpath.to_path_buf(),
+                &create_index_name(&domain, &commit),
+                &store,
+                config.index.strict,
+            )?;
+            let duplicate_domain = store.get_existing_domain(&domain)?;
+            let bar = progress_bar();
+            let duplicates = find_duplicates(
+                &hnsw,
+                &duplicate_domain,
+                threshold,
+                &Cancellation::default(),
+                &bar,
+            )?;
+            bar.finish();
+            let pairs: Vec<(&str, &str)> = duplicates
+                .into_iter()
+                .map(|(i, j)| (hnsw.feature(i).id(), hnsw.feature(j).id()))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&pairs)?);
+        }
+        Commands::KnnGraph {
+            commit,
+            domain,
+            directory,
+            output,
+            k,
+            format,
+            size,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            let dirpath = Path::new(&directory);
+            let store = open_store(dirpath, size, &config)?;
             let index_id = create_index_name(&domain, &commit);
-            serialize_index(dirpath.to_path_buf(), &index_id, hnsw.clone()).unwrap();
+            let hnsw = deserialize_index(&mut dirpath.to_path_buf(), &index_id, &store, false)?;
+            let bar = progress_bar();
+            let graph = knn_graph(&hnsw, k, DEFAULT_EF.max(k + 1), &bar);
+            bar.finish();
+            let writer = io::BufWriter::new(File::create(output)?);
+            match format {
+                GraphFormat::Jsonl => write_knn_graph_jsonl(&hnsw, &graph, writer)?,
+                GraphFormat::Binary => write_knn_graph_binary(&graph, writer)?,
+            }
+        }
+        Commands::Validate {
+            commit,
+            domain,
+            directory,
+            config,
+        } => {
+            let (_, config) = config.load()?;
+            if let Err(e) = config.validate() {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            if let (Some(commit), Some(domain)) = (commit, domain) {
+                let directory = directory_or_config(directory, &config)?;
+                let dirpath = Path::new(&directory);
+                let store = open_store(dirpath, 0, &config)?;
+                let resolved_domain = store.get_domain(&domain)?;
+                let index_id = create_index_name(&domain, &commit);
+                let hnsw = read_storage_index(dirpath, &index_id, store.cipher())?;
+                let report = validate_index(&hnsw, &resolved_domain);
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if !report.is_valid() {
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Estimate {
+            vectors,
+            dimension,
+            m,
+            m0,
+        } => {
+            let estimate = estimate_memory(vectors, dimension, m, m0);
+            println!("{}", serde_json::to_string_pretty(&estimate)?);
         }
     }
 
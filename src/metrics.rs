@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use serde::Serialize;
+
+use crate::error::Unpoisoned;
+
+/// A count that only goes up, like pages read or searches done.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down, like pages in memory.
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Observations counted into buckets by upper bound, with their sum.
+pub struct Histogram {
+    bounds: &'static [f64],
+    // one more than there are bounds, for observations above them all
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// The number of observations at or below every bound, and then
+    /// the total.
+    fn cumulative(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .scan(0, |total, bucket| {
+                *total += bucket.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect()
+    }
+}
+
+/// Counters of one metric by the value of a label, like requests by
+/// tenant. A counter is created the first time its value is seen.
+pub struct LabeledCounter {
+    label: &'static str,
+    counters: RwLock<BTreeMap<String, Arc<Counter>>>,
+}
+
+impl LabeledCounter {
+    fn new(label: &'static str) -> Self {
+        LabeledCounter {
+            label,
+            counters: RwLock::default(),
+        }
+    }
+
+    /// The counter for one value of the label.
+    pub fn with(&self, value: &str) -> Arc<Counter> {
+        if let Some(counter) = self.counters.read().unpoisoned().get(value) {
+            return counter.clone();
+        }
+        self.counters
+            .write()
+            .unpoisoned()
+            .entry(value.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn values(&self) -> BTreeMap<String, u64> {
+        self.counters
+            .read()
+            .unpoisoned()
+            .iter()
+            .map(|(value, counter)| (value.clone(), counter.get()))
+            .collect()
+    }
+}
+
+/// A label value as the Prometheus text format quotes it.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Bounds for durations in seconds, from 100µs to 10s.
+pub const SECONDS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+/// Bounds for counts of things done per operation, like distances
+/// computed in a search.
+pub const COUNTS: &[f64] = &[
+    10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0, 50000.0, 100000.0,
+];
+
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+    LabeledCounter(Arc<LabeledCounter>),
+}
+
+struct Entry {
+    help: &'static str,
+    metric: Metric,
+}
+
+/// The metrics of the process, by name. Metrics are registered the
+/// first time they are asked for, and asking again gives the same
+/// one.
+#[derive(Default)]
+pub struct Registry {
+    metrics: RwLock<BTreeMap<&'static str, Entry>>,
+}
+
+/// The current values of all metrics. Histograms are given as their
+/// count and sum.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub counters: BTreeMap<&'static str, u64>,
+    pub gauges: BTreeMap<&'static str, i64>,
+    pub histograms: BTreeMap<&'static str, HistogramSnapshot>,
+    /// Labeled counters by the value of their label.
+    pub labeled_counters: BTreeMap<&'static str, BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Registry {
+    fn get_or_register(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        create: impl FnOnce() -> Metric,
+    ) -> Metric {
+        let clone = |metric: &Metric| match metric {
+            Metric::Counter(c) => Metric::Counter(c.clone()),
+            Metric::Gauge(g) => Metric::Gauge(g.clone()),
+            Metric::Histogram(h) => Metric::Histogram(h.clone()),
+            Metric::LabeledCounter(c) => Metric::LabeledCounter(c.clone()),
+        };
+        if let Some(entry) = self.metrics.read().unpoisoned().get(name) {
+            return clone(&entry.metric);
+        }
+        let mut metrics = self.metrics.write().unpoisoned();
+        let entry = metrics.entry(name).or_insert_with(|| Entry {
+            help,
+            metric: create(),
+        });
+        clone(&entry.metric)
+    }
+
+    /// Panics if `name` is already registered as another kind of
+    /// metric, which is a programming error.
+    pub fn counter(&self, name: &'static str, help: &'static str) -> Arc<Counter> {
+        match self.get_or_register(name, help, || Metric::Counter(Default::default())) {
+            Metric::Counter(counter) => counter,
+            _ => panic!("metric {name} is not a counter"),
+        }
+    }
+
+    pub fn gauge(&self, name: &'static str, help: &'static str) -> Arc<Gauge> {
+        match self.get_or_register(name, help, || Metric::Gauge(Default::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => panic!("metric {name} is not a gauge"),
+        }
+    }
+
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [f64],
+    ) -> Arc<Histogram> {
+        match self.get_or_register(name, help, || {
+            Metric::Histogram(Arc::new(Histogram::new(bounds)))
+        }) {
+            Metric::Histogram(histogram) => histogram,
+            _ => panic!("metric {name} is not a histogram"),
+        }
+    }
+
+    pub fn labeled_counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        label: &'static str,
+    ) -> Arc<LabeledCounter> {
+        match self.get_or_register(name, help, || {
+            Metric::LabeledCounter(Arc::new(LabeledCounter::new(label)))
+        }) {
+            Metric::LabeledCounter(counter) => counter,
+            _ => panic!("metric {name} is not a labeled counter"),
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut snapshot = MetricsSnapshot::default();
+        for (name, entry) in self.metrics.read().unpoisoned().iter() {
+            match &entry.metric {
+                Metric::Counter(c) => {
+                    snapshot.counters.insert(name, c.get());
+                }
+                Metric::Gauge(g) => {
+                    snapshot.gauges.insert(name, g.get());
+                }
+                Metric::Histogram(h) => {
+                    snapshot.histograms.insert(
+                        name,
+                        HistogramSnapshot {
+                            count: h.count(),
+                            sum: h.sum(),
+                        },
+                    );
+                }
+                Metric::LabeledCounter(c) => {
+                    snapshot.labeled_counters.insert(name, c.values());
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, entry) in self.metrics.read().unpoisoned().iter() {
+            let _ = writeln!(out, "# HELP {name} {}", entry.help);
+            match &entry.metric {
+                Metric::Counter(c) => {
+                    let _ = writeln!(out, "# TYPE {name} counter\n{name} {}", c.get());
+                }
+                Metric::Gauge(g) => {
+                    let _ = writeln!(out, "# TYPE {name} gauge\n{name} {}", g.get());
+                }
+                Metric::Histogram(h) => {
+                    let _ = writeln!(out, "# TYPE {name} histogram");
+                    let cumulative = h.cumulative();
+                    for (bound, count) in h.bounds.iter().zip(&cumulative) {
+                        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+                    }
+                    let total = cumulative.last().copied().unwrap_or(0);
+                    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {total}");
+                    let _ = writeln!(out, "{name}_sum {}", h.sum());
+                    let _ = writeln!(out, "{name}_count {total}");
+                }
+                Metric::LabeledCounter(c) => {
+                    let _ = writeln!(out, "# TYPE {name} counter");
+                    for (value, count) in c.values() {
+                        let value = escape_label(&value);
+                        let _ = writeln!(out, "{name}{{{}=\"{value}\"}} {count}", c.label);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// The registry that the store, the indexer and the server record
+/// into.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(Registry::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_render() {
+        let registry = Registry::default();
+        let reads = registry.counter("test_reads_total", "Reads.");
+        reads.add(3);
+        registry.counter("test_reads_total", "Reads.").inc();
+        registry.gauge("test_pages", "Pages.").set(-2);
+        let latency = registry.histogram("test_seconds", "Latency.", &[0.1, 1.0]);
+        latency.observe(0.05);
+        latency.observe(0.5);
+        latency.observe(2.0);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(4, snapshot.counters["test_reads_total"]);
+        assert_eq!(-2, snapshot.gauges["test_pages"]);
+        assert_eq!(3, snapshot.histograms["test_seconds"].count);
+        assert!((snapshot.histograms["test_seconds"].sum - 2.55).abs() < 1e-9);
+
+        let text = registry.render_prometheus();
+        assert!(text.contains("# TYPE test_reads_total counter\ntest_reads_total 4\n"));
+        assert!(text.contains("test_pages -2\n"));
+        assert!(text.contains("test_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(text.contains("test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("test_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn count_by_label() {
+        let registry = Registry::default();
+        let requests = registry.labeled_counter("test_requests_total", "Requests.", "tenant");
+        requests.with("acme").add(2);
+        registry
+            .labeled_counter("test_requests_total", "Requests.", "tenant")
+            .with("acme")
+            .inc();
+        requests.with("say \"hi\"").inc();
+
+        let snapshot = registry.snapshot();
+        assert_eq!(3, snapshot.labeled_counters["test_requests_total"]["acme"]);
+
+        let text = registry.render_prometheus();
+        assert!(text.contains("# TYPE test_requests_total counter\n"));
+        assert!(text.contains("test_requests_total{tenant=\"acme\"} 3\n"));
+        assert!(text.contains("test_requests_total{tenant=\"say \\\"hi\\\"\"} 1\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a gauge")]
+    fn kinds_dont_mix() {
+        let registry = Registry::default();
+        registry.counter("test_mixed", "Mixed.");
+        registry.gauge("test_mixed", "Mixed.");
+    }
+}
@@ -0,0 +1,145 @@
+use futures::future::BoxFuture;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+
+use crate::openai::{
+    self, count_tokens, EmbeddingError, EmbeddingLimits, EmbeddingModel, EmbeddingProvider,
+    ImageInput,
+};
+use crate::vecmath::{empty_embedding, normalize_vec, Embedding};
+
+/// Texts embedded in one batch.
+const BATCH_SIZE: usize = 100;
+
+/// FNV-1a, which unlike the hasher of the standard library is the
+/// same in every build, so that a text gets the same embedding
+/// across releases.
+fn text_hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// A normalized pseudo-random embedding seeded with the hash of the
+/// text. Equal texts get equal embeddings, other texts unrelated ones.
+pub fn mock_embedding(text: &str, dimension: usize) -> Embedding {
+    let mut rng = Pcg64::seed_from_u64(text_hash(text));
+    let mut embedding = empty_embedding();
+    for x in embedding[..dimension].iter_mut() {
+        *x = rng.gen_range(-1.0..1.0);
+    }
+    normalize_vec(&mut embedding);
+    embedding
+}
+
+/// Embeds without calling any model, for tests and dry runs. The
+/// tokens of the texts are charged as if the model had embedded
+/// them, so that a dry run estimates what an ingest costs.
+pub struct MockEmbedder {
+    model: EmbeddingModel,
+    dimension: usize,
+}
+
+impl MockEmbedder {
+    pub fn new(model: &EmbeddingModel, dimension: usize) -> Self {
+        MockEmbedder {
+            model: model.clone(),
+            dimension,
+        }
+    }
+}
+
+impl EmbeddingProvider for MockEmbedder {
+    fn model_name(&self) -> &str {
+        &self.model.model
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn limits(&self) -> EmbeddingLimits {
+        EmbeddingLimits {
+            batch_size: BATCH_SIZE,
+            concurrency: openai::concurrency(),
+        }
+    }
+
+    fn embed_batch<'a>(
+        &'a self,
+        texts: &'a [String],
+    ) -> BoxFuture<'a, Result<Vec<Embedding>, EmbeddingError>> {
+        let tokens = texts.iter().map(|text| count_tokens(text) as u64).sum();
+        let cost = self.model.estimated_cost(tokens);
+        crate::usage::charge(|usage| {
+            usage.embedding_tokens += tokens;
+            usage.embedding_cost += cost;
+        });
+        let embeddings = texts
+            .iter()
+            .map(|text| mock_embedding(text, self.dimension))
+            .collect();
+        Box::pin(async move { Ok(embeddings) })
+    }
+
+    /// Images are embedded like the text of their URI, so that an
+    /// image can be found again by searching for its URI.
+    fn embed_image_batch<'a>(
+        &'a self,
+        images: &'a [ImageInput],
+    ) -> BoxFuture<'a, Result<Vec<Embedding>, EmbeddingError>> {
+        let embeddings = images
+            .iter()
+            .map(|image| mock_embedding(&image.uri(), self.dimension))
+            .collect();
+        Box::pin(async move { Ok(embeddings) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic_embeddings() {
+        let first = mock_embedding("Luke Skywalker", 384);
+        assert_eq!(first, mock_embedding("Luke Skywalker", 384));
+        assert_ne!(first, mock_embedding("Leia Organa", 384));
+        assert!(first[384..].iter().all(|x| *x == 0.0));
+        let magnitude: f32 = first.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((magnitude - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn mock_model_dimensions() {
+        let model: EmbeddingModel = serde_json::from_str(
+            r#"{"model": "text-embedding-3-small", "provider": {"type": "mock"}}"#,
+        )
+        .unwrap();
+        assert!(!model.provider.needs_key());
+        assert_eq!(1536, model.embedder("").unwrap().dimension());
+        let model: EmbeddingModel = serde_json::from_str(
+            r#"{"model": "fake", "dimensions": 384, "provider": {"type": "mock"}}"#,
+        )
+        .unwrap();
+        let embedder = model.embedder("").unwrap();
+        let embeddings = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(embedder.embed_batch(&["Luke Skywalker".to_string()]))
+            .unwrap();
+        assert_eq!(mock_embedding("Luke Skywalker", 384), embeddings[0]);
+
+        let images = [ImageInput::Url("https://example.com/luke.png".to_string())];
+        let embeddings = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(openai::embed_images(&*embedder, &images))
+            .unwrap();
+        assert_eq!(
+            mock_embedding("https://example.com/luke.png", 384),
+            embeddings[0]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use indicatif::ProgressBar;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Where long operations report how far along they are. An operation
+/// goes through one or more stages, each with a number of steps, like
+/// points inserted or nodes searched.
+pub trait Progress: Send + Sync {
+    /// A new stage starts, with `total` steps if that is known.
+    fn stage(&self, name: &'static str, total: Option<u64>);
+    /// `steps` more steps of the current stage are done.
+    fn advance(&self, steps: u64);
+    /// The operation is done.
+    fn finish(&self);
+}
+
+/// For callers that don't want to know.
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn stage(&self, _name: &'static str, _total: Option<u64>) {}
+    fn advance(&self, _steps: u64) {}
+    fn finish(&self) {}
+}
+
+/// Progress bars for the command line. These are only drawn when
+/// stderr is a terminal.
+impl Progress for ProgressBar {
+    fn stage(&self, name: &'static str, total: Option<u64>) {
+        self.reset();
+        match total {
+            Some(total) => self.set_length(total),
+            None => self.unset_length(),
+        }
+        self.set_message(name);
+    }
+
+    fn advance(&self, steps: u64) {
+        self.inc(steps);
+    }
+
+    fn finish(&self) {
+        self.finish_and_clear();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    Stage {
+        name: &'static str,
+        total: Option<u64>,
+    },
+    Advance(u64),
+    Finish,
+}
+
+/// Sends progress as events, for reporting it from another task, like
+/// the status of a server job.
+#[derive(Clone)]
+pub struct ChannelProgress(UnboundedSender<ProgressEvent>);
+
+pub fn channel() -> (ChannelProgress, UnboundedReceiver<ProgressEvent>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (ChannelProgress(sender), receiver)
+}
+
+impl Progress for ChannelProgress {
+    // Nobody may be listening anymore, which is fine.
+    fn stage(&self, name: &'static str, total: Option<u64>) {
+        let _ = self.0.send(ProgressEvent::Stage { name, total });
+    }
+
+    fn advance(&self, steps: u64) {
+        let _ = self.0.send(ProgressEvent::Advance(steps));
+    }
+
+    fn finish(&self) {
+        let _ = self.0.send(ProgressEvent::Finish);
+    }
+}
+
+/// The progress of an operation, put together from its events.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ProgressState {
+    #[serde(rename = "name")]
+    pub stage: Option<&'static str>,
+    pub done: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    #[serde(skip)]
+    pub finished: bool,
+}
+
+impl ProgressState {
+    pub fn update(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Stage { name, total } => {
+                self.stage = Some(name);
+                self.done = 0;
+                self.total = total;
+            }
+            ProgressEvent::Advance(steps) => self.done += steps,
+            ProgressEvent::Finish => self.finished = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follow_events() {
+        let (progress, mut receiver) = channel();
+        progress.stage("inserting", Some(10));
+        progress.advance(4);
+        progress.advance(3);
+        progress.stage("writing", None);
+        progress.advance(1);
+        progress.finish();
+        drop(progress);
+
+        let mut state = ProgressState::default();
+        let mut seen = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            state.update(event);
+            seen.push(state.clone());
+        }
+        assert_eq!(Some("inserting"), seen[2].stage);
+        assert_eq!(7, seen[2].done);
+        assert_eq!(Some(10), seen[2].total);
+        assert_eq!(
+            ProgressState {
+                stage: Some("writing"),
+                done: 1,
+                total: None,
+                finished: true,
+            },
+            state
+        );
+    }
+}
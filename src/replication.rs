@@ -0,0 +1,773 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use reqwest::{header, Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use urlencoding::{decode, encode};
+
+use crate::config::ReplicationConfig;
+use crate::indexer::{read_active_commit, write_active_commit};
+use crate::secret::Secret;
+use crate::vectors::{
+    VectorStore, DERIVED_FILE_EXTENSIONS, DOMAIN_FILE_EXTENSIONS, ENCRYPTION_CHECK_FILE,
+};
+
+/// What a follower needs to know to catch up with the storage
+/// directory of a leader.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+    /// The active commit of every domain that has one.
+    pub active: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+    pub name: String,
+    pub size: u64,
+    /// The hex encoded SHA-256 of the file, which only snapshots
+    /// record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Domain files only ever grow, so a follower only fetches the part
+/// it doesn't have yet.
+fn is_append_only(name: &str) -> bool {
+    name.rsplit_once('.')
+        .map(|(_, extension)| DOMAIN_FILE_EXTENSIONS.contains(&extension))
+        .unwrap_or(false)
+}
+
+/// Files made from the domain files, like clusterings, which would
+/// describe other vectors after a restore.
+fn is_derived(name: &str) -> bool {
+    name.rsplit_once('.')
+        .map(|(_, extension)| DERIVED_FILE_EXTENSIONS.contains(&extension))
+        .unwrap_or(false)
+}
+
+/// Index files are complete once they appear, and never change after.
+fn is_index(name: &str) -> bool {
+    name.ends_with(".hnsw")
+}
+
+/// The key check of an encrypted directory goes along with the files,
+/// so that copies of them are opened with the same key.
+fn is_key_check(name: &str) -> bool {
+    name == ENCRYPTION_CHECK_FILE
+}
+
+/// Whether a leader shares the file with its followers.
+pub fn is_replicated(name: &str) -> bool {
+    !name.contains('/') && (is_append_only(name) || is_index(name) || is_key_check(name))
+}
+
+/// The domain a replicated file belongs to.
+fn file_domain(name: &str) -> Option<String> {
+    if is_key_check(name) {
+        return None;
+    }
+    let stem = if is_index(name) {
+        name.split_once('@')?.0
+    } else {
+        name.rsplit_once('.')?.0
+    };
+    decode(stem).ok().map(|domain| domain.into_owned())
+}
+
+/// List the replicated files in `dir` as they are right now.
+pub fn manifest(dir: &Path, store: &VectorStore) -> io::Result<Manifest> {
+    // Read before the indexes are listed, so that every active index
+    // is part of the manifest.
+    let mut active = HashMap::new();
+    for domain in store.list_domains()? {
+        if let Some(commit) = read_active_commit(dir, &domain)? {
+            active.insert(domain, commit);
+        }
+    }
+    let mut indexes = Vec::new();
+    let mut domain_files = Vec::new();
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_key_check(&name) {
+            // hashed, so that followers can tell whether theirs is the
+            // same
+            let check = std::fs::read(entry.path())?;
+            files.push(ManifestFile {
+                name,
+                size: check.len() as u64,
+                sha256: Some(copy_hashed(&mut &check[..], &mut io::sink())?),
+            });
+        } else if is_index(&name) {
+            let size = entry.metadata()?.len();
+            indexes.push(ManifestFile {
+                name,
+                size,
+                sha256: None,
+            });
+        } else if is_append_only(&name) {
+            domain_files.push(name);
+        }
+    }
+    // Sized after the indexes are listed, so that they hold every
+    // vector those indexes refer to.
+    let open_sizes = store.open_file_sizes()?;
+    domain_files.sort();
+    for name in domain_files {
+        let size = match open_sizes.get(&name) {
+            Some(size) => *size,
+            None => std::fs::metadata(dir.join(&name))?.len(),
+        };
+        files.push(ManifestFile {
+            name,
+            size,
+            sha256: None,
+        });
+    }
+    // Followers fetch in this order, so vectors arrive before the
+    // indexes that refer to them.
+    indexes.sort_by(|a, b| a.name.cmp(&b.name));
+    files.extend(indexes);
+
+    Ok(Manifest { files, active })
+}
+
+/// The version of the layout of snapshots and of the files in them.
+/// It goes up whenever a change to either would make an older build
+/// misread a snapshot.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Describes a snapshot. It is written last, so a directory without
+/// it holds an incomplete snapshot.
+pub const SNAPSHOT_FILE: &str = "snapshot.json";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotInfo {
+    pub format_version: u32,
+    /// When the snapshot was taken, in RFC 3339.
+    pub created: String,
+    #[serde(flatten)]
+    pub manifest: Manifest,
+}
+
+impl SnapshotInfo {
+    pub fn size(&self) -> u64 {
+        self.manifest.files.iter().map(|file| file.size).sum()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("{0:?}")]
+    Io(#[from] io::Error),
+    #[error("{0:?}")]
+    Serde(#[from] serde_json::Error),
+    #[error("{} is not a complete snapshot, it has no {SNAPSHOT_FILE}", .0.display())]
+    Incomplete(PathBuf),
+    #[error(
+        "snapshot has format version {0}, this build reads up to version {SNAPSHOT_FORMAT_VERSION}"
+    )]
+    UnsupportedVersion(u32),
+    #[error("snapshot lists {0}, which is not a storage file")]
+    UnknownFile(String),
+    #[error("{name} has {actual} bytes where the snapshot recorded {expected}")]
+    WrongSize {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("{0} does not match its checksum")]
+    WrongChecksum(String),
+    #[error("{} already holds domains, restore with force to replace them", .0.display())]
+    NotEmpty(PathBuf),
+}
+
+/// Copy `source` to `target`, returning the hex encoded SHA-256 of
+/// what was copied.
+fn copy_hashed(source: &mut impl Read, target: &mut impl Write) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let read = source.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        target.write_all(&buf[..read])?;
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Copy the replicated files of `dir` into the empty or new directory
+/// `output`, as far as the manifest lists them, so that the copy is
+/// consistent while the server keeps writing. The checksums of the
+/// copies are recorded in the snapshot file.
+pub fn snapshot(dir: &Path, store: &VectorStore, output: &Path) -> io::Result<SnapshotInfo> {
+    let created = chrono::Utc::now().to_rfc3339();
+    let mut manifest = manifest(dir, store)?;
+    std::fs::create_dir_all(output)?;
+    for file in manifest.files.iter_mut() {
+        let mut source = File::open(dir.join(&file.name))?.take(file.size);
+        let mut target = File::create(output.join(&file.name))?;
+        file.sha256 = Some(copy_hashed(&mut source, &mut target)?);
+        target.sync_all()?;
+    }
+    for (domain, commit) in &manifest.active {
+        write_active_commit(output, domain, commit)?;
+    }
+    let info = SnapshotInfo {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        created,
+        manifest,
+    };
+    let mut file = File::create(output.join(SNAPSHOT_FILE))?;
+    serde_json::to_writer_pretty(&mut file, &info)?;
+    file.sync_all()?;
+    Ok(info)
+}
+
+fn read_snapshot_info(snapshot: &Path) -> Result<SnapshotInfo, SnapshotError> {
+    match File::open(snapshot.join(SNAPSHOT_FILE)) {
+        Ok(file) => Ok(serde_json::from_reader(io::BufReader::new(file))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(SnapshotError::Incomplete(snapshot.to_path_buf()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The complete snapshots in `dir`, oldest first.
+pub fn list_snapshots(dir: &Path) -> io::Result<Vec<(PathBuf, SnapshotInfo)>> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        match read_snapshot_info(&path) {
+            Ok(info) => snapshots.push((path, info)),
+            Err(SnapshotError::Io(e)) => return Err(e),
+            Err(SnapshotError::Serde(e)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            // not a snapshot, or one that was never finished
+            Err(_) => {}
+        }
+    }
+    snapshots.sort_by(|(a_path, a), (b_path, b)| (&a.created, a_path).cmp(&(&b.created, b_path)));
+    Ok(snapshots)
+}
+
+/// Check that a snapshot can be read by this build, and that every
+/// file in it has the size and checksum it was taken with.
+pub fn verify_snapshot(snapshot: &Path) -> Result<SnapshotInfo, SnapshotError> {
+    let info = read_snapshot_info(snapshot)?;
+    if info.format_version > SNAPSHOT_FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(info.format_version));
+    }
+    for file in &info.manifest.files {
+        if !is_replicated(&file.name) {
+            return Err(SnapshotError::UnknownFile(file.name.clone()));
+        }
+        let mut source = File::open(snapshot.join(&file.name))?;
+        let actual = source.metadata()?.len();
+        if actual != file.size {
+            return Err(SnapshotError::WrongSize {
+                name: file.name.clone(),
+                expected: file.size,
+                actual,
+            });
+        }
+        let checksum = copy_hashed(&mut source, &mut io::sink())?;
+        if file.sha256.as_ref() != Some(&checksum) {
+            return Err(SnapshotError::WrongChecksum(file.name.clone()));
+        }
+    }
+    Ok(info)
+}
+
+/// Replace the contents of the storage directory `dir` with a
+/// snapshot, after verifying it. A directory that already holds
+/// domains is only restored into with `force`, which replaces its
+/// domains and indexes, and removes what was derived from them. The
+/// snapshot is copied next to the existing files before any of them is
+/// touched, so a copy that fails leaves `dir` as it was. No server
+/// should be running on `dir` meanwhile.
+pub fn restore_snapshot(
+    snapshot: &Path,
+    dir: &Path,
+    force: bool,
+) -> Result<SnapshotInfo, SnapshotError> {
+    let info = verify_snapshot(snapshot)?;
+    std::fs::create_dir_all(dir)?;
+    let mut existing = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_replicated(&name) || name.ends_with(".active") || is_derived(&name) {
+            existing.push(name);
+        }
+    }
+    // a key check alone is left by a server that stored nothing yet
+    if !force && existing.iter().any(|name| !is_key_check(name)) {
+        return Err(SnapshotError::NotEmpty(dir.to_path_buf()));
+    }
+    let tmp_path = |name: &str| dir.join(format!("{name}.tmp"));
+    for file in &info.manifest.files {
+        let copied = File::open(snapshot.join(&file.name)).and_then(|mut source| {
+            let mut target = File::create(tmp_path(&file.name))?;
+            io::copy(&mut source, &mut target)?;
+            target.sync_all()
+        });
+        if let Err(e) = copied {
+            for file in &info.manifest.files {
+                let _ = std::fs::remove_file(tmp_path(&file.name));
+            }
+            return Err(e.into());
+        }
+    }
+    for file in &info.manifest.files {
+        std::fs::rename(tmp_path(&file.name), dir.join(&file.name))?;
+    }
+    let mut restored: HashSet<String> = info
+        .manifest
+        .files
+        .iter()
+        .map(|file| file.name.clone())
+        .collect();
+    for (domain, commit) in &info.manifest.active {
+        write_active_commit(dir, domain, commit)?;
+        restored.insert(format!("{}.active", encode(domain)));
+    }
+    // whatever the snapshot did not replace is from the old contents
+    for name in existing {
+        if !restored.contains(&name) {
+            std::fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(info)
+}
+
+/// Parse a single `bytes=from-to` range, where the end is optional,
+/// into a half-open range within a file of `size` bytes.
+pub fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (from, to) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let from: u64 = from.parse().ok()?;
+    let to = if to.is_empty() {
+        size
+    } else {
+        to.parse::<u64>().ok()?.checked_add(1)?.min(size)
+    };
+    (from < to).then_some((from, to))
+}
+
+#[derive(Debug, Error)]
+pub enum ReplicationError {
+    #[error("{0:?}")]
+    Io(#[from] io::Error),
+    #[error("{0:?}")]
+    Http(#[from] reqwest::Error),
+    #[error("{0:?}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Leader answered with status {0}")]
+    Status(StatusCode),
+    #[error("Leader sent {received} bytes of {name} where {expected} were expected")]
+    ShortRead {
+        name: String,
+        expected: u64,
+        received: u64,
+    },
+    #[error("The files of the leader can't be read here: {0}")]
+    KeyMismatch(&'static str),
+}
+
+/// What changed in the storage directory of a follower during a sync.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Domains whose files or active commit changed.
+    pub domains: HashSet<String>,
+    /// Index files that were replaced.
+    pub indexes: Vec<String>,
+    /// Domains that the leader no longer has.
+    pub dropped: Vec<String>,
+}
+
+/// Pulls the storage directory of a leader.
+pub struct Follower {
+    leader: String,
+    api_key: Option<Secret>,
+    client: Client,
+}
+
+impl Follower {
+    pub fn new(config: &ReplicationConfig) -> Self {
+        Follower {
+            leader: config.leader.trim_end_matches('/').to_string(),
+            api_key: config.api_key.clone(),
+            client: Client::new(),
+        }
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        let request = self.client.get(format!("{}{path}", self.leader));
+        match &self.api_key {
+            Some(key) => request.bearer_auth(key.expose()),
+            None => request,
+        }
+    }
+
+    pub async fn manifest(&self) -> Result<Manifest, ReplicationError> {
+        let response = self.get("/replication/manifest").send().await?;
+        if !response.status().is_success() {
+            return Err(ReplicationError::Status(response.status()));
+        }
+        Ok(serde_json::from_slice(&response.bytes().await?)?)
+    }
+
+    /// Bring `dir`, the directory of `store`, up to date with the
+    /// leader. Files are fetched in the order of the manifest, and
+    /// active commits are switched once all files are in place.
+    pub async fn sync(
+        &self,
+        dir: &Path,
+        store: &VectorStore,
+    ) -> Result<SyncReport, ReplicationError> {
+        let manifest = self.manifest().await?;
+        self.check_key(dir, store, &manifest).await?;
+        let mut report = SyncReport::default();
+        for file in &manifest.files {
+            if !self.sync_file(dir, file).await? {
+                continue;
+            }
+            if is_index(&file.name) {
+                report
+                    .indexes
+                    .push(file.name.trim_end_matches(".hnsw").to_string());
+            }
+            if let Some(domain) = file_domain(&file.name) {
+                report.domains.insert(domain);
+            }
+        }
+        for (domain, commit) in &manifest.active {
+            if read_active_commit(dir, domain)?.as_deref() != Some(commit) {
+                write_active_commit(dir, domain, commit)?;
+                report.domains.insert(domain.clone());
+            }
+        }
+        let leader_domains: HashSet<String> = manifest
+            .files
+            .iter()
+            .filter(|file| file.name.ends_with(".vecs"))
+            .filter_map(|file| file_domain(&file.name))
+            .collect();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".vecs") {
+                continue;
+            }
+            if let Some(domain) = file_domain(&name) {
+                if !leader_domains.contains(&domain) {
+                    report.dropped.push(domain);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Make sure that the files of the leader are read with the key
+    /// they were sealed with, before any of them is fetched. A key
+    /// check of the leader that differs from the follower's is only
+    /// taken over if the follower's key opens it.
+    async fn check_key(
+        &self,
+        dir: &Path,
+        store: &VectorStore,
+        manifest: &Manifest,
+    ) -> Result<(), ReplicationError> {
+        let leader_check = manifest.files.iter().find(|file| is_key_check(&file.name));
+        let file = match (leader_check, store.cipher()) {
+            (None, None) => return Ok(()),
+            (None, Some(_)) => {
+                return Err(ReplicationError::KeyMismatch(
+                    "they are not encrypted, but a key is configured",
+                ))
+            }
+            (Some(_), None) => {
+                return Err(ReplicationError::KeyMismatch(
+                    "they are encrypted, but no key is configured",
+                ))
+            }
+            (Some(file), Some(_)) => file,
+        };
+        let path = dir.join(&file.name);
+        match std::fs::read(&path) {
+            Ok(check) if Some(copy_hashed(&mut &check[..], &mut io::sink())?) == file.sha256 => {
+                return Ok(())
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut check = Vec::new();
+        self.fetch(&file.name, 0, file.size, &mut check).await?;
+        if !store.accepts_key_check(&String::from_utf8_lossy(&check)) {
+            return Err(ReplicationError::KeyMismatch(
+                "they are encrypted with another key",
+            ));
+        }
+        let tmp_path = dir.join(format!("{}.tmp", file.name));
+        std::fs::write(&tmp_path, check)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Fetch whatever is missing of a file. Returns whether the file
+    /// changed.
+    async fn sync_file(&self, dir: &Path, file: &ManifestFile) -> Result<bool, ReplicationError> {
+        // the key check is taken care of by check_key
+        if !is_replicated(&file.name) || is_key_check(&file.name) {
+            return Ok(false);
+        }
+        let path = dir.join(&file.name);
+        let local = match std::fs::metadata(&path) {
+            Ok(metadata) => Some(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if local == Some(file.size) {
+            return Ok(false);
+        }
+        if is_append_only(&file.name) {
+            // A file that shrank belongs to a domain that was dropped
+            // and created again on the leader.
+            let from = local.filter(|local| *local < file.size).unwrap_or(0);
+            let mut out = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            out.set_len(from)?;
+            out.seek(SeekFrom::Start(from))?;
+            if let Err(e) = self.fetch(&file.name, from, file.size, &mut out).await {
+                // Never leave a partial vector or line behind.
+                out.set_len(from)?;
+                return Err(e);
+            }
+            out.sync_all()?;
+        } else {
+            let tmp_path = dir.join(format!("{}.tmp", file.name));
+            let mut out = File::create(&tmp_path)?;
+            self.fetch(&file.name, 0, file.size, &mut out).await?;
+            out.sync_all()?;
+            std::fs::rename(tmp_path, path)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Write the bytes `from..to` of a file on the leader to `out`.
+    async fn fetch(
+        &self,
+        name: &str,
+        from: u64,
+        to: u64,
+        out: &mut impl Write,
+    ) -> Result<(), ReplicationError> {
+        if from == to {
+            return Ok(());
+        }
+        let mut response = self
+            .get(&format!("/replication/files/{}", encode(name)))
+            .header(header::RANGE, format!("bytes={from}-{}", to - 1))
+            .send()
+            .await?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(ReplicationError::Status(response.status()));
+        }
+        let mut received = 0;
+        while let Some(chunk) = response.chunk().await? {
+            out.write_all(&chunk)?;
+            received += chunk.len() as u64;
+        }
+        if received != to - from {
+            return Err(ReplicationError::ShortRead {
+                name: name.to_string(),
+                expected: to - from,
+                received,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::create_index_name;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(Some((0, 100)), parse_range("bytes=0-", 100));
+        assert_eq!(Some((10, 20)), parse_range("bytes=10-19", 100));
+        assert_eq!(Some((90, 100)), parse_range("bytes=90-200", 100));
+        assert_eq!(None, parse_range("bytes=100-", 100));
+        assert_eq!(None, parse_range("bytes=-10", 100));
+        assert_eq!(None, parse_range("items=0-10", 100));
+    }
+
+    #[test]
+    fn manifest_of_a_store() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("admin/star_wars").unwrap();
+        store.add_vecs(&domain, [[0.0; 1536]].iter()).unwrap();
+        let index_name = create_index_name("admin/star_wars", "c1");
+        std::fs::write(tempdir.path().join(format!("{index_name}.hnsw")), "{}").unwrap();
+        write_active_commit(tempdir.path(), "admin/star_wars", "c1").unwrap();
+
+        let manifest = manifest(tempdir.path(), &store).unwrap();
+        let sizes: HashMap<_, _> = manifest
+            .files
+            .iter()
+            .map(|file| (file.name.as_str(), file.size))
+            .collect();
+        assert_eq!(6, sizes.len());
+        assert_eq!(Some(&(1536 * 4)), sizes.get("admin%2Fstar_wars.vecs"));
+        assert_eq!(Some(&0), sizes.get("admin%2Fstar_wars.docs"));
+        assert_eq!(Some(&0), sizes.get("admin%2Fstar_wars.tomb"));
+        assert_eq!(Some(&0), sizes.get("admin%2Fstar_wars.model"));
+        assert_eq!(Some(&2), sizes.get("admin%2Fstar_wars@c1.hnsw"));
+        assert_eq!(
+            Some(&"c1".to_string()),
+            manifest.active.get("admin/star_wars")
+        );
+
+        for file in &manifest.files {
+            assert!(is_replicated(&file.name));
+            assert_eq!(Some("admin/star_wars".to_string()), file_domain(&file.name));
+        }
+        assert!(!is_replicated("admin%2Fstar_wars.active"));
+        assert!(!is_replicated("../admin%2Fstar_wars.vecs"));
+    }
+
+    #[test]
+    fn manifest_hashes_the_key_check() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let key: Secret = serde_json::from_str(r#""correct horse battery staple""#).unwrap();
+        let store = VectorStore::new(tempdir.path(), 2).encrypted(&key).unwrap();
+
+        let manifest = manifest(tempdir.path(), &store).unwrap();
+        let check = std::fs::read(tempdir.path().join(ENCRYPTION_CHECK_FILE)).unwrap();
+        assert_eq!(
+            vec![ManifestFile {
+                name: ENCRYPTION_CHECK_FILE.to_string(),
+                size: check.len() as u64,
+                sha256: Some(copy_hashed(&mut &check[..], &mut io::sink()).unwrap()),
+            }],
+            manifest.files
+        );
+    }
+
+    #[test]
+    fn snapshot_of_a_store() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("admin/star_wars").unwrap();
+        store.add_vecs(&domain, [[0.0; 1536]].iter()).unwrap();
+        write_active_commit(tempdir.path(), "admin/star_wars", "c1").unwrap();
+
+        let output = tempdir.path().join("snapshot");
+        let info = snapshot(tempdir.path(), &store, &output).unwrap();
+        assert_eq!(SNAPSHOT_FORMAT_VERSION, info.format_version);
+        for file in &info.manifest.files {
+            assert_eq!(
+                file.size,
+                std::fs::metadata(output.join(&file.name)).unwrap().len()
+            );
+        }
+        assert_eq!(
+            Some("c1".to_string()),
+            read_active_commit(&output, "admin/star_wars").unwrap()
+        );
+        let copy = VectorStore::new(&output, 2);
+        assert_eq!(1, copy.get_domain("admin/star_wars").unwrap().num_vecs());
+    }
+
+    #[test]
+    fn verify_and_restore_snapshots() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = tempdir.path().join("store");
+        std::fs::create_dir(&dir).unwrap();
+        let store = VectorStore::new(&dir, 2);
+        let domain = store.get_domain("admin/star_wars").unwrap();
+        store.add_vecs(&domain, [[0.5; 1536]].iter()).unwrap();
+        write_active_commit(&dir, "admin/star_wars", "c1").unwrap();
+        let snapshots = tempdir.path().join("snapshots");
+        let first = snapshots.join("first");
+        snapshot(&dir, &store, &first).unwrap();
+        std::fs::create_dir_all(snapshots.join("unfinished")).unwrap();
+
+        let listed = list_snapshots(&snapshots).unwrap();
+        assert_eq!(1, listed.len());
+        assert_eq!(first, listed[0].0);
+        assert_eq!(1536 * 4, listed[0].1.size());
+
+        // restoring into a store with domains needs force
+        let other = store.get_domain("admin/other").unwrap();
+        store.add_vecs(&other, [[0.5; 1536]].iter()).unwrap();
+        store.sync().unwrap();
+        let clusters = dir.join("admin%2Fstar_wars.clusters");
+        std::fs::write(&clusters, b"of the vectors before the restore").unwrap();
+        assert!(matches!(
+            restore_snapshot(&first, &dir, false),
+            Err(SnapshotError::NotEmpty(_))
+        ));
+        restore_snapshot(&first, &dir, true).unwrap();
+        assert!(!clusters.exists());
+        assert!(!dir.join("admin%2Fstar_wars.vecs.tmp").exists());
+        let restored = VectorStore::new(&dir, 2);
+        assert_eq!(
+            vec!["admin/star_wars".to_string()],
+            restored.list_domains().unwrap()
+        );
+        assert_eq!(
+            Some("c1".to_string()),
+            read_active_commit(&dir, "admin/star_wars").unwrap()
+        );
+
+        let vecs = first.join("admin%2Fstar_wars.vecs");
+        let mut bytes = std::fs::read(&vecs).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&vecs, bytes).unwrap();
+        assert!(matches!(
+            verify_snapshot(&first),
+            Err(SnapshotError::WrongChecksum(name)) if name == "admin%2Fstar_wars.vecs"
+        ));
+
+        let info_path = first.join(SNAPSHOT_FILE);
+        let mut info: SnapshotInfo =
+            serde_json::from_slice(&std::fs::read(&info_path).unwrap()).unwrap();
+        info.format_version = SNAPSHOT_FORMAT_VERSION + 1;
+        std::fs::write(&info_path, serde_json::to_vec(&info).unwrap()).unwrap();
+        assert!(matches!(
+            verify_snapshot(&first),
+            Err(SnapshotError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            verify_snapshot(&snapshots.join("unfinished")),
+            Err(SnapshotError::Incomplete(_))
+        ));
+    }
+}
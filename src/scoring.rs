@@ -0,0 +1,204 @@
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::filter;
+use crate::indexer::MAX_RERANK_CANDIDATES;
+
+/// How the nearest results of a search are scored anew with their
+/// metadata, like to favour recent or popular ones. The score is the
+/// similarity times every decay, plus every boost.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Scoring {
+    #[serde(default)]
+    pub decay: Vec<Decay>,
+    #[serde(default)]
+    pub boost: Vec<Boost>,
+    /// How many of the nearest results to score. Defaults to four
+    /// times as many as are returned, up to `MAX_RERANK_CANDIDATES`.
+    #[serde(default)]
+    pub candidates: Option<usize>,
+}
+
+/// A factor that halves every `half_life` that a field is away from
+/// `origin`. Fields are numbers, or RFC 3339 dates, which count in
+/// seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Decay {
+    pub field: String,
+    /// A number or a date. Defaults to now.
+    #[serde(default)]
+    pub origin: Option<Value>,
+    pub half_life: f64,
+    /// The factor for results without the field.
+    #[serde(default = "default_missing")]
+    pub missing: f64,
+}
+
+fn default_missing() -> f64 {
+    1.0
+}
+
+/// A numeric field, times `weight`. Results without the field get
+/// nothing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Boost {
+    pub field: String,
+    pub weight: f64,
+    #[serde(default)]
+    pub modifier: Modifier,
+}
+
+/// What is done to a boosted field first, to tame large values like
+/// view counts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Modifier {
+    #[default]
+    None,
+    Log1p,
+    Sqrt,
+}
+
+/// A field as a number. Dates are seconds since the epoch.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|date| date.timestamp_millis() as f64 / 1000.0),
+        _ => None,
+    }
+}
+
+impl Scoring {
+    /// The candidates to score for `num` results.
+    pub fn candidates(&self, num: usize) -> usize {
+        self.candidates
+            .unwrap_or(num.saturating_mul(4))
+            .min(MAX_RERANK_CANDIDATES)
+            .max(num)
+    }
+
+    /// Whether scores depend on when they are taken, as they do with
+    /// decays that have no origin.
+    pub fn uses_now(&self) -> bool {
+        self.decay.iter().any(|decay| decay.origin.is_none())
+    }
+
+    /// What is wrong with this scoring, if anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, decay) in self.decay.iter().enumerate() {
+            if !(decay.half_life.is_finite() && decay.half_life > 0.0) {
+                problems.push(format!("decay[{i}].half_life: has to be more than 0"));
+            }
+            if decay.origin.as_ref().is_some_and(|o| number(o).is_none()) {
+                problems.push(format!("decay[{i}].origin: has to be a number or a date"));
+            }
+        }
+        for (i, boost) in self.boost.iter().enumerate() {
+            if !boost.weight.is_finite() {
+                problems.push(format!("boost[{i}].weight: has to be a number"));
+            }
+        }
+        if self.candidates > Some(MAX_RERANK_CANDIDATES) {
+            problems.push(format!(
+                "candidates: has to be at most {MAX_RERANK_CANDIDATES}"
+            ));
+        }
+        problems
+    }
+
+    /// The score of a result with the given similarity and metadata.
+    /// `now` is the origin of decays without one, in seconds since the
+    /// epoch.
+    pub fn score(&self, similarity: f32, metadata: Option<&Value>, now: f64) -> f32 {
+        let mut score = similarity as f64;
+        for decay in &self.decay {
+            let origin = decay.origin.as_ref().and_then(number).unwrap_or(now);
+            score *= match filter::lookup(metadata, &decay.field).and_then(number) {
+                Some(value) => 0.5f64.powf((value - origin).abs() / decay.half_life),
+                None => decay.missing,
+            };
+        }
+        for boost in &self.boost {
+            let Some(value) = filter::lookup(metadata, &boost.field).and_then(number) else {
+                continue;
+            };
+            let value = match boost.modifier {
+                Modifier::None => value,
+                Modifier::Log1p => value.max(0.0).ln_1p(),
+                Modifier::Sqrt => value.max(0.0).sqrt(),
+            };
+            score += boost.weight * value;
+        }
+        score as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn decay_and_boost() {
+        let scoring: Scoring = serde_json::from_value(json!({
+            "decay": [{"field": "published", "half_life": 86400}],
+            "boost": [{"field": "stats.views", "weight": 0.1, "modifier": "log1p"}],
+        }))
+        .unwrap();
+        assert!(scoring.problems().is_empty());
+        let now = 1_700_000_000.0;
+
+        // a day old halves the similarity
+        let day_old = json!({"published": now - 86400.0});
+        assert!((scoring.score(0.8, Some(&day_old), now) - 0.4).abs() < 1e-6);
+        let date = json!({"published": "2023-11-13T22:13:20Z"});
+        assert!((scoring.score(0.8, Some(&date), now) - 0.4).abs() < 1e-6);
+
+        let popular = json!({"published": now, "stats": {"views": std::f64::consts::E - 1.0}});
+        assert!((scoring.score(0.8, Some(&popular), now) - 0.9).abs() < 1e-6);
+
+        // results without the fields keep their similarity
+        assert_eq!(0.8, scoring.score(0.8, None, now));
+        assert_eq!(
+            0.8,
+            scoring.score(0.8, Some(&json!({"published": "never"})), now)
+        );
+    }
+
+    #[test]
+    fn refuse_bad_scorings() {
+        let scoring: Scoring = serde_json::from_value(json!({
+            "decay": [{"field": "year", "half_life": 0, "origin": "last year"}],
+            "boost": [{"field": "stars", "weight": 1}],
+            "candidates": 1_000_000,
+        }))
+        .unwrap();
+        assert_eq!(
+            vec![
+                "decay[0].half_life: has to be more than 0",
+                "decay[0].origin: has to be a number or a date",
+                "candidates: has to be at most 10000"
+            ],
+            scoring.problems()
+        );
+    }
+
+    #[test]
+    fn capped_candidates() {
+        let scoring = Scoring::default();
+        assert_eq!(40, scoring.candidates(10));
+        assert_eq!(MAX_RERANK_CANDIDATES, scoring.candidates(5_000));
+        // but never fewer than are returned
+        assert_eq!(20_000, scoring.candidates(20_000));
+        assert!(!scoring.uses_now());
+        let decayed: Scoring = serde_json::from_value(json!({
+            "decay": [{"field": "published", "half_life": 86400}],
+        }))
+        .unwrap();
+        assert!(decayed.uses_now());
+    }
+}
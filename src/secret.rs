@@ -0,0 +1,142 @@
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A key or password from the configuration. It is given in place,
+/// or read from a file, an environment variable or the output of a
+/// command when the configuration is loaded. It never shows in debug
+/// output, and is serialized redacted, so configurations can be
+/// logged and shown.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The secret itself, to send it where it belongs.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    Plain(String),
+    File { file: String },
+    Env { env: String },
+    Command { command: String },
+}
+
+/// Read a secret from a file only the owner can read, so that a key
+/// doesn't leak through a file with loose permissions.
+fn read_secret_file(path: &str) -> Result<String, String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::metadata(Path::new(path))
+            .map_err(|e| format!("can't read secret file {path}: {e}"))?;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            return Err(format!(
+                "secret file {path} can be read by other users, make it readable by its owner only, like with chmod 600"
+            ));
+        }
+    }
+    std::fs::read_to_string(Path::new(path))
+        .map_err(|e| format!("can't read secret file {path}: {e}"))
+}
+
+/// Run a command like `pass show openai` through the shell, taking
+/// what it prints as the secret. Its output is left out of errors,
+/// since it may hold the secret.
+fn run_secret_command(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| format!("can't run secret command {command}: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "secret command {command} failed with {}",
+            output.status
+        ));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| format!("secret command {command} printed something other than text"))
+}
+
+impl SecretSource {
+    fn resolve(self) -> Result<Secret, String> {
+        let secret = match self {
+            SecretSource::Plain(secret) => return Ok(Secret(secret)),
+            SecretSource::File { file } => read_secret_file(&file)?,
+            SecretSource::Env { env } => std::env::var(&env)
+                .map_err(|_| format!("environment variable {env} of a secret is not set"))?,
+            SecretSource::Command { command } => run_secret_command(&command)?,
+        };
+        Ok(Secret(secret.trim().to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SecretSource::deserialize(deserializer)?
+            .resolve()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_sources() {
+        let plain: Secret = serde_json::from_str(r#""sk-plain""#).unwrap();
+        assert_eq!("sk-plain", plain.expose());
+        assert!(!format!("{plain:?}").contains("sk-plain"));
+        assert_eq!(r#""<redacted>""#, serde_json::to_string(&plain).unwrap());
+
+        std::env::set_var("VECTORLINK_TEST_SECRET", "sk-env\n");
+        let env: Secret = serde_json::from_str(r#"{"env": "VECTORLINK_TEST_SECRET"}"#).unwrap();
+        assert_eq!("sk-env", env.expose());
+
+        let command: Secret = serde_json::from_str(r#"{"command": "echo sk-command"}"#).unwrap();
+        assert_eq!("sk-command", command.expose());
+        let failed = serde_json::from_str::<Secret>(r#"{"command": "printf sk-%s leak; exit 3"}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(failed.contains("failed"));
+        assert!(!failed.contains("sk-leak"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_files_need_strict_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("openai");
+        std::fs::write(&path, "sk-file\n").unwrap();
+        let json = serde_json::json!({ "file": path }).to_string();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let error = serde_json::from_str::<Secret>(&json).unwrap_err();
+        assert!(error.to_string().contains("other users"));
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let secret: Secret = serde_json::from_str(&json).unwrap();
+        assert_eq!("sk-file", secret.expose());
+    }
+}
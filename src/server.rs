@@ -2,10 +2,11 @@
 use bytes::Bytes;
 use futures::StreamExt;
 use futures::TryStreamExt;
-use hnsw::Hnsw;
+use hyper::header::HeaderValue;
 use hyper::HeaderMap;
 use hyper::StatusCode;
 use hyper::{
+    server::conn::Http,
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, Uri,
 };
@@ -16,46 +17,192 @@ use regex::Regex;
 use serde::Serialize;
 use serde::{self, Deserialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use space::Metric;
 use std::collections::HashSet;
+use std::hash::Hash;
 use std::string;
 use std::{
     collections::HashMap,
     convert::Infallible,
     net::{IpAddr, Ipv6Addr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use std::{
     future,
     io::{self, ErrorKind},
 };
 use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tokio::task;
 use tokio::{io::AsyncBufReadExt, sync::RwLock};
-use tokio_stream::{wrappers::LinesStream, Stream};
-use tokio_util::io::StreamReader;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::{
+    wrappers::{LinesStream, ReceiverStream},
+    Stream,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::Instrument;
 
+use crate::arithmetic::{self, Combination, Feedback};
+use crate::audit;
+use crate::cache::QueryCache;
+use crate::cluster::{merge_hits, shard_for, ShardClient, ShardError};
+use crate::compression;
+use crate::config::{
+    Access, ApiKey, Config, ReplicationConfig, ShardConfig, TenantConfig, DEFAULT_LOG_LEVEL,
+    TENANT_SEPARATOR,
+};
+use crate::deadletter::{DeadLetter, DeadLetters};
+use crate::embed::{EmbeddingCache, RateLimiter};
+use crate::encryption::{self, Cipher};
+use crate::error::{Unpoisoned, VectorlinkError};
+use crate::filter::Filter;
+use crate::indexer::check_embedder;
 use crate::indexer::create_index_name;
 use crate::indexer::deserialize_index;
+use crate::indexer::find_duplicates;
+use crate::indexer::find_outliers;
+use crate::indexer::index_statistics;
+use crate::indexer::new_index;
 use crate::indexer::operations_to_point_operations;
+use crate::indexer::parse_index_name;
+use crate::indexer::records_to_point_operations;
 use crate::indexer::search;
+use crate::indexer::search_after_among;
+use crate::indexer::search_after_filtered;
+use crate::indexer::search_exact_cancellable;
+use crate::indexer::search_with_ef;
 use crate::indexer::serialize_index;
+use crate::indexer::warm_up_index;
 use crate::indexer::IndexError;
+use crate::indexer::OutlierMethod;
 use crate::indexer::Point;
 use crate::indexer::PointOperation;
+use crate::indexer::PointQuery;
 use crate::indexer::SearchError;
-use crate::indexer::{start_indexing_from_operations, HnswIndex, IndexIdentifier, OpenAI};
-use crate::openai::{embeddings_for, EmbeddingError};
-use crate::vectors::VectorStore;
+use crate::indexer::DEFAULT_EF;
+use crate::indexer::{copy_index_versions, remove_index_versions};
+use crate::indexer::{list_index_versions, read_active_commit, write_active_commit};
+use crate::indexer::{
+    rerank_mmr, search_hybrid, Fusion, Mmr, OpenAI, Terms, MAX_RERANK_CANDIDATES,
+};
+use crate::indexer::{search_documents, Aggregation};
+use crate::indexer::{start_indexing_from_operations, start_indexing_with_progress};
+use crate::indexer::{Cancellation, SCAN_BATCH};
+use crate::indexer::{HnswIndex, IndexIdentifier};
+use crate::ingestion::{IngestionError, IngestionLimits, QueuedJob};
+use crate::lexical::{metadata_text, LexicalIndex};
+use crate::metrics;
+use crate::openai::{
+    self, embeddings_for_model, EmbeddingError, EmbeddingModel, EmbeddingProvider, ImageInput,
+};
+use crate::progress::{self, ChannelProgress, NoProgress, Progress, ProgressState};
+use crate::replication::{self, Follower, ReplicationError, SyncReport};
+use crate::scoring::Scoring;
+use crate::sparse::{SparseIndex, SparseVector};
+use crate::tls;
+use crate::usage::{self, UsageTracker};
+use crate::vecmath::{self, Embedding};
+use crate::vectors::{ClusterParams, Clustering, Domain, VectorStore};
+use crate::webhook::WebhookClient;
 
-#[derive(Clone, Deserialize, Debug)]
+/// Hand-maintained description of the routes below. Keep it in sync
+/// when adding or changing routes.
+const OPENAPI_SPEC: &str = include_str!("openapi.json");
+const SWAGGER_UI: &str = include_str!("swagger.html");
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(tag = "op")]
 pub enum Operation {
-    Inserted { string: String, id: String },
-    Changed { string: String, id: String },
-    Deleted { id: String },
-    Error { message: String },
+    Inserted {
+        string: String,
+        id: String,
+        /// The document this is a chunk of, if any.
+        #[serde(default)]
+        document: Option<String>,
+    },
+    Changed {
+        string: String,
+        id: String,
+        #[serde(default)]
+        document: Option<String>,
+    },
+    Deleted {
+        id: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// A line of an NDJSON vector upload. Records give either a vector,
+/// or an image that the model of the domain embeds.
+#[derive(Deserialize, Debug)]
+struct VectorRecord {
+    id: String,
+    #[serde(default)]
+    vector: Option<Vec<f32>>,
+    #[serde(default)]
+    image: Option<ImageInput>,
+    /// Fields that searches can filter on. A `document` string is
+    /// also recorded as the document, see `Operation`.
+    #[serde(default)]
+    metadata: Option<serde_json::Value>,
+    /// A sparse embedding of the same text, for hybrid searches.
+    #[serde(default)]
+    sparse: Option<SparseVector>,
+}
+
+impl VectorRecord {
+    /// What is wrong with this record, if anything.
+    fn problem(&self) -> Option<String> {
+        match (&self.vector, &self.image) {
+            (Some(vector), None) if vector.len() != vecmath::EMBEDDING_LENGTH => Some(format!(
+                "record {} has a vector of length {} instead of {}",
+                self.id,
+                vector.len(),
+                vecmath::EMBEDDING_LENGTH
+            )),
+            (Some(_), None) => None,
+            (None, Some(image)) => image
+                .problem()
+                .map(|problem| format!("record {}: {problem}", self.id)),
+            _ => Some(format!(
+                "record {} needs exactly one of vector and image",
+                self.id
+            )),
+        }
+    }
+}
+
+/// A line of an NDJSON export. Exports can be uploaded again as they
+/// are.
+#[derive(Serialize, Debug)]
+struct ExportRecord<'a> {
+    id: &'a str,
+    vector: &'a [f32],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sparse: Option<SparseVector>,
+}
+
+/// A vector as returned by scrolls and lookups. The vector and
+/// metadata are only there when asked for.
+#[derive(Serialize, Debug)]
+struct StoredRecord<'a> {
+    id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vector: Option<&'a [f32]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -66,12 +213,279 @@ struct IndexRequest {
     operations: Vec<Operation>,
 }
 
+/// Body of a search against `/domains/{domain}/search`. Exactly one of
+/// `vector`, `id` and `combine` should be given.
+#[derive(Serialize, Deserialize, Debug)]
+struct SearchRequest {
+    commit: Option<String>,
+    /// A query vector. It is normalized before searching.
+    vector: Option<Vec<f32>>,
+    /// The external id of an indexed vector to search around.
+    id: Option<String>,
+    /// A query vector made of indexed vectors. The vectors it is made
+    /// of are left out of the hits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    combine: Option<Combination>,
+    #[serde(default = "default_k")]
+    k: usize,
+    ef: Option<usize>,
+    #[serde(default)]
+    filter: SearchFilter,
+    /// Skip this many results.
+    #[serde(default)]
+    offset: usize,
+    /// Continue after the hit this cursor was returned with.
+    cursor: Option<String>,
+    /// Also rank by how well these keywords match the texts of the
+    /// domain, which makes this a hybrid search. The domain needs a
+    /// lexical index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keywords: Option<String>,
+    /// Also rank by the dot product of this with the sparse vectors
+    /// of the domain, instead of by keywords.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sparse: Option<SparseVector>,
+    /// How the rankings of a hybrid search are combined.
+    #[serde(default)]
+    fusion: Fusion,
+    /// Rerank the nearest results so that they are not all alike.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mmr: Option<Mmr>,
+    /// Rescore the nearest results with their metadata. Defaults to
+    /// the scoring configured for the domain.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scoring: Option<Scoring>,
+    /// Move the query towards its nearest results, and search again.
+    /// Distances are then to the moved query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feedback: Option<Feedback>,
+}
+
+impl<'a> StoredRecord<'a> {
+    fn new(point: &'a Point, domain: &Domain, vectors: bool, metadata: bool) -> Self {
+        StoredRecord {
+            id: point.id(),
+            vector: vectors.then(|| &point.vec()[..]),
+            metadata: metadata.then(|| domain.metadata(point.vec_id())).flatten(),
+        }
+    }
+}
+
+/// Body of a batch delete against `/domains/{domain}/vectors:delete`.
+#[derive(Deserialize, Debug)]
+struct DeleteRequest {
+    ids: Vec<String>,
+}
+
+/// Body of a batch lookup against `/domains/{domain}/vectors:get`.
+#[derive(Deserialize, Debug)]
+struct GetVectorsRequest {
+    ids: Vec<String>,
+    commit: Option<String>,
+    #[serde(default = "default_true")]
+    vectors: bool,
+    #[serde(default)]
+    metadata: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Body of a POST to `/domains/{domain}/clusters`.
+#[derive(Deserialize, Debug)]
+struct ClusterRequest {
+    k: usize,
+    #[serde(flatten)]
+    params: ClusterParams,
+}
+
+/// The clusters of a clustering, with their size and centroid. The
+/// assignments of the vectors stay with the domain.
+fn clusters_json(clustering: &Clustering) -> serde_json::Value {
+    let clusters: Vec<_> = clustering
+        .centroids
+        .iter()
+        .zip(clustering.sizes())
+        .map(|(centroid, size)| json!({ "size": size, "centroid": &centroid[..] }))
+        .collect();
+    json!({ "params": clustering.params, "clusters": clusters })
+}
+
+/// Body of a request to `/embed`.
+#[derive(Deserialize, Debug)]
+struct EmbedRequest {
+    input: Vec<String>,
+    /// Defaults to the configured model.
+    model: Option<String>,
+    dimensions: Option<usize>,
+}
+
+/// Optional body of a request creating a domain.
+#[derive(Deserialize, Debug, Default)]
+struct CreateDomainRequest {
+    embedding_model: Option<EmbeddingModel>,
+}
+
+/// Body of a search against `/domains/{domain}/search:text`. The text
+/// is embedded on the server, and then searched like a vector.
+#[derive(Deserialize, Debug)]
+struct TextSearchRequest {
+    text: String,
+    commit: Option<String>,
+    #[serde(default = "default_k")]
+    k: usize,
+    ef: Option<usize>,
+    #[serde(default)]
+    filter: SearchFilter,
+    #[serde(default)]
+    offset: usize,
+    cursor: Option<String>,
+    /// Also match the text as keywords against the lexical index of
+    /// the domain.
+    #[serde(default)]
+    hybrid: bool,
+    /// Also rank by the dot product of this with the sparse vectors
+    /// of the domain.
+    sparse: Option<SparseVector>,
+    #[serde(default)]
+    fusion: Fusion,
+    mmr: Option<Mmr>,
+    scoring: Option<Scoring>,
+    feedback: Option<Feedback>,
+}
+
+fn default_k() -> usize {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SearchFilter {
+    /// Only return these ids.
+    ids: Option<HashSet<String>>,
+    /// Never return these ids.
+    #[serde(default)]
+    exclude: HashSet<String>,
+    /// Only return results at most this far from the query.
+    max_distance: Option<f32>,
+    /// Only return results with at least this score.
+    min_score: Option<f32>,
+    /// Only return results whose metadata matches this expression,
+    /// see `Filter`.
+    expression: Option<String>,
+}
+
+impl SearchFilter {
+    fn keep(&self, id: &str, distance: f32) -> bool {
+        self.ids
+            .as_ref()
+            .map(|ids| ids.contains(id))
+            .unwrap_or(true)
+            && !self.exclude.contains(id)
+            && self.max_distance.map(|max| distance <= max).unwrap_or(true)
+            && self
+                .min_score
+                .map(|min| 1.0 - distance >= min)
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct SearchHit {
+    id: String,
+    /// Similarity between 0 and 1, higher is closer. For hybrid
+    /// searches this is the fused score instead, and for rescored
+    /// searches the score of their scoring.
+    score: f32,
+    distance: f32,
+    /// The document this vector is a chunk of, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<String>,
+    /// Pass this to get the results that follow this one.
+    cursor: String,
+}
+
+/// Cursors are the distance bits and the external id of a hit. The
+/// results are ordered by exactly these, so a cursor stays valid for
+/// as long as the index doesn't change.
+fn encode_cursor(distance: u32, id: &str) -> String {
+    format!("{distance:08x}:{}", urlencoding::encode(id))
+}
+
+/// A scroll token holds the commit that a scroll started on, and the
+/// position in its index to go on from.
+fn encode_scroll_token(commit: &str, position: usize) -> String {
+    format!("{position:x}:{}", urlencoding::encode(commit))
+}
+
+fn decode_scroll_token(token: &str) -> Result<(String, usize), ResponseError> {
+    let invalid = || ResponseError::InvalidQuery(format!("invalid scroll token {token}"));
+    let (position, commit) = token.split_once(':').ok_or_else(invalid)?;
+    let position = usize::from_str_radix(position, 16).map_err(|_| invalid())?;
+    let commit = urlencoding::decode(commit).map_err(|_| invalid())?;
+    Ok((commit.into_owned(), position))
+}
+
+fn decode_cursor(cursor: &str) -> Result<(f32, String), ResponseError> {
+    let invalid = || ResponseError::InvalidQuery(format!("invalid cursor {cursor}"));
+    let (distance, id) = cursor.split_once(':').ok_or_else(invalid)?;
+    let distance = u32::from_str_radix(distance, 16).map_err(|_| invalid())?;
+    let id = urlencoding::decode(id).map_err(|_| invalid())?;
+    Ok((f32::from_bits(distance), id.into_owned()))
+}
+
+/// The nodes of the points of an index, by external id. An id that
+/// was uploaded more than once has a node for every upload, in order.
+type IdMap = HashMap<String, Vec<usize>>;
+
+/// The point of an index with the given external id, unless its
+/// vector was deleted.
+fn find_point(
+    hnsw: &HnswIndex,
+    id_map: &IdMap,
+    domain: &Domain,
+    id: &str,
+) -> Result<Point, ResponseError> {
+    find_points(hnsw, id_map, domain, &HashSet::from([id]))
+        .remove(id)
+        .cloned()
+        .ok_or_else(|| ResponseError::IdMissing(id.to_string()))
+}
+
+/// The points of an index with the given external ids, the latest
+/// one for ids that were uploaded more than once. Ids of deleted
+/// vectors, or that were never indexed, are left out.
+fn find_points<'a>(
+    hnsw: &'a HnswIndex,
+    id_map: &IdMap,
+    domain: &Domain,
+    ids: &HashSet<&str>,
+) -> HashMap<&'a str, &'a Point> {
+    ids.iter()
+        .filter_map(|id| id_map.get(*id))
+        .filter_map(|nodes| {
+            nodes
+                .iter()
+                .rev()
+                .map(|&i| hnsw.feature(i))
+                .find(|p| !domain.is_deleted(p.vec_id()))
+        })
+        .map(|p| (p.id(), p))
+        .collect()
+}
+
 #[derive(Debug)]
 enum ResourceSpec {
     Search {
         domain: String,
-        commit: String,
+        commit: Option<String>,
         count: usize,
+        exact: bool,
+        ef: Option<usize>,
+        aggregate: Option<Aggregation>,
+        stream: bool,
+        offset: usize,
+        max_distance: Option<f32>,
     },
     StartIndex {
         domain: String,
@@ -86,18 +500,388 @@ enum ResourceSpec {
     CheckTask {
         task_id: String,
     },
+    GetJob {
+        job_id: String,
+    },
     Similar {
         domain: String,
-        commit: String,
+        commit: Option<String>,
         id: String,
         count: usize,
+        ef: Option<usize>,
+        max_distance: Option<f32>,
     },
     DuplicateCandidates {
         domain: String,
         commit: String,
         threshold: f32,
+        stream: bool,
+    },
+    IndexStatistics {
+        domain: String,
+        commit: String,
+    },
+    Outliers {
+        domain: String,
+        commit: String,
+        threshold: f32,
+        k: usize,
+        ef: Option<usize>,
+        clusters: bool,
+    },
+    ActivateIndex {
+        domain: String,
+        commit: String,
+    },
+    GetVersions {
+        domain: String,
+    },
+    DomainSearch {
+        domain: String,
+    },
+    DomainTextSearch {
+        domain: String,
+    },
+    DeleteVectors {
+        domain: String,
+    },
+    GetVectors {
+        domain: String,
+    },
+    /// A vector by its external id, to get or to delete.
+    DomainVector {
+        domain: String,
+        id: String,
+        commit: Option<String>,
+        vectors: bool,
+        metadata: bool,
+    },
+    ExportVectors {
+        domain: String,
+        commit: Option<String>,
+        /// Go on after the vector with this external id.
+        after: Option<String>,
+    },
+    ScrollVectors {
+        domain: String,
+        commit: Option<String>,
+        /// Where the previous page left off.
+        token: Option<String>,
+        size: usize,
+        vectors: bool,
+        metadata: bool,
+    },
+    DomainSimilar {
+        domain: String,
+        id: String,
+        commit: Option<String>,
+        k: usize,
+        ef: Option<usize>,
+        max_distance: Option<f32>,
+    },
+    UploadVectors {
+        domain: String,
+        dimension: Option<usize>,
+        commit: Option<String>,
+        previous: Option<String>,
+    },
+    /// The saved clustering of a domain, or a new one on a POST.
+    DomainClusters {
+        domain: String,
     },
     GetStatistics,
+    Metrics,
+    Healthz,
+    Readyz,
+    OpenApi,
+    Docs,
+    AdminListDomains {
+        /// Only list the domains of this tenant.
+        tenant: Option<String>,
+    },
+    /// Describe, create or drop a domain, depending on the method.
+    AdminDomain {
+        domain: String,
+    },
+    AdminDeriveDomain {
+        domain: String,
+        source: String,
+    },
+    AdminIndexDomain {
+        domain: String,
+        commit: String,
+        previous: Option<String>,
+    },
+    /// List the dead letters of a domain, or replay them on a POST.
+    AdminDeadLetters {
+        domain: String,
+    },
+    /// The configuration the server runs with, secrets redacted.
+    AdminConfig,
+    AdminReloadConfig,
+    AdminUsage,
+    Embed,
+    ReplicationManifest,
+    ReplicationFile {
+        name: String,
+    },
+}
+
+impl ResourceSpec {
+    /// The domain this request acts on, if any.
+    fn domain(&self) -> Option<&str> {
+        match self {
+            ResourceSpec::Search { domain, .. }
+            | ResourceSpec::StartIndex { domain, .. }
+            | ResourceSpec::AssignIndex { domain, .. }
+            | ResourceSpec::Similar { domain, .. }
+            | ResourceSpec::DuplicateCandidates { domain, .. }
+            | ResourceSpec::IndexStatistics { domain, .. }
+            | ResourceSpec::Outliers { domain, .. }
+            | ResourceSpec::ActivateIndex { domain, .. }
+            | ResourceSpec::GetVersions { domain }
+            | ResourceSpec::DomainSearch { domain }
+            | ResourceSpec::DomainTextSearch { domain }
+            | ResourceSpec::DomainSimilar { domain, .. }
+            | ResourceSpec::ExportVectors { domain, .. }
+            | ResourceSpec::ScrollVectors { domain, .. }
+            | ResourceSpec::DeleteVectors { domain }
+            | ResourceSpec::GetVectors { domain }
+            | ResourceSpec::DomainVector { domain, .. }
+            | ResourceSpec::UploadVectors { domain, .. }
+            | ResourceSpec::DomainClusters { domain }
+            | ResourceSpec::AdminDomain { domain }
+            | ResourceSpec::AdminDeriveDomain { domain, .. }
+            | ResourceSpec::AdminIndexDomain { domain, .. }
+            | ResourceSpec::AdminDeadLetters { domain } => Some(domain),
+            ResourceSpec::CheckTask { .. }
+            | ResourceSpec::GetJob { .. }
+            | ResourceSpec::GetStatistics
+            | ResourceSpec::Metrics
+            | ResourceSpec::Healthz
+            | ResourceSpec::Readyz
+            | ResourceSpec::OpenApi
+            | ResourceSpec::Docs
+            | ResourceSpec::AdminListDomains { .. }
+            | ResourceSpec::AdminConfig
+            | ResourceSpec::AdminReloadConfig
+            | ResourceSpec::AdminUsage
+            | ResourceSpec::Embed
+            | ResourceSpec::ReplicationManifest
+            | ResourceSpec::ReplicationFile { .. } => None,
+        }
+    }
+
+    fn domain_mut(&mut self) -> Option<&mut String> {
+        match self {
+            ResourceSpec::Search { domain, .. }
+            | ResourceSpec::StartIndex { domain, .. }
+            | ResourceSpec::AssignIndex { domain, .. }
+            | ResourceSpec::Similar { domain, .. }
+            | ResourceSpec::DuplicateCandidates { domain, .. }
+            | ResourceSpec::IndexStatistics { domain, .. }
+            | ResourceSpec::Outliers { domain, .. }
+            | ResourceSpec::ActivateIndex { domain, .. }
+            | ResourceSpec::GetVersions { domain }
+            | ResourceSpec::DomainSearch { domain }
+            | ResourceSpec::DomainTextSearch { domain }
+            | ResourceSpec::DomainSimilar { domain, .. }
+            | ResourceSpec::ExportVectors { domain, .. }
+            | ResourceSpec::ScrollVectors { domain, .. }
+            | ResourceSpec::DeleteVectors { domain }
+            | ResourceSpec::GetVectors { domain }
+            | ResourceSpec::DomainVector { domain, .. }
+            | ResourceSpec::UploadVectors { domain, .. }
+            | ResourceSpec::DomainClusters { domain }
+            | ResourceSpec::AdminDomain { domain }
+            | ResourceSpec::AdminDeriveDomain { domain, .. }
+            | ResourceSpec::AdminIndexDomain { domain, .. }
+            | ResourceSpec::AdminDeadLetters { domain } => Some(domain),
+            ResourceSpec::CheckTask { .. }
+            | ResourceSpec::GetJob { .. }
+            | ResourceSpec::GetStatistics
+            | ResourceSpec::Metrics
+            | ResourceSpec::Healthz
+            | ResourceSpec::Readyz
+            | ResourceSpec::OpenApi
+            | ResourceSpec::Docs
+            | ResourceSpec::AdminListDomains { .. }
+            | ResourceSpec::AdminConfig
+            | ResourceSpec::AdminReloadConfig
+            | ResourceSpec::AdminUsage
+            | ResourceSpec::Embed
+            | ResourceSpec::ReplicationManifest
+            | ResourceSpec::ReplicationFile { .. } => None,
+        }
+    }
+
+    /// Rewrite the domains of this request to the tenant's namespace,
+    /// so that a tenant can never reach the domains of another.
+    fn scope_to_tenant(&mut self, tenant: &str) {
+        match self {
+            ResourceSpec::AdminListDomains { tenant: t } => *t = Some(tenant.to_string()),
+            ResourceSpec::AdminDeriveDomain { source, .. } => {
+                *source = scoped_domain(tenant, source)
+            }
+            _ => {}
+        }
+        if let Some(domain) = self.domain_mut() {
+            *domain = scoped_domain(tenant, domain);
+        }
+    }
+
+    fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            ResourceSpec::AdminListDomains { .. }
+                | ResourceSpec::AdminDomain { .. }
+                | ResourceSpec::AdminDeriveDomain { .. }
+                | ResourceSpec::AdminIndexDomain { .. }
+                | ResourceSpec::AdminDeadLetters { .. }
+                | ResourceSpec::AdminConfig
+                | ResourceSpec::AdminReloadConfig
+                | ResourceSpec::AdminUsage
+                | ResourceSpec::ReplicationManifest
+                | ResourceSpec::ReplicationFile { .. }
+        )
+    }
+
+    /// The access to its domain that a key needs for this request.
+    fn required_access(&self, method: &Method) -> Access {
+        match self {
+            ResourceSpec::AdminDomain { .. } if *method != Method::GET => Access::Admin,
+            ResourceSpec::AdminDeriveDomain { .. } => Access::Admin,
+            _ if self.is_write(method) => Access::Write,
+            _ => Access::Read,
+        }
+    }
+
+    /// Whether this request is a search, as counted in usage.
+    fn is_query(&self) -> bool {
+        matches!(
+            self,
+            ResourceSpec::Search { .. }
+                | ResourceSpec::Similar { .. }
+                | ResourceSpec::DomainSearch { .. }
+                | ResourceSpec::DomainTextSearch { .. }
+                | ResourceSpec::DomainSimilar { .. }
+        )
+    }
+
+    /// Whether this request changes what is stored, which followers
+    /// refuse.
+    fn is_write(&self, method: &Method) -> bool {
+        match self {
+            ResourceSpec::StartIndex { .. }
+            | ResourceSpec::AssignIndex { .. }
+            | ResourceSpec::ActivateIndex { .. }
+            | ResourceSpec::UploadVectors { .. }
+            | ResourceSpec::DeleteVectors { .. }
+            | ResourceSpec::AdminDeriveDomain { .. }
+            | ResourceSpec::AdminIndexDomain { .. } => true,
+            ResourceSpec::AdminDomain { .. }
+            | ResourceSpec::DomainVector { .. }
+            | ResourceSpec::AdminDeadLetters { .. }
+            | ResourceSpec::DomainClusters { .. } => *method != Method::GET,
+            _ => false,
+        }
+    }
+}
+
+lazy_static! {
+    static ref TENANT_REQUESTS: Arc<metrics::LabeledCounter> = metrics::registry().labeled_counter(
+        "vectorlink_tenant_requests_total",
+        "Requests made with the keys of a tenant.",
+        "tenant"
+    );
+    static ref TENANT_QUERIES: Arc<metrics::LabeledCounter> = metrics::registry().labeled_counter(
+        "vectorlink_tenant_queries_total",
+        "Searches made with the keys of a tenant.",
+        "tenant"
+    );
+}
+
+/// The stored name of a tenant's domain. Tenant names can't contain
+/// the separator, so the tenant is everything before its first
+/// occurrence.
+fn scoped_domain(tenant: &str, domain: &str) -> String {
+    format!("{tenant}{TENANT_SEPARATOR}{domain}")
+}
+
+/// The tenant a stored domain belongs to, if any.
+fn domain_tenant(domain: &str) -> Option<&str> {
+    domain
+        .split_once(TENANT_SEPARATOR)
+        .map(|(tenant, _)| tenant)
+}
+
+/// What the ids of the jobs of a tenant start with. Job ids only hold
+/// letters and digits, so this is a hash of the tenant's name.
+fn tenant_job_tag(tenant: &str) -> String {
+    Sha256::digest(tenant.as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// A new id for a job on the given domain. The jobs of a tenant carry
+/// its tag, so that only its keys can look them up.
+fn generate_job_id(domain: &str) -> String {
+    match domain_tenant(domain) {
+        Some(tenant) => format!("{}{}", tenant_job_tag(tenant), Service::generate_task()),
+        None => Service::generate_task(),
+    }
+}
+
+/// Whether the job is one of the tenant's.
+fn is_tenant_job(job_id: &str, tenant: &str) -> bool {
+    let tag = tenant_job_tag(tenant);
+    job_id.len() == tag.len() + 8 && job_id.starts_with(&tag)
+}
+
+fn check_access(key: &ApiKey, domain: &str, needed: Access) -> Result<(), AuthError> {
+    match key.access(domain) {
+        Some(access) if access >= needed => Ok(()),
+        Some(_) => Err(AuthError::AccessNotPermitted {
+            domain: domain.to_string(),
+            needed,
+        }),
+        None => Err(AuthError::DomainNotPermitted(domain.to_string())),
+    }
+}
+
+#[derive(Debug, Error)]
+enum AuthError {
+    #[error("No API key given")]
+    MissingKey,
+    #[error("Unknown API key")]
+    UnknownKey,
+    #[error("API key does not give access to domain {0}")]
+    DomainNotPermitted(String),
+    #[error("API key does not give {needed} access to domain {domain}")]
+    AccessNotPermitted { domain: String, needed: Access },
+    #[error("API key does not give access to admin routes")]
+    AdminNotPermitted,
+    #[error("Admin routes are disabled as no API keys are configured")]
+    AdminDisabled,
+    #[error("This server is a read-only follower")]
+    ReadOnly,
+    #[error("API key does not give access to job {0}")]
+    JobNotPermitted(String),
+}
+
+impl AuthError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::MissingKey | AuthError::UnknownKey => StatusCode::UNAUTHORIZED,
+            AuthError::DomainNotPermitted(_)
+            | AuthError::AccessNotPermitted { .. }
+            | AuthError::AdminNotPermitted
+            | AuthError::AdminDisabled
+            | AuthError::ReadOnly
+            | AuthError::JobNotPermitted(_) => StatusCode::FORBIDDEN,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -108,6 +892,10 @@ enum SpecParseError {
     NoTaskId,
     #[error("No commit id or domain id given")]
     NoCommitIdOrDomain,
+    #[error("Unknown aggregation {0}, expected max or mean")]
+    UnknownAggregation(String),
+    #[error("Invalid value for query parameter {0}")]
+    InvalidParameter(&'static str),
 }
 
 fn query_map(uri: &Uri) -> HashMap<String, String> {
@@ -120,7 +908,7 @@ fn query_map(uri: &Uri) -> HashMap<String, String> {
         .unwrap_or_else(|| HashMap::with_capacity(0))
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 enum HeaderError {
     #[error("Key was not valid utf8")]
     KeyNotUtf8,
@@ -142,6 +930,37 @@ fn get_header_value(header: &HeaderMap, key: &str) -> Result<String, HeaderError
     }
 }
 
+/// A query parameter parsed as a `T`, if it was given.
+fn query_param<T: FromStr>(
+    query: &HashMap<String, String>,
+    name: &'static str,
+) -> Result<Option<T>, SpecParseError> {
+    query
+        .get(name)
+        .map(|v| v.parse::<T>())
+        .transpose()
+        .map_err(|_| SpecParseError::InvalidParameter(name))
+}
+
+/// The distance beyond which results are dropped, from a
+/// `max_distance` or `min_score` query parameter. With both, the
+/// stricter one wins.
+fn distance_threshold(query: &HashMap<String, String>) -> Result<Option<f32>, SpecParseError> {
+    let max_distance = query_param::<f32>(query, "max_distance")?;
+    let min_score = query_param::<f32>(query, "min_score")?.map(|score| 1.0 - score);
+    Ok(match (max_distance, min_score) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    })
+}
+
+/// Decode a domain name that was given as part of the path.
+fn path_domain(segment: &str) -> Result<String, SpecParseError> {
+    urlencoding::decode(segment)
+        .map(|d| d.into_owned())
+        .map_err(|_| SpecParseError::UnknownPath)
+}
+
 fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
     lazy_static! {
         static ref RE_INDEX: Regex = Regex::new(r"^/index(/?)$").unwrap();
@@ -151,6 +970,46 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         static ref RE_SIMILAR: Regex = Regex::new(r"^/similar(/?)$").unwrap();
         static ref RE_DUPLICATES: Regex = Regex::new(r"^/duplicates(/?)$").unwrap();
         static ref RE_STATISTICS: Regex = Regex::new(r"^/statistics$").unwrap();
+        static ref RE_METRICS: Regex = Regex::new(r"^/metrics$").unwrap();
+        static ref RE_INDEX_STATISTICS: Regex = Regex::new(r"^/index_statistics(/?)$").unwrap();
+        static ref RE_OUTLIERS: Regex = Regex::new(r"^/outliers(/?)$").unwrap();
+        static ref RE_ACTIVATE: Regex = Regex::new(r"^/activate(/?)$").unwrap();
+        static ref RE_VERSIONS: Regex = Regex::new(r"^/versions(/?)$").unwrap();
+        static ref RE_DOMAIN_SEARCH: Regex = Regex::new(r"^/domains/(.+)/search(/?)$").unwrap();
+        static ref RE_DOMAIN_TEXT_SEARCH: Regex =
+            Regex::new(r"^/domains/(.+)/search:text$").unwrap();
+        static ref RE_DOMAIN_VECTORS: Regex = Regex::new(r"^/domains/(.+)/vectors(/?)$").unwrap();
+        static ref RE_DOMAIN_SIMILAR: Regex =
+            Regex::new(r"^/domains/(.+)/vectors/([^/]+)/similar(/?)$").unwrap();
+        static ref RE_DOMAIN_EXPORT: Regex = Regex::new(r"^/domains/(.+)/export(/?)$").unwrap();
+        static ref RE_DOMAIN_SCROLL: Regex = Regex::new(r"^/domains/(.+)/scroll(/?)$").unwrap();
+        static ref RE_DOMAIN_CLUSTERS: Regex = Regex::new(r"^/domains/(.+)/clusters(/?)$").unwrap();
+        static ref RE_DOMAIN_VECTOR: Regex =
+            Regex::new(r"^/domains/(.+)/vectors/([^/]+?)(/?)$").unwrap();
+        static ref RE_DOMAIN_VECTORS_DELETE: Regex =
+            Regex::new(r"^/domains/(.+)/vectors:delete$").unwrap();
+        static ref RE_DOMAIN_VECTORS_GET: Regex =
+            Regex::new(r"^/domains/(.+)/vectors:get$").unwrap();
+        static ref RE_JOB: Regex = Regex::new(r"^/jobs/([A-Za-z0-9]+)(/?)$").unwrap();
+        static ref RE_HEALTHZ: Regex = Regex::new(r"^/healthz(/?)$").unwrap();
+        static ref RE_READYZ: Regex = Regex::new(r"^/readyz(/?)$").unwrap();
+        static ref RE_OPENAPI: Regex = Regex::new(r"^/openapi.json$").unwrap();
+        static ref RE_DOCS: Regex = Regex::new(r"^/docs(/?)$").unwrap();
+        static ref RE_EMBED: Regex = Regex::new(r"^/embed(/?)$").unwrap();
+        static ref RE_ADMIN_DOMAINS: Regex = Regex::new(r"^/admin/domains(/?)$").unwrap();
+        static ref RE_ADMIN_CONFIG: Regex = Regex::new(r"^/admin/config(/?)$").unwrap();
+        static ref RE_ADMIN_RELOAD: Regex = Regex::new(r"^/admin/config/reload(/?)$").unwrap();
+        static ref RE_ADMIN_USAGE: Regex = Regex::new(r"^/admin/usage(/?)$").unwrap();
+        static ref RE_ADMIN_DERIVE: Regex =
+            Regex::new(r"^/admin/domains/(.+)/derive(/?)$").unwrap();
+        static ref RE_ADMIN_INDEX: Regex = Regex::new(r"^/admin/domains/(.+)/index(/?)$").unwrap();
+        static ref RE_ADMIN_DEAD_LETTERS: Regex =
+            Regex::new(r"^/admin/domains/(.+)/dead_letters(/?)$").unwrap();
+        static ref RE_ADMIN_DOMAIN: Regex = Regex::new(r"^/admin/domains/(.+?)(/?)$").unwrap();
+        static ref RE_REPLICATION_MANIFEST: Regex =
+            Regex::new(r"^/replication/manifest(/?)$").unwrap();
+        static ref RE_REPLICATION_FILE: Regex =
+            Regex::new(r"^/replication/files/([^/]+)$").unwrap();
     }
     let path = uri.path();
 
@@ -195,14 +1054,31 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         let query = query_map(uri);
         let domain = query.get("domain").map(|v| v.to_string());
         let commit = query.get("commit").map(|v| v.to_string());
-        let count = query.get("count").map(|v| v.parse::<usize>().unwrap());
+        let count = query_param::<usize>(&query, "count")?;
+        let exact = query.get("exact").map(|v| v == "true").unwrap_or(false);
+        let ef = query_param::<usize>(&query, "ef")?;
+        let aggregate = match query.get("aggregate").map(|v| v.as_str()) {
+            None => None,
+            Some("max") => Some(Aggregation::Max),
+            Some("mean") => Some(Aggregation::Mean),
+            Some(other) => return Err(SpecParseError::UnknownAggregation(other.to_string())),
+        };
+        let stream = query.get("stream").map(|v| v == "true").unwrap_or(false);
+        let offset = query_param::<usize>(&query, "offset")?;
+        let max_distance = distance_threshold(&query)?;
         match (domain, commit) {
-            (Some(domain), Some(commit)) => {
+            (Some(domain), commit) => {
                 let count = count.unwrap_or(10);
                 Ok(ResourceSpec::Search {
                     domain,
                     commit,
                     count,
+                    exact,
+                    ef,
+                    aggregate,
+                    stream,
+                    offset: offset.unwrap_or(0),
+                    max_distance,
                 })
             }
             _ => Err(SpecParseError::NoCommitIdOrDomain),
@@ -212,15 +1088,19 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         let domain = query.get("domain").map(|v| v.to_string());
         let commit = query.get("commit").map(|v| v.to_string());
         let id = query.get("id").map(|v| v.to_string());
-        let count = query.get("count").map(|v| v.parse::<usize>().unwrap());
+        let count = query_param::<usize>(&query, "count")?;
+        let ef = query_param::<usize>(&query, "ef")?;
+        let max_distance = distance_threshold(&query)?;
         match (domain, commit, id) {
-            (Some(domain), Some(commit), Some(id)) => {
+            (Some(domain), commit, Some(id)) => {
                 let count = count.unwrap_or(10);
                 Ok(ResourceSpec::Similar {
                     domain,
                     commit,
                     id,
                     count,
+                    ef,
+                    max_distance,
                 })
             }
             _ => Err(SpecParseError::NoCommitIdOrDomain),
@@ -229,7 +1109,8 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         let query = query_map(uri);
         let domain = query.get("domain").map(|v| v.to_string());
         let commit = query.get("commit").map(|v| v.to_string());
-        let threshold = query.get("threshold").map(|v| v.parse::<f32>().unwrap());
+        let threshold = query_param::<f32>(&query, "threshold")?;
+        let stream = query.get("stream").map(|v| v == "true").unwrap_or(false);
         match (domain, commit) {
             (Some(domain), Some(commit)) => {
                 let threshold = threshold.unwrap_or(0.0);
@@ -237,24 +1118,285 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
                     domain,
                     commit,
                     threshold,
+                    stream,
                 })
             }
             _ => Err(SpecParseError::NoCommitIdOrDomain),
         }
+    } else if let Some(captures) = RE_JOB.captures(path) {
+        Ok(ResourceSpec::GetJob {
+            job_id: captures[1].to_string(),
+        })
+    } else if RE_HEALTHZ.is_match(path) {
+        Ok(ResourceSpec::Healthz)
+    } else if RE_READYZ.is_match(path) {
+        Ok(ResourceSpec::Readyz)
+    } else if RE_OPENAPI.is_match(path) {
+        Ok(ResourceSpec::OpenApi)
+    } else if RE_DOCS.is_match(path) {
+        Ok(ResourceSpec::Docs)
+    } else if RE_EMBED.is_match(path) {
+        Ok(ResourceSpec::Embed)
     } else if RE_STATISTICS.is_match(path) {
         Ok(ResourceSpec::GetStatistics)
+    } else if RE_METRICS.is_match(path) {
+        Ok(ResourceSpec::Metrics)
+    } else if RE_INDEX_STATISTICS.is_match(path) {
+        let query = query_map(uri);
+        let domain = query.get("domain").map(|v| v.to_string());
+        let commit = query.get("commit").map(|v| v.to_string());
+        match (domain, commit) {
+            (Some(domain), Some(commit)) => Ok(ResourceSpec::IndexStatistics { domain, commit }),
+            _ => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if RE_OUTLIERS.is_match(path) {
+        let query = query_map(uri);
+        let domain = query.get("domain").map(|v| v.to_string());
+        let commit = query.get("commit").map(|v| v.to_string());
+        let threshold = query_param::<f32>(&query, "threshold")?
+            .ok_or(SpecParseError::InvalidParameter("threshold"))?;
+        let k = query_param::<usize>(&query, "k")?.unwrap_or(DEFAULT_OUTLIER_NEIGHBORS);
+        if k == 0 {
+            return Err(SpecParseError::InvalidParameter("k"));
+        }
+        let ef = query_param::<usize>(&query, "ef")?;
+        let clusters = query.get("clusters").map(|v| v == "true").unwrap_or(false);
+        match (domain, commit) {
+            (Some(domain), Some(commit)) => Ok(ResourceSpec::Outliers {
+                domain,
+                commit,
+                threshold,
+                k,
+                ef,
+                clusters,
+            }),
+            _ => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if RE_ACTIVATE.is_match(path) {
+        let query = query_map(uri);
+        let domain = query.get("domain").map(|v| v.to_string());
+        let commit = query.get("commit").map(|v| v.to_string());
+        match (domain, commit) {
+            (Some(domain), Some(commit)) => Ok(ResourceSpec::ActivateIndex { domain, commit }),
+            _ => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if RE_VERSIONS.is_match(path) {
+        let query = query_map(uri);
+        match query.get("domain") {
+            Some(domain) => Ok(ResourceSpec::GetVersions {
+                domain: domain.to_string(),
+            }),
+            None => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if let Some(captures) = RE_DOMAIN_SEARCH.captures(path) {
+        Ok(ResourceSpec::DomainSearch {
+            domain: path_domain(&captures[1])?,
+        })
+    } else if let Some(captures) = RE_DOMAIN_TEXT_SEARCH.captures(path) {
+        Ok(ResourceSpec::DomainTextSearch {
+            domain: path_domain(&captures[1])?,
+        })
+    } else if let Some(captures) = RE_DOMAIN_SIMILAR.captures(path) {
+        let query = query_map(uri);
+        let k = match query.get("k") {
+            Some(k) => k
+                .parse()
+                .map_err(|_| SpecParseError::InvalidParameter("k"))?,
+            None => default_k(),
+        };
+        let ef = query
+            .get("ef")
+            .map(|v| v.parse())
+            .transpose()
+            .map_err(|_| SpecParseError::InvalidParameter("ef"))?;
+        Ok(ResourceSpec::DomainSimilar {
+            domain: path_domain(&captures[1])?,
+            id: path_domain(&captures[2])?,
+            commit: query.get("commit").map(|v| v.to_string()),
+            k,
+            ef,
+            max_distance: distance_threshold(&query)?,
+        })
+    } else if let Some(captures) = RE_DOMAIN_EXPORT.captures(path) {
+        let query = query_map(uri);
+        Ok(ResourceSpec::ExportVectors {
+            domain: path_domain(&captures[1])?,
+            commit: query.get("commit").map(|v| v.to_string()),
+            after: query.get("after").map(|v| v.to_string()),
+        })
+    } else if let Some(captures) = RE_DOMAIN_SCROLL.captures(path) {
+        let query = query_map(uri);
+        let size = query
+            .get("size")
+            .map(|v| v.parse())
+            .transpose()
+            .map_err(|_| SpecParseError::InvalidParameter("size"))?;
+        Ok(ResourceSpec::ScrollVectors {
+            domain: path_domain(&captures[1])?,
+            commit: query.get("commit").map(|v| v.to_string()),
+            token: query.get("token").map(|v| v.to_string()),
+            size: size.unwrap_or(DEFAULT_SCROLL_SIZE),
+            vectors: query.get("vectors").map(|v| v == "true").unwrap_or(true),
+            metadata: query.get("metadata").map(|v| v == "true").unwrap_or(false),
+        })
+    } else if let Some(captures) = RE_DOMAIN_CLUSTERS.captures(path) {
+        Ok(ResourceSpec::DomainClusters {
+            domain: path_domain(&captures[1])?,
+        })
+    } else if let Some(captures) = RE_DOMAIN_VECTORS_DELETE.captures(path) {
+        Ok(ResourceSpec::DeleteVectors {
+            domain: path_domain(&captures[1])?,
+        })
+    } else if let Some(captures) = RE_DOMAIN_VECTORS_GET.captures(path) {
+        Ok(ResourceSpec::GetVectors {
+            domain: path_domain(&captures[1])?,
+        })
+    } else if let Some(captures) = RE_DOMAIN_VECTOR.captures(path) {
+        let query = query_map(uri);
+        Ok(ResourceSpec::DomainVector {
+            domain: path_domain(&captures[1])?,
+            id: path_domain(&captures[2])?,
+            commit: query.get("commit").map(|v| v.to_string()),
+            vectors: query.get("vectors").map(|v| v == "true").unwrap_or(true),
+            metadata: query.get("metadata").map(|v| v == "true").unwrap_or(false),
+        })
+    } else if let Some(captures) = RE_DOMAIN_VECTORS.captures(path) {
+        let query = query_map(uri);
+        let dimension = query_param::<usize>(&query, "dimension")?;
+        Ok(ResourceSpec::UploadVectors {
+            domain: path_domain(&captures[1])?,
+            dimension,
+            commit: query.get("commit").map(|v| v.to_string()),
+            previous: query.get("previous").map(|v| v.to_string()),
+        })
+    } else if RE_ADMIN_CONFIG.is_match(path) {
+        Ok(ResourceSpec::AdminConfig)
+    } else if RE_ADMIN_RELOAD.is_match(path) {
+        Ok(ResourceSpec::AdminReloadConfig)
+    } else if RE_ADMIN_USAGE.is_match(path) {
+        Ok(ResourceSpec::AdminUsage)
+    } else if RE_REPLICATION_MANIFEST.is_match(path) {
+        Ok(ResourceSpec::ReplicationManifest)
+    } else if let Some(captures) = RE_REPLICATION_FILE.captures(path) {
+        Ok(ResourceSpec::ReplicationFile {
+            name: path_domain(&captures[1])?,
+        })
+    } else if RE_ADMIN_DOMAINS.is_match(path) {
+        Ok(ResourceSpec::AdminListDomains { tenant: None })
+    } else if let Some(captures) = RE_ADMIN_DERIVE.captures(path) {
+        let query = query_map(uri);
+        match query.get("source") {
+            Some(source) => Ok(ResourceSpec::AdminDeriveDomain {
+                domain: path_domain(&captures[1])?,
+                source: source.to_string(),
+            }),
+            None => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if let Some(captures) = RE_ADMIN_INDEX.captures(path) {
+        let query = query_map(uri);
+        let previous = query.get("previous").map(|v| v.to_string());
+        match query.get("commit") {
+            Some(commit) => Ok(ResourceSpec::AdminIndexDomain {
+                domain: path_domain(&captures[1])?,
+                commit: commit.to_string(),
+                previous,
+            }),
+            None => Err(SpecParseError::NoCommitIdOrDomain),
+        }
+    } else if let Some(captures) = RE_ADMIN_DEAD_LETTERS.captures(path) {
+        Ok(ResourceSpec::AdminDeadLetters {
+            domain: path_domain(&captures[1])?,
+        })
+    } else if let Some(captures) = RE_ADMIN_DOMAIN.captures(path) {
+        Ok(ResourceSpec::AdminDomain {
+            domain: path_domain(&captures[1])?,
+        })
     } else {
         Err(SpecParseError::UnknownPath)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum TaskStatus {
     Pending(f32),
     Error(String),
     Completed(usize),
 }
 
+impl TaskStatus {
+    fn to_json(&self, job_id: &str) -> serde_json::Value {
+        match self {
+            TaskStatus::Pending(f) => {
+                json!({"id": job_id, "state": "pending", "progress": f * 100.0})
+            }
+            TaskStatus::Error(msg) => {
+                json!({"id": job_id, "state": "error", "error": msg})
+            }
+            TaskStatus::Completed(u) => {
+                json!({"id": job_id, "state": "completed", "progress": 100.0, "indexed_documents": u})
+            }
+        }
+    }
+}
+
+fn jobs_dir(dir: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push("jobs");
+    path
+}
+
+/// Persist the status of a job, so that it can still be queried after
+/// a restart. Errors can quote the documents, so the status is sealed
+/// in an encrypted store.
+fn write_job(
+    dir: &Path,
+    job_id: &str,
+    status: &TaskStatus,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let dir = jobs_dir(dir);
+    std::fs::create_dir_all(&dir)?;
+    let mut path = dir;
+    let file_name = format!("{job_id}.json");
+    path.push(&file_name);
+    let mut tmp_path = path.clone();
+    tmp_path.set_extension("json.tmp");
+    let file = std::fs::File::create(&tmp_path)?;
+    encryption::write_json(cipher, file_name.as_bytes(), &file, status)?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
+
+/// Read all persisted jobs. Jobs that were still running when the
+/// server stopped can't be resumed, so they are marked as failed.
+fn read_jobs(dir: &Path, cipher: Option<&Cipher>) -> io::Result<HashMap<String, TaskStatus>> {
+    let mut jobs = HashMap::new();
+    let entries = match std::fs::read_dir(jobs_dir(dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(jobs),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let job_id = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => match name.strip_suffix(".json") {
+                Some(job_id) => job_id.to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+        let file_name = format!("{job_id}.json");
+        let mut status: TaskStatus =
+            encryption::read_json(cipher, file_name.as_bytes(), std::fs::File::open(&path)?)?;
+        if let TaskStatus::Pending(_) = status {
+            status = TaskStatus::Error("interrupted by a server restart".to_string());
+            write_job(dir, &job_id, &status, cipher)?;
+        }
+        jobs.insert(job_id, status);
+    }
+    Ok(jobs)
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct QueryResult {
     id: String,
@@ -265,20 +1407,143 @@ pub struct Service {
     content_endpoint: Option<String>,
     user_forward_header: String,
     path: PathBuf,
-    vector_store: VectorStore,
+    vector_store: Arc<VectorStore>,
     pending: Mutex<HashSet<String>>,
     tasks: RwLock<HashMap<String, TaskStatus>>,
     indexes: RwLock<HashMap<String, Arc<HnswIndex>>>,
+    /// Keyword indexes for hybrid searches, by index id.
+    lexical: RwLock<HashMap<String, Arc<LexicalIndex>>>,
+    /// Sparse vector indexes for hybrid searches, by index id.
+    sparse: RwLock<HashMap<String, Arc<SparseIndex>>>,
+    /// The nodes of the points of indexes by their external ids, by
+    /// index id, for looking up vectors by id.
+    id_maps: RwLock<HashMap<String, Arc<IdMap>>>,
+    /// Indexes that are not loaded but being scrolled, by index id,
+    /// with when a page was last taken from them. They are kept
+    /// between the pages of a scroll so that every page doesn't read
+    /// the index again.
+    scrolled: std::sync::Mutex<HashMap<String, (Instant, Arc<HnswIndex>)>>,
+    active: RwLock<HashMap<String, String>>,
+    strict: bool,
+    seed: Option<u64>,
+    /// Replaced as a whole on reload, so a request always sees one
+    /// consistent configuration.
+    config: std::sync::RwLock<Arc<Config>>,
+    config_path: Option<PathBuf>,
+    /// Set once all indexes to preload are loaded and warmed up.
+    ready: AtomicBool,
+    cache: Option<QueryCache>,
+    /// Whether this server follows a leader, and refuses writes.
+    follower: bool,
+    shard_client: ShardClient,
+    webhook_client: WebhookClient,
+    ingestion: Arc<IngestionLimits>,
+    usage: Arc<UsageTracker>,
+    /// What every index job used, by job id.
+    job_usage: Arc<UsageTracker>,
+    /// What the index jobs of every domain used, by domain.
+    domain_usage: Arc<UsageTracker>,
+    /// The current stage of running index jobs, by job id.
+    job_progress: Arc<std::sync::Mutex<HashMap<String, ProgressState>>>,
+    dead_letters: DeadLetters,
+    embed_cache: EmbeddingCache,
+    /// Requests per key to `/embed`, if they are limited.
+    embed_limiter: Option<RateLimiter>,
 }
 
-#[derive(Debug, Error)]
-enum StartIndexError {
-    #[error("No content endpoint found: specify at server startup or supply indexing data from the command line")]
-    NoContentEndpoint,
+/// Apply the embedding settings of a configuration. The proxy is
+/// applied last, so that a proxy that can't be used doesn't keep the
+/// other settings from being applied.
+fn configure_embedding_client(config: &Config) -> Result<(), EmbeddingError> {
+    openai::set_rate_limits(
+        config.embedding_rate_limit.requests_per_minute,
+        config.embedding_rate_limit.tokens_per_minute,
+    );
+    openai::set_batching(
+        config.embedding_batching.batch_size,
+        config.embedding_batching.concurrency,
+    );
+    openai::set_chunking(config.embedding_chunking.clone());
+    openai::set_fallbacks(config.embedding_fallbacks.clone());
+    audit::set_audit_log(config.embedding_audit_log.as_ref());
+    openai::set_proxy(config.embedding_proxy.as_ref())
 }
 
-async fn extract_body(req: Request<Body>) -> Bytes {
-    hyper::body::to_bytes(req.into_body()).await.unwrap()
+fn load_usage(dir: &Path, name: &str) -> UsageTracker {
+    let usage_path = dir.join(name);
+    UsageTracker::load(usage_path.clone()).unwrap_or_else(|e| {
+        tracing::error!(error = %e, file = name, "could not read usage, starting from zero");
+        UsageTracker::new(usage_path)
+    })
+}
+
+/// Replaces the log filter, where the binary sets one up.
+pub type LogFilterReloader = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+lazy_static! {
+    static ref LOG_FILTER_RELOADER: std::sync::RwLock<Option<LogFilterReloader>> =
+        std::sync::RwLock::new(None);
+}
+
+/// Let reloads of the configuration change the log filter. Without
+/// this, `log_level` only applies at startup.
+pub fn set_log_filter_reloader(reloader: LogFilterReloader) {
+    *LOG_FILTER_RELOADER.write().unpoisoned() = Some(reloader);
+}
+
+fn set_log_filter(directives: &str) -> Result<(), String> {
+    if std::env::var_os("RUST_LOG").is_some() {
+        return Ok(());
+    }
+    match &*LOG_FILTER_RELOADER.read().unpoisoned() {
+        Some(reload) => reload(directives),
+        None => Ok(()),
+    }
+}
+
+/// The account of requests when no API keys are configured.
+const ANONYMOUS_ACCOUNT: &str = "anonymous";
+
+// How many embedded chunks an indexing job holds while the index is
+// busy inserting.
+const INDEXING_PIPELINE_DEPTH: usize = 2;
+
+#[derive(Debug, Error)]
+enum StartIndexError {
+    #[error("No content endpoint found: specify at server startup or supply indexing data from the command line")]
+    NoContentEndpoint,
+}
+
+fn modified(path: &Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload the configuration whenever its file changes. A change that
+/// can't be applied is reported once, and the file is checked again on
+/// its next change.
+async fn watch_config(service: Arc<Service>, path: PathBuf, interval: Duration) {
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let modified = modified(&path);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+        if let Err(e) = service.reload_config() {
+            tracing::error!(error = %e, path = ?path, "could not reload changed configuration");
+        }
+    }
+}
+
+/// The body of a request. A body that can't be read, because the
+/// client went away, is a `BodyError`, which is answered with a 400
+/// instead of handling the request as if it had no body.
+async fn extract_body(req: Request<Body>) -> Result<Bytes, ResponseError> {
+    hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(ResponseError::BodyError)
 }
 
 enum TerminusIndexOperationError {}
@@ -295,14 +1560,15 @@ async fn get_operations_from_content_endpoint(
         params.push(("previous", previous))
     }
     let endpoint = format!("{}/{}", content_endpoint, &domain);
-    let url = reqwest::Url::parse_with_params(&endpoint, &params).unwrap();
+    let url = reqwest::Url::parse_with_params(&endpoint, &params)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
     let client = reqwest::Client::new();
     let res = client
         .get(url)
         .header(user_forward_header, "admin")
         .send()
         .await
-        .unwrap();
+        .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
     let status = res.status();
     if status != StatusCode::OK {
         let raw_s = res
@@ -338,6 +1604,8 @@ enum ResponseError {
     HeaderError(#[from] HeaderError),
     #[error("{0:?}")]
     IoError(#[from] std::io::Error),
+    #[error("{0}")]
+    StoreError(#[from] VectorlinkError),
     #[error("{0:?}")]
     SerdeError(#[from] serde_json::Error),
     #[error("{0:?}")]
@@ -348,34 +1616,496 @@ enum ResponseError {
     IdMissing(String),
     #[error("Embedding error: {0:?}")]
     EmbeddingError(#[from] EmbeddingError),
+    #[error("No commit given and no active index for domain {0}")]
+    NoActiveIndex(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Domain {0} already exists")]
+    DomainExists(String),
+    #[error("Domain {0} does not exist")]
+    DomainMissing(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("{0}")]
+    IndexError(#[from] IndexError),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("{0}")]
+    ShardError(#[from] ShardError),
+    #[error("{0}")]
+    Overloaded(#[from] IngestionError),
+    #[error("Too many requests, retry in {} seconds", retry_after_seconds(*.0))]
+    RateLimited(Duration),
+    #[error("Could not read the request body: {0}")]
+    BodyError(hyper::Error),
+}
+
+fn retry_after_seconds(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+impl ResponseError {
+    fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            ResponseError::Timeout | ResponseError::SearchError(SearchError::Cancelled)
+        )
+    }
+
+    fn status(&self) -> StatusCode {
+        if self.is_timeout() {
+            StatusCode::GATEWAY_TIMEOUT
+        } else {
+            match self {
+                ResponseError::ShardError(_) => StatusCode::BAD_GATEWAY,
+                ResponseError::Overloaded(IngestionError::TooManyUploads) => {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+                ResponseError::Overloaded(IngestionError::QueueFull) => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                ResponseError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::BAD_REQUEST,
+            }
+        }
+    }
+
+    /// The message for the client. Store errors name files by their
+    /// full path, which is only logged.
+    fn client_message(&self) -> String {
+        let store_error = match self {
+            ResponseError::StoreError(e) => Some(e),
+            ResponseError::IoError(e) => e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<VectorlinkError>()),
+            _ => None,
+        };
+        match store_error {
+            Some(e) => {
+                tracing::warn!(error = %e, "store error");
+                e.without_paths()
+            }
+            None => self.to_string(),
+        }
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut response = Response::builder().status(self.status());
+        match self {
+            ResponseError::Overloaded(_) => {
+                response = response.header(hyper::header::RETRY_AFTER, "1");
+            }
+            ResponseError::RateLimited(wait) => {
+                response = response.header(hyper::header::RETRY_AFTER, retry_after_seconds(wait));
+            }
+            _ => {}
+        }
+        response.body(self.client_message().into()).unwrap()
+    }
 }
 
-fn add_to_duplicates(duplicates: &mut HashMap<usize, usize>, id1: usize, id2: usize) {
-    if id1 < id2 {
-        duplicates.insert(id1, id2);
+/// Run a request handler with a timeout in milliseconds. Blocking work
+/// in the handler sees the same deadline through the cancellation it
+/// is handed, so it stops too instead of running on unobserved.
+async fn with_timeout<T, F, Fut>(timeout: Option<u64>, handler: F) -> Result<T, ResponseError>
+where
+    F: FnOnce(Cancellation) -> Fut,
+    Fut: future::Future<Output = Result<T, ResponseError>>,
+{
+    let timeout = match timeout {
+        Some(timeout) => Duration::from_millis(timeout),
+        None => return handler(Cancellation::default()).await,
+    };
+    match tokio::time::timeout(timeout, handler(Cancellation::with_timeout(timeout))).await {
+        Ok(result) => result,
+        Err(_) => Err(ResponseError::Timeout),
     }
 }
 
 impl Service {
+    /// The status of a job, with what it used so far.
+    fn job_json(&self, job_id: &str, status: &TaskStatus) -> serde_json::Value {
+        let mut job = status.to_json(job_id);
+        job["usage"] = json!(self.job_usage.get(job_id).unwrap_or_default());
+        if let TaskStatus::Pending(_) = status {
+            let jobs = self.job_progress.lock().unpoisoned();
+            if let Some(progress) = jobs.get(job_id).filter(|p| !p.finished) {
+                job["stage"] = json!(progress);
+            }
+        }
+        job
+    }
+
+    /// Progress that shows up as the stage of a job until it is
+    /// dropped.
+    fn job_progress(&self, task_id: &str) -> ChannelProgress {
+        let (progress, mut events) = progress::channel();
+        let jobs = self.job_progress.clone();
+        let job = task_id.to_string();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                jobs.lock()
+                    .unpoisoned()
+                    .entry(job.clone())
+                    .or_default()
+                    .update(event);
+            }
+            jobs.lock().unpoisoned().remove(&job);
+        });
+        progress
+    }
+
     async fn get_task_status(&self, task_id: &str) -> Option<TaskStatus> {
         self.tasks.read().await.get(task_id).cloned()
     }
 
     async fn set_task_status(&self, task_id: String, status: TaskStatus) {
+        if let Err(e) = write_job(&self.path, &task_id, &status, self.vector_store.cipher()) {
+            tracing::error!(job = %task_id, error = ?e, "could not persist status of job");
+        }
+        let event = match status {
+            TaskStatus::Pending(_) => None,
+            TaskStatus::Completed(_) => Some("job.completed"),
+            TaskStatus::Error(_) => Some("job.failed"),
+        };
+        if let Some(event) = event {
+            let payload = json!({
+                "event": event,
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "job": self.job_json(&task_id, &status),
+            });
+            self.webhook_client
+                .notify(&self.config().webhooks, event, payload);
+        }
         self.tasks.write().await.insert(task_id, status);
     }
 
+    /// Forget the jobs that finished longer than the job retention
+    /// ago, with their usage.
+    async fn expire_jobs(&self) {
+        let retention = Duration::from_millis(self.config().job_retention);
+        let dir = jobs_dir(&self.path);
+        let finished: Vec<String> = self
+            .tasks
+            .read()
+            .await
+            .iter()
+            .filter(|(_, status)| !matches!(status, TaskStatus::Pending(_)))
+            .map(|(job_id, _)| job_id.clone())
+            .collect();
+        // Finished jobs are not written again, so their files can be
+        // looked at without holding the lock.
+        let expired = task::spawn_blocking(move || {
+            finished
+                .into_iter()
+                .filter(|job_id| {
+                    let path = dir.join(format!("{job_id}.json"));
+                    // a job file is last written when the job finishes
+                    let old = std::fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .map(|finished| finished.elapsed().unwrap_or_default() > retention)
+                        .unwrap_or(false);
+                    if !old {
+                        return false;
+                    }
+                    match std::fs::remove_file(&path) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            tracing::warn!(job = %job_id, error = %e, "could not remove expired job");
+                            false
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+        let expired = match expired {
+            Ok(expired) => expired,
+            Err(e) => {
+                tracing::warn!(error = %e, "could not expire jobs");
+                return;
+            }
+        };
+        let mut tasks = self.tasks.write().await;
+        for job_id in expired {
+            tasks.remove(&job_id);
+            self.job_usage.remove(&job_id);
+        }
+    }
+
     async fn get_index(&self, index_id: &str) -> io::Result<Arc<HnswIndex>> {
         if let Some(hnsw) = self.indexes.read().await.get(index_id) {
             Ok(hnsw).cloned()
         } else {
+            // Loading reads every vector of the index, so it happens on
+            // a blocking thread.
             let mut path = self.path.clone();
-            Ok(deserialize_index(&mut path, index_id, &self.vector_store)?.into())
+            let name = index_id.to_string();
+            let strict = self.strict;
+            let hnsw = self
+                .vector_store
+                .blocking(move |store| deserialize_index(&mut path, &name, store, strict))
+                .await?;
+            Ok(hnsw.into())
         }
     }
 
     async fn set_index(&self, index_id: String, hnsw: Arc<HnswIndex>) {
+        // Index ids are made with `create_index_name`, so they parse.
+        let domain = parse_index_name(&index_id).map(|(domain, _)| domain);
+        self.forget_derived(&index_id).await;
         self.indexes.write().await.insert(index_id, hnsw);
+        if let Ok(domain) = domain {
+            self.invalidate_cache(&domain);
+        }
+    }
+
+    /// Forget what was built from an index that is replaced.
+    async fn forget_derived(&self, index_id: &str) {
+        self.lexical.write().await.remove(index_id);
+        self.sparse.write().await.remove(index_id);
+        self.id_maps.write().await.remove(index_id);
+        self.scrolled.lock().unpoisoned().remove(index_id);
+    }
+
+    /// The index a scroll pages through. An index that isn't loaded is
+    /// read off the async runtime, and kept for as long as pages keep
+    /// being taken from it.
+    async fn get_scrolled_index(&self, index_id: &str) -> Result<Arc<HnswIndex>, ResponseError> {
+        if let Some(hnsw) = self.indexes.read().await.get(index_id) {
+            return Ok(hnsw.clone());
+        }
+        let now = Instant::now();
+        {
+            let mut scrolled = self.scrolled.lock().unpoisoned();
+            scrolled.retain(|_, (used, _)| now.duration_since(*used) < SCROLL_KEEP_ALIVE);
+            if let Some((used, hnsw)) = scrolled.get_mut(index_id) {
+                *used = now;
+                return Ok(hnsw.clone());
+            }
+        }
+        let mut path = self.path.clone();
+        let vector_store = self.vector_store.clone();
+        let strict = self.strict;
+        let name = index_id.to_string();
+        let hnsw = task::spawn_blocking(move || {
+            deserialize_index(&mut path, &name, &vector_store, strict)
+        })
+        .await
+        .map_err(io::Error::from)??;
+        let hnsw = Arc::new(hnsw);
+        self.scrolled
+            .lock()
+            .unpoisoned()
+            .insert(index_id.to_string(), (now, hnsw.clone()));
+        Ok(hnsw)
+    }
+
+    /// Where the points of an index are by their external ids, built
+    /// the first time an id is looked up in the index, so lookups
+    /// don't scan the index.
+    async fn get_id_map(
+        &self,
+        index_id: &str,
+        hnsw: &Arc<HnswIndex>,
+    ) -> Result<Arc<IdMap>, ResponseError> {
+        if let Some(id_map) = self.id_maps.read().await.get(index_id) {
+            return Ok(id_map.clone());
+        }
+        let hnsw = hnsw.clone();
+        let id_map = task::spawn_blocking(move || {
+            let mut id_map = IdMap::new();
+            for i in 0..hnsw.layer_len(0) {
+                id_map
+                    .entry(hnsw.feature(i).id().to_string())
+                    .or_default()
+                    .push(i);
+            }
+            id_map
+        })
+        .await
+        .map_err(io::Error::from)?;
+        let id_map = Arc::new(id_map);
+        self.id_maps
+            .write()
+            .await
+            .insert(index_id.to_string(), id_map.clone());
+        Ok(id_map)
+    }
+
+    /// The keyword index over the points of an index, built from the
+    /// metadata of their vectors the first time a hybrid search needs
+    /// it, and again when the configured fields change.
+    async fn get_lexical_index(
+        &self,
+        index_id: &str,
+        domain: &Arc<Domain>,
+        hnsw: &Arc<HnswIndex>,
+    ) -> Result<Arc<LexicalIndex>, ResponseError> {
+        let config = self
+            .config()
+            .domains
+            .get(domain.name())
+            .and_then(|domain_config| domain_config.lexical.clone())
+            .ok_or_else(|| {
+                ResponseError::InvalidQuery(format!(
+                    "domain {} has no lexical index for hybrid searches",
+                    domain.name()
+                ))
+            })?;
+        if let Some(lexical) = self.lexical.read().await.get(index_id) {
+            if lexical.is_built_with(&config) {
+                return Ok(lexical.clone());
+            }
+        }
+        let domain = domain.clone();
+        let hnsw = hnsw.clone();
+        let lexical = task::spawn_blocking(move || {
+            let _span =
+                tracing::info_span!("build_lexical_index", domain = domain.name()).entered();
+            let start = Instant::now();
+            let texts = (0..hnsw.layer_len(0)).map(|i| {
+                let metadata = domain.metadata(hnsw.feature(i).vec_id());
+                metadata_text(metadata.as_ref(), &config.fields)
+            });
+            let lexical = LexicalIndex::build(&config, texts);
+            tracing::info!(
+                points = lexical.len(),
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "built lexical index"
+            );
+            lexical
+        })
+        .await
+        .map_err(io::Error::from)?;
+        let lexical = Arc::new(lexical);
+        self.lexical
+            .write()
+            .await
+            .insert(index_id.to_string(), lexical.clone());
+        Ok(lexical)
+    }
+
+    /// The index over the sparse vectors of the points of an index,
+    /// built the first time a hybrid search needs it.
+    async fn get_sparse_index(
+        &self,
+        index_id: &str,
+        domain: &Arc<Domain>,
+        hnsw: &Arc<HnswIndex>,
+    ) -> Result<Arc<SparseIndex>, ResponseError> {
+        if let Some(sparse) = self.sparse.read().await.get(index_id) {
+            return Ok(sparse.clone());
+        }
+        let domain = domain.clone();
+        let hnsw = hnsw.clone();
+        let sparse = task::spawn_blocking(move || {
+            let _span = tracing::info_span!("build_sparse_index", domain = domain.name()).entered();
+            let vectors: Vec<Option<SparseVector>> = (0..hnsw.layer_len(0))
+                .map(|i| domain.sparse_vector(hnsw.feature(i).vec_id()))
+                .collect();
+            SparseIndex::build(vectors.iter().map(Option::as_ref))
+        })
+        .await
+        .map_err(io::Error::from)?;
+        let sparse = Arc::new(sparse);
+        self.sparse
+            .write()
+            .await
+            .insert(index_id.to_string(), sparse.clone());
+        Ok(sparse)
+    }
+
+    fn invalidate_cache(&self, domain: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(domain);
+        }
+    }
+
+    /// Answer a search from the cache if the same query ran against
+    /// the domain recently, and cache the result of `search` otherwise.
+    async fn cached_search<Q, Fut>(
+        &self,
+        domain: &str,
+        query: Q,
+        search: Fut,
+    ) -> Result<String, ResponseError>
+    where
+        Q: Hash,
+        Fut: future::Future<Output = Result<String, ResponseError>>,
+    {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return search.await,
+        };
+        let key = cache.key(domain, &query);
+        if let Some(result) = cache.get(&key) {
+            return Ok(result.to_string());
+        }
+        let result = search.await?;
+        cache.insert(&key, Arc::new(result.clone()));
+        Ok(result)
+    }
+
+    /// Use the given commit, or fall back to the active commit of the domain.
+    async fn resolve_commit(
+        &self,
+        domain: &str,
+        commit: Option<String>,
+    ) -> Result<String, ResponseError> {
+        if let Some(commit) = commit {
+            return Ok(commit);
+        }
+        if let Some(commit) = self.active.read().await.get(domain) {
+            return Ok(commit.clone());
+        }
+        match read_active_commit(&self.path, domain)? {
+            Some(commit) => {
+                self.active
+                    .write()
+                    .await
+                    .insert(domain.to_string(), commit.clone());
+                Ok(commit)
+            }
+            None => Err(ResponseError::NoActiveIndex(domain.to_string())),
+        }
+    }
+
+    /// Make the index for `commit` the one served by default for the
+    /// domain. The index is loaded before the switch, so searches
+    /// against the domain never observe a missing index.
+    async fn activate_index(&self, domain: String, commit: String) -> Result<(), ResponseError> {
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        self.set_index(index_id, hnsw).await;
+        let mut active = self.active.write().await;
+        write_active_commit(&self.path, &domain, &commit)?;
+        self.invalidate_cache(&domain);
+        active.insert(domain, commit);
+        Ok(())
+    }
+
+    async fn get_versions(&self, domain: String) -> Result<String, ResponseError> {
+        let versions = list_index_versions(&self.path, &domain)?;
+        let active = match self.resolve_commit(&domain, None).await {
+            Ok(commit) => Some(commit),
+            Err(ResponseError::NoActiveIndex(_)) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(json!({"active": active, "versions": versions}).to_string())
+    }
+
+    /// Load, warm up and cache the given indexes, so that the first
+    /// searches against them don't pay for deserialization and cold pages.
+    async fn warm_up(&self, index_ids: &[String]) -> io::Result<()> {
+        for index_id in index_ids {
+            let hnsw = self.get_index(index_id).await?;
+            let hnsw_ref = hnsw.clone();
+            let count = task::block_in_place(move || warm_up_index(&hnsw_ref));
+            tracing::info!(index = %index_id, points = count, "warmed up index");
+            self.set_index(index_id.clone(), hnsw).await;
+        }
+        Ok(())
     }
 
     async fn test_and_set_pending(&self, index_id: String) -> bool {
@@ -404,182 +2134,799 @@ impl Service {
     fn new<P: Into<PathBuf>>(
         path: P,
         user_forward_header: String,
-        num_bufs: usize,
+        vector_store: VectorStore,
         content_endpoint: Option<String>,
+        strict: bool,
+        seed: Option<u64>,
+        config: Config,
+        config_path: Option<PathBuf>,
     ) -> Self {
         let path = path.into();
+        let tasks = read_jobs(&path, vector_store.cipher()).unwrap_or_else(|e| {
+            tracing::error!(error = ?e, "could not read persisted jobs");
+            HashMap::new()
+        });
+        let cache = config
+            .cache
+            .as_ref()
+            .map(|cache| QueryCache::new(cache.capacity, Duration::from_millis(cache.ttl)));
+        let follower = config.replication.is_some();
+        let ingestion = Arc::new(IngestionLimits::new(&config.ingestion));
+        let usage = load_usage(&path, "usage.json");
+        let job_usage = load_usage(&path, "job_usage.json");
+        // usage of jobs that expired before it was last written
+        job_usage.retain(|job_id| tasks.contains_key(job_id));
+        let domain_usage = load_usage(&path, "domain_usage.json");
+        if let Err(e) = configure_embedding_client(&config) {
+            tracing::error!(error = %e, "could not configure the embedding client");
+        }
+        let embed_cache = EmbeddingCache::new(config.embed.cache_capacity);
+        let embed_limiter = config.embed.requests_per_minute.map(RateLimiter::new);
         Service {
             content_endpoint,
             user_forward_header,
             path: path.clone(),
-            vector_store: VectorStore::new(path, num_bufs),
+            vector_store: Arc::new(vector_store),
             pending: Mutex::new(HashSet::new()),
-            tasks: RwLock::new(HashMap::new()),
+            tasks: RwLock::new(tasks),
             indexes: RwLock::new(HashMap::new()),
+            lexical: RwLock::new(HashMap::new()),
+            sparse: RwLock::new(HashMap::new()),
+            id_maps: RwLock::new(HashMap::new()),
+            scrolled: std::sync::Mutex::new(HashMap::new()),
+            active: RwLock::new(HashMap::new()),
+            strict,
+            seed,
+            config: std::sync::RwLock::new(Arc::new(config)),
+            config_path,
+            ready: AtomicBool::new(false),
+            cache,
+            follower,
+            shard_client: ShardClient::default(),
+            webhook_client: WebhookClient::default(),
+            ingestion,
+            usage: Arc::new(usage),
+            job_usage: Arc::new(job_usage),
+            domain_usage: Arc::new(domain_usage),
+            job_progress: Default::default(),
+            dead_letters: DeadLetters::new(&path),
+            embed_cache,
+            embed_limiter,
         }
     }
 
-    async fn serve(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        eprintln!(
-            "{:?}: {:?} {:?}",
-            chrono::offset::Local::now(),
-            req.method(),
-            req.uri()
-        );
-        match *req.method() {
-            Method::POST => self.post(req).await,
-            Method::GET => self.get(req).await,
-            _ => todo!(),
-        }
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unpoisoned().clone()
     }
 
-    async fn load_hnsw_for_indexing(&self, idxid: IndexIdentifier) -> HnswIndex {
-        if let Some(previous_id) = idxid.previous {
-            //let commit = idxid.commit;
-            let domain = idxid.domain;
-            let previous_id = create_index_name(&domain, &previous_id);
-            let hnsw = self.get_index(&previous_id).await.unwrap();
-            (*hnsw).clone()
-        } else {
-            Hnsw::new(OpenAI)
+    /// Read the configuration file again. Everything but the TLS
+    /// settings takes effect for the next request.
+    fn reload_config(&self) -> io::Result<()> {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::NotFound,
+                    "the server was started without a configuration file",
+                ))
+            }
+        };
+        let (profile, command_line) = {
+            let current = self.config();
+            (current.active_profile.clone(), current.command_line.clone())
+        };
+        let mut config = Config::load(path, profile.as_deref())?;
+        config.apply_command_line(command_line);
+        config.validate()?;
+        let mut current = self.config.write().unpoisoned();
+        let restart_changes = current.restart_changes(&config);
+        if !restart_changes.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "not reloading, since changes to {} only take effect on a restart",
+                    restart_changes.join(", ")
+                ),
+            ));
         }
+        configure_embedding_client(&config)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        // a log level that was removed goes back to the default
+        set_log_filter(config.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        *current = Arc::new(config);
+        tracing::info!(path = ?path, "reloaded configuration");
+        Ok(())
     }
 
-    async fn start_indexing_inner(
-        self: Arc<Self>,
-        domain: String,
-        commit: String,
-        previous: Option<String>,
-        task_id: &str,
-        api_key: String,
-        index_id: &str,
-        content_endpoint: String,
-    ) -> Result<(String, HnswIndex), IndexError> {
-        let internal_task_id = task_id;
-        let opstream = get_operations_from_content_endpoint(
-            content_endpoint.to_string(),
-            self.user_forward_header.clone(),
-            domain.clone(),
-            commit.clone(),
-            previous.clone(),
-        )
-        .await?
-        .chunks(100);
-        self.process_operation_chunks(
-            opstream, domain, commit, previous, index_id, task_id, &api_key,
-        )
-        .await
+    /// Serve a file of the storage directory to a follower, or the
+    /// part of it given by a `bytes=from-to` range.
+    async fn replication_file(
+        &self,
+        name: &str,
+        range: Option<&str>,
+    ) -> Result<Response<Body>, ResponseError> {
+        if !replication::is_replicated(name) {
+            return Err(ResponseError::InvalidQuery(format!(
+                "{name} is not a replicated file"
+            )));
+        }
+        let mut file = tokio::fs::File::open(self.path.join(name)).await?;
+        let size = file.metadata().await?.len();
+        let (from, to) = match range {
+            Some(range) => replication::parse_range(range, size).ok_or_else(|| {
+                ResponseError::InvalidQuery(format!("invalid range {range} for {size} bytes"))
+            })?,
+            None => (0, size),
+        };
+        file.seek(io::SeekFrom::Start(from)).await?;
+        let mut response = Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header(hyper::header::CONTENT_LENGTH, to - from);
+        if range.is_some() {
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                hyper::header::CONTENT_RANGE,
+                format!("bytes {from}-{}/{size}", to - 1),
+            );
+        }
+        let body = Body::wrap_stream(ReaderStream::new(file.take(to - from)));
+        Ok(response.body(body).unwrap())
     }
 
-    fn start_indexing(
-        self: Arc<Self>,
-        domain: String,
-        commit: String,
-        previous: Option<String>,
-        task_id: String,
-        api_key: String,
-    ) -> Result<(), StartIndexError> {
-        let content_endpoint = self.content_endpoint.clone();
-        let internal_task_id = task_id.clone();
-        if let Some(content_endpoint) = content_endpoint {
-            tokio::spawn(async move {
-                let index_id = create_index_name(&domain, &commit);
-                if self.test_and_set_pending(index_id.clone()).await {
-                    match self
-                        .clone()
-                        .start_indexing_inner(
-                            domain,
-                            commit,
-                            previous,
-                            &task_id,
-                            api_key,
-                            &index_id,
-                            content_endpoint,
-                        )
-                        .await
-                    {
-                        Ok((id, hnsw)) => {
-                            let layer_len = hnsw.layer_len(0);
-                            self.set_index(id, hnsw.into()).await;
-                            self.set_task_status(task_id, TaskStatus::Completed(layer_len.clone()))
-                                .await;
-                            self.clear_pending(&index_id).await;
-                        }
-                        Err(err) => {
-                            eprintln!(
-                                "{:?}: error while indexing: {:?}",
-                                chrono::offset::Local::now(),
-                                err
-                            );
-                            self.set_task_status(
-                                internal_task_id,
-                                TaskStatus::Error(err.to_string()),
-                            )
-                            .await;
-                            self.clear_pending(&index_id).await;
-                        }
-                    }
+    /// Keep pulling changes from the leader. A failed round is logged,
+    /// and the next one tries again, unless the files of the leader
+    /// can't be read with the key of this server.
+    async fn follow(&self, config: ReplicationConfig) {
+        let follower = Follower::new(&config);
+        let mut interval = tokio::time::interval(Duration::from_millis(config.interval));
+        loop {
+            interval.tick().await;
+            match follower.sync(&self.path, &self.vector_store).await {
+                Ok(report) => self.apply_sync(report).await,
+                // trying again won't change the key
+                Err(e @ ReplicationError::KeyMismatch(_)) => {
+                    tracing::error!(error = %e, "stopped replicating from the leader");
+                    return;
                 }
-            });
-            Ok(())
-        } else {
-            Err(StartIndexError::NoContentEndpoint)
+                Err(e) => tracing::warn!(error = %e, "replication from the leader failed"),
+            }
         }
     }
 
-    async fn assign_index(
-        self: Arc<Self>,
-        domain: String,
-        source_commit: String,
-        target_commit: String,
-    ) -> Result<(), AssignIndexError> {
-        let source_name = create_index_name(&domain, &source_commit);
-        let target_name = create_index_name(&domain, &target_commit);
-        let index = self.get_index(&source_name).await?;
-        let mut indexes = self.indexes.write().await;
-        indexes.insert(target_name.clone(), index.clone());
-        std::mem::drop(indexes);
-        tokio::task::block_in_place(move || {
-            let path = self.path.clone();
-            serialize_index(path, &target_name, (*index).clone()).unwrap();
-        });
-        Ok(())
+    /// Serve what a sync brought in: reopen changed domains, and switch
+    /// to new active commits.
+    async fn apply_sync(&self, report: SyncReport) {
+        for domain in report.dropped {
+            tracing::info!(domain, "dropping domain that the leader dropped");
+            if let Err(e) = self.drop_domain(domain).await {
+                tracing::warn!(error = %e, "could not drop domain");
+            }
+        }
+        for index_id in &report.indexes {
+            self.indexes.write().await.remove(index_id);
+            self.forget_derived(index_id).await;
+        }
+        for domain in report.domains {
+            self.vector_store.reopen_domain(&domain);
+            self.invalidate_cache(&domain);
+            self.active.write().await.remove(&domain);
+            let result = match read_active_commit(&self.path, &domain) {
+                Ok(Some(commit)) => self.activate_index(domain.clone(), commit).await,
+                Ok(None) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => tracing::info!(domain, "replicated changes from the leader"),
+                Err(e) => tracing::warn!(domain, error = %e, "could not activate replicated index"),
+            }
+        }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    async fn process_operation_chunks(
-        self: &Arc<Self>,
-        mut opstream: futures::stream::Chunks<
-            impl Stream<Item = Result<Operation, io::Error>> + Unpin,
-        >,
-        domain: String,
-        commit: String,
-        previous: Option<String>,
-        index_id: &str,
-        task_id: &str,
-        api_key: &str,
-    ) -> Result<(String, HnswIndex), IndexError> {
-        let id = create_index_name(&domain, &commit);
-        let mut hnsw = self
-            .load_hnsw_for_indexing(IndexIdentifier {
-                domain: domain.clone(),
-                commit,
+    fn default_ef(&self) -> usize {
+        self.config().default_ef.unwrap_or(DEFAULT_EF)
+    }
+
+    /// Drain the service after the server stopped taking requests.
+    /// Running index jobs get some time to finish. Jobs still running
+    /// after that are recorded as interrupted, and all vector files are
+    /// flushed to disk.
+    async fn shutdown(&self) {
+        self.ready.store(false, Ordering::Release);
+        let drain = Duration::from_millis(self.config().timeouts.shutdown.unwrap_or(30_000));
+        let deadline = tokio::time::Instant::now() + drain;
+        while !self.pending.lock().await.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let interrupted: Vec<String> = self
+            .tasks
+            .read()
+            .await
+            .iter()
+            .filter(|(_, status)| matches!(status, TaskStatus::Pending(_)))
+            .map(|(task_id, _)| task_id.clone())
+            .collect();
+        for task_id in interrupted {
+            tracing::warn!(job = %task_id, "job interrupted by shutdown");
+            self.set_task_status(
+                task_id,
+                TaskStatus::Error("interrupted by a server shutdown".to_string()),
+            )
+            .await;
+        }
+        if let Err(e) = self.vector_store.sync_async().await {
+            tracing::error!(error = ?e, "error while flushing vector files");
+        }
+    }
+
+    /// Handle a request inside its own span, which collects the request
+    /// id, what the request acts on, and how long its stages took.
+    async fn serve(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let start = Instant::now();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .unwrap_or_else(Service::generate_task);
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            domain = tracing::field::Empty,
+            tenant = tracing::field::Empty,
+            k = tracing::field::Empty,
+            ef = tracing::field::Empty,
+            embed_ms = tracing::field::Empty,
+            search_ms = tracing::field::Empty,
+        );
+        let mut response = self.serve_in_span(req).instrument(span.clone()).await?;
+        span.in_scope(|| {
+            tracing::info!(
+                status = response.status().as_u16(),
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "request finished"
+            )
+        });
+        if let Ok(value) = request_id.parse() {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        Ok(response)
+    }
+
+    async fn serve_in_span(
+        self: Arc<Self>,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        let origin = req
+            .headers()
+            .get(hyper::header::ORIGIN)
+            .and_then(|o| o.to_str().ok())
+            .map(|o| o.to_string());
+        let accept_encoding = req
+            .headers()
+            .get(hyper::header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let preflight = *req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(hyper::header::ACCESS_CONTROL_REQUEST_METHOD);
+        let mut response = if preflight && self.config().cors.is_some() {
+            // Preflights carry no credentials, so they skip authorization.
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap()
+        } else {
+            self.clone().route(req).await?
+        };
+        if let Some(origin) = origin {
+            self.add_cors_headers(&origin, preflight, response.headers_mut());
+        }
+        Ok(self.compress_response(accept_encoding.as_deref(), response))
+    }
+
+    /// Compress the response with the best encoding the client
+    /// accepts, if it is worth compressing.
+    fn compress_response(
+        &self,
+        accept_encoding: Option<&str>,
+        mut response: Response<Body>,
+    ) -> Response<Body> {
+        let config = self.config();
+        if !config.compression.enabled
+            || !compression::is_compressible(&response, config.compression.min_size)
+        {
+            return response;
+        }
+        response.headers_mut().append(
+            hyper::header::VARY,
+            HeaderValue::from_static("accept-encoding"),
+        );
+        let encoding = match accept_encoding.and_then(compression::negotiate) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(hyper::header::CONTENT_LENGTH);
+        parts.headers.insert(
+            hyper::header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.name()),
+        );
+        Response::from_parts(parts, compression::compress(encoding, body))
+    }
+
+    /// Add the CORS headers for a request from `origin`, if the
+    /// configuration allows that origin.
+    fn add_cors_headers(&self, origin: &str, preflight: bool, headers: &mut HeaderMap) {
+        let config = self.config();
+        let cors = match &config.cors {
+            Some(cors) if cors.allows_origin(origin) => cors,
+            _ => return,
+        };
+        let allow_origin = if cors.allowed_origins.iter().any(|o| o == "*") {
+            "*"
+        } else {
+            origin
+        };
+        if let Ok(value) = allow_origin.parse() {
+            headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        }
+        headers.append(hyper::header::VARY, HeaderValue::from_static("Origin"));
+        if preflight {
+            if let Ok(value) = cors.allowed_methods.join(", ").parse() {
+                headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_METHODS, value);
+            }
+            if let Ok(value) = cors.allowed_headers.join(", ").parse() {
+                headers.insert(hyper::header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+            }
+            if let Some(max_age) = cors.max_age {
+                headers.insert(hyper::header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+            }
+        }
+    }
+
+    async fn route(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let spec = uri_to_spec(req.uri());
+        let allowed = self
+            .authorize(&req, spec.as_ref().ok())
+            .and_then(|key| match &spec {
+                Ok(spec) if self.follower && spec.is_write(req.method()) => {
+                    Err(AuthError::ReadOnly)
+                }
+                _ => Ok(key),
+            });
+        let (tenant, account) = match allowed {
+            Ok(Some(key)) => (key.tenant.clone(), key.account()),
+            Ok(None) => (None, ANONYMOUS_ACCOUNT.to_string()),
+            Err(e) => {
+                tracing::warn!(reason = %e, "request refused");
+                return Ok(Response::builder()
+                    .status(e.status())
+                    .body(e.to_string().into())
+                    .unwrap());
+            }
+        };
+        let span = tracing::Span::current();
+        if let Some(tenant) = &tenant {
+            span.record("tenant", tenant.as_str());
+        }
+        if let Some(domain) = spec.as_ref().ok().and_then(|s| s.domain()) {
+            span.record("domain", domain);
+        }
+        let spec = spec.map(|mut spec| {
+            if let Some(tenant) = &tenant {
+                spec.scope_to_tenant(tenant);
+            }
+            spec
+        });
+        let is_query = spec.as_ref().map(|s| s.is_query()).unwrap_or(false);
+        if is_query {
+            self.usage.record(&account, |usage| usage.queries += 1);
+        }
+        if let Some(tenant) = &tenant {
+            TENANT_REQUESTS.with(tenant).inc();
+            if is_query {
+                TENANT_QUERIES.with(tenant).inc();
+            }
+        }
+        let usage = self.usage.clone();
+        usage::charge_to(usage, account, async move {
+            match *req.method() {
+                Method::POST => self.post(req, spec).await,
+                Method::GET => self.get(req, spec).await,
+                Method::DELETE => self.delete(spec).await,
+                _ => Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())
+                    .unwrap()),
+            }
+        })
+        .await
+    }
+
+    /// Check the API key in the `Authorization: Bearer` header against
+    /// the configured keys, and that it gives the access the request
+    /// needs to the requested domain. Without configured keys, every
+    /// request is allowed.
+    /// Returns the key that was used, if any.
+    fn authorize(
+        &self,
+        req: &Request<Body>,
+        spec: Option<&ResourceSpec>,
+    ) -> Result<Option<ApiKey>, AuthError> {
+        let config = self.config();
+        let is_admin = spec.map(|s| s.is_admin()).unwrap_or(false);
+        if !config.requires_api_key() {
+            // Dropping domains is too dangerous to leave open.
+            return if is_admin {
+                Err(AuthError::AdminDisabled)
+            } else {
+                Ok(None)
+            };
+        }
+        // Orchestrators probe these without credentials, and the API
+        // documentation is public.
+        if matches!(
+            spec,
+            Some(
+                ResourceSpec::Healthz
+                    | ResourceSpec::Readyz
+                    | ResourceSpec::OpenApi
+                    | ResourceSpec::Docs
+            )
+        ) {
+            return Ok(None);
+        }
+        let key = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingKey)?;
+        let key = config.api_key(key).ok_or(AuthError::UnknownKey)?;
+        let domain = spec.and_then(|s| s.domain());
+        // An ACL gives access to the admin routes of the domains it
+        // lets the key administer.
+        if is_admin && !key.admin && (key.acl.is_none() || domain.is_none()) {
+            return Err(AuthError::AdminNotPermitted);
+        }
+        // The configuration and the storage directory are shared by
+        // all tenants.
+        if matches!(
+            spec,
+            Some(
+                ResourceSpec::AdminConfig
+                    | ResourceSpec::AdminReloadConfig
+                    | ResourceSpec::AdminUsage
+                    | ResourceSpec::Metrics
+                    | ResourceSpec::ReplicationManifest
+                    | ResourceSpec::ReplicationFile { .. }
+            )
+        ) && key.tenant.is_some()
+        {
+            return Err(AuthError::AdminNotPermitted);
+        }
+        if let Some(ResourceSpec::AdminDeriveDomain { source, .. }) = spec {
+            check_access(key, source, Access::Read)?;
+        }
+        if let (
+            Some(ResourceSpec::GetJob { job_id } | ResourceSpec::CheckTask { task_id: job_id }),
+            Some(tenant),
+        ) = (spec, &key.tenant)
+        {
+            if !is_tenant_job(job_id, tenant) {
+                return Err(AuthError::JobNotPermitted(job_id.clone()));
+            }
+        }
+        // Unknown paths are left to the handlers to reject.
+        if let (Some(spec), Some(domain)) = (spec, domain) {
+            check_access(key, domain, spec.required_access(req.method()))?;
+        }
+        Ok(Some(key.clone()))
+    }
+
+    fn tenant_limits<'a>(&self, domain: &'a str) -> Option<(&'a str, TenantConfig)> {
+        let tenant = domain_tenant(domain)?;
+        self.config()
+            .tenants
+            .get(tenant)
+            .map(|limits| (tenant, limits.clone()))
+    }
+
+    /// The stored names of all domains of a tenant.
+    fn tenant_domains(&self, tenant: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .vector_store
+            .list_domains()?
+            .into_iter()
+            .filter(|d| domain_tenant(d) == Some(tenant))
+            .collect())
+    }
+
+    fn check_domain_quota(&self, domain: &str) -> Result<(), ResponseError> {
+        if let Some((tenant, limits)) = self.tenant_limits(domain) {
+            if let Some(max_domains) = limits.max_domains {
+                if self.tenant_domains(tenant)?.len() >= max_domains {
+                    return Err(ResponseError::QuotaExceeded(format!(
+                        "tenant {tenant} can't have more than {max_domains} domains"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that adding `additional` vectors to the domain keeps its
+    /// tenant within its vector quota.
+    fn check_vector_quota(&self, domain: &str, additional: usize) -> Result<(), IndexError> {
+        if let Some((tenant, limits)) = self.tenant_limits(domain) {
+            if let Some(max_vectors) = limits.max_vectors {
+                let mut used = 0;
+                for domain in self.tenant_domains(tenant)? {
+                    used += self.vector_store.get_domain(&domain)?.num_vecs();
+                }
+                if used + additional > max_vectors {
+                    return Err(IndexError::QuotaExceeded(format!(
+                        "tenant {tenant} can't have more than {max_vectors} vectors"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn load_hnsw_for_indexing(&self, idxid: IndexIdentifier) -> io::Result<HnswIndex> {
+        if let Some(previous_id) = idxid.previous {
+            //let commit = idxid.commit;
+            let domain = idxid.domain;
+            let previous_id = create_index_name(&domain, &previous_id);
+            let hnsw = self.get_index(&previous_id).await?;
+            Ok((*hnsw).clone())
+        } else {
+            Ok(new_index(self.seed))
+        }
+    }
+
+    async fn start_indexing_inner(
+        self: Arc<Self>,
+        domain: String,
+        commit: String,
+        previous: Option<String>,
+        task_id: &str,
+        api_key: String,
+        index_id: &str,
+        content_endpoint: String,
+    ) -> Result<(String, HnswIndex), IndexError> {
+        let internal_task_id = task_id;
+        let model = self.embedding_model(&domain)?;
+        let embedder = model.embedder(&api_key)?;
+        let limits = embedder.limits();
+        let opstream = get_operations_from_content_endpoint(
+            content_endpoint.to_string(),
+            self.user_forward_header.clone(),
+            domain.clone(),
+            commit.clone(),
+            previous.clone(),
+        )
+        .await?
+        // enough texts to keep every concurrent embedding request busy
+        .chunks(limits.batch_size * limits.concurrency);
+        let span = tracing::info_span!(
+            "index",
+            domain = %domain,
+            commit = %commit,
+            job = task_id,
+            model = %model.model
+        );
+        let start = Instant::now();
+        let result = self
+            .process_operation_chunks(
+                opstream, domain, commit, previous, index_id, task_id, &model, &*embedder,
+            )
+            .instrument(span.clone())
+            .await;
+        span.in_scope(|| match &result {
+            Ok((_, hnsw)) => tracing::info!(
+                points = hnsw.layer_len(0),
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "indexed commit"
+            ),
+            Err(e) => tracing::warn!(
+                error = %e,
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "indexing failed"
+            ),
+        });
+        result
+    }
+
+    fn start_indexing(
+        self: Arc<Self>,
+        domain: String,
+        commit: String,
+        previous: Option<String>,
+        task_id: String,
+        api_key: String,
+        queued: QueuedJob,
+    ) -> Result<(), StartIndexError> {
+        let content_endpoint = self.content_endpoint.clone();
+        let internal_task_id = task_id.clone();
+        let charge = usage::current_account();
+        if let Some(content_endpoint) = content_endpoint {
+            let job = async move {
+                let _running = queued.start().await;
+                let index_id = create_index_name(&domain, &commit);
+                if self.test_and_set_pending(index_id.clone()).await {
+                    let indexing = self.clone().start_indexing_inner(
+                        domain.clone(),
+                        commit,
+                        previous,
+                        &task_id,
+                        api_key,
+                        &index_id,
+                        content_endpoint,
+                    );
+                    let indexing =
+                        usage::charge_to(self.job_usage.clone(), task_id.clone(), indexing);
+                    match usage::charge_to(self.domain_usage.clone(), domain, indexing).await {
+                        Ok((id, hnsw)) => {
+                            let layer_len = hnsw.layer_len(0);
+                            self.set_index(id, hnsw.into()).await;
+                            self.set_task_status(task_id, TaskStatus::Completed(layer_len.clone()))
+                                .await;
+                            self.clear_pending(&index_id).await;
+                        }
+                        Err(err) => {
+                            tracing::error!(job = %task_id, error = ?err, "error while indexing");
+                            self.set_task_status(
+                                internal_task_id,
+                                TaskStatus::Error(err.to_string()),
+                            )
+                            .await;
+                            self.clear_pending(&index_id).await;
+                        }
+                    }
+                }
+            };
+            match charge {
+                Some((tracker, account)) => {
+                    tokio::spawn(usage::charge_to(tracker, account, job));
+                }
+                None => {
+                    tokio::spawn(job);
+                }
+            }
+            Ok(())
+        } else {
+            Err(StartIndexError::NoContentEndpoint)
+        }
+    }
+
+    async fn assign_index(
+        self: Arc<Self>,
+        domain: String,
+        source_commit: String,
+        target_commit: String,
+    ) -> Result<(), AssignIndexError> {
+        let source_name = create_index_name(&domain, &source_commit);
+        let target_name = create_index_name(&domain, &target_commit);
+        let index = self.get_index(&source_name).await?;
+        self.set_index(target_name.clone(), index.clone()).await;
+        tokio::task::block_in_place(move || {
+            let path = self.path.clone();
+            serialize_index(
+                path,
+                &target_name,
+                (*index).clone(),
+                self.vector_store.cipher(),
+            )
+        })?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn process_operation_chunks(
+        self: &Arc<Self>,
+        mut opstream: futures::stream::Chunks<
+            impl Stream<Item = Result<Operation, io::Error>> + Unpin,
+        >,
+        domain: String,
+        commit: String,
+        previous: Option<String>,
+        index_id: &str,
+        task_id: &str,
+        model: &EmbeddingModel,
+        embedder: &dyn EmbeddingProvider,
+    ) -> Result<(String, HnswIndex), IndexError> {
+        let id = create_index_name(&domain, &commit);
+        let hnsw = self
+            .load_hnsw_for_indexing(IndexIdentifier {
+                domain: domain.clone(),
+                commit: commit.clone(),
                 previous,
             })
-            .await;
-        let domain = self.vector_store.get_domain(&domain)?;
+            .await?;
+        let domain_name = domain;
+        let domain = self.vector_store.get_domain_async(&domain_name).await?;
+        check_embedder(&domain, embedder)?;
+        if domain.embedding_model().is_none() {
+            domain.set_embedding_model(model)?;
+        }
         self.set_task_status(task_id.to_string(), TaskStatus::Pending(0.3))
             .await;
+        let progress = self.job_progress(task_id);
+        progress.stage("indexing", None);
+        // Insert into the index on a blocking thread while the next
+        // chunk is being embedded. The channel bounds how many embedded
+        // chunks wait for insertion.
+        let (sender, mut receiver) =
+            tokio::sync::mpsc::channel::<Vec<PointOperation>>(INDEXING_PIPELINE_DEPTH);
+        let span = tracing::Span::current();
+        let inserter_progress = progress.clone();
+        let inserter = task::spawn_blocking(move || {
+            let _span = span.enter();
+            let mut hnsw = hnsw;
+            while let Some(new_ops) = receiver.blocking_recv() {
+                hnsw = start_indexing_with_progress(hnsw, new_ops, &inserter_progress)?;
+            }
+            Ok::<_, io::Error>(hnsw)
+        });
         while let Some(structs) = opstream.next().await {
-            let new_ops =
-                operations_to_point_operations(&domain, &self.vector_store, structs, api_key)
-                    .await?;
-            hnsw = start_indexing_from_operations(hnsw, new_ops)?;
+            self.check_vector_quota(&domain_name, structs.len())?;
+            let ops = structs.into_iter().collect::<Result<Vec<_>, _>>()?;
+            let count = ops.len();
+            let new_ops = match operations_to_point_operations(
+                &domain,
+                &self.vector_store,
+                ops.clone(),
+                embedder,
+            )
+            .await
+            {
+                Ok(new_ops) => new_ops,
+                // keep the operations for a replay, instead of losing
+                // everything indexed so far
+                Err(IndexError::EmbeddingError(e)) => {
+                    tracing::warn!(
+                        domain = domain_name,
+                        job = task_id,
+                        error = %e,
+                        "embedding failed, keeping the batch as a dead letter"
+                    );
+                    self.dead_letters.add(
+                        &domain_name,
+                        &DeadLetter {
+                            job: task_id.to_string(),
+                            commit: commit.clone(),
+                            error: e.to_string(),
+                            failed_at: chrono::Utc::now().to_rfc3339(),
+                            operations: ops,
+                        },
+                        self.vector_store.cipher(),
+                    )?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            usage::charge(|usage| usage.vectors += count as u64);
+            if sender.send(new_ops).await.is_err() {
+                // the inserter failed, its error is reported below
+                break;
+            }
         }
+        drop(sender);
+        let hnsw = inserter.await.map_err(io::Error::from)??;
         self.set_task_status(task_id.to_string(), TaskStatus::Pending(0.8))
             .await;
+        progress.stage("writing", None);
         let path = self.path.clone();
-        serialize_index(path, index_id, hnsw.clone())?;
+        let name = index_id.to_string();
+        let serialized = hnsw.clone();
+        self.vector_store
+            .blocking(move |store| serialize_index(path, &name, serialized, store.cipher()))
+            .await?;
         Ok((id, hnsw))
     }
 
@@ -590,16 +2937,21 @@ impl Service {
         commit: String,
         previous: Option<String>,
     ) -> Result<String, ResponseError> {
-        let task_id = Service::generate_task();
-        let api_key = get_header_value(req.headers(), "VECTORLINK_EMBEDDING_API_KEY")?;
-        self.set_task_status(task_id.clone(), TaskStatus::Pending(0.0));
-        self.start_indexing(domain, commit, previous, task_id.clone(), api_key)?;
+        let task_id = generate_job_id(&domain);
+        let api_key = self.embedding_api_key(req.headers())?;
+        let queued = self.ingestion.try_queue_job()?;
+        self.set_task_status(task_id.clone(), TaskStatus::Pending(0.0))
+            .await;
+        self.start_indexing(domain, commit, previous, task_id.clone(), api_key, queued)?;
         Ok(task_id)
     }
 
-    async fn get(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        let uri = req.uri();
-        match uri_to_spec(uri) {
+    async fn get(
+        self: Arc<Self>,
+        req: Request<Body>,
+        spec: Result<ResourceSpec, SpecParseError>,
+    ) -> Result<Response<Body>, Infallible> {
+        match spec {
             Ok(ResourceSpec::StartIndex {
                 domain,
                 commit,
@@ -644,14 +2996,44 @@ impl Service {
                     Ok(Response::builder().status(404).body(Body::empty()).unwrap())
                 }
             }
+            Ok(ResourceSpec::GetJob { job_id }) => match self.get_task_status(&job_id).await {
+                Some(status) => Ok(Response::builder()
+                    .header("Content-Type", "application/json")
+                    .body(self.job_json(&job_id, &status).to_string().into())
+                    .unwrap()),
+                None => Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
+            },
             Ok(ResourceSpec::DuplicateCandidates {
                 domain,
                 commit,
                 threshold,
+                stream: true,
             }) => {
-                let result = self
-                    .get_duplicate_candidates(domain, commit, threshold)
-                    .await;
+                let cancel = self
+                    .config()
+                    .timeouts
+                    .scan
+                    .map(|timeout| Cancellation::with_timeout(Duration::from_millis(timeout)))
+                    .unwrap_or_default();
+                match self
+                    .stream_duplicate_candidates(domain, commit, threshold, cancel)
+                    .await
+                {
+                    Ok(response) => Ok(response),
+                    Err(e) => Ok(e.into_response()),
+                }
+            }
+            Ok(ResourceSpec::DuplicateCandidates {
+                domain,
+                commit,
+                threshold,
+                stream: false,
+            }) => {
+                let timeout = self.config().timeouts.scan;
+                let result = with_timeout(timeout, |cancel| {
+                    self.get_duplicate_candidates(domain, commit, threshold, cancel)
+                })
+                .await;
                 string_response_or_error(result)
             }
             Ok(ResourceSpec::Similar {
@@ -659,133 +3041,2041 @@ impl Service {
                 commit,
                 count,
                 id,
+                ef,
+                max_distance,
             }) => {
-                let result = self.get_similar_documents(domain, commit, id, count).await;
+                let timeout = self.config().timeouts.search;
+                let result = with_timeout(timeout, |_| {
+                    self.get_similar_documents(domain, commit, id, count, ef, max_distance)
+                })
+                .await;
                 string_response_or_error(result)
             }
+            Ok(ResourceSpec::DomainSimilar {
+                domain,
+                id,
+                commit,
+                k,
+                ef,
+                max_distance,
+            }) => {
+                let key = (
+                    "similar",
+                    id.clone(),
+                    commit.clone(),
+                    k,
+                    ef,
+                    max_distance.map(f32::to_bits),
+                );
+                // The vector is always its own closest hit.
+                let request = SearchRequest {
+                    commit,
+                    vector: None,
+                    id: Some(id.clone()),
+                    combine: None,
+                    k,
+                    ef,
+                    filter: SearchFilter {
+                        exclude: HashSet::from([id]),
+                        max_distance,
+                        ..Default::default()
+                    },
+                    offset: 0,
+                    cursor: None,
+                    keywords: None,
+                    sparse: None,
+                    fusion: Fusion::default(),
+                    mmr: None,
+                    scoring: None,
+                    feedback: None,
+                };
+                let cacheable = self.is_cacheable(&domain, None, false, false);
+                let search = with_timeout(self.config().timeouts.search, |cancel| {
+                    self.domain_search(domain.clone(), request, cancel)
+                });
+                let result = if cacheable {
+                    self.cached_search(&domain, key, search).await
+                } else {
+                    search.await
+                };
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::Healthz) => Ok(Response::builder().body("ok".into()).unwrap()),
+            Ok(ResourceSpec::Readyz) => {
+                if self.ready.load(Ordering::Acquire) {
+                    Ok(Response::builder().body("ready".into()).unwrap())
+                } else {
+                    Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body("loading indexes".into())
+                        .unwrap())
+                }
+            }
+            Ok(ResourceSpec::OpenApi) => Ok(Response::builder()
+                .header("Content-Type", "application/json")
+                .body(OPENAPI_SPEC.into())
+                .unwrap()),
+            Ok(ResourceSpec::Docs) => Ok(Response::builder()
+                .header("Content-Type", "text/html")
+                .body(SWAGGER_UI.into())
+                .unwrap()),
+            Ok(ResourceSpec::AdminListDomains { tenant }) => {
+                json_response_or_error(self.list_domains(tenant.as_deref()))
+            }
+            Ok(ResourceSpec::AdminDomain { domain }) => {
+                let result = self.describe_domain(domain).await;
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::AdminDeadLetters { domain }) => {
+                json_response_or_error(self.list_dead_letters(&domain))
+            }
+            Ok(ResourceSpec::DomainClusters { domain }) => {
+                json_response_or_error(self.get_clusters(domain).await)
+            }
+            Ok(ResourceSpec::ExportVectors {
+                domain,
+                commit,
+                after,
+            }) => match self.export_vectors(domain, commit, after).await {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(e.into_response()),
+            },
+            Ok(ResourceSpec::DomainVector {
+                domain,
+                id,
+                commit,
+                vectors,
+                metadata,
+            }) => {
+                let result = self.get_vector(domain, id, commit, vectors, metadata).await;
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::ScrollVectors {
+                domain,
+                commit,
+                token,
+                size,
+                vectors,
+                metadata,
+            }) => {
+                let result = self
+                    .scroll_vectors(domain, commit, token, size, vectors, metadata)
+                    .await;
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::AdminConfig) => {
+                let result = serde_json::to_string(&*self.config());
+                json_response_or_error(result.map_err(ResponseError::from))
+            }
+            Ok(ResourceSpec::AdminUsage) => {
+                let result = serde_json::to_string(&self.usage.snapshot());
+                json_response_or_error(result.map_err(ResponseError::from))
+            }
+            Ok(ResourceSpec::ReplicationManifest) => {
+                let result = replication::manifest(&self.path, &self.vector_store)
+                    .map_err(ResponseError::from)
+                    .and_then(|manifest| Ok(serde_json::to_string(&manifest)?));
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::ReplicationFile { name }) => {
+                let range = req
+                    .headers()
+                    .get(hyper::header::RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .map(|range| range.to_string());
+                match self.replication_file(&name, range.as_deref()).await {
+                    Ok(response) => Ok(response),
+                    Err(e) => Ok(e.into_response()),
+                }
+            }
             Ok(ResourceSpec::GetStatistics) => {
                 let statistics = self.vector_store.statistics();
-                let json_string = serde_json::to_string_pretty(&statistics).map_err(|e| e.into());
+                let json_string = serde_json::to_value(&statistics)
+                    .and_then(|mut statistics| {
+                        statistics["ingestion"] =
+                            serde_json::to_value(self.ingestion.statistics())?;
+                        statistics["usage"] = serde_json::to_value(self.domain_usage.snapshot())?;
+                        statistics["metrics"] =
+                            serde_json::to_value(metrics::registry().snapshot())?;
+                        serde_json::to_string_pretty(&statistics)
+                    })
+                    .map_err(|e| e.into());
                 json_response_or_error(json_string)
             }
-            Ok(_) => todo!(),
-            Err(e) => Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(e.to_string().into())
-                .unwrap()),
+            Ok(ResourceSpec::Metrics) => {
+                self.vector_store.record_metrics();
+                Ok(Response::builder()
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(metrics::registry().render_prometheus().into())
+                    .unwrap())
+            }
+            Ok(ResourceSpec::IndexStatistics { domain, commit }) => {
+                let result = self.get_index_statistics(domain, commit).await;
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::Outliers {
+                domain,
+                commit,
+                threshold,
+                k,
+                ef,
+                clusters,
+            }) => {
+                let result = self
+                    .get_outliers(domain, commit, threshold, k, ef, clusters)
+                    .await;
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::ActivateIndex { domain, commit }) => {
+                match self.activate_index(domain, commit).await {
+                    Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+                    Err(e) => Ok(Response::builder()
+                        .status(400)
+                        .body(e.client_message().into())
+                        .unwrap()),
+                }
+            }
+            Ok(ResourceSpec::GetVersions { domain }) => {
+                let result = self.get_versions(domain).await;
+                json_response_or_error(result)
+            }
+            Ok(_) => Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+                .unwrap()),
+            Err(e) => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(e.to_string().into())
+                .unwrap()),
+        }
+    }
+
+    async fn get_similar_documents(
+        self: Arc<Self>,
+        domain: String,
+        commit: Option<String>,
+        id: String,
+        count: usize,
+        ef: Option<usize>,
+        max_distance: Option<f32>,
+    ) -> Result<String, ResponseError> {
+        let commit = self.resolve_commit(&domain, commit).await?;
+        let index_id = create_index_name(&domain, &commit);
+        // if None, then return 404
+        let hnsw = self.get_index(&index_id).await?;
+        let store_domain = self.vector_store.get_domain_async(&domain).await?;
+        let id_map = self.get_id_map(&index_id, &hnsw).await?;
+        let qp = find_points(&hnsw, &id_map, &store_domain, &HashSet::from([id.as_str()]))
+            .remove(id.as_str());
+        match qp {
+            Some(qp) => {
+                let res =
+                    search_with_ef(qp, count, ef.unwrap_or_else(|| self.default_ef()), &hnsw)?;
+                let ids: Vec<QueryResult> = res
+                    .iter()
+                    .filter(|p| !store_domain.is_deleted(p.vector_id()))
+                    .map(|p| QueryResult {
+                        id: p.id().to_string(),
+                        distance: f32::from_bits(p.distance()),
+                    })
+                    .filter(|r| within(max_distance, r.distance))
+                    .collect();
+                let s = serde_json::to_string(&ids)?;
+                Ok(s)
+            }
+            None => Err(ResponseError::IdMissing(id)),
+        }
+    }
+
+    async fn get_index_statistics(
+        self: Arc<Self>,
+        domain: String,
+        commit: String,
+    ) -> Result<String, ResponseError> {
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        let statistics = tokio::task::block_in_place(move || index_statistics(&hnsw, &NoProgress));
+        Ok(serde_json::to_string_pretty(&statistics)?)
+    }
+
+    /// The points of the index of a commit that are far from the
+    /// others, scored by their neighbours or by the centroids of the
+    /// saved clustering of the domain.
+    async fn get_outliers(
+        self: Arc<Self>,
+        domain: String,
+        commit: String,
+        threshold: f32,
+        k: usize,
+        ef: Option<usize>,
+        clusters: bool,
+    ) -> Result<String, ResponseError> {
+        if !self.vector_store.domain_exists(&domain) {
+            return Err(ResponseError::DomainMissing(domain));
+        }
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        let store_domain = self.vector_store.get_domain_async(&domain).await?;
+        let clustering = if clusters {
+            let name = domain.clone();
+            let clustering = self
+                .vector_store
+                .blocking(move |store| store.get_domain(&name)?.clustering())
+                .await?;
+            Some(clustering.ok_or_else(|| {
+                ResponseError::InvalidQuery(format!("domain {domain} has not been clustered"))
+            })?)
+        } else {
+            None
+        };
+        let method = match &clustering {
+            Some(clustering) => OutlierMethod::Centroid(clustering),
+            None => OutlierMethod::Neighbors {
+                k,
+                ef: ef.unwrap_or_else(|| self.default_ef()).max(k + 1),
+            },
+        };
+        let outliers = tokio::task::block_in_place(|| {
+            find_outliers(&hnsw, &store_domain, &method, threshold, &NoProgress)
+        })?;
+        Ok(serde_json::to_string(&outliers)?)
+    }
+
+    async fn get_duplicate_candidates(
+        self: Arc<Self>,
+        domain: String,
+        commit: String,
+        threshold: f32,
+        cancel: Cancellation,
+    ) -> Result<String, ResponseError> {
+        let index_id = create_index_name(&domain, &commit);
+        // if None, then return 404
+        let hnsw = self.get_index(&index_id).await?;
+        let store_domain = self.vector_store.get_domain_async(&domain).await?;
+        let duplicates = task::block_in_place(|| {
+            find_duplicates(&hnsw, &store_domain, threshold, &cancel, &NoProgress)
+        })?;
+        let v: Vec<(&str, &str)> = duplicates
+            .into_iter()
+            .map(|(i, j)| (hnsw.feature(i).id(), hnsw.feature(j).id()))
+            .collect();
+        let result = serde_json::to_string(&v)?;
+        Ok(result)
+    }
+
+    /// Stream the id, vector and metadata of every vector in the index
+    /// of a commit, the active one by default, in the order they were
+    /// stored. Deleted vectors are left out. An export resumes after
+    /// the vector with the id `after`, even if it was deleted since.
+    async fn export_vectors(
+        &self,
+        domain: String,
+        commit: Option<String>,
+        after: Option<String>,
+    ) -> Result<Response<Body>, ResponseError> {
+        if self.config().shards.contains_key(&domain) {
+            return Err(ResponseError::InvalidQuery(
+                "sharded domains have to be exported from their shards".to_string(),
+            ));
+        }
+        let commit = self.resolve_commit(&domain, commit).await?;
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        let domain = self.vector_store.get_domain_async(&domain).await?;
+        let from = match after {
+            Some(after) => {
+                let id_map = self.get_id_map(&index_id, &hnsw).await?;
+                let node = id_map
+                    .get(&after)
+                    .and_then(|nodes| nodes.first())
+                    .ok_or(ResponseError::IdMissing(after))?;
+                hnsw.feature(*node).vec_id() + 1
+            }
+            None => 0,
+        };
+        Ok(ndjson_response(move |sender| {
+            let mut points: Vec<&Point> = (0..hnsw.layer_len(0))
+                .map(|i| hnsw.feature(i))
+                .filter(|p| p.vec_id() >= from && !domain.is_deleted(p.vec_id()))
+                .collect();
+            points.sort_by_key(|p| p.vec_id());
+            for point in points {
+                let record = ExportRecord {
+                    id: point.id(),
+                    vector: &point.vec()[..],
+                    metadata: domain.metadata(point.vec_id()),
+                    sparse: domain.sparse_vector(point.vec_id()),
+                };
+                if sender.blocking_send(ndjson_line(&record)).is_err() {
+                    return;
+                }
+            }
+        }))
+    }
+
+    /// The vector with an external id in the index of a commit, the
+    /// active one by default.
+    async fn get_vector(
+        &self,
+        domain: String,
+        id: String,
+        commit: Option<String>,
+        vectors: bool,
+        metadata: bool,
+    ) -> Result<String, ResponseError> {
+        if self.config().shards.contains_key(&domain) {
+            return Err(ResponseError::InvalidQuery(
+                "vectors of a sharded domain have to be looked up on its shards".to_string(),
+            ));
+        }
+        let commit = self.resolve_commit(&domain, commit).await?;
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        let id_map = self.get_id_map(&index_id, &hnsw).await?;
+        let domain = self.vector_store.get_domain_async(&domain).await?;
+        let point = find_point(&hnsw, &id_map, &domain, &id)?;
+        let record = StoredRecord::new(&point, &domain, vectors, metadata);
+        Ok(serde_json::to_string(&record)?)
+    }
+
+    /// The vectors with the requested external ids, in the order they
+    /// were asked for, and the ids that have no vector.
+    async fn get_vectors(
+        &self,
+        domain: String,
+        request: GetVectorsRequest,
+    ) -> Result<String, ResponseError> {
+        if self.config().shards.contains_key(&domain) {
+            return Err(ResponseError::InvalidQuery(
+                "vectors of a sharded domain have to be looked up on its shards".to_string(),
+            ));
+        }
+        if request.ids.len() > MAX_VECTOR_IDS {
+            return Err(ResponseError::InvalidQuery(format!(
+                "at most {MAX_VECTOR_IDS} vectors can be looked up at once, not {}",
+                request.ids.len()
+            )));
+        }
+        let commit = self.resolve_commit(&domain, request.commit).await?;
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        let id_map = self.get_id_map(&index_id, &hnsw).await?;
+        let domain = self.vector_store.get_domain_async(&domain).await?;
+        let ids: HashSet<&str> = request.ids.iter().map(String::as_str).collect();
+        let points = find_points(&hnsw, &id_map, &domain, &ids);
+        let mut records = Vec::new();
+        let mut missing = Vec::new();
+        for id in &request.ids {
+            match points.get(id.as_str()) {
+                Some(point) => records.push(StoredRecord::new(
+                    point,
+                    &domain,
+                    request.vectors,
+                    request.metadata,
+                )),
+                None => missing.push(id),
+            }
+        }
+        Ok(serde_json::to_string(
+            &json!({ "vectors": records, "missing": missing }),
+        )?)
+    }
+
+    /// A page of up to `size` vectors of the index of a commit, the
+    /// active one by default, with a token for the next page if there
+    /// is one. The token keeps to the commit the scroll started on, so
+    /// vectors indexed later don't show up and none show up twice.
+    /// Vectors deleted during a scroll are left out from then on.
+    async fn scroll_vectors(
+        &self,
+        domain: String,
+        commit: Option<String>,
+        token: Option<String>,
+        size: usize,
+        vectors: bool,
+        metadata: bool,
+    ) -> Result<String, ResponseError> {
+        if self.config().shards.contains_key(&domain) {
+            return Err(ResponseError::InvalidQuery(
+                "sharded domains have to be scrolled on their shards".to_string(),
+            ));
+        }
+        if size == 0 || size > MAX_SCROLL_SIZE {
+            return Err(ResponseError::InvalidQuery(format!(
+                "the size of a page goes from 1 to {MAX_SCROLL_SIZE}"
+            )));
+        }
+        let (commit, mut position) = match (token, commit) {
+            (Some(token), None) => decode_scroll_token(&token)?,
+            (None, commit) => (self.resolve_commit(&domain, commit).await?, 0),
+            (Some(_), Some(_)) => {
+                return Err(ResponseError::InvalidQuery(
+                    "a scroll keeps to the commit of its token".to_string(),
+                ))
+            }
+        };
+        let hnsw = self
+            .get_scrolled_index(&create_index_name(&domain, &commit))
+            .await?;
+        let domain = self.vector_store.get_domain_async(&domain).await?;
+        let mut records = Vec::with_capacity(size);
+        while records.len() < size && position < hnsw.layer_len(0) {
+            let point = hnsw.feature(position);
+            position += 1;
+            if domain.is_deleted(point.vec_id()) {
+                continue;
+            }
+            records.push(StoredRecord::new(point, &domain, vectors, metadata));
+        }
+        let next = (position < hnsw.layer_len(0)).then(|| encode_scroll_token(&commit, position));
+        Ok(serde_json::to_string(
+            &json!({ "vectors": records, "next": next }),
+        )?)
+    }
+
+    /// Like [`Service::get_duplicate_candidates`], but sends every pair
+    /// as soon as it is found, instead of after the whole index has been
+    /// scanned.
+    async fn stream_duplicate_candidates(
+        self: Arc<Self>,
+        domain: String,
+        commit: String,
+        threshold: f32,
+        cancel: Cancellation,
+    ) -> Result<Response<Body>, ResponseError> {
+        let index_id = create_index_name(&domain, &commit);
+        let hnsw = self.get_index(&index_id).await?;
+        Ok(ndjson_response(move |sender| {
+            for i in 0..hnsw.layer_len(0) {
+                if i % SCAN_BATCH == 0 && cancel.is_cancelled() {
+                    tracing::warn!(index = %index_id, "duplicate scan timed out");
+                    return;
+                }
+                let current_point = hnsw.feature(i);
+                let results = match search(current_point, 2, &hnsw) {
+                    Ok(results) => results,
+                    Err(e) => {
+                        tracing::error!(
+                            index = %index_id,
+                            error = ?e,
+                            "error while streaming duplicates"
+                        );
+                        return;
+                    }
+                };
+                for result in results.iter() {
+                    let j = result.internal_id();
+                    if i < j && f32::from_bits(result.distance()) < threshold {
+                        let pair = (current_point.id(), hnsw.feature(j).id());
+                        if sender.blocking_send(ndjson_line(&pair)).is_err() {
+                            // the client went away
+                            return;
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    async fn post(
+        self: Arc<Self>,
+        req: Request<Body>,
+        spec: Result<ResourceSpec, SpecParseError>,
+    ) -> Result<Response<Body>, Infallible> {
+        match spec {
+            Ok(ResourceSpec::Search {
+                domain,
+                commit,
+                count,
+                exact,
+                ef,
+                aggregate,
+                stream,
+                offset,
+                max_distance,
+            }) => {
+                let headers = req.headers().clone();
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let Ok(q) = String::from_utf8(body_bytes.to_vec()) else {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body("The query is not valid utf8".into())
+                        .unwrap());
+                };
+                let api_key = self.embedding_api_key(&headers);
+                let result: Result<Response<Body>, ResponseError> =
+                    with_timeout(self.config().timeouts.search, |cancel| {
+                        self.index_response(
+                            api_key,
+                            q,
+                            domain,
+                            commit,
+                            count,
+                            exact,
+                            ef,
+                            aggregate,
+                            stream,
+                            offset,
+                            max_distance,
+                            cancel,
+                        )
+                    })
+                    .await;
+                match result {
+                    Ok(body) => Ok(body),
+                    Err(e) if e.is_timeout() => Ok(Response::builder()
+                        .status(e.status())
+                        .body(e.to_string().into())
+                        .unwrap()),
+                    Err(e) => Ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(e.to_string().into())
+                        .unwrap()),
+                }
+            }
+            Ok(ResourceSpec::DomainSearch { domain }) => {
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let result = match serde_json::from_slice::<SearchRequest>(&body_bytes) {
+                    Ok(request) => {
+                        let cacheable = self.is_cacheable(
+                            &domain,
+                            request.scoring.as_ref(),
+                            request.mmr.is_some(),
+                            request.cursor.is_some(),
+                        );
+                        let search = with_timeout(self.config().timeouts.search, |cancel| {
+                            self.domain_search(domain.clone(), request, cancel)
+                        });
+                        if cacheable {
+                            self.cached_search(&domain, ("search", &body_bytes[..]), search)
+                                .await
+                        } else {
+                            search.await
+                        }
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::DomainTextSearch { domain }) => {
+                let api_key = self.embedding_api_key(req.headers());
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let result = match serde_json::from_slice::<TextSearchRequest>(&body_bytes) {
+                    Ok(request) => {
+                        let cacheable = self.is_cacheable(
+                            &domain,
+                            request.scoring.as_ref(),
+                            request.mmr.is_some(),
+                            request.cursor.is_some(),
+                        );
+                        let search = with_timeout(self.config().timeouts.search, |cancel| {
+                            self.domain_text_search(api_key, domain.clone(), request, cancel)
+                        });
+                        if cacheable {
+                            self.cached_search(&domain, ("search:text", &body_bytes[..]), search)
+                                .await
+                        } else {
+                            search.await
+                        }
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::UploadVectors {
+                domain,
+                dimension,
+                commit,
+                previous,
+            }) => {
+                let result = self
+                    .upload_vectors(req, domain, dimension, commit, previous)
+                    .await;
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::DeleteVectors { domain }) => {
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let result = match serde_json::from_slice::<DeleteRequest>(&body_bytes) {
+                    Ok(request) => {
+                        self.delete_vectors(&domain, request.ids)
+                            .await
+                            .and_then(|deleted| {
+                                Ok(serde_json::to_string(&json!({ "deleted": deleted }))?)
+                            })
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::GetVectors { domain }) => {
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let result = match serde_json::from_slice::<GetVectorsRequest>(&body_bytes) {
+                    Ok(request) => self.get_vectors(domain, request).await,
+                    Err(e) => Err(e.into()),
+                };
+                json_response_or_error(result)
+            }
+            Ok(ResourceSpec::Embed) => {
+                let api_key = self.embedding_api_key(req.headers());
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let result = match serde_json::from_slice(&body_bytes) {
+                    Ok(request) => self.embed(api_key, request).await,
+                    Err(e) => Err(e.into()),
+                };
+                match result {
+                    Ok(body) => json_response_or_error(Ok(body)),
+                    Err(e) => Ok(e.into_response()),
+                }
+            }
+            Ok(ResourceSpec::AdminDomain { domain }) => {
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let request = if body_bytes.is_empty() {
+                    Ok(CreateDomainRequest::default())
+                } else {
+                    serde_json::from_slice::<CreateDomainRequest>(&body_bytes)
+                };
+                let result = request.map_err(ResponseError::from).and_then(|request| {
+                    let embedding_model = request
+                        .embedding_model
+                        .map(|model| self.config().requested_model(model))
+                        .transpose()
+                        .map_err(ResponseError::InvalidQuery)?;
+                    self.create_domain(domain, embedding_model)
+                });
+                empty_response_or_error(result)
+            }
+            Ok(ResourceSpec::AdminReloadConfig) => {
+                empty_response_or_error(self.reload_config().map_err(ResponseError::from))
+            }
+            Ok(ResourceSpec::DomainClusters { domain }) => {
+                let body_bytes = match extract_body(req).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => return Ok(e.into_response()),
+                };
+                let result = match serde_json::from_slice(&body_bytes) {
+                    Ok(request) => self.cluster_domain(domain, request).await,
+                    Err(e) => Err(e.into()),
+                };
+                string_response_or_error(result)
+            }
+            Ok(ResourceSpec::AdminDeadLetters { domain }) => {
+                let api_key = self.embedding_api_key(req.headers());
+                json_response_or_error(self.replay_dead_letters(&domain, api_key).await)
+            }
+            Ok(ResourceSpec::AdminDeriveDomain { domain, source }) => {
+                empty_response_or_error(self.derive_domain(domain, source))
+            }
+            Ok(ResourceSpec::StartIndex {
+                domain,
+                commit,
+                previous,
+            })
+            | Ok(ResourceSpec::AdminIndexDomain {
+                domain,
+                commit,
+                previous,
+            }) => {
+                let result = self.get_start_index(req, domain, commit, previous).await;
+                string_response_or_error(result)
+            }
+            Ok(_) => Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+                .unwrap()),
+            Err(e) => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(e.to_string().into())
+                .unwrap()),
+        }
+    }
+
+    async fn delete(
+        self: Arc<Self>,
+        spec: Result<ResourceSpec, SpecParseError>,
+    ) -> Result<Response<Body>, Infallible> {
+        match spec {
+            Ok(ResourceSpec::AdminDomain { domain }) => {
+                empty_response_or_error(self.drop_domain(domain).await)
+            }
+            Ok(ResourceSpec::DomainVector { domain, id, .. }) => {
+                let result = self
+                    .delete_vectors(&domain, vec![id.clone()])
+                    .await
+                    .and_then(|deleted| {
+                        Ok(serde_json::to_string(&json!({
+                            "id": id,
+                            "existed": !deleted.is_empty(),
+                        }))?)
+                    });
+                json_response_or_error(result)
+            }
+            Ok(_) => Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+                .unwrap()),
+            Err(e) => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(e.to_string().into())
+                .unwrap()),
+        }
+    }
+
+    /// Add vectors to a domain. The body is either raw little-endian
+    /// f32 vectors (`Content-Type: application/octet-stream`), or one
+    /// JSON `VectorRecord` per line. The body is processed as it comes
+    /// in, so uploads can be larger than memory. Records with ids can
+    /// also be added to the index for a commit.
+    async fn upload_vectors(
+        self: Arc<Self>,
+        req: Request<Body>,
+        domain: String,
+        dimension: Option<usize>,
+        commit: Option<String>,
+        previous: Option<String>,
+    ) -> Result<String, ResponseError> {
+        let _upload = self.ingestion.try_upload()?;
+        self.invalidate_cache(&domain);
+        if let Some(dimension) = dimension {
+            if dimension != vecmath::EMBEDDING_LENGTH {
+                return Err(ResponseError::InvalidQuery(format!(
+                    "only vectors of dimension {} are supported",
+                    vecmath::EMBEDDING_LENGTH
+                )));
+            }
+        }
+        let raw = req
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .map(|v| v.as_bytes().starts_with(b"application/octet-stream"))
+            .unwrap_or(false);
+        if raw {
+            if dimension.is_none() {
+                return Err(ResponseError::InvalidQuery(
+                    "raw uploads need a dimension".to_string(),
+                ));
+            }
+            if commit.is_some() {
+                return Err(ResponseError::InvalidQuery(
+                    "raw uploads have no ids, so they can't be indexed".to_string(),
+                ));
+            }
+            if self.config().shards.contains_key(&domain) {
+                return Err(ResponseError::InvalidQuery(
+                    "raw uploads have no ids, so they can't be sharded".to_string(),
+                ));
+            }
+            return self.upload_raw_vectors(req.into_body(), domain).await;
+        }
+        if let Some(shards) = self.config().shards.get(&domain) {
+            let query = req.uri().query().map(|query| query.to_string());
+            return self
+                .sharded_upload(shards, &domain, query.as_deref(), req.into_body())
+                .await;
+        }
+        let api_key = self.embedding_api_key(req.headers());
+        match commit {
+            Some(commit) => {
+                let index_id = create_index_name(&domain, &commit);
+                if !self.test_and_set_pending(index_id.clone()).await {
+                    return Err(ResponseError::InvalidQuery(format!(
+                        "index {index_id} is already being built"
+                    )));
+                }
+                let result = async {
+                    let hnsw = self
+                        .load_hnsw_for_indexing(IndexIdentifier {
+                            domain: domain.clone(),
+                            commit,
+                            previous,
+                        })
+                        .await?;
+                    let (count, hnsw) = self
+                        .upload_vector_records(req.into_body(), &domain, api_key, Some(hnsw))
+                        .await?;
+                    let hnsw = hnsw.expect("index was passed in");
+                    let hnsw_ref = hnsw.clone();
+                    let path = self.path.clone();
+                    let index_ref = index_id.clone();
+                    let cipher = self.vector_store.cipher();
+                    task::block_in_place(move || {
+                        serialize_index(path, &index_ref, hnsw_ref, cipher)
+                    })?;
+                    self.set_index(index_id.clone(), hnsw.into()).await;
+                    Ok::<_, ResponseError>(json!({ "vectors": count }).to_string())
+                }
+                .await;
+                self.clear_pending(&index_id).await;
+                result
+            }
+            None => {
+                let (count, _) = self
+                    .upload_vector_records(req.into_body(), &domain, api_key, None)
+                    .await?;
+                Ok(json!({ "vectors": count }).to_string())
+            }
+        }
+    }
+
+    async fn upload_raw_vectors(
+        &self,
+        mut body: Body,
+        domain: String,
+    ) -> Result<String, ResponseError> {
+        let resolved_domain = self.vector_store.get_domain_async(&domain).await?;
+        let mut buf: Vec<u8> = Vec::new();
+        let mut first = None;
+        let mut count = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            buf.extend_from_slice(&chunk);
+            let whole = buf.len() - buf.len() % vecmath::EMBEDDING_BYTE_LENGTH;
+            if whole == 0 {
+                continue;
+            }
+            let vecs: Vec<Embedding> = buf[..whole]
+                .chunks_exact(vecmath::EMBEDDING_BYTE_LENGTH)
+                .map(|bytes| {
+                    let mut vec = vecmath::embedding_from_le_bytes(bytes);
+                    vecmath::normalize_vec(&mut vec);
+                    vec
+                })
+                .collect();
+            self.check_vector_quota(&domain, vecs.len())?;
+            let ids = self
+                .vector_store
+                .add_vecs_async(resolved_domain.clone(), vecs)
+                .await?;
+            usage::charge(|usage| usage.vectors += ids.len() as u64);
+            first = first.or(ids.first().copied());
+            count += ids.len();
+            buf.drain(..whole);
+        }
+        if !buf.is_empty() {
+            return Err(ResponseError::InvalidQuery(format!(
+                "upload ended in a partial vector, after {count} complete ones"
+            )));
+        }
+        Ok(json!({ "vectors": count, "first": first }).to_string())
+    }
+
+    async fn upload_vector_records(
+        &self,
+        body: Body,
+        domain: &str,
+        api_key: Result<String, HeaderError>,
+        mut hnsw: Option<HnswIndex>,
+    ) -> Result<(usize, Option<HnswIndex>), ResponseError> {
+        let resolved_domain = self.vector_store.get_domain_async(domain).await?;
+        let body = body.map_err(|e| io::Error::new(ErrorKind::Other, e));
+        let mut lines = LinesStream::new(StreamReader::new(body).lines()).chunks(100);
+        let mut count = 0;
+        while let Some(lines) = lines.next().await {
+            let mut records = Vec::with_capacity(lines.len());
+            let mut sparse = Vec::with_capacity(lines.len());
+            // the records to embed from an image, by position
+            let mut images = Vec::new();
+            for line in lines {
+                let record: VectorRecord = serde_json::from_str(&line?)?;
+                if let Some(problem) = record.problem() {
+                    return Err(ResponseError::InvalidQuery(problem));
+                }
+                let vec = match (record.vector, record.image) {
+                    (Some(vector), _) => {
+                        let mut vec = vecmath::empty_embedding();
+                        vec.copy_from_slice(&vector);
+                        vecmath::normalize_vec(&mut vec);
+                        vec
+                    }
+                    (_, image) => {
+                        images.push((records.len(), image.expect("checked by problem")));
+                        vecmath::empty_embedding()
+                    }
+                };
+                records.push((record.id, vec, record.metadata));
+                sparse.push(record.sparse);
+            }
+            if !images.is_empty() {
+                let inputs: Vec<ImageInput> =
+                    images.iter().map(|(_, image)| image.clone()).collect();
+                let vecs = self
+                    .embed_images(&api_key, &resolved_domain, &inputs)
+                    .await?;
+                for ((record, _), vec) in images.into_iter().zip(vecs) {
+                    records[record].1 = vec;
+                }
+            }
+            self.check_vector_quota(domain, records.len())?;
+            usage::charge(|usage| usage.vectors += records.len() as u64);
+            count += records.len();
+            let records_domain = resolved_domain.clone();
+            let operations = self
+                .vector_store
+                .blocking(move |store| {
+                    let operations = records_to_point_operations(&records_domain, store, records)?;
+                    let sparse: Vec<(usize, SparseVector)> = operations
+                        .iter()
+                        .zip(sparse)
+                        .filter_map(|(operation, sparse)| match operation {
+                            PointOperation::Insert { point } => Some((point.vec_id(), sparse?)),
+                            _ => None,
+                        })
+                        .collect();
+                    if !sparse.is_empty() {
+                        records_domain.add_sparse_vectors(&sparse)?;
+                    }
+                    Ok::<_, IndexError>(operations)
+                })
+                .await?;
+            if let Some(index) = hnsw {
+                hnsw = Some(task::block_in_place(move || {
+                    start_indexing_from_operations(index, operations)
+                })?);
+            }
+        }
+        Ok((count, hnsw))
+    }
+
+    /// Embed uploaded images with the model of the domain, which has
+    /// to be one that embeds images, so that texts embedded with it
+    /// find them.
+    async fn embed_images(
+        &self,
+        api_key: &Result<String, HeaderError>,
+        domain: &Domain,
+        images: &[ImageInput],
+    ) -> Result<Vec<Embedding>, ResponseError> {
+        let model = self.embedding_model(domain.name())?;
+        let api_key = match api_key {
+            Ok(api_key) => api_key.clone(),
+            Err(_) if !model.provider.needs_key() => String::new(),
+            Err(e) => return Err(e.clone().into()),
+        };
+        let embedder = model.embedder(&api_key)?;
+        check_embedder(domain, &*embedder)?;
+        if domain.embedding_model().is_none() {
+            domain.set_embedding_model(&model)?;
+        }
+        let embed_start = Instant::now();
+        let mut vecs = openai::embed_images(&*embedder, images).await?;
+        record_timing("embed_ms", embed_start);
+        // a missing embedding would be stored as an empty vector
+        if vecs.len() != images.len() {
+            return Err(EmbeddingError::WrongCount {
+                expected: images.len(),
+                got: vecs.len(),
+            }
+            .into());
+        }
+        vecs.iter_mut().for_each(vecmath::normalize_vec);
+        Ok(vecs)
+    }
+
+    /// List domains. A tenant only sees its own domains, under the
+    /// names it created them with.
+    fn list_domains(&self, tenant: Option<&str>) -> Result<String, ResponseError> {
+        let domains = self.vector_store.list_domains()?;
+        let domains: Vec<&str> = match tenant {
+            Some(tenant) => {
+                let prefix = scoped_domain(tenant, "");
+                domains
+                    .iter()
+                    .filter_map(|d| d.strip_prefix(&prefix))
+                    .collect()
+            }
+            None => domains.iter().map(|d| d.as_str()).collect(),
+        };
+        Ok(serde_json::to_string(&domains)?)
+    }
+
+    async fn describe_domain(&self, domain: String) -> Result<String, ResponseError> {
+        if !self.vector_store.domain_exists(&domain) {
+            return Err(ResponseError::DomainMissing(domain));
+        }
+        let num_vecs = self
+            .vector_store
+            .get_domain_async(&domain)
+            .await?
+            .num_vecs();
+        let embedding_model = self.embedding_model(&domain)?;
+        let dimensions = embedding_model.dimension().ok();
+        let versions = list_index_versions(&self.path, &domain)?;
+        let active = match self.resolve_commit(&domain, None).await {
+            Ok(commit) => Some(commit),
+            Err(ResponseError::NoActiveIndex(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let usage = self.domain_usage.get(&domain).unwrap_or_default();
+        Ok(json!({
+            "vectors": num_vecs,
+            "embedding_model": embedding_model.model,
+            "dimensions": dimensions,
+            "usage": usage,
+            "active": active,
+            "versions": versions,
+        })
+        .to_string())
+    }
+
+    /// The clustering saved with a domain.
+    async fn get_clusters(&self, domain: String) -> Result<String, ResponseError> {
+        if !self.vector_store.domain_exists(&domain) {
+            return Err(ResponseError::DomainMissing(domain));
+        }
+        let name = domain.clone();
+        let clustering = self
+            .vector_store
+            .blocking(move |store| store.get_domain(&name)?.clustering())
+            .await?;
+        let clustering = clustering.ok_or_else(|| {
+            ResponseError::InvalidQuery(format!("domain {domain} has not been clustered"))
+        })?;
+        Ok(clusters_json(&clustering).to_string())
+    }
+
+    /// Cluster the vectors of a domain, replacing its saved clustering.
+    async fn cluster_domain(
+        self: Arc<Self>,
+        domain: String,
+        request: ClusterRequest,
+    ) -> Result<String, ResponseError> {
+        if !self.vector_store.domain_exists(&domain) {
+            return Err(ResponseError::DomainMissing(domain));
+        }
+        if request.k == 0 {
+            return Err(ResponseError::InvalidQuery(
+                "k has to be at least 1".to_string(),
+            ));
+        }
+        let task_id = generate_job_id(&domain);
+        self.set_task_status(task_id.clone(), TaskStatus::Pending(0.0))
+            .await;
+        let progress = self.job_progress(&task_id);
+        let job_id = task_id.clone();
+        tokio::spawn(async move {
+            let result = self
+                .vector_store
+                .blocking(move |store| {
+                    store
+                        .get_domain(&domain)?
+                        .cluster(request.k, &request.params, &progress)
+                })
+                .await;
+            let status = match result {
+                Ok(clustering) => {
+                    TaskStatus::Completed(clustering.assignments.iter().flatten().count())
+                }
+                Err(e) => {
+                    tracing::warn!(job = %task_id, error = %e, "clustering failed");
+                    TaskStatus::Error(e.to_string())
+                }
+            };
+            self.set_task_status(task_id, status).await;
+        });
+        Ok(job_id)
+    }
+
+    /// The dead letters of a domain, without their operations.
+    fn list_dead_letters(&self, domain: &str) -> Result<String, ResponseError> {
+        let letters: Vec<_> = self
+            .dead_letters
+            .list(domain, self.vector_store.cipher())?
+            .iter()
+            .map(|letter| {
+                json!({
+                    "job": letter.job,
+                    "commit": letter.commit,
+                    "error": letter.error,
+                    "failed_at": letter.failed_at,
+                    "operations": letter.operations.len(),
+                })
+            })
+            .collect();
+        Ok(serde_json::to_string(&letters)?)
+    }
+
+    /// Embed the dead letters of a domain again, adding them to the
+    /// indexes of their commits. Letters that fail again, or whose
+    /// index is being built right now, stay queued. A commit that
+    /// fails doesn't stop the others from being replayed; the first
+    /// error is returned after all of them were tried.
+    async fn replay_dead_letters(
+        &self,
+        domain: &str,
+        api_key: Result<String, HeaderError>,
+    ) -> Result<String, ResponseError> {
+        let api_key = api_key?;
+        let embedder = self.embedding_model(domain)?.embedder(&api_key)?;
+        check_embedder(
+            &*self.vector_store.get_domain_async(domain).await?,
+            &*embedder,
+        )?;
+        let cipher = self.vector_store.cipher();
+        let replay = self.dead_letters.take(domain, cipher)?.ok_or_else(|| {
+            ResponseError::InvalidQuery(format!(
+                "the dead letters of {domain} are being replayed already"
+            ))
+        })?;
+        let mut by_commit: HashMap<String, Vec<DeadLetter>> = HashMap::new();
+        for letter in &replay.letters {
+            by_commit
+                .entry(letter.commit.clone())
+                .or_default()
+                .push(letter.clone());
+        }
+        let mut replayed = 0;
+        let mut failed = Vec::new();
+        let mut error = None;
+        for (commit, letters) in by_commit {
+            let index_id = create_index_name(domain, &commit);
+            if !self.test_and_set_pending(index_id.clone()).await {
+                failed.extend(letters);
+                continue;
+            }
+            // If the commit fails as a whole, all its letters are
+            // queued again, including those that failed by themselves.
+            let before = failed.len();
+            match self
+                .replay_commit(domain, &index_id, letters.clone(), &*embedder, &mut failed)
+                .await
+            {
+                Ok(count) => replayed += count,
+                Err(e) => {
+                    failed.truncate(before);
+                    failed.extend(letters.into_iter().map(|letter| DeadLetter {
+                        error: e.to_string(),
+                        failed_at: chrono::Utc::now().to_rfc3339(),
+                        ..letter
+                    }));
+                    error.get_or_insert(e);
+                }
+            }
+            self.clear_pending(&index_id).await;
+        }
+        replay.put_back(&failed, cipher)?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Ok(json!({ "replayed": replayed, "remaining": failed.len() }).to_string())
+    }
+
+    /// Add the dead letters of a commit to its index, returning how
+    /// many were added. Letters that fail again go to `failed`.
+    async fn replay_commit(
+        &self,
+        domain: &str,
+        index_id: &str,
+        letters: Vec<DeadLetter>,
+        embedder: &dyn EmbeddingProvider,
+        failed: &mut Vec<DeadLetter>,
+    ) -> Result<usize, ResponseError> {
+        let mut hnsw = match self.get_index(index_id).await {
+            Ok(hnsw) => (*hnsw).clone(),
+            Err(e) => {
+                failed.extend(letters.into_iter().map(|letter| DeadLetter {
+                    error: e.to_string(),
+                    ..letter
+                }));
+                return Ok(0);
+            }
+        };
+        let resolved_domain = self.vector_store.get_domain_async(domain).await?;
+        let mut replayed = 0;
+        for letter in letters {
+            match operations_to_point_operations(
+                &resolved_domain,
+                &self.vector_store,
+                letter.operations.clone(),
+                embedder,
+            )
+            .await
+            {
+                Ok(new_ops) => {
+                    hnsw = task::block_in_place(move || {
+                        start_indexing_from_operations(hnsw, new_ops)
+                    })?;
+                    replayed += 1;
+                }
+                Err(e) => failed.push(DeadLetter {
+                    error: e.to_string(),
+                    failed_at: chrono::Utc::now().to_rfc3339(),
+                    ..letter
+                }),
+            }
+        }
+        if replayed > 0 {
+            let path = self.path.clone();
+            let index_ref = index_id.to_string();
+            let hnsw_ref = hnsw.clone();
+            let cipher = self.vector_store.cipher();
+            task::block_in_place(move || serialize_index(path, &index_ref, hnsw_ref, cipher))?;
+            self.set_index(index_id.to_string(), hnsw.into()).await;
+        }
+        Ok(replayed)
+    }
+
+    /// Create an empty domain, embedding texts with the given model
+    /// or else the configured one.
+    fn create_domain(
+        &self,
+        domain: String,
+        embedding_model: Option<EmbeddingModel>,
+    ) -> Result<(), ResponseError> {
+        if self.vector_store.domain_exists(&domain) {
+            return Err(ResponseError::DomainExists(domain));
+        }
+        let embedding_model = embedding_model.unwrap_or_else(|| self.default_embedding_model());
+        embedding_model.dimension()?;
+        self.check_domain_quota(&domain)?;
+        self.vector_store
+            .get_domain(&domain)?
+            .set_embedding_model(&embedding_model)?;
+        Ok(())
+    }
+
+    /// Create the domains of the configuration that don't exist yet.
+    fn create_configured_domains(&self) {
+        let config = self.config();
+        for (domain, domain_config) in config.domains.iter() {
+            if self.vector_store.domain_exists(domain) {
+                continue;
+            }
+            match self.create_domain(domain.clone(), domain_config.embedding_model.clone()) {
+                Ok(()) => tracing::info!(domain = %domain, "created configured domain"),
+                Err(e) => {
+                    tracing::error!(domain = %domain, error = %e, "could not create configured domain")
+                }
+            }
         }
     }
 
-    async fn get_similar_documents(
-        self: Arc<Self>,
-        domain: String,
-        commit: String,
-        id: String,
-        count: usize,
-    ) -> Result<String, ResponseError> {
-        let index_id = create_index_name(&domain, &commit);
-        // if None, then return 404
+    /// Create a domain from the vectors, documents and indexes of an
+    /// existing one.
+    fn derive_domain(&self, domain: String, source: String) -> Result<(), ResponseError> {
+        if !self.vector_store.domain_exists(&source) {
+            return Err(ResponseError::DomainMissing(source));
+        }
+        // Tenant keys are scoped to their own domains already, but a
+        // derived domain never leaves the tenant of its source either.
+        if domain_tenant(&source) != domain_tenant(&domain) {
+            return Err(ResponseError::InvalidQuery(format!(
+                "{domain} can't be derived from {source}, which belongs to another tenant"
+            )));
+        }
+        self.check_domain_quota(&domain)?;
+        let additional = self.vector_store.get_domain(&source)?.num_vecs();
+        self.check_vector_quota(&domain, additional)?;
+        self.vector_store.copy_domain(&source, &domain)?;
+        copy_index_versions(&self.path, &source, &domain, self.vector_store.cipher())?;
+        Ok(())
+    }
+
+    /// Delete vectors by id. The ids are looked up in the active
+    /// index, and every vector stored under them gets a tombstone, so
+    /// that searches leave it out from then on. Returns the ids that
+    /// existed.
+    async fn delete_vectors(
+        &self,
+        domain: &str,
+        ids: Vec<String>,
+    ) -> Result<Vec<String>, ResponseError> {
+        if self.config().shards.contains_key(domain) {
+            return Err(ResponseError::InvalidQuery(
+                "vectors of a sharded domain have to be deleted on its shards".to_string(),
+            ));
+        }
+        let commit = self.resolve_commit(domain, None).await?;
+        let index_id = create_index_name(domain, &commit);
         let hnsw = self.get_index(&index_id).await?;
-        let elts = hnsw.layer_len(0);
-        let mut qp = None;
-        for i in 0..elts {
-            if *hnsw.feature(i).id() == id {
-                qp = Some(hnsw.feature(i))
+        let id_map = self.get_id_map(&index_id, &hnsw).await?;
+        let store_domain = self.vector_store.get_domain_async(domain).await?;
+        let ids: HashSet<String> = ids.into_iter().collect();
+        let mut vectors = Vec::new();
+        let mut existed = Vec::new();
+        for id in ids {
+            // every upload of the id goes
+            let live: Vec<usize> = id_map
+                .get(&id)
+                .into_iter()
+                .flatten()
+                .map(|&i| hnsw.feature(i).vec_id())
+                .filter(|&vector| !store_domain.is_deleted(vector))
+                .collect();
+            if !live.is_empty() {
+                vectors.extend(live);
+                existed.push(id);
             }
         }
-        match qp {
-            Some(qp) => {
-                let res = search(qp, count, &hnsw)?;
-                let ids: Vec<QueryResult> = res
-                    .iter()
-                    .map(|p| QueryResult {
-                        id: p.id().to_string(),
-                        distance: f32::from_bits(p.distance()),
-                    })
-                    .collect();
-                let s = serde_json::to_string(&ids)?;
-                Ok(s)
+        store_domain.add_tombstones(&vectors)?;
+        self.invalidate_cache(domain);
+        existed.sort();
+        Ok(existed)
+    }
+
+    /// Drop a domain with all its indexes. Searches that are already
+    /// running finish against the loaded index.
+    async fn drop_domain(&self, domain: String) -> Result<(), ResponseError> {
+        if !self.vector_store.drop_domain(&domain)? {
+            return Err(ResponseError::DomainMissing(domain));
+        }
+        remove_index_versions(&self.path, &domain)?;
+        let prefix = create_index_name(&domain, "");
+        self.indexes
+            .write()
+            .await
+            .retain(|index_id, _| !index_id.starts_with(&prefix));
+        self.lexical
+            .write()
+            .await
+            .retain(|index_id, _| !index_id.starts_with(&prefix));
+        self.sparse
+            .write()
+            .await
+            .retain(|index_id, _| !index_id.starts_with(&prefix));
+        self.id_maps
+            .write()
+            .await
+            .retain(|index_id, _| !index_id.starts_with(&prefix));
+        self.scrolled
+            .lock()
+            .unpoisoned()
+            .retain(|index_id, _| !index_id.starts_with(&prefix));
+        self.active.write().await.remove(&domain);
+        self.domain_usage.remove(&domain);
+        self.invalidate_cache(&domain);
+        Ok(())
+    }
+
+    /// The OpenAI key of the request, or the configured one if the
+    /// request has none. Without either, the key is empty if the
+    /// default model doesn't need one.
+    fn embedding_api_key(&self, headers: &HeaderMap) -> Result<String, HeaderError> {
+        match get_header_value(headers, "VECTORLINK_EMBEDDING_API_KEY") {
+            Err(HeaderError::MissingKey(key)) => match &self.config().embedding_api_key {
+                Some(api_key) => Ok(api_key.expose().to_string()),
+                None if !self.default_embedding_model().provider.needs_key() => Ok(String::new()),
+                None => Err(HeaderError::MissingKey(key)),
+            },
+            result => result,
+        }
+    }
+
+    /// Embed texts for a client, so that its embeddings are paid for
+    /// and counted through this server. Texts embedded before are
+    /// answered from the cache.
+    async fn embed(
+        &self,
+        api_key: Result<String, HeaderError>,
+        request: EmbedRequest,
+    ) -> Result<String, ResponseError> {
+        if let Some(limiter) = &self.embed_limiter {
+            let account = usage::current_account()
+                .map(|(_, account)| account)
+                .unwrap_or_else(|| ANONYMOUS_ACCOUNT.to_string());
+            limiter
+                .check(&account)
+                .map_err(ResponseError::RateLimited)?;
+        }
+        let default = self.default_embedding_model();
+        let model = match request.model {
+            Some(model) => EmbeddingModel {
+                model,
+                dimensions: request.dimensions,
+                provider: default.provider,
+            },
+            None => EmbeddingModel {
+                dimensions: request.dimensions.or(default.dimensions),
+                ..default
+            },
+        };
+        let dimension = model.dimension()?;
+        let mut embeddings: Vec<Option<Arc<Embedding>>> = request
+            .input
+            .iter()
+            .map(|text| self.embed_cache.get(&model, text))
+            .collect();
+        let missing: Vec<String> = request
+            .input
+            .iter()
+            .zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| text.clone())
+            .collect();
+        if !missing.is_empty() {
+            let mut fresh = embeddings_for_model(&api_key?, &model, &missing)
+                .await?
+                .into_iter();
+            for (text, slot) in request.input.into_iter().zip(embeddings.iter_mut()) {
+                if slot.is_none() {
+                    let embedding = Arc::new(fresh.next().expect("one embedding per text"));
+                    self.embed_cache.insert(&model, text, embedding.clone());
+                    *slot = Some(embedding);
+                }
             }
-            None => Err(ResponseError::IdMissing(id)),
         }
+        let embeddings: Vec<&[f32]> = embeddings
+            .iter()
+            .map(|embedding| &embedding.as_ref().expect("every text is embedded")[..dimension])
+            .collect();
+        Ok(serde_json::to_string(&json!({
+            "model": model.model,
+            "dimensions": dimension,
+            "embeddings": embeddings,
+        }))?)
     }
 
-    async fn get_duplicate_candidates(
-        self: Arc<Self>,
+    fn default_embedding_model(&self) -> EmbeddingModel {
+        self.config().embedding_model.clone().unwrap_or_default()
+    }
+
+    /// The model that texts for a domain are embedded with. A domain
+    /// keeps the model it was first indexed with. Domains with
+    /// vectors from before models were recorded use ada-002.
+    fn embedding_model(&self, domain: &str) -> io::Result<EmbeddingModel> {
+        if !self.vector_store.domain_exists(domain) {
+            // sharded domains only exist on the shards
+            return Ok(self.default_embedding_model());
+        }
+        let domain = self.vector_store.get_domain(domain)?;
+        Ok(match domain.embedding_model() {
+            Some(model) => model,
+            None if domain.num_vecs() == 0 => self.default_embedding_model(),
+            None => EmbeddingModel::default(),
+        })
+    }
+
+    async fn domain_text_search(
+        &self,
+        api_key: Result<String, HeaderError>,
         domain: String,
-        commit: String,
-        threshold: f32,
+        request: TextSearchRequest,
+        cancel: Cancellation,
+    ) -> Result<String, ResponseError> {
+        let api_key = api_key?;
+        let model = self.embedding_model(&domain)?;
+        let embed_start = Instant::now();
+        let vec = embeddings_for_model(&api_key, &model, &[request.text.clone()]).await?;
+        record_timing("embed_ms", embed_start);
+        self.domain_search(
+            domain,
+            SearchRequest {
+                commit: request.commit,
+                vector: Some(vec[0].to_vec()),
+                id: None,
+                combine: None,
+                k: request.k,
+                ef: request.ef,
+                filter: request.filter,
+                offset: request.offset,
+                cursor: request.cursor,
+                keywords: request.hybrid.then_some(request.text),
+                sparse: request.sparse,
+                fusion: request.fusion,
+                mmr: request.mmr,
+                scoring: request.scoring,
+                feedback: request.feedback,
+            },
+            cancel,
+        )
+        .await
+    }
+
+    /// The scoring of a search: the one it gives, or else the one
+    /// configured for the domain. Searches that are reranked by mmr or
+    /// continue from a cursor are ordered by distance, so the scoring
+    /// of the domain doesn't apply to them.
+    fn search_scoring(
+        &self,
+        domain: &str,
+        scoring: Option<&Scoring>,
+        mmr: bool,
+        cursor: bool,
+    ) -> Option<Scoring> {
+        match scoring {
+            Some(scoring) => Some(scoring.clone()),
+            None if !mmr && !cursor => self
+                .config()
+                .domains
+                .get(domain)
+                .and_then(|domain_config| domain_config.scoring.clone()),
+            None => None,
+        }
+    }
+
+    /// Whether the results of a search can be cached. Scores that
+    /// decay from now change by the second, so they can't.
+    fn is_cacheable(
+        &self,
+        domain: &str,
+        scoring: Option<&Scoring>,
+        mmr: bool,
+        cursor: bool,
+    ) -> bool {
+        !self
+            .search_scoring(domain, scoring, mmr, cursor)
+            .is_some_and(|scoring| scoring.uses_now())
+    }
+
+    async fn domain_search(
+        &self,
+        domain: String,
+        request: SearchRequest,
+        cancel: Cancellation,
     ) -> Result<String, ResponseError> {
+        if let Some(problem) = request.combine.as_ref().and_then(Combination::problem) {
+            return Err(ResponseError::InvalidQuery(problem));
+        }
+        if let Some(problem) = request.fusion.problem() {
+            return Err(ResponseError::InvalidQuery(problem));
+        }
+        if let Some(shards) = self.config().shards.get(&domain) {
+            return self.sharded_search(shards, &domain, request).await;
+        }
+        let commit = self.resolve_commit(&domain, request.commit).await?;
         let index_id = create_index_name(&domain, &commit);
-        // if None, then return 404
         let hnsw = self.get_index(&index_id).await?;
-        let mut duplicates: HashMap<usize, usize> = HashMap::new();
-        let elts = hnsw.layer_len(0);
-        for i in 0..elts {
-            let current_point = &hnsw.feature(i);
-            let results = search(current_point, 2, &hnsw)?;
-            for result in results.iter() {
-                if f32::from_bits(result.distance()) < threshold {
-                    add_to_duplicates(&mut duplicates, i, result.internal_id())
+        let domain = self.vector_store.get_domain_async(&domain).await?;
+        let mut inputs = HashSet::new();
+        let qp = match (request.vector, request.id, request.combine) {
+            (Some(vector), None, None) => {
+                let mut vec: Embedding = vector.try_into().map_err(|v: Vec<f32>| {
+                    ResponseError::InvalidQuery(format!(
+                        "expected a vector of length {} but got {}",
+                        vecmath::EMBEDDING_LENGTH,
+                        v.len()
+                    ))
+                })?;
+                vecmath::normalize_vec(&mut vec);
+                Point::Mem { vec: Box::new(vec) }
+            }
+            (None, Some(id), None) => {
+                let id_map = self.get_id_map(&index_id, &hnsw).await?;
+                find_point(&hnsw, &id_map, &domain, &id)?
+            }
+            (None, None, Some(combination)) => {
+                let terms = combination.terms();
+                let id_map = self.get_id_map(&index_id, &hnsw).await?;
+                let points = terms
+                    .iter()
+                    .map(|(id, _)| find_point(&hnsw, &id_map, &domain, id))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let vec = arithmetic::combine(
+                    points
+                        .iter()
+                        .zip(&terms)
+                        .map(|(point, (_, weight))| (point.vec(), *weight)),
+                )
+                .ok_or_else(|| {
+                    ResponseError::InvalidQuery("the combined vectors cancel out".to_string())
+                })?;
+                inputs.extend(terms.iter().map(|(id, _)| id.to_string()));
+                Point::Mem { vec: Box::new(vec) }
+            }
+            _ => {
+                return Err(ResponseError::InvalidQuery(
+                    "give exactly one of vector, id and combine".to_string(),
+                ))
+            }
+        };
+        let mut filter = request.filter;
+        filter.exclude.extend(inputs);
+        let expression = filter
+            .expression
+            .as_deref()
+            .map(Filter::parse)
+            .transpose()
+            .map_err(|e| ResponseError::InvalidQuery(e.to_string()))?;
+        let after = request.cursor.as_deref().map(decode_cursor).transpose()?;
+        let ef = request.ef.unwrap_or_else(|| self.default_ef());
+        let span = tracing::Span::current();
+        span.record("k", request.k);
+        span.record("ef", ef);
+        if let Some(mmr) = &request.mmr {
+            if !(0.0..=1.0).contains(&mmr.lambda) {
+                return Err(ResponseError::InvalidQuery(
+                    "the lambda of mmr goes from 0 to 1".to_string(),
+                ));
+            }
+            if mmr.candidates > Some(MAX_RERANK_CANDIDATES) {
+                return Err(ResponseError::InvalidQuery(format!(
+                    "the candidates of mmr are at most {MAX_RERANK_CANDIDATES}"
+                )));
+            }
+        }
+        if let Some(problem) = request.feedback.as_ref().and_then(Feedback::problem) {
+            return Err(ResponseError::InvalidQuery(problem.to_string()));
+        }
+        if let Some(scoring) = &request.scoring {
+            let problems = scoring.problems();
+            if !problems.is_empty() {
+                return Err(ResponseError::InvalidQuery(problems.join(", ")));
+            }
+            if request.mmr.is_some() {
+                return Err(ResponseError::InvalidQuery(
+                    "give at most one of scoring and mmr".to_string(),
+                ));
+            }
+        }
+        let scoring = self.search_scoring(
+            domain.name(),
+            request.scoring.as_ref(),
+            request.mmr.is_some(),
+            after.is_some(),
+        );
+        if after.is_some() && (request.mmr.is_some() || scoring.is_some()) {
+            return Err(ResponseError::InvalidQuery(
+                "cursors don't apply to reranked searches, use an offset".to_string(),
+            ));
+        }
+        let bitmap_fields = self
+            .config()
+            .domains
+            .get(domain.name())
+            .map(|domain_config| domain_config.bitmap_fields.clone())
+            .unwrap_or_default();
+        task::block_in_place(|| domain.index_fields(&bitmap_fields));
+        // The vectors that the bitmaps narrow the expression down to.
+        // Exact answers leave the metadata unread.
+        let narrowed = expression
+            .as_ref()
+            .and_then(|e| domain.filter_candidates(e));
+        let keep = |r: &PointQuery| {
+            !domain.is_deleted(r.vector_id())
+                && filter.keep(r.id(), f32::from_bits(r.distance()))
+                && narrowed
+                    .as_ref()
+                    .map(|n| n.contains(r.vector_id()))
+                    .unwrap_or(true)
+                && (narrowed.as_ref().is_some_and(|n| n.exact)
+                    || expression
+                        .as_ref()
+                        .map(|e| e.matches(domain.metadata(r.vector_id()).as_ref()))
+                        .unwrap_or(true))
+        };
+        // The feedback pass sees the same filters, so the query only
+        // moves towards results that could be returned.
+        let qp = match request.feedback {
+            Some(feedback) => {
+                let nearest =
+                    search_after_filtered(&qp, feedback.m, None, ef, &hnsw, &keep, &cancel)?;
+                let vecs: Vec<&Embedding> = nearest
+                    .iter()
+                    .map(|r| hnsw.feature(r.internal_id()).vec())
+                    .collect();
+                Point::Mem {
+                    vec: Box::new(feedback.expand(qp.vec(), &vecs)),
+                }
+            }
+            None => qp,
+        };
+        if request.keywords.is_some() || request.sparse.is_some() {
+            if after.is_some() {
+                return Err(ResponseError::InvalidQuery(
+                    "cursors don't apply to hybrid searches, use an offset".to_string(),
+                ));
+            }
+            if request.mmr.is_some() || request.scoring.is_some() {
+                return Err(ResponseError::InvalidQuery(
+                    "hybrid searches can't be reranked with mmr or scoring".to_string(),
+                ));
+            }
+            let lexical;
+            let sparse;
+            let terms = match (&request.keywords, &request.sparse) {
+                (Some(keywords), None) => {
+                    lexical = self.get_lexical_index(&index_id, &domain, &hnsw).await?;
+                    Terms::Keywords(keywords, &lexical)
+                }
+                (None, Some(query)) => {
+                    sparse = self.get_sparse_index(&index_id, &domain, &hnsw).await?;
+                    Terms::Sparse(query, &sparse)
                 }
+                _ => {
+                    return Err(ResponseError::InvalidQuery(
+                        "give at most one of keywords and sparse".to_string(),
+                    ))
+                }
+            };
+            let search_start = Instant::now();
+            let mut results = search_hybrid(
+                &qp,
+                terms,
+                request.offset.saturating_add(request.k),
+                ef,
+                &hnsw,
+                request.fusion,
+                keep,
+                &cancel,
+            )?;
+            record_timing("search_ms", search_start);
+            results.drain(..request.offset.min(results.len()));
+            let hits: Vec<SearchHit> = results
+                .iter()
+                .map(|r| {
+                    let point = r.point();
+                    SearchHit {
+                        id: point.id().to_string(),
+                        score: r.score(),
+                        distance: f32::from_bits(point.distance()),
+                        document: domain.document(point.vector_id()),
+                        cursor: encode_cursor(point.distance(), point.id()),
+                    }
+                })
+                .collect();
+            return Ok(serde_json::to_string(&hits)?);
+        }
+        let num = request.offset.saturating_add(request.k);
+        let search_start = Instant::now();
+        let candidates = match (&request.mmr, &scoring) {
+            (Some(mmr), _) => mmr.candidates(num),
+            (None, Some(scoring)) => scoring.candidates(num),
+            (None, None) => num,
+        };
+        let after = after
+            .as_ref()
+            .map(|(distance, id)| (*distance, id.as_str()));
+        let mut results = match &narrowed {
+            Some(narrowed)
+                if narrowed.vectors.len().saturating_mul(EXACT_FILTER_RATIO)
+                    < hnsw.layer_len(0) as u64 =>
+            {
+                search_after_among(
+                    &qp,
+                    candidates,
+                    after,
+                    &hnsw,
+                    |point| narrowed.contains(point.vec_id()),
+                    keep,
+                    &cancel,
+                )?
             }
+            _ => search_after_filtered(&qp, candidates, after, ef, &hnsw, keep, &cancel)?,
+        };
+        if let Some(mmr) = request.mmr {
+            results = rerank_mmr(&qp, results, num, mmr.lambda, |a, b| {
+                f32::from_bits(OpenAI.distance(a, b))
+            });
         }
-        let mut v: Vec<(&str, &str)> = duplicates
-            .into_iter()
-            .map(|(i, j)| (hnsw.feature(i).id(), hnsw.feature(j).id()))
+        record_timing("search_ms", search_start);
+        let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+        let mut hits: Vec<SearchHit> = results
+            .iter()
+            .map(|r| {
+                let distance = f32::from_bits(r.distance());
+                let score = match &scoring {
+                    Some(scoring) => {
+                        scoring.score(1.0 - distance, domain.metadata(r.vector_id()).as_ref(), now)
+                    }
+                    None => 1.0 - distance,
+                };
+                SearchHit {
+                    id: r.id().to_string(),
+                    score,
+                    distance,
+                    document: domain.document(r.vector_id()),
+                    cursor: encode_cursor(r.distance(), r.id()),
+                }
+            })
             .collect();
-        let result = serde_json::to_string(&v)?;
-        Ok(result)
+        if scoring.is_some() {
+            // by score, and otherwise in the order they were found
+            hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+            hits.truncate(num);
+        }
+        hits.drain(..request.offset.min(hits.len()));
+        Ok(serde_json::to_string(&hits)?)
     }
 
-    async fn post(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        let uri = req.uri();
-        match uri_to_spec(uri) {
-            Ok(ResourceSpec::Search {
-                domain,
-                commit,
-                count,
-            }) => {
-                let headers = req.headers().clone();
-                let body = req.into_body();
-                let body_bytes = hyper::body::to_bytes(body).await.unwrap();
-                let q = String::from_utf8(body_bytes.to_vec()).unwrap();
-                let api_key = get_header_value(&headers, "VECTORLINK_EMBEDDING_API_KEY");
-                let result: Result<Response<Body>, ResponseError> =
-                    self.index_response(api_key, q, domain, commit, count).await;
-                match result {
-                    Ok(body) => Ok(body),
-                    Err(e) => Ok(Response::builder()
-                        .status(StatusCode::NOT_FOUND)
-                        .body(e.to_string().into())
-                        .unwrap()),
-                }
+    /// Run a search on every shard of a domain and merge the hits. Any
+    /// shard may hold all of the first hits, so every shard is asked
+    /// for `offset + k` of them. Cursors carry over, as shards order
+    /// their hits the same way.
+    async fn sharded_search(
+        &self,
+        shards: &[ShardConfig],
+        domain: &str,
+        mut request: SearchRequest,
+    ) -> Result<String, ResponseError> {
+        if request.id.is_some() || request.combine.is_some() {
+            return Err(ResponseError::InvalidQuery(
+                "searches by id or combination are not supported on sharded domains".to_string(),
+            ));
+        }
+        if request.keywords.is_some() || request.sparse.is_some() {
+            return Err(ResponseError::InvalidQuery(
+                "hybrid searches are not supported on sharded domains".to_string(),
+            ));
+        }
+        if request.mmr.is_some() || request.scoring.is_some() || request.feedback.is_some() {
+            return Err(ResponseError::InvalidQuery(
+                "mmr, scoring and feedback are not supported on sharded domains".to_string(),
+            ));
+        }
+        let offset = request.offset;
+        request.k = request.k.saturating_add(offset);
+        request.offset = 0;
+        tracing::Span::current().record("k", request.k);
+        let search_start = Instant::now();
+        let hits = self.shard_client.search(shards, domain, &request).await?;
+        record_timing("search_ms", search_start);
+        let mut hits = merge_hits(hits, request.k);
+        hits.drain(..offset.min(hits.len()));
+        Ok(serde_json::to_string(&hits)?)
+    }
+
+    /// Pass an upload of vector records on to the shards of a domain,
+    /// where every record goes to the shard its id belongs to. The
+    /// query is passed on too, so every shard indexes its part under
+    /// the same commit.
+    async fn sharded_upload(
+        &self,
+        shards: &[ShardConfig],
+        domain: &str,
+        query: Option<&str>,
+        body: Body,
+    ) -> Result<String, ResponseError> {
+        // Every record is checked before any shard is written to, so
+        // that a bad line doesn't leave part of the upload on some of
+        // the shards.
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(ResponseError::BodyError)?;
+        let mut split = vec![Vec::new(); shards.len()];
+        for line in io::BufRead::lines(&body[..]) {
+            let line = line?;
+            let record: VectorRecord = serde_json::from_str(&line)?;
+            if let Some(problem) = record.problem() {
+                return Err(ResponseError::InvalidQuery(problem));
             }
-            Ok(_) => todo!(),
-            Err(e) => Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(e.to_string().into())
-                .unwrap()),
+            let lines = &mut split[shard_for(&record.id, shards.len())];
+            lines.extend_from_slice(line.as_bytes());
+            lines.push(b'\n');
         }
+        let uploads = shards.iter().zip(split).map(|(shard, lines)| {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            if !lines.is_empty() {
+                sender
+                    .try_send(lines.into())
+                    .expect("the channel has room for the lines");
+            }
+            self.shard_client.upload(shard, domain, query, receiver)
+        });
+        let count: usize = futures::future::try_join_all(uploads)
+            .await?
+            .into_iter()
+            .sum();
+        Ok(json!({ "vectors": count }).to_string())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn index_response(
         &self,
         api_key: Result<String, HeaderError>,
         q: String,
         domain: String,
-        commit: String,
+        commit: Option<String>,
         count: usize,
+        exact: bool,
+        ef: Option<usize>,
+        aggregate: Option<Aggregation>,
+        stream: bool,
+        offset: usize,
+        max_distance: Option<f32>,
+        cancel: Cancellation,
     ) -> Result<Response<Body>, ResponseError> {
         let api_key = api_key?;
-        let vec: Vec<[f32; 1536]> = embeddings_for(&api_key, &[q]).await?;
+        let count = count.saturating_add(offset);
+        let commit = self.resolve_commit(&domain, commit).await?;
+        let span = tracing::Span::current();
+        span.record("k", count);
+        let ef = ef.unwrap_or_else(|| self.default_ef());
+        span.record("ef", ef);
+        let cached = self.cache.as_ref().filter(|_| !stream).map(|cache| {
+            let query = (
+                "query",
+                &q,
+                &commit,
+                count,
+                exact,
+                ef,
+                aggregate,
+                offset,
+                max_distance.map(f32::to_bits),
+            );
+            (cache, cache.key(&domain, &query))
+        });
+        if let Some(result) = cached.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            return Ok(Response::builder().body(result.to_string().into()).unwrap());
+        }
+        let model = self.embedding_model(&domain)?;
+        let embed_start = Instant::now();
+        let vec: Vec<[f32; 1536]> = embeddings_for_model(&api_key, &model, &[q]).await?;
+        record_timing("embed_ms", embed_start);
         let qp = Point::Mem {
             vec: Box::new(vec[0]),
         };
         let index_id = create_index_name(&domain, &commit);
         // if None, then return 404
         let hnsw = self.get_index(&index_id).await?;
-        let res = search(&qp, count, &hnsw).unwrap();
+        let store_domain = self.vector_store.get_domain_async(&domain).await?;
+        if let Some(aggregation) = aggregate {
+            let search_start = Instant::now();
+            let documents = search_documents(&qp, count, ef, aggregation, &store_domain, &hnsw)?;
+            record_timing("search_ms", search_start);
+            let documents: Vec<_> = documents
+                .into_iter()
+                .skip(offset)
+                .filter(|d| within(max_distance, d.distance()))
+                .collect();
+            let s = serde_json::to_string(&documents)?;
+            if let Some((cache, key)) = &cached {
+                cache.insert(key, Arc::new(s.clone()));
+            }
+            return Ok(Response::builder().body(s.into()).unwrap());
+        }
+        let search_start = Instant::now();
+        let mut res = if exact {
+            search_exact_cancellable(&qp, count, &hnsw, &cancel)?
+        } else {
+            search_with_ef(&qp, count, ef, &hnsw)?
+        };
+        record_timing("search_ms", search_start);
+        res.retain(|p| !store_domain.is_deleted(p.vector_id()));
+        res.drain(..offset.min(res.len()));
+        res.retain(|p| within(max_distance, f32::from_bits(p.distance())));
+        if stream {
+            return Ok(ndjson_response(move |sender| {
+                for p in res {
+                    let id = QueryResult {
+                        id: p.id().to_string(),
+                        distance: f32::from_bits(p.distance()),
+                    };
+                    if sender.blocking_send(ndjson_line(&id)).is_err() {
+                        return;
+                    }
+                }
+            }));
+        }
         let ids: Vec<QueryResult> = res
             .iter()
             .map(|p| QueryResult {
@@ -794,19 +5084,87 @@ impl Service {
             })
             .collect();
         let s = serde_json::to_string(&ids)?;
+        if let Some((cache, key)) = &cached {
+            cache.insert(key, Arc::new(s.clone()));
+        }
         Ok(Response::builder().body(s.into()).unwrap())
     }
 }
 
+/// Clients can pass their own request id in this header. It is
+/// generated otherwise, and always sent back in the response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Record how long a stage of the current request took on its span.
+fn record_timing(stage: &'static str, start: Instant) {
+    tracing::Span::current().record(stage, start.elapsed().as_millis() as u64);
+}
+
+/// Whether a result at `distance` is within the threshold, if any.
+fn within(max_distance: Option<f32>, distance: f32) -> bool {
+    max_distance.map(|max| distance <= max).unwrap_or(true)
+}
+
+// Filters that the bitmaps of a domain narrow down to at most one in
+// this many vectors are searched exactly among those vectors, as the
+// graph would have to be searched nearly all the way to find them.
+const EXACT_FILTER_RATIO: u64 = 20;
+
+// How many vectors a page of a scroll has by default, and at most.
+const DEFAULT_SCROLL_SIZE: usize = 100;
+const MAX_SCROLL_SIZE: usize = 10_000;
+
+// How many vectors a lookup by ids may ask for.
+const MAX_VECTOR_IDS: usize = 10_000;
+
+// The neighbours an outlier is scored by when a request doesn't say,
+// as for the `outliers` command.
+const DEFAULT_OUTLIER_NEIGHBORS: usize = 10;
+
+/// How long an index that is scrolled is kept after its last page.
+const SCROLL_KEEP_ALIVE: Duration = Duration::from_secs(300);
+
+// How many lines a streaming response may run ahead of the client.
+const STREAM_BUFFER: usize = 64;
+
+/// Respond with newline-delimited JSON produced by a blocking
+/// computation. Every line is sent to the client as soon as the
+/// producer hands it over. A failed send means the client has gone
+/// away, and the producer should stop.
+fn ndjson_response<F>(produce: F) -> Response<Body>
+where
+    F: FnOnce(tokio::sync::mpsc::Sender<Bytes>) + Send + 'static,
+{
+    let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    task::spawn_blocking(move || produce(sender));
+    let stream = ReceiverStream::new(receiver).map(Ok::<_, Infallible>);
+    Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::wrap_stream(stream))
+        .unwrap()
+}
+
+fn ndjson_line<T: Serialize>(item: &T) -> Bytes {
+    let mut line = serde_json::to_vec(item).unwrap();
+    line.push(b'\n');
+    line.into()
+}
+
 fn string_response_or_error(
     result: Result<String, ResponseError>,
 ) -> Result<Response<Body>, Infallible> {
     match result {
         Ok(task_id) => Ok(Response::builder().body(task_id.into()).unwrap()),
-        Err(e) => Ok(Response::builder()
-            .status(400)
-            .body(e.to_string().into())
-            .unwrap()),
+        Err(e) => Ok(e.into_response()),
+    }
+}
+
+fn empty_response_or_error(
+    result: Result<(), ResponseError>,
+) -> Result<Response<Body>, Infallible> {
+    match result {
+        Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+        Err(e) => Ok(e.into_response()),
     }
 }
 
@@ -818,10 +5176,7 @@ fn json_response_or_error(
             .header("Content-Type", "application/json")
             .body(task_id.into())
             .unwrap()),
-        Err(e) => Ok(Response::builder()
-            .status(400)
-            .body(e.to_string().into())
-            .unwrap()),
+        Err(e) => Ok(e.into_response()),
     }
 }
 
@@ -841,26 +5196,298 @@ pub async fn serve<P: Into<PathBuf>>(
     port: u16,
     num_bufs: usize,
     content_endpoint: Option<String>,
+    strict: bool,
+    preload: Vec<String>,
+    seed: Option<u64>,
+    config: Config,
+    config_path: Option<PathBuf>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+    let acceptor = config.tls.as_ref().map(tls::acceptor).transpose()?;
+    let directory: PathBuf = directory.into();
+    let mut vector_store = VectorStore::new(&directory, num_bufs);
+    if let Some(encryption) = &config.encryption {
+        vector_store = vector_store.encrypted(&encryption.key)?;
+    }
+    vector_store.check_key()?;
     let service = Arc::new(Service::new(
         directory,
         user_forward_header,
-        num_bufs,
+        vector_store,
         content_endpoint,
+        strict,
+        seed,
+        config,
+        config_path,
     ));
-    let make_svc = make_service_fn(move |_conn| {
-        let s = service.clone();
-        async {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                let s = s.clone();
-                async move { s.serve(req).await }
-            }))
+    service.create_configured_domains();
+    // Warm up in the background, so that liveness probes are answered
+    // while a large index loads. Readiness reports when this is done.
+    let warm_up_service = service.clone();
+    tokio::spawn(async move {
+        match warm_up_service.warm_up(&preload).await {
+            Ok(()) => warm_up_service.ready.store(true, Ordering::Release),
+            Err(e) => tracing::error!(error = ?e, "error while warming up indexes"),
+        }
+    });
+    if let Some(replication) = service.config().replication.clone() {
+        let follow_service = service.clone();
+        tokio::spawn(async move { follow_service.follow(replication).await });
+    }
+    let usage_service = service.clone();
+    let usage_interval = Duration::from_millis(service.config().usage_interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(usage_interval);
+        let trackers = [
+            usage_service.usage.clone(),
+            usage_service.job_usage.clone(),
+            usage_service.domain_usage.clone(),
+        ];
+        loop {
+            interval.tick().await;
+            usage_service.expire_jobs().await;
+            for usage in &trackers {
+                if let Err(e) = usage.persist() {
+                    tracing::warn!(error = %e, "could not write usage");
+                }
+            }
+        }
+    });
+    let reload_service = service.clone();
+    tokio::spawn(async move {
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                tracing::error!(error = ?e, "could not listen for SIGHUP");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if let Err(e) = reload_service.reload_config() {
+                tracing::error!(error = ?e, "could not reload configuration");
+            }
         }
     });
+    let watch_interval = service.config().config_watch_interval;
+    if let (Some(path), true) = (service.config_path.clone(), watch_interval > 0) {
+        let watch_service = service.clone();
+        tokio::spawn(async move {
+            watch_config(watch_service, path, Duration::from_millis(watch_interval)).await
+        });
+    }
+    if let Some(acceptor) = acceptor {
+        serve_tls(service.clone(), addr, acceptor).await?;
+    } else {
+        let make_service = service.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let s = make_service.clone();
+            async {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let s = s.clone();
+                    async move { s.serve(req).await }
+                }))
+            }
+        });
+
+        let server = Server::bind(&addr)
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown_signal());
+        server.await?;
+    }
+    service.shutdown().await;
+
+    Ok(())
+}
 
-    let server = Server::bind(&addr).serve(make_svc);
-    server.await?;
+/// Resolves on SIGTERM or ctrl-c. The server then stops accepting
+/// connections and finishes the requests it is working on.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("could not listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    tracing::info!("shutting down");
+}
+
+async fn serve_tls(
+    service: Arc<Service>,
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr).await?;
+    let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut signal => break,
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let mut shutdown = shutdown.clone();
+        // Handshakes happen on their own task, so that a slow client
+        // can't hold up accepting other connections.
+        connections.spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!(peer = %peer, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let svc = service_fn(move |req| {
+                let s = service.clone();
+                async move { s.serve(req).await }
+            });
+            let connection = Http::new().serve_connection(stream, svc);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.changed() => {
+                    // finish the request in flight, then close
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                tracing::warn!(peer = %peer, error = %e, "error serving connection");
+            }
+        });
+    }
+    let _ = shutdown_sender.send(true);
+    while connections.join_next().await.is_some() {}
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documented_paths_are_routed() {
+        let spec: serde_json::Value = serde_json::from_str(OPENAPI_SPEC).unwrap();
+        let paths = spec["paths"].as_object().unwrap();
+        assert!(!paths.is_empty());
+        for path in paths.keys() {
+            let concrete = path
+                .replace("{domain}", "admin%2Fstar_wars")
+                .replace("{id}", "People1")
+                .replace("{name}", "admin%2Fstar_wars.vecs");
+            let uri: Uri = concrete.parse().unwrap();
+            assert!(
+                !matches!(uri_to_spec(&uri), Err(SpecParseError::UnknownPath)),
+                "{path} is documented but not routed"
+            );
+        }
+        let unknown: Uri = "/undocumented".parse().unwrap();
+        assert!(matches!(
+            uri_to_spec(&unknown),
+            Err(SpecParseError::UnknownPath)
+        ));
+    }
+
+    fn tenant_service(directory: &Path) -> Service {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "api_keys": [
+                {"key": "acme-key", "tenant": "acme", "domains": ["admin/star_wars"]},
+                {"key": "other-key", "tenant": "other"},
+                {"key": "admin-key", "admin": true}
+            ],
+            "tenants": {"acme": {}, "other": {}}
+        }))
+        .unwrap();
+        Service::new(
+            directory,
+            "X-User-Forward".to_string(),
+            VectorStore::new(directory, 2),
+            None,
+            false,
+            None,
+            config,
+            None,
+        )
+    }
+
+    fn request(uri: &str, key: &str) -> (Request<Body>, ResourceSpec) {
+        let req = Request::builder()
+            .uri(uri)
+            .header(hyper::header::AUTHORIZATION, format!("Bearer {key}"))
+            .body(Body::empty())
+            .unwrap();
+        let spec = uri_to_spec(req.uri()).unwrap();
+        (req, spec)
+    }
+
+    #[test]
+    fn scope_requests_to_tenant() {
+        let (_, mut spec) = request("/domains/admin%2Fstar_wars/search", "acme-key");
+        spec.scope_to_tenant("acme");
+        assert_eq!(Some("acme::admin/star_wars"), spec.domain());
+        assert_eq!(Some("acme"), domain_tenant(spec.domain().unwrap()));
+
+        let (_, mut spec) = request(
+            "/admin/domains/admin%2Fcopy/derive?source=admin%2Fstar_wars",
+            "acme-key",
+        );
+        spec.scope_to_tenant("acme");
+        assert!(matches!(
+            &spec,
+            ResourceSpec::AdminDeriveDomain { domain, source }
+                if domain == "acme::admin/copy" && source == "acme::admin/star_wars"
+        ));
+
+        let (_, mut spec) = request("/admin/domains", "acme-key");
+        spec.scope_to_tenant("acme");
+        assert!(matches!(
+            &spec,
+            ResourceSpec::AdminListDomains { tenant: Some(tenant) } if tenant == "acme"
+        ));
+
+        assert_eq!(None, domain_tenant("admin/star_wars"));
+    }
+
+    #[test]
+    fn authorize_tenant_keys() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let service = tenant_service(tempdir.path());
+        let authorize = |uri: &str, key: &str| {
+            let (req, spec) = request(uri, key);
+            service.authorize(&req, Some(&spec))
+        };
+
+        // a tenant key's domains are the tenant's own names
+        let key = authorize("/domains/admin%2Fstar_wars/search", "acme-key")
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("acme"), key.tenant.as_deref());
+        assert!(matches!(
+            authorize("/domains/admin%2Fother/search", "acme-key"),
+            Err(AuthError::DomainNotPermitted(_))
+        ));
+
+        // shared routes are for keys without a tenant
+        for uri in ["/metrics", "/admin/config", "/admin/usage"] {
+            assert!(matches!(
+                authorize(uri, "other-key"),
+                Err(AuthError::AdminNotPermitted)
+            ));
+            assert!(authorize(uri, "admin-key").is_ok());
+        }
+
+        // jobs can only be looked up by their tenant
+        let job = generate_job_id("acme::admin/star_wars");
+        let uri = format!("/jobs/{job}");
+        assert!(authorize(&uri, "acme-key").is_ok());
+        assert!(matches!(
+            authorize(&uri, "other-key"),
+            Err(AuthError::JobNotPermitted(_))
+        ));
+        assert!(authorize(&uri, "admin-key").is_ok());
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SparseError {
+    #[error("a sparse vector has {indices} indices but {values} values")]
+    LengthMismatch { indices: usize, values: usize },
+    #[error("a sparse vector has dimension {0} more than once")]
+    DuplicateIndex(u32),
+    #[error("a sparse vector has a value that is not a number at dimension {0}")]
+    NotFinite(u32),
+}
+
+/// A vector with few nonzero dimensions out of very many, like the
+/// term weights of a learned sparse embedding such as SPLADE. Only the
+/// nonzero dimensions are kept, ordered by index.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "SparseParts")]
+pub struct SparseVector {
+    indices: Vec<u32>,
+    values: Vec<f32>,
+}
+
+// What a sparse vector is deserialized from, before it is checked.
+#[derive(Deserialize)]
+struct SparseParts {
+    indices: Vec<u32>,
+    values: Vec<f32>,
+}
+
+impl TryFrom<SparseParts> for SparseVector {
+    type Error = SparseError;
+
+    fn try_from(parts: SparseParts) -> Result<Self, SparseError> {
+        SparseVector::new(parts.indices, parts.values)
+    }
+}
+
+impl SparseVector {
+    /// Pair up indices and values, in any order. Zero values are
+    /// dropped.
+    pub fn new(indices: Vec<u32>, values: Vec<f32>) -> Result<Self, SparseError> {
+        if indices.len() != values.len() {
+            return Err(SparseError::LengthMismatch {
+                indices: indices.len(),
+                values: values.len(),
+            });
+        }
+        let mut pairs: Vec<(u32, f32)> = indices.into_iter().zip(values).collect();
+        pairs.sort_by_key(|(index, _)| *index);
+        for window in pairs.windows(2) {
+            if window[0].0 == window[1].0 {
+                return Err(SparseError::DuplicateIndex(window[0].0));
+            }
+        }
+        if let Some((index, _)) = pairs.iter().find(|(_, value)| !value.is_finite()) {
+            return Err(SparseError::NotFinite(*index));
+        }
+        let (indices, values) = pairs.into_iter().filter(|(_, value)| *value != 0.0).unzip();
+        Ok(SparseVector { indices, values })
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// The nonzero dimensions with their values, by index.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        self.indices
+            .iter()
+            .copied()
+            .zip(self.values.iter().copied())
+    }
+
+    /// The dot product, over the dimensions both vectors have.
+    pub fn dot(&self, other: &SparseVector) -> f32 {
+        let (mut i, mut j) = (0, 0);
+        let mut sum = 0.0;
+        while i < self.indices.len() && j < other.indices.len() {
+            match self.indices[i].cmp(&other.indices[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    sum += self.values[i] * other.values[j];
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        sum
+    }
+}
+
+/// An inverted index over the sparse vectors of the points of an
+/// index, for scoring them all against a query by dot product. Points
+/// are known by their position in the index, like the nodes of the
+/// HNSW, and points without a sparse vector never match.
+pub struct SparseIndex {
+    // the points that have each dimension, with their value for it
+    postings: HashMap<u32, Vec<(usize, f32)>>,
+    len: usize,
+}
+
+impl SparseIndex {
+    pub fn build<'a>(vectors: impl Iterator<Item = Option<&'a SparseVector>>) -> Self {
+        let mut postings: HashMap<u32, Vec<(usize, f32)>> = HashMap::new();
+        let mut len = 0;
+        for (point, vector) in vectors.enumerate() {
+            len = point + 1;
+            for (index, value) in vector.into_iter().flat_map(|v| v.iter()) {
+                postings.entry(index).or_default().push((point, value));
+            }
+        }
+        SparseIndex { postings, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The `k` points with the highest dot product with `query`, with
+    /// their score, best first. Points that `keep` refuses are left
+    /// out, and so are points sharing no dimension with the query or
+    /// scoring zero or less.
+    pub fn search(
+        &self,
+        query: &SparseVector,
+        k: usize,
+        keep: impl Fn(usize) -> bool,
+    ) -> Vec<(usize, f32)> {
+        let mut scores: HashMap<usize, f32> = HashMap::new();
+        for (index, weight) in query.iter() {
+            let Some(postings) = self.postings.get(&index) else {
+                continue;
+            };
+            for (point, value) in postings {
+                *scores.entry(*point).or_default() += weight * value;
+            }
+        }
+        let mut scores: Vec<(usize, f32)> = scores
+            .into_iter()
+            .filter(|(point, score)| *score > 0.0 && keep(*point))
+            .collect();
+        scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        scores.truncate(k);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_and_multiply() {
+        let a = SparseVector::new(vec![7, 1, 1000], vec![0.5, 2.0, 1.0]).unwrap();
+        assert_eq!(
+            vec![(1, 2.0), (7, 0.5), (1000, 1.0)],
+            a.iter().collect::<Vec<_>>()
+        );
+        let b = SparseVector::new(vec![1000, 3, 7, 9], vec![2.0, 4.0, 2.0, 0.0]).unwrap();
+        assert_eq!(3, b.len());
+        assert_eq!(3.0, a.dot(&b));
+        assert_eq!(a.dot(&b), b.dot(&a));
+
+        assert_eq!(
+            Err(SparseError::LengthMismatch {
+                indices: 2,
+                values: 1
+            }),
+            SparseVector::new(vec![1, 2], vec![1.0])
+        );
+        assert_eq!(
+            Err(SparseError::DuplicateIndex(2)),
+            SparseVector::new(vec![2, 1, 2], vec![1.0, 1.0, 1.0])
+        );
+        assert_eq!(
+            Err(SparseError::NotFinite(1)),
+            SparseVector::new(vec![1], vec![f32::NAN])
+        );
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(r#"{"indices":[1,7,1000],"values":[2.0,0.5,1.0]}"#, json);
+        assert_eq!(a, serde_json::from_str(&json).unwrap());
+        assert!(serde_json::from_str::<SparseVector>(r#"{"indices":[1],"values":[]}"#).is_err());
+    }
+
+    #[test]
+    fn rank_by_dot_product() {
+        let vectors = [
+            Some(SparseVector::new(vec![1, 2], vec![1.0, 1.0]).unwrap()),
+            None,
+            Some(SparseVector::new(vec![2, 3], vec![3.0, 1.0]).unwrap()),
+            Some(SparseVector::new(vec![4], vec![5.0]).unwrap()),
+        ];
+        let index = SparseIndex::build(vectors.iter().map(Option::as_ref));
+        assert_eq!(4, index.len());
+
+        let query = SparseVector::new(vec![1, 2], vec![2.0, 1.0]).unwrap();
+        assert_eq!(vec![(0, 3.0), (2, 3.0)], index.search(&query, 10, |_| true));
+        assert_eq!(vec![(0, 3.0)], index.search(&query, 1, |_| true));
+        assert_eq!(vec![(2, 3.0)], index.search(&query, 10, |p| p != 0));
+    }
+}
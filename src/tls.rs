@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+fn load_certificates(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+    if certificates.is_empty() {
+        return Err(invalid_data(format!("no certificates found in {path}")));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid_data(format!("no private key found in {path}")))
+}
+
+/// Build a TLS acceptor from the configured certificate chain and key.
+/// If a client CA is configured, clients must present a certificate
+/// signed by it.
+pub fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let certificates = load_certificates(&config.cert)?;
+    let key = load_private_key(&config.key)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca)? {
+                roots.add(&certificate).map_err(invalid_data)?;
+            }
+            builder.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(roots)))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(certificates, key)
+        .map_err(invalid_data)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Unpoisoned;
+
+/// Usage of a single account.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Usage {
+    pub queries: u64,
+    /// Vectors stored, whether uploaded or embedded by an index job.
+    pub vectors: u64,
+    pub embedding_tokens: u64,
+    /// Estimated dollars paid for the embedding tokens.
+    #[serde(default)]
+    pub embedding_cost: f64,
+}
+
+/// Usage per account, kept in a file so that it survives restarts.
+pub struct UsageTracker {
+    path: PathBuf,
+    usage: Mutex<HashMap<String, Usage>>,
+    /// Whether anything was recorded since the last write.
+    dirty: AtomicBool,
+}
+
+impl UsageTracker {
+    /// Start from zero, writing usage to `path`.
+    pub fn new(path: PathBuf) -> Self {
+        UsageTracker {
+            path,
+            usage: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Continue from the usage in `path`, if there is any.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let usage = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(UsageTracker {
+            path,
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn record(&self, account: &str, update: impl FnOnce(&mut Usage)) {
+        let mut usage = self.usage.lock().unpoisoned();
+        update(usage.entry(account.to_string()).or_default());
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Forget the usage of an account that no longer exists.
+    pub fn remove(&self, account: &str) {
+        if self.usage.lock().unpoisoned().remove(account).is_some() {
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Keep only the usage of the accounts for which `keep` is true.
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) {
+        let mut usage = self.usage.lock().unpoisoned();
+        let before = usage.len();
+        usage.retain(|account, _| keep(account));
+        if usage.len() != before {
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Usage> {
+        self.usage.lock().unpoisoned().get(key).cloned()
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, Usage> {
+        self.usage.lock().unpoisoned().clone()
+    }
+
+    /// Write the usage to disk, if it changed since the last write.
+    pub fn persist(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let bytes = serde_json::to_vec(&*self.usage.lock().unpoisoned())?;
+        let mut tmp_path = self.path.clone();
+        tmp_path.set_extension("json.tmp");
+        let result =
+            std::fs::write(&tmp_path, bytes).and_then(|()| std::fs::rename(&tmp_path, &self.path));
+        if result.is_err() {
+            // try again next time
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+}
+
+#[derive(Clone)]
+struct Charge {
+    tracker: Arc<UsageTracker>,
+    account: String,
+}
+
+tokio::task_local! {
+    static CHARGES: Vec<Charge>;
+}
+
+/// Run `work`, charging what it uses to `account`, on top of whatever
+/// the surrounding work is charged to. Jobs are charged to their
+/// account, but also to the job and to the domain they fill.
+pub async fn charge_to<F: Future>(
+    tracker: Arc<UsageTracker>,
+    account: String,
+    work: F,
+) -> F::Output {
+    let mut charges = CHARGES
+        .try_with(|charges| charges.clone())
+        .unwrap_or_default();
+    charges.push(Charge { tracker, account });
+    CHARGES.scope(charges, work).await
+}
+
+/// The account the current work is charged to first, if any.
+pub fn current_account() -> Option<(Arc<UsageTracker>, String)> {
+    CHARGES
+        .try_with(|charges| {
+            charges
+                .first()
+                .map(|charge| (charge.tracker.clone(), charge.account.clone()))
+        })
+        .ok()
+        .flatten()
+}
+
+/// Record usage against everything the current work is charged to.
+/// Work that isn't charged to an account, such as warming up, is not
+/// recorded.
+pub fn charge(update: impl Fn(&mut Usage)) {
+    let _ = CHARGES.try_with(|charges| {
+        for charge in charges {
+            charge.tracker.record(&charge.account, &update);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_reload() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("usage.json");
+        let tracker = UsageTracker::load(path.clone()).unwrap();
+        assert!(tracker.snapshot().is_empty());
+        tracker.record("team-a", |usage| usage.queries += 2);
+        tracker.record("team-a", |usage| usage.embedding_tokens += 100);
+        tracker.record("team-b", |usage| usage.vectors += 10);
+        tracker.persist().unwrap();
+
+        let reloaded = UsageTracker::load(path).unwrap();
+        assert_eq!(tracker.snapshot(), reloaded.snapshot());
+        assert_eq!(
+            Usage {
+                queries: 2,
+                vectors: 0,
+                embedding_tokens: 100,
+                embedding_cost: 0.0,
+            },
+            reloaded.snapshot()["team-a"]
+        );
+    }
+
+    #[test]
+    fn forget_accounts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("usage.json");
+        let tracker = UsageTracker::new(path.clone());
+        for account in ["job-1", "job-2", "job-3"] {
+            tracker.record(account, |usage| usage.vectors += 1);
+        }
+        tracker.persist().unwrap();
+        tracker.remove("job-1");
+        tracker.remove("job-4");
+        tracker.retain(|account| account != "job-2");
+        tracker.persist().unwrap();
+
+        let reloaded = UsageTracker::load(path).unwrap();
+        assert_eq!(
+            vec!["job-3"],
+            reloaded.snapshot().into_keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn nested_charges() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let accounts = Arc::new(UsageTracker::new(tempdir.path().join("usage.json")));
+        let jobs = Arc::new(UsageTracker::new(tempdir.path().join("job_usage.json")));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(charge_to(accounts.clone(), "team-a".to_string(), async {
+            charge(|usage| usage.queries += 1);
+            charge_to(jobs.clone(), "job-1".to_string(), async {
+                charge(|usage| usage.embedding_tokens += 10);
+                assert_eq!("team-a", current_account().unwrap().1);
+            })
+            .await;
+        }));
+        assert_eq!(1, accounts.get("team-a").unwrap().queries);
+        assert_eq!(10, accounts.get("team-a").unwrap().embedding_tokens);
+        assert_eq!(0, jobs.get("job-1").unwrap().queries);
+        assert_eq!(10, jobs.get("job-1").unwrap().embedding_tokens);
+    }
+}
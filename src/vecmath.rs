@@ -10,6 +10,18 @@ pub fn empty_embedding() -> Embedding {
     [0.0; EMBEDDING_LENGTH]
 }
 
+/// Read an embedding from little-endian f32 bytes, independent of the
+/// byte order of this machine.
+pub fn embedding_from_le_bytes(bytes: &[u8]) -> Embedding {
+    assert_eq!(EMBEDDING_BYTE_LENGTH, bytes.len());
+    let mut embedding = [0.0; EMBEDDING_LENGTH];
+    for (f, b) in embedding.iter_mut().zip(bytes.chunks_exact(4)) {
+        *f = f32::from_le_bytes(b.try_into().unwrap());
+    }
+
+    embedding
+}
+
 pub fn random_embedding<R: Rng>(rng: &mut R) -> Embedding {
     let mut embedding = [0.0; EMBEDDING_LENGTH];
     rng.fill(&mut embedding[..]);
@@ -53,9 +65,11 @@ pub fn normalized_cosine_distance_simd(left: &Embedding, right: &Embedding) -> f
     simd::normalized_cosine_distance_simd(left, right)
 }
 
+/// Without the `simd` feature, which needs a nightly compiler, this
+/// is the scalar distance.
 #[cfg(not(feature = "simd"))]
 pub fn normalized_cosine_distance_simd(left: &Embedding, right: &Embedding) -> f32 {
-    unimplemented!("simd support is not enabled");
+    normalized_cosine_distance_scalar(left, right)
 }
 
 pub fn normalize_vec_scalar(vec: &mut Embedding) {
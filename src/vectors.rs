@@ -1,9 +1,9 @@
 #![allow(unused)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::mem::MaybeUninit;
 use std::ops::Deref;
 use std::os::unix::prelude::FileExt;
@@ -11,10 +11,22 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{self, AtomicUsize};
 use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
 
+use lazy_static::lazy_static;
 use lru::LruCache;
-use serde::Serialize;
-use urlencoding::encode;
-
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use urlencoding::{decode, encode};
+
+use crate::bitmap::{BitmapIndex, Candidates};
+use crate::encryption::{self, Cipher, SEAL_OVERHEAD};
+use crate::error::{self, Checked, IoContext, Unpoisoned, VectorlinkError};
+use crate::filter::Filter;
+use crate::kmeans;
+use crate::metrics::{self, Counter, Gauge};
+use crate::openai::EmbeddingModel;
+use crate::progress::Progress;
+use crate::secret::Secret;
+use crate::sparse::SparseVector;
 use crate::vecmath::{Embedding, EmbeddingBytes, EMBEDDING_BYTE_LENGTH, EMBEDDING_LENGTH};
 
 // 3 memory pages of 4K hold 2 OpenAI vectors.
@@ -40,58 +52,680 @@ struct PinnedVectorPage {
 pub struct Domain {
     name: Arc<String>,
     index: usize,
+    dir: PathBuf,
     read_file: File,
     write_file: Mutex<File>,
     num_vecs: AtomicUsize,
+    documents: RwLock<HashMap<usize, String>>,
+    documents_file: Mutex<Log>,
+    metadata: RwLock<HashMap<usize, serde_json::Value>>,
+    metadata_file: Mutex<Log>,
+    /// Bitmaps of the metadata fields that filters should be fast on.
+    /// Always taken after `metadata`.
+    bitmaps: RwLock<BitmapIndex>,
+    sparse: RwLock<HashMap<usize, SparseVector>>,
+    sparse_file: Mutex<Log>,
+    tombstones: RwLock<HashSet<usize>>,
+    tombstones_file: Mutex<Log>,
+    embedding_model: RwLock<Option<ModelEntry>>,
+    model_file: Mutex<Log>,
+    /// Seals every vector and line of the files when the store is
+    /// encrypted.
+    cipher: Option<Arc<Cipher>>,
+}
+
+/// A line in a domain's document file, recording that a vector is a
+/// chunk of a larger document.
+#[derive(Serialize, Deserialize)]
+struct DocumentEntry {
+    vector: usize,
+    document: String,
+}
+
+/// A line in a domain's metadata file, holding the metadata that a
+/// vector was uploaded with.
+#[derive(Serialize, Deserialize)]
+struct MetadataEntry {
+    vector: usize,
+    metadata: serde_json::Value,
+}
+
+/// A line in a domain's sparse file, holding the sparse vector that
+/// was uploaded next to a vector.
+#[derive(Serialize, Deserialize)]
+struct SparseEntry {
+    vector: usize,
+    sparse: SparseVector,
+}
+
+/// A line in a domain's tombstone file, recording that a vector was
+/// deleted.
+#[derive(Serialize, Deserialize)]
+struct TombstoneEntry {
+    vector: usize,
+}
+
+/// A line in a domain's model file, recording the model that texts
+/// are embedded with, and the dimension of its embeddings. The last
+/// line counts.
+#[derive(Serialize, Deserialize, Clone)]
+struct ModelEntry {
+    model: EmbeddingModel,
+    /// Missing from lines written before dimensions were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dimension: Option<usize>,
+}
+
+/// How a domain is clustered.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterParams {
+    /// Passes of k-means over the vectors.
+    #[serde(default = "default_cluster_iterations")]
+    pub iterations: usize,
+    /// Seed for picking the initial centroids, which are random
+    /// otherwise.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+fn default_cluster_iterations() -> usize {
+    10
+}
+
+impl Default for ClusterParams {
+    fn default() -> Self {
+        ClusterParams {
+            iterations: default_cluster_iterations(),
+            seed: None,
+        }
+    }
+}
+
+/// The clusters of the vectors of a domain, as they were when the
+/// domain was clustered.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Clustering {
+    pub params: ClusterParams,
+    #[serde(with = "embeddings")]
+    pub centroids: Vec<Embedding>,
+    /// The cluster of every vector, by vector id. Vectors that were
+    /// deleted have none.
+    pub assignments: Vec<Option<u32>>,
+}
+
+impl Clustering {
+    /// The number of vectors in every cluster.
+    pub fn sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![0; self.centroids.len()];
+        for cluster in self.assignments.iter().flatten() {
+            sizes[*cluster as usize] += 1;
+        }
+        sizes
+    }
+}
+
+/// Embeddings are longer than serde serializes arrays, so they go as
+/// sequences.
+mod embeddings {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::vecmath::Embedding;
+
+    pub fn serialize<S: Serializer>(vecs: &[Embedding], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(vecs.iter().map(|vec| &vec[..]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Embedding>, D::Error> {
+        Vec::<Vec<f32>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|vec| {
+                vec.try_into().map_err(|v: Vec<f32>| {
+                    serde::de::Error::invalid_length(v.len(), &"a vector of the embedding length")
+                })
+            })
+            .collect()
+    }
+}
+
+// Vectors read at once when going over all vectors of a domain.
+const SCAN_VECTORS: usize = 1024;
+
+/// What the sealed contents of a domain file are bound to: the name
+/// of the file, which names the domain.
+fn file_aad(name: &str, extension: &str) -> Vec<u8> {
+    format!("{}.{extension}", encode(name)).into_bytes()
+}
+
+/// What a sealed vector or line is bound to: its file, and its place
+/// in the file, which is the vector id or the line number. A vector
+/// or line that is moved to another domain, file or place is refused
+/// rather than read.
+fn sealed_aad(name: &str, extension: &str, place: usize) -> Vec<u8> {
+    let mut aad = file_aad(name, extension);
+    aad.extend_from_slice(&(place as u64).to_le_bytes());
+    aad
+}
+
+/// One of the append-only JSON line files of a domain, with the
+/// number of lines in it, which the next sealed line is bound to.
+struct Log {
+    file: File,
+    lines: usize,
+}
+
+impl Log {
+    /// Append an entry, as a sealed line when there is a cipher.
+    fn write_entry<T: Serialize>(
+        &mut self,
+        name: &str,
+        extension: &str,
+        cipher: Option<&Cipher>,
+        entry: &T,
+    ) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        if let Some(cipher) = cipher {
+            line = cipher
+                .seal_line(&line, &sealed_aad(name, extension, self.lines))
+                .into_bytes();
+        }
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.lines += 1;
+        Ok(())
+    }
+}
+
+/// Open one of the append-only JSON line files of a domain, reading
+/// the entries it holds so far.
+fn open_log<T: serde::de::DeserializeOwned>(
+    dir: &Path,
+    name: &str,
+    extension: &str,
+    cipher: Option<&Cipher>,
+) -> error::Result<(Log, Vec<T>)> {
+    let path = domain_file_path(dir, name, extension);
+    let file = File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(&path)
+        .context("open", &path)?;
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(&file).lines().enumerate() {
+        let line = line.context("read", &path)?;
+        let entry = match cipher {
+            None => serde_json::from_str(&line).map_err(|e| e.to_string()),
+            Some(cipher) => cipher
+                .open_line(&line, &sealed_aad(name, extension, number))
+                .map_err(|e| e.to_string())
+                .and_then(|line| serde_json::from_slice(&line).map_err(|e| e.to_string())),
+        }
+        .map_err(|reason| VectorlinkError::Corrupt {
+            path: path.clone(),
+            reason: format!("line {}: {reason}", number + 1),
+        })?;
+        entries.push(entry);
+    }
+    let lines = entries.len();
+    Ok((Log { file, lines }, entries))
+}
+
+/// Copy a sealed domain file to the same file of another domain,
+/// sealing every vector or line again for its new place.
+fn reseal_file(
+    cipher: &Cipher,
+    extension: &str,
+    (source, source_name): (&Path, &str),
+    (target, target_name): (&Path, &str),
+) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut writer = io::BufWriter::new(File::create(target)?);
+    if extension == "vecs" {
+        let mut sealed = vec![0; EMBEDDING_BYTE_LENGTH + SEAL_OVERHEAD];
+        let count = std::fs::metadata(source)?.len() as usize / sealed.len();
+        for vector in 0..count {
+            reader.read_exact(&mut sealed)?;
+            let plain = cipher.open(&sealed, &sealed_aad(source_name, extension, vector))?;
+            writer.write_all(&cipher.seal(&plain, &sealed_aad(target_name, extension, vector)))?;
+        }
+    } else {
+        for (number, line) in reader.lines().enumerate() {
+            let plain = cipher.open_line(&line?, &sealed_aad(source_name, extension, number))?;
+            let line = cipher.seal_line(&plain, &sealed_aad(target_name, extension, number));
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// The length of a vector in the vector file. Sealed vectors are
+/// longer by the nonce and tag.
+fn vector_record_length(cipher: Option<&Cipher>) -> usize {
+    match cipher {
+        None => EMBEDDING_BYTE_LENGTH,
+        Some(_) => EMBEDDING_BYTE_LENGTH + SEAL_OVERHEAD,
+    }
 }
 
 impl Domain {
-    fn open(dir: &Path, name: &str, index: usize) -> io::Result<Self> {
-        let mut path = dir.to_path_buf();
-        let name = encode(name);
-        path.push(format!("{name}.vecs"));
+    fn open(
+        dir: &Path,
+        name: &str,
+        index: usize,
+        cipher: Option<Arc<Cipher>>,
+    ) -> error::Result<Self> {
+        let path = domain_file_path(dir, name, "vecs");
         let mut write_file = File::options()
             .read(true)
             .write(true)
             .create(true)
             .truncate(false)
-            .open(dbg!(&path))?;
-        let pos = write_file.seek(SeekFrom::End(0))?;
-        if pos as usize % EMBEDDING_BYTE_LENGTH != 0 {
-            panic!("domain {name} has unexpected length");
+            .open(&path)
+            .context("open", &path)?;
+        let pos = write_file
+            .seek(SeekFrom::End(0))
+            .context("seek to the end of", &path)?;
+        let record_length = vector_record_length(cipher.as_deref());
+        if pos as usize % record_length != 0 {
+            return Err(VectorlinkError::Corrupt {
+                path,
+                reason: format!("its length {pos} is not a whole number of vectors"),
+            });
         }
-        let num_vecs = AtomicUsize::new(pos as usize / EMBEDDING_BYTE_LENGTH);
+        let num_vecs = AtomicUsize::new(pos as usize / record_length);
         let write_file = Mutex::new(write_file);
         let read_file = File::options()
             .read(true)
             .write(false)
             .create(false)
             .truncate(false)
-            .open(path)?;
+            .open(&path)
+            .context("open", &path)?;
+
+        let (documents_file, entries) =
+            open_log::<DocumentEntry>(dir, name, "docs", cipher.as_deref())?;
+        let documents = entries
+            .into_iter()
+            .map(|entry| (entry.vector, entry.document))
+            .collect();
+
+        let (metadata_file, entries) =
+            open_log::<MetadataEntry>(dir, name, "meta", cipher.as_deref())?;
+        let metadata = entries
+            .into_iter()
+            .map(|entry| (entry.vector, entry.metadata))
+            .collect();
+
+        let (sparse_file, entries) =
+            open_log::<SparseEntry>(dir, name, "sparse", cipher.as_deref())?;
+        let sparse = entries
+            .into_iter()
+            .map(|entry| (entry.vector, entry.sparse))
+            .collect();
+
+        let (tombstones_file, entries) =
+            open_log::<TombstoneEntry>(dir, name, "tomb", cipher.as_deref())?;
+        let tombstones = entries.into_iter().map(|entry| entry.vector).collect();
+
+        let (model_file, entries) = open_log::<ModelEntry>(dir, name, "model", cipher.as_deref())?;
+        let embedding_model = entries.into_iter().last();
 
         Ok(Domain {
             name: Arc::new(name.to_string()),
             index,
+            dir: dir.to_path_buf(),
             read_file,
             write_file,
             num_vecs,
+            documents: RwLock::new(documents),
+            documents_file: Mutex::new(documents_file),
+            metadata: RwLock::new(metadata),
+            metadata_file: Mutex::new(metadata_file),
+            bitmaps: RwLock::new(BitmapIndex::default()),
+            sparse: RwLock::new(sparse),
+            sparse_file: Mutex::new(sparse_file),
+            tombstones: RwLock::new(tombstones),
+            tombstones_file: Mutex::new(tombstones_file),
+            embedding_model: RwLock::new(embedding_model),
+            model_file: Mutex::new(model_file),
+            cipher,
         })
     }
 
+    /// Record that the given vectors are chunks of the given documents.
+    pub fn add_documents(&self, entries: &[(usize, String)]) -> io::Result<()> {
+        let mut documents_file = self.documents_file.checked("documents")?;
+        for (vector, document) in entries {
+            let entry = DocumentEntry {
+                vector: *vector,
+                document: document.clone(),
+            };
+            documents_file.write_entry(&self.name, "docs", self.cipher.as_deref(), &entry)?;
+        }
+        documents_file.file.flush()?;
+        documents_file.file.sync_data()?;
+        let mut documents = self.documents.write().unpoisoned();
+        documents.extend(entries.iter().cloned());
+
+        Ok(())
+    }
+
+    /// The document the given vector is a chunk of, if any.
+    pub fn document(&self, vector: usize) -> Option<String> {
+        self.documents.read().unpoisoned().get(&vector).cloned()
+    }
+
+    /// Record the metadata of the given vectors, for filtering.
+    pub fn add_metadata(&self, entries: &[(usize, serde_json::Value)]) -> io::Result<()> {
+        let mut metadata_file = self.metadata_file.checked("metadata")?;
+        for (vector, metadata) in entries {
+            let entry = MetadataEntry {
+                vector: *vector,
+                metadata: metadata.clone(),
+            };
+            metadata_file.write_entry(&self.name, "meta", self.cipher.as_deref(), &entry)?;
+        }
+        metadata_file.file.flush()?;
+        metadata_file.file.sync_data()?;
+        let mut metadata = self.metadata.write().unpoisoned();
+        let mut bitmaps = self.bitmaps.write().unpoisoned();
+        for (vector, m) in entries {
+            if let Some(old) = metadata.insert(*vector, m.clone()) {
+                bitmaps.remove(*vector, &old);
+            }
+            bitmaps.insert(*vector, m);
+        }
+
+        Ok(())
+    }
+
+    /// Keep bitmaps of these metadata fields from now on, replacing
+    /// those of other fields. The bitmaps are built from the metadata
+    /// recorded so far when the fields change, and kept up to date as
+    /// metadata is added.
+    pub fn index_fields(&self, fields: &[String]) {
+        if self.bitmaps.read().unpoisoned().has_fields(fields) {
+            return;
+        }
+        let metadata = self.metadata.read().unpoisoned();
+        let mut bitmaps = self.bitmaps.write().unpoisoned();
+        if bitmaps.has_fields(fields) {
+            return;
+        }
+        let _span = tracing::info_span!("index_fields", domain = %self.name, fields = fields.len())
+            .entered();
+        *bitmaps = BitmapIndex::new(fields);
+        for (vector, m) in metadata.iter() {
+            bitmaps.insert(*vector, m);
+        }
+    }
+
+    /// The vectors that may match a filter, as far as the bitmaps of
+    /// the indexed fields tell, see [`BitmapIndex::candidates`].
+    pub fn filter_candidates(&self, filter: &Filter) -> Option<Candidates> {
+        self.bitmaps
+            .read()
+            .unpoisoned()
+            .candidates(filter, self.num_vecs())
+    }
+
+    /// The metadata the given vector was uploaded with, if any.
+    pub fn metadata(&self, vector: usize) -> Option<serde_json::Value> {
+        self.metadata.read().unpoisoned().get(&vector).cloned()
+    }
+
+    /// Record the sparse vectors that go with the given vectors, for
+    /// hybrid searches.
+    pub fn add_sparse_vectors(&self, entries: &[(usize, SparseVector)]) -> io::Result<()> {
+        let mut sparse_file = self.sparse_file.checked("sparse vectors")?;
+        for (vector, sparse) in entries {
+            let entry = SparseEntry {
+                vector: *vector,
+                sparse: sparse.clone(),
+            };
+            sparse_file.write_entry(&self.name, "sparse", self.cipher.as_deref(), &entry)?;
+        }
+        sparse_file.file.flush()?;
+        sparse_file.file.sync_data()?;
+        let mut sparse = self.sparse.write().unpoisoned();
+        sparse.extend(entries.iter().cloned());
+
+        Ok(())
+    }
+
+    /// The sparse vector the given vector was uploaded with, if any.
+    pub fn sparse_vector(&self, vector: usize) -> Option<SparseVector> {
+        self.sparse.read().unpoisoned().get(&vector).cloned()
+    }
+
+    /// Read the vectors starting at `start` into `vecs`.
+    fn read_vecs(&self, start: usize, vecs: &mut [Embedding]) -> io::Result<()> {
+        let bytes: &mut [u8] = unsafe {
+            std::slice::from_raw_parts_mut(
+                vecs.as_mut_ptr() as *mut u8,
+                vecs.len() * EMBEDDING_BYTE_LENGTH,
+            )
+        };
+        self.read_vec_bytes(start, bytes)
+    }
+
+    /// Read the bytes of the vectors starting at `start` into `bytes`,
+    /// which holds a whole number of vectors. Sealed vectors are
+    /// opened one by one, so any vector can be read on its own.
+    fn read_vec_bytes(&self, start: usize, bytes: &mut [u8]) -> io::Result<()> {
+        let Some(cipher) = &self.cipher else {
+            self.read_file
+                .read_exact_at(bytes, (start * EMBEDDING_BYTE_LENGTH) as u64)?;
+            BYTES_READ.add(bytes.len() as u64);
+            return Ok(());
+        };
+        let record_length = EMBEDDING_BYTE_LENGTH + SEAL_OVERHEAD;
+        let mut sealed = vec![0; bytes.len() / EMBEDDING_BYTE_LENGTH * record_length];
+        self.read_file
+            .read_exact_at(&mut sealed, (start * record_length) as u64)?;
+        BYTES_READ.add(sealed.len() as u64);
+        for (i, (plain, sealed)) in bytes
+            .chunks_exact_mut(EMBEDDING_BYTE_LENGTH)
+            .zip(sealed.chunks_exact(record_length))
+            .enumerate()
+        {
+            plain
+                .copy_from_slice(&cipher.open(sealed, &sealed_aad(&self.name, "vecs", start + i))?);
+        }
+        Ok(())
+    }
+
+    /// Go over the first `num_vecs` vectors that are not deleted,
+    /// handing them to `f` in batches along with their ids. They are
+    /// read straight from the file, without going through the pages.
+    pub fn scan_vecs(
+        &self,
+        num_vecs: usize,
+        mut f: impl FnMut(&[usize], &[&Embedding]),
+    ) -> error::Result<()> {
+        let mut batch = vec![crate::vecmath::empty_embedding(); SCAN_VECTORS];
+        for start in (0..num_vecs).step_by(SCAN_VECTORS) {
+            let len = SCAN_VECTORS.min(num_vecs - start);
+            self.read_vecs(start, &mut batch[..len])
+                .context("read", &domain_file_path(&self.dir, &self.name, "vecs"))?;
+            let (ids, vecs): (Vec<usize>, Vec<&Embedding>) = batch[..len]
+                .iter()
+                .enumerate()
+                .map(|(i, vec)| (start + i, vec))
+                .filter(|(id, _)| !self.is_deleted(*id))
+                .unzip();
+            f(&ids, &vecs);
+        }
+        Ok(())
+    }
+
+    /// Cluster the vectors of this domain into `k` clusters with
+    /// spherical k-means, reading them from disk on every pass. The
+    /// clustering is saved with the domain, replacing the previous
+    /// one, and can be had again with `clustering`.
+    pub fn cluster(
+        &self,
+        k: usize,
+        params: &ClusterParams,
+        progress: &dyn Progress,
+    ) -> error::Result<Clustering> {
+        let path = domain_file_path(&self.dir, &self.name, "clusters");
+        let num_vecs = self.num_vecs();
+        let live: Vec<usize> = (0..num_vecs).filter(|v| !self.is_deleted(*v)).collect();
+        let k = k.min(live.len());
+        let _span =
+            tracing::info_span!("cluster_domain", domain = %self.name, vectors = live.len(), k)
+                .entered();
+        let picked = match params.seed {
+            Some(seed) => rand::seq::index::sample(&mut StdRng::seed_from_u64(seed), live.len(), k),
+            None => rand::seq::index::sample(&mut rand::thread_rng(), live.len(), k),
+        };
+        let mut centroids = vec![crate::vecmath::empty_embedding(); k];
+        for (centroid, i) in centroids.iter_mut().zip(picked) {
+            self.read_vecs(live[i], std::slice::from_mut(centroid))
+                .context("read", &domain_file_path(&self.dir, &self.name, "vecs"))?;
+        }
+
+        progress.stage("clustering", Some(params.iterations as u64));
+        let centroids = kmeans::train(centroids, params.iterations, progress, |f| {
+            self.scan_vecs(num_vecs, |_, vecs| f(vecs))
+        })?;
+        let mut assignments = vec![None; num_vecs];
+        if !centroids.is_empty() {
+            progress.stage("assigning", Some(live.len() as u64));
+            self.scan_vecs(num_vecs, |ids, vecs| {
+                for (id, vec) in ids.iter().zip(vecs) {
+                    let (cluster, _) = kmeans::nearest_centroid(&centroids, vec);
+                    assignments[*id] = Some(cluster as u32);
+                }
+                progress.advance(ids.len() as u64);
+            })?;
+        }
+        let clustering = Clustering {
+            params: params.clone(),
+            centroids,
+            assignments,
+        };
+
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("clusters.tmp");
+        let file = File::create(&tmp_path).context("create", &tmp_path)?;
+        let mut writer = io::BufWriter::new(file);
+        let aad = file_aad(&self.name, "clusters");
+        encryption::write_json(self.cipher.as_deref(), &aad, &mut writer, &clustering)
+            .context("write", &tmp_path)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .context("write", &tmp_path)?;
+        std::fs::rename(&tmp_path, &path).context("rename", &tmp_path)?;
+        progress.finish();
+
+        Ok(clustering)
+    }
+
+    /// The clustering saved by the last `cluster`, if any.
+    pub fn clustering(&self) -> error::Result<Option<Clustering>> {
+        let path = domain_file_path(&self.dir, &self.name, "clusters");
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("open", &path),
+        };
+        let aad = file_aad(&self.name, "clusters");
+        encryption::read_json(self.cipher.as_deref(), &aad, BufReader::new(file))
+            .map(Some)
+            .map_err(|e| VectorlinkError::Corrupt {
+                path,
+                reason: e.to_string(),
+            })
+    }
+
+    /// Mark the given vectors as deleted. Returns how many of them
+    /// weren't deleted already.
+    pub fn add_tombstones(&self, vectors: &[usize]) -> io::Result<usize> {
+        let mut tombstones_file = self.tombstones_file.checked("tombstones")?;
+        let mut new = Vec::new();
+        for vector in vectors {
+            if !self.is_deleted(*vector) && !new.contains(vector) {
+                new.push(*vector);
+            }
+        }
+        for vector in &new {
+            let entry = TombstoneEntry { vector: *vector };
+            tombstones_file.write_entry(&self.name, "tomb", self.cipher.as_deref(), &entry)?;
+        }
+        tombstones_file.file.flush()?;
+        tombstones_file.file.sync_data()?;
+        self.tombstones
+            .write()
+            .unpoisoned()
+            .extend(new.iter().copied());
+
+        Ok(new.len())
+    }
+
+    /// Whether the given vector was deleted.
+    pub fn is_deleted(&self, vector: usize) -> bool {
+        self.tombstones.read().unpoisoned().contains(&vector)
+    }
+
+    /// The model that texts are embedded with for this domain, if one
+    /// was recorded.
+    pub fn embedding_model(&self) -> Option<EmbeddingModel> {
+        self.embedding_model
+            .read()
+            .unpoisoned()
+            .as_ref()
+            .map(|entry| entry.model.clone())
+    }
+
+    /// The dimension of the embeddings of the recorded model.
+    pub fn embedding_dimension(&self) -> Option<usize> {
+        let entry = self.embedding_model.read().unpoisoned();
+        let entry = entry.as_ref()?;
+        entry.dimension.or_else(|| entry.model.dimension().ok())
+    }
+
+    pub fn set_embedding_model(&self, model: &EmbeddingModel) -> io::Result<()> {
+        let entry = ModelEntry {
+            model: model.clone(),
+            dimension: model.dimension().ok(),
+        };
+        let mut model_file = self.model_file.checked("model")?;
+        model_file.write_entry(&self.name, "model", self.cipher.as_deref(), &entry)?;
+        model_file.file.flush()?;
+        model_file.file.sync_data()?;
+        *self.embedding_model.write().unpoisoned() = Some(entry);
+
+        Ok(())
+    }
+
     fn add_vecs<'a, I: Iterator<Item = &'a Embedding>>(
         &self,
         vecs: I,
     ) -> io::Result<(usize, usize)> {
-        let mut write_file = self.write_file.lock().unwrap();
+        let mut write_file = self.write_file.checked("vectors")?;
+        let num_vecs = self.num_vecs.load(atomic::Ordering::Relaxed);
         let mut count = 0;
         for embedding in vecs {
             let bytes: &EmbeddingBytes = unsafe { std::mem::transmute(embedding) };
-            write_file.write_all(bytes)?;
+            match &self.cipher {
+                None => write_file.write_all(bytes)?,
+                Some(cipher) => {
+                    let aad = sealed_aad(&self.name, "vecs", num_vecs + count);
+                    write_file.write_all(&cipher.seal(bytes, &aad))?
+                }
+            }
             count += 1;
         }
         write_file.flush()?;
         write_file.sync_data()?;
-        let num_vecs = self.num_vecs.load(atomic::Ordering::Relaxed);
+        BYTES_WRITTEN.add((count * vector_record_length(self.cipher.as_deref())) as u64);
         let new_num_vecs = num_vecs + count;
         self.num_vecs.store(new_num_vecs, atomic::Ordering::Relaxed);
 
@@ -103,10 +737,7 @@ impl Domain {
         let end = self.num_vecs() * std::mem::size_of::<Embedding>();
         if end <= offset {
             // this page does not exist.
-            eprintln!(
-                " :( page {} does not exist (need {} but end is {})",
-                index, offset, end
-            );
+            tracing::debug!(page = index, offset, end, "page does not exist");
             return Ok(false);
         }
         let remainder = end - offset;
@@ -115,34 +746,151 @@ impl Domain {
         } else {
             remainder
         };
-        eprintln!(
-            "loading page {}, range at offset {} of len {}",
-            index, offset, data_len
-        );
+        tracing::trace!(page = index, offset, len = data_len, "loading page");
         let data: &mut VectorPageBytes = unsafe { std::mem::transmute(data) };
         let data_slice = &mut data[..data_len];
-        self.read_file.read_exact_at(data_slice, offset as u64)?;
+        self.read_vec_bytes(index * VECTORS_PER_PAGE, data_slice)?;
 
         Ok(true)
     }
 
     fn load_partial_page(&self, index: usize, offset: usize, data: &mut [u8]) -> io::Result<()> {
-        assert!(
-            offset + data.len() <= std::mem::size_of::<VectorPage>(),
-            "requested partial load would read past a page boundary"
-        );
+        if offset + data.len() > std::mem::size_of::<VectorPage>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "requested partial load would read past a page boundary",
+            ));
+        }
         let offset = index * std::mem::size_of::<VectorPage>() + offset;
-        eprintln!(
-            "loading partial range at offset {} of len {}",
-            offset,
-            data.len()
-        );
-        self.read_file.read_exact_at(data, offset as u64)
+        tracing::trace!(offset, len = data.len(), "loading partial page");
+        self.read_vec_bytes(offset / EMBEDDING_BYTE_LENGTH, data)
     }
 
-    fn num_vecs(&self) -> usize {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether a store operation panicked while writing to the files
+    /// of this domain.
+    fn is_poisoned(&self) -> bool {
+        self.write_file.is_poisoned()
+            || self.documents_file.is_poisoned()
+            || self.metadata_file.is_poisoned()
+            || self.sparse_file.is_poisoned()
+            || self.tombstones_file.is_poisoned()
+            || self.model_file.is_poisoned()
+    }
+
+    pub fn num_vecs(&self) -> usize {
         self.num_vecs.load(atomic::Ordering::Relaxed)
     }
+
+    /// Wait for running writes to finish and flush the files of this
+    /// domain to disk.
+    fn sync(&self) -> io::Result<()> {
+        self.write_file.checked("vectors")?.sync_all()?;
+        self.documents_file.checked("documents")?.file.sync_all()?;
+        self.metadata_file.checked("metadata")?.file.sync_all()?;
+        self.sparse_file
+            .checked("sparse vectors")?
+            .file
+            .sync_all()?;
+        self.tombstones_file
+            .checked("tombstones")?
+            .file
+            .sync_all()?;
+        self.model_file.checked("model")?.file.sync_all()
+    }
+
+    /// The names and sizes of the files of this domain. Writes are
+    /// blocked while the sizes are taken, so the files end on a whole
+    /// vector or line.
+    fn file_sizes(&self, dir: &Path) -> io::Result<Vec<(String, u64)>> {
+        let _write_file = self.write_file.checked("vectors")?;
+        let _documents_file = self.documents_file.checked("documents")?;
+        let _metadata_file = self.metadata_file.checked("metadata")?;
+        let _sparse_file = self.sparse_file.checked("sparse vectors")?;
+        let _tombstones_file = self.tombstones_file.checked("tombstones")?;
+        let _model_file = self.model_file.checked("model")?;
+        DOMAIN_FILE_EXTENSIONS
+            .iter()
+            .map(|extension| {
+                let size = std::fs::metadata(domain_file_path(dir, &self.name, extension))?.len();
+                Ok((format!("{}.{extension}", encode(&self.name)), size))
+            })
+            .collect()
+    }
+
+    /// Create the files of a new domain derived from this one, as a
+    /// copy of its vector and document files. Writes are blocked during
+    /// the copy, so the copy never ends in a partial vector. Sealed
+    /// files are sealed again for the new domain.
+    fn create_derived(&self, dir: &Path, target: &str) -> error::Result<()> {
+        let _write_file = self.write_file.checked("vectors")?;
+        let _documents_file = self.documents_file.checked("documents")?;
+        let _metadata_file = self.metadata_file.checked("metadata")?;
+        let _sparse_file = self.sparse_file.checked("sparse vectors")?;
+        let _tombstones_file = self.tombstones_file.checked("tombstones")?;
+        let _model_file = self.model_file.checked("model")?;
+        for extension in DOMAIN_FILE_EXTENSIONS {
+            let source = domain_file_path(dir, &self.name, extension);
+            let target_path = domain_file_path(dir, target, extension);
+            match self.cipher.as_deref() {
+                None => std::fs::copy(&source, &target_path).map(|_| ()),
+                Some(cipher) => reseal_file(
+                    cipher,
+                    extension,
+                    (&source, self.name.as_str()),
+                    (&target_path, target),
+                ),
+            }
+            .context("copy", &source)?;
+        }
+
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref PAGE_HITS: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_page_hits_total",
+        "Vectors read from pages that were already in memory."
+    );
+    static ref PAGE_LOADS: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_page_loads_total",
+        "Vector pages read from disk."
+    );
+    static ref BYTES_READ: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_vector_bytes_read_total",
+        "Bytes read from vector files."
+    );
+    static ref BYTES_WRITTEN: Arc<Counter> = metrics::registry().counter(
+        "vectorlink_vector_bytes_written_total",
+        "Bytes appended to vector files."
+    );
+    static ref PAGES_FREE: Arc<Gauge> =
+        metrics::registry().gauge("vectorlink_pages_free", "Vector pages not in use.");
+    static ref PAGES_LOADED: Arc<Gauge> = metrics::registry().gauge(
+        "vectorlink_pages_loaded",
+        "Vector pages holding vectors that are in use."
+    );
+    static ref PAGES_CACHED: Arc<Gauge> = metrics::registry().gauge(
+        "vectorlink_pages_cached",
+        "Vector pages kept in memory although no vector of them is in use."
+    );
+}
+
+pub const DOMAIN_FILE_EXTENSIONS: [&str; 6] = ["vecs", "docs", "meta", "sparse", "tomb", "model"];
+
+/// Files that are derived from the others, and can be made again.
+/// They are not copied or replicated, but they go when the domain
+/// goes.
+pub const DERIVED_FILE_EXTENSIONS: [&str; 1] = ["clusters"];
+
+fn domain_file_path(dir: &Path, name: &str, extension: &str) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push(format!("{}.{extension}", encode(name)));
+    path
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
@@ -195,7 +943,7 @@ impl PageArena {
             return;
         }
         // TODO would be much better if we could have uninit allocs.
-        let mut free = self.free.lock().unwrap();
+        let mut free = self.free.lock().unpoisoned();
         let zeroed = Box::new([0.0f32; VECTOR_PAGE_FLOAT_SIZE]);
         for _ in 0..count - 1 {
             free.push(zeroed.clone());
@@ -204,12 +952,12 @@ impl PageArena {
     }
 
     fn free_page_from_free(&self) -> Option<Box<VectorPage>> {
-        let mut free = self.free.lock().unwrap();
+        let mut free = self.free.lock().unpoisoned();
         free.pop()
     }
 
     fn free_page_from_cache(&self) -> Option<Box<VectorPage>> {
-        let mut cache = self.cache.write().unwrap();
+        let mut cache = self.cache.write().unpoisoned();
         cache.pop_lru().map(|p| p.1.page)
     }
 
@@ -219,24 +967,24 @@ impl PageArena {
     }
 
     fn page_is_loaded(&self, spec: PageSpec) -> bool {
-        let loaded = self.loaded.read().unwrap();
+        let loaded = self.loaded.read().unpoisoned();
         loaded.contains_key(&spec)
     }
 
     fn page_is_cached(&self, spec: PageSpec) -> bool {
-        let cache = self.cache.read().unwrap();
+        let cache = self.cache.read().unpoisoned();
         cache.contains(&spec)
     }
 
     fn start_loading_or_wait(self: &Arc<Self>, spec: PageSpec) -> LoadState {
-        let mut loading = self.loading.lock().unwrap();
+        let mut loading = self.loading.lock().unpoisoned();
         if let Some(x) = loading.get(&spec).cloned() {
             // someone is already loading. Let's wait.
             std::mem::drop(loading);
             let (cv, m) = &*x;
-            let mut load_state = m.lock().unwrap();
+            let mut load_state = m.lock().unpoisoned();
             while load_state.is_loading() {
-                load_state = cv.wait(load_state).unwrap();
+                load_state = cv.wait(load_state).unpoisoned();
             }
             // this will now either be loaded or canceled
             load_state.clone()
@@ -262,7 +1010,7 @@ impl PageArena {
             arena: self.clone(),
             p: &*page,
         });
-        let mut loaded = self.loaded.write().unwrap();
+        let mut loaded = self.loaded.write().unpoisoned();
         loaded.insert(
             spec,
             PinnedVectorPage {
@@ -272,54 +1020,64 @@ impl PageArena {
         );
         std::mem::drop(loaded);
 
-        let mut loading = self.loading.lock().unwrap();
-        let x = loading
-            .remove(&spec)
-            .expect("entry that was finished loading was not in load map");
-        let (cv, m) = &*x;
-
-        let mut load_state = m.lock().unwrap();
-        *load_state = LoadState::Loaded(handle.clone());
-        cv.notify_all();
+        let mut loading = self.loading.lock().unpoisoned();
+        // Only missing if whoever started this load panicked halfway,
+        // in which case there's nobody waiting either.
+        if let Some(x) = loading.remove(&spec) {
+            let (cv, m) = &*x;
+            let mut load_state = m.lock().unpoisoned();
+            *load_state = LoadState::Loaded(handle.clone());
+            cv.notify_all();
+        }
 
         handle
     }
 
     fn cancel_loading(&self, spec: PageSpec, page: Box<VectorPage>) {
-        let mut free = self.free.lock().unwrap();
+        let mut free = self.free.lock().unpoisoned();
         free.push(page);
         std::mem::drop(free);
 
-        let mut loading = self.loading.lock().unwrap();
-        let x = loading
-            .remove(&spec)
-            .expect("entry that canceled loading was not in load map");
-        let (cv, m) = &*x;
-
-        let mut load_state = m.lock().unwrap();
-        *load_state = LoadState::Canceled;
-        cv.notify_all();
+        let mut loading = self.loading.lock().unpoisoned();
+        if let Some(x) = loading.remove(&spec) {
+            let (cv, m) = &*x;
+            let mut load_state = m.lock().unpoisoned();
+            *load_state = LoadState::Canceled;
+            cv.notify_all();
+        }
     }
 
     fn loaded_to_cached(&self, spec: PageSpec) -> bool {
         // We're acquiring two locks. In order to make sure there won't be deadlocks, we have to ensure that these locks are always acquired in this order.
         // Luckily, there's only two functions that need to acquire both of these locks, and we can easily verify that both do indeed acquire in this order, thus preventing deadlocks.
-        let mut loaded = self.loaded.write().unwrap();
-        let mut cache = self.cache.write().unwrap();
-
-        if loaded
-            .get(&spec)
-            .expect("page that was supposedly loaded was not found in the load map")
-            .handle
-            .strong_count()
-            != 0
-        {
+        let mut loaded = self.loaded.write().unpoisoned();
+        let mut cache = self.cache.write().unpoisoned();
+
+        // This runs when a page handle is dropped, where panicking
+        // would abort, so a page that went missing is only logged.
+        let Some(pinned) = loaded.get(&spec) else {
+            tracing::error!(
+                domain = spec.domain,
+                page = spec.index,
+                "loaded page was not in the load map"
+            );
+            return false;
+        };
+        if pinned.handle.strong_count() != 0 {
             // Whoops! Looks like someone re-acquired this page while we weren't looking!
             // Best to leave it alone.
             return false;
         }
-        assert!(!cache.contains(&spec), "page already in cache");
-        let page = loaded.remove(&spec).unwrap();
+        let Some(page) = loaded.remove(&spec) else {
+            return false;
+        };
+        if cache.contains(&spec) {
+            tracing::error!(
+                domain = spec.domain,
+                page = spec.index,
+                "loaded page was already in the cache"
+            );
+        }
         cache.get_or_insert(spec, move || page.page);
 
         true
@@ -328,29 +1086,26 @@ impl PageArena {
     fn cached_to_loaded(self: &Arc<Self>, spec: PageSpec) -> Option<Arc<PageHandle>> {
         // We're acquiring two locks. In order to make sure there won't be deadlocks, we have to ensure that these locks are always acquired in this order.
         // Luckily, there's only two functions that need to acquire both of these locks, and we can easily verify that both do indeed acquire in this order, thus preventing deadlocks.
-        let mut loaded = self.loaded.write().unwrap();
-        let mut cache = self.cache.write().unwrap();
+        let mut loaded = self.loaded.write().unpoisoned();
+        let mut cache = self.cache.write().unpoisoned();
 
-        let page = cache.pop(&spec);
-        page.as_ref()?;
-        let page = page.unwrap();
+        let page = cache.pop(&spec)?;
         let handle = Arc::new(PageHandle {
             spec,
             arena: self.clone(),
             p: &*page.page,
         });
-        assert!(
-            loaded
-                .insert(
-                    spec,
-                    PinnedVectorPage {
-                        page,
-                        handle: Arc::downgrade(&handle)
-                    }
-                )
-                .is_none(),
-            "page from cache was already in load map"
-        );
+        let pinned = PinnedVectorPage {
+            page,
+            handle: Arc::downgrade(&handle),
+        };
+        if loaded.insert(spec, pinned).is_some() {
+            tracing::error!(
+                domain = spec.domain,
+                page = spec.index,
+                "page from cache was already in the load map"
+            );
+        }
 
         Some(handle)
     }
@@ -360,7 +1115,7 @@ impl PageArena {
         // we will have to solve this race condition.
         // basically, while holding the lock we have to replace the pagehandle (as the original one cannot be safely upgraded anymore). We also have to inhibit the move to cache that was triggered.
         // this is just a small race condition window but it is there. so best make sure.
-        let loaded = self.loaded.read().unwrap();
+        let loaded = self.loaded.read().unpoisoned();
         if let Some(page) = loaded.get(&spec) {
             if let Some(handle) = page.handle.upgrade() {
                 Some(handle)
@@ -368,7 +1123,7 @@ impl PageArena {
                 // Uh oh, this handle was dropped but somehow we still encountered this page in the loaded map.
                 // That means someone is right around the corner to move this page into the cache. We gotta stop them.
                 std::mem::drop(loaded);
-                let mut loaded = self.loaded.write().unwrap();
+                let mut loaded = self.loaded.write().unpoisoned();
                 if let Some(page) = loaded.get_mut(&spec) {
                     // ok it is still here. To be absolutely sure, we have to recheck the lock
                     if let Some(handle) = page.handle.upgrade() {
@@ -400,10 +1155,10 @@ impl PageArena {
     }
 
     pub fn statistics(&self) -> VectorStoreStatistics {
-        let free = self.free.lock().unwrap().len();
-        let loading = self.loading.lock().unwrap().len();
-        let loaded = self.loaded.read().unwrap().len();
-        let cached = self.cache.read().unwrap().len();
+        let free = self.free.lock().unpoisoned().len();
+        let loading = self.loading.lock().unpoisoned().len();
+        let loaded = self.loaded.read().unpoisoned().len();
+        let cached = self.cache.read().unpoisoned().len();
 
         VectorStoreStatistics {
             free,
@@ -519,6 +1274,21 @@ pub struct VectorStore {
     dir: PathBuf,
     arena: Arc<PageArena>,
     domains: RwLock<HashMap<String, Arc<Domain>>>,
+    // Domains can be dropped, so the size of the domain map can't be
+    // used to number them. Numbers are never reused, as pages of a
+    // dropped domain may still be cached under its number.
+    next_domain_index: AtomicUsize,
+    cipher: Option<Arc<Cipher>>,
+}
+
+/// A sealed line in an encrypted store directory, to tell at startup
+/// whether the configured key is the one the files were sealed with.
+pub const ENCRYPTION_CHECK_FILE: &str = "encryption.check";
+const ENCRYPTION_CHECK: &[u8] = b"vectorlink";
+
+/// Whether a key check was sealed with the key of `cipher`.
+fn opens_key_check(cipher: &Cipher, check: &str) -> bool {
+    cipher.open_line(check, b"check").ok().as_deref() == Some(ENCRYPTION_CHECK)
 }
 
 impl VectorStore {
@@ -530,20 +1300,92 @@ impl VectorStore {
             dir: path.into(),
             arena: Arc::new(arena),
             domains: Default::default(),
+            next_domain_index: AtomicUsize::new(0),
+            cipher: None,
+        }
+    }
+
+    /// Encrypt the files of this store with the given key: vectors,
+    /// the lines of the other domain files, clusterings and indexes.
+    /// Only a directory without domains can become encrypted, as files
+    /// written in the clear aren't sealed after the fact, and once it
+    /// is, it needs the same key every time.
+    pub fn encrypted(mut self, key: &Secret) -> error::Result<Self> {
+        let cipher = Cipher::new(key);
+        let path = self.dir.join(ENCRYPTION_CHECK_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(check) => {
+                if !opens_key_check(&cipher, &check) {
+                    return Err(VectorlinkError::Encryption {
+                        dir: self.dir.clone(),
+                        reason: "the files are encrypted with another key",
+                    });
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !self.list_domains().unwrap_or_default().is_empty() {
+                    return Err(VectorlinkError::Encryption {
+                        dir: self.dir.clone(),
+                        reason: "the domains in it are not encrypted",
+                    });
+                }
+                std::fs::create_dir_all(&self.dir).context("create", &self.dir)?;
+                let check = cipher.seal_line(ENCRYPTION_CHECK, b"check");
+                std::fs::write(&path, check).context("write", &path)?;
+            }
+            Err(e) => return Err(e).context("read", &path),
+        }
+        self.cipher = Some(Arc::new(cipher));
+
+        Ok(self)
+    }
+
+    /// The cipher that the files of this store are sealed with, if it
+    /// is encrypted.
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_deref()
+    }
+
+    /// Check that the files can be read, which they can't if they are
+    /// encrypted and the store has no key. Domains are checked as they
+    /// are opened, but servers and commands should not start at all.
+    pub fn check_key(&self) -> error::Result<()> {
+        if self.cipher.is_none() && self.dir.join(ENCRYPTION_CHECK_FILE).exists() {
+            return Err(VectorlinkError::Encryption {
+                dir: self.dir.clone(),
+                reason: "the files are encrypted, but no key is configured",
+            });
         }
+        Ok(())
     }
 
-    pub fn get_domain(&self, name: &str) -> io::Result<Arc<Domain>> {
-        let domains = self.domains.read().unwrap();
-        if let Some(domain) = domains.get(name) {
+    /// Whether the files of another directory, with the given key
+    /// check, can be read with the key of this store.
+    pub fn accepts_key_check(&self, check: &str) -> bool {
+        self.cipher()
+            .map_or(false, |cipher| opens_key_check(cipher, check))
+    }
+
+    /// The open domain of the given name, opening it if it isn't. A
+    /// domain whose files were left half written by a panic is opened
+    /// again from disk.
+    pub fn get_domain(&self, name: &str) -> error::Result<Arc<Domain>> {
+        let domains = self.domains.read().unpoisoned();
+        if let Some(domain) = domains.get(name).filter(|domain| !domain.is_poisoned()) {
             Ok(domain.clone())
         } else {
             std::mem::drop(domains);
-            let mut domains = self.domains.write().unwrap();
-            if let Some(domain) = domains.get(name) {
+            let mut domains = self.domains.write().unpoisoned();
+            if let Some(domain) = domains.get(name).filter(|domain| !domain.is_poisoned()) {
                 Ok(domain.clone())
             } else {
-                let domain = Arc::new(Domain::open(&self.dir, name, domains.len())?);
+                let index = self
+                    .next_domain_index
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+                self.check_key()?;
+                let domain = Domain::open(&self.dir, name, index, self.cipher.clone())
+                    .map_err(|e| e.in_domain(name))?;
+                let domain = Arc::new(domain);
                 domains.insert(name.to_string(), domain.clone());
 
                 Ok(domain)
@@ -555,8 +1397,9 @@ impl VectorStore {
         &self,
         domain: &Domain,
         vecs: I,
-    ) -> io::Result<Vec<usize>> {
-        let (offset, num_added) = domain.add_vecs(vecs)?;
+    ) -> error::Result<Vec<usize>> {
+        let vecs_path = domain_file_path(&self.dir, domain.name(), "vecs");
+        let (offset, num_added) = domain.add_vecs(vecs).context("append to", &vecs_path)?;
         if offset % VECTORS_PER_PAGE != 0 {
             // vecs got added to a page that might actually already be in memory. We'll have to refresh it.
             let page_index = offset / VECTORS_PER_PAGE;
@@ -582,13 +1425,15 @@ impl VectorStore {
                 let offset_byte = offset_in_page * std::mem::size_of::<Embedding>();
                 let end_byte = offset_byte + vecs_to_load * std::mem::size_of::<Embedding>();
                 let mutation_range = &mut data[offset_byte..end_byte];
-                domain.load_partial_page(page_index, offset_byte, mutation_range)?;
+                domain
+                    .load_partial_page(page_index, offset_byte, mutation_range)
+                    .context("read", &vecs_path)?;
             }
         }
         Ok((offset..offset + num_added).collect())
     }
 
-    pub fn get_vec(&self, domain: &Domain, index: usize) -> io::Result<Option<LoadedVec>> {
+    pub fn get_vec(&self, domain: &Domain, index: usize) -> error::Result<Option<LoadedVec>> {
         if domain.num_vecs() <= index {
             return Ok(None);
         }
@@ -600,39 +1445,38 @@ impl VectorStore {
             index: page_index,
         };
         if let Some(page) = self.arena.page_from_any(page_spec) {
+            PAGE_HITS.inc();
             Ok(Some(page.get_loaded_vec(index_in_page)))
         } else {
             // the page is on disk but not yet in memory. Let's load it.
             match self.arena.start_loading_or_wait(page_spec) {
                 LoadState::Loading => {
-                    eprintln!(" loading page");
                     // we are the loader. get a free page and load things
                     if let Some(mut page) = self.arena.free_page() {
                         match domain.load_page(page_index, &mut page) {
                             Ok(true) => {
+                                PAGE_LOADS.inc();
                                 let handle = self.arena.finish_loading(page_spec, page);
                                 Ok(Some(handle.get_loaded_vec(index_in_page)))
                             }
-                            Ok(false) => {
-                                Err(io::Error::new(io::ErrorKind::NotFound, "page not found"))
-                            }
+                            Ok(false) => Err(VectorlinkError::VectorNotFound(index)),
                             Err(e) => {
                                 // something went wrong. cancel the load
                                 self.arena.cancel_loading(page_spec, page);
-                                Err(e)
+                                let path = domain_file_path(&self.dir, domain.name(), "vecs");
+                                Err(VectorlinkError::Io {
+                                    operation: "read",
+                                    path,
+                                    source: e,
+                                })
                             }
                         }
                     } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "no more free space in vector arena",
-                        ))
+                        Err(VectorlinkError::NoFreePages)
                     }
                 }
                 LoadState::Loaded(page) => Ok(Some(page.get_loaded_vec(index_in_page))),
-                LoadState::Canceled => {
-                    Err(io::Error::new(io::ErrorKind::Other, "load was canceled"))
-                }
+                LoadState::Canceled => Err(VectorlinkError::LoadCancelled(page_index)),
             }
         }
     }
@@ -640,35 +1484,41 @@ impl VectorStore {
         &self,
         domain: &Domain,
         vecs: I,
-    ) -> io::Result<Vec<LoadedVec>> {
+    ) -> error::Result<Vec<LoadedVec>> {
         let ids = self.add_vecs(domain, vecs)?;
 
         let mut result = Vec::with_capacity(ids.len());
         for id in ids.into_iter() {
-            let e = self.get_vec(domain, id)?.unwrap();
-            result.push(e);
+            result.push(self.get_stored_vec(domain, id)?);
         }
 
         Ok(result)
     }
 
-    pub fn add_and_load_vec(&self, domain: &Domain, vec: &Embedding) -> io::Result<LoadedVec> {
+    pub fn add_and_load_vec(&self, domain: &Domain, vec: &Embedding) -> error::Result<LoadedVec> {
         let ids = self.add_vecs(domain, [vec].into_iter())?;
 
-        Ok(self.get_vec(domain, ids[0])?.unwrap())
+        self.get_stored_vec(domain, ids[0])
+    }
+
+    /// A vector that has to be there, like one that was just added.
+    pub fn get_stored_vec(&self, domain: &Domain, index: usize) -> error::Result<LoadedVec> {
+        self.get_vec(domain, index)?
+            .ok_or(VectorlinkError::VectorNotFound(index))
+            .map_err(|e| e.in_domain(domain.name()))
     }
 
     pub fn add_and_load_vec_array<const N: usize>(
         &self,
         domain: &Domain,
         embeddings: &[Embedding; N],
-    ) -> io::Result<[LoadedVec; N]> {
+    ) -> error::Result<[LoadedVec; N]> {
         let ids = self.add_vecs(domain, embeddings.iter())?;
 
         let mut result: [MaybeUninit<LoadedVec>; N] =
             unsafe { MaybeUninit::uninit().assume_init() };
         for (r, id) in result.iter_mut().zip(ids.into_iter()) {
-            let e: LoadedVec = self.get_vec(domain, id)?.unwrap();
+            let e: LoadedVec = self.get_stored_vec(domain, id)?;
             r.write(e);
         }
 
@@ -687,6 +1537,198 @@ impl VectorStore {
     pub fn statistics(&self) -> VectorStoreStatistics {
         self.arena.statistics()
     }
+
+    /// Set the gauges of the pages in memory, which are only counted
+    /// when asked for.
+    pub fn record_metrics(&self) {
+        let statistics = self.statistics();
+        PAGES_FREE.set(statistics.free as i64);
+        PAGES_LOADED.set(statistics.loaded as i64);
+        PAGES_CACHED.set(statistics.cached as i64);
+    }
+
+    /// Flush the files of every open domain to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let domains: Vec<Arc<Domain>> =
+            self.domains.read().unpoisoned().values().cloned().collect();
+        for domain in domains {
+            domain.sync()?;
+        }
+        Ok(())
+    }
+
+    /// The sizes of the files of all open domains, by file name. Only
+    /// open domains are written to.
+    pub fn open_file_sizes(&self) -> io::Result<HashMap<String, u64>> {
+        let domains: Vec<Arc<Domain>> =
+            self.domains.read().unpoisoned().values().cloned().collect();
+        let mut sizes = HashMap::new();
+        for domain in domains {
+            sizes.extend(domain.file_sizes(&self.dir)?);
+        }
+        Ok(sizes)
+    }
+
+    /// Read a domain from disk again the next time it is used, to pick
+    /// up changes made to its files by someone else. Vectors that are
+    /// already loaded stay valid.
+    pub fn reopen_domain(&self, name: &str) {
+        self.domains.write().unpoisoned().remove(name);
+    }
+
+    pub fn domain_exists(&self, name: &str) -> bool {
+        domain_file_path(&self.dir, name, "vecs").exists()
+    }
+
+    /// A domain that has to exist already, for readers that would
+    /// otherwise create an empty one for a misspelled name.
+    pub fn get_existing_domain(&self, name: &str) -> error::Result<Arc<Domain>> {
+        if !self.domain_exists(name) {
+            return Err(VectorlinkError::DomainNotFound(name.to_string()));
+        }
+        self.get_domain(name)
+    }
+
+    /// The names of all domains stored in this vector store.
+    pub fn list_domains(&self) -> error::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.dir).context("list", &self.dir)? {
+            let file_name = entry.context("list", &self.dir)?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(name) = file_name.strip_suffix(".vecs") {
+                if let Ok(name) = decode(name) {
+                    names.push(name.into_owned());
+                }
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Delete a domain's files. Vectors that are already loaded stay
+    /// valid until they are dropped. Returns false if the domain did
+    /// not exist.
+    pub fn drop_domain(&self, name: &str) -> error::Result<bool> {
+        let mut domains = self.domains.write().unpoisoned();
+        if !self.domain_exists(name) {
+            return Ok(false);
+        }
+        domains.remove(name);
+        for extension in DOMAIN_FILE_EXTENSIONS
+            .iter()
+            .chain(&DERIVED_FILE_EXTENSIONS)
+        {
+            let path = domain_file_path(&self.dir, name, extension);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(VectorlinkError::Io {
+                        operation: "remove",
+                        path,
+                        source: e,
+                    }
+                    .in_domain(name))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Create a new domain holding a copy of the vectors and documents
+    /// of an existing one. Vector ids are the same in both domains.
+    pub fn copy_domain(&self, source: &str, target: &str) -> error::Result<Arc<Domain>> {
+        if self.domain_exists(target) {
+            return Err(VectorlinkError::DomainExists(target.to_string()));
+        }
+        if !self.domain_exists(source) {
+            return Err(VectorlinkError::DomainNotFound(source.to_string()));
+        }
+        self.get_domain(source)?
+            .create_derived(&self.dir, target)
+            .map_err(|e| e.in_domain(source))?;
+
+        self.get_domain(target)
+    }
+}
+
+/// Async variants of the operations that read or append to the files
+/// of a domain. They run on tokio's blocking threads, so that a
+/// multi-megabyte read or write doesn't hold up the runtime's workers.
+impl VectorStore {
+    /// Run `f` against this store on a blocking thread. If `f` panics,
+    /// that is returned as an error rather than passed on to the task
+    /// waiting for it.
+    pub async fn blocking<T, E, F>(self: &Arc<Self>, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&VectorStore) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<VectorlinkError> + Send + 'static,
+    {
+        let store = self.clone();
+        match tokio::task::spawn_blocking(move || f(&store)).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => {
+                Err(VectorlinkError::Panicked(error::panic_message(e.into_panic())).into())
+            }
+            Err(e) => Err(VectorlinkError::Panicked(e.to_string()).into()),
+        }
+    }
+
+    /// Opening a domain reads its documents and metadata, so that is
+    /// only done on a blocking thread when the domain isn't open yet.
+    pub async fn get_domain_async(self: &Arc<Self>, name: &str) -> error::Result<Arc<Domain>> {
+        if let Some(domain) = self.domains.read().unpoisoned().get(name) {
+            return Ok(domain.clone());
+        }
+        let name = name.to_string();
+        self.blocking(move |store| store.get_domain(&name)).await
+    }
+
+    pub async fn add_vecs_async(
+        self: &Arc<Self>,
+        domain: Arc<Domain>,
+        vecs: Vec<Embedding>,
+    ) -> error::Result<Vec<usize>> {
+        self.blocking(move |store| store.add_vecs(&domain, vecs.iter()))
+            .await
+    }
+
+    pub async fn add_and_load_vecs_async(
+        self: &Arc<Self>,
+        domain: Arc<Domain>,
+        vecs: Vec<Embedding>,
+    ) -> error::Result<Vec<LoadedVec>> {
+        self.blocking(move |store| store.add_and_load_vecs(&domain, vecs.iter()))
+            .await
+    }
+
+    /// Vectors on pages that are already in memory are returned
+    /// without going to a blocking thread.
+    pub async fn get_vec_async(
+        self: &Arc<Self>,
+        domain: Arc<Domain>,
+        index: usize,
+    ) -> error::Result<Option<LoadedVec>> {
+        if domain.num_vecs() <= index {
+            return Ok(None);
+        }
+        let page_spec = PageSpec {
+            domain: domain.index,
+            index: index / VECTORS_PER_PAGE,
+        };
+        if let Some(page) = self.arena.page_from_any(page_spec) {
+            PAGE_HITS.inc();
+            return Ok(Some(page.get_loaded_vec(index % VECTORS_PER_PAGE)));
+        }
+        self.blocking(move |store| store.get_vec(&domain, index))
+            .await
+    }
+
+    pub async fn sync_async(self: &Arc<Self>) -> io::Result<()> {
+        self.blocking(|store| store.sync()).await
+    }
 }
 
 #[cfg(test)]
@@ -935,4 +1977,371 @@ mod tests {
         assert_eq!(e4, *e4_from_memory);
         assert_eq!(e5, *e5_from_memory);
     }
+
+    #[test]
+    fn documents_survive_reopen() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let store = VectorStore::new(path, 100);
+        let seed: u64 = 42;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let domain = store.get_domain("foo").unwrap();
+
+        let e1 = random_embedding(&mut rng);
+        let e2 = random_embedding(&mut rng);
+        let ids = store.add_vecs(&domain, [e1, e2].iter()).unwrap();
+        domain
+            .add_documents(&[(ids[1], "Doc/1".to_string())])
+            .unwrap();
+        assert_eq!(None, domain.document(ids[0]));
+        assert_eq!(Some("Doc/1".to_string()), domain.document(ids[1]));
+        let metadata = serde_json::json!({"year": 1977});
+        domain.add_metadata(&[(ids[0], metadata.clone())]).unwrap();
+        let sparse = SparseVector::new(vec![3, 30000], vec![0.5, 1.5]).unwrap();
+        domain
+            .add_sparse_vectors(&[(ids[1], sparse.clone())])
+            .unwrap();
+        assert_eq!(1, domain.add_tombstones(&[ids[1], ids[1]]).unwrap());
+        assert_eq!(0, domain.add_tombstones(&[ids[1]]).unwrap());
+        assert_eq!(None, domain.embedding_model());
+        let model = EmbeddingModel {
+            model: "text-embedding-3-small".to_string(),
+            dimensions: Some(512),
+            ..Default::default()
+        };
+        domain.set_embedding_model(&model).unwrap();
+
+        let store2 = VectorStore::new(path, 100);
+        let domain2 = store2.get_domain("foo").unwrap();
+        assert_eq!(None, domain2.document(ids[0]));
+        assert_eq!(Some("Doc/1".to_string()), domain2.document(ids[1]));
+        assert_eq!(Some(metadata), domain2.metadata(ids[0]));
+        assert_eq!(None, domain2.metadata(ids[1]));
+        assert_eq!(None, domain2.sparse_vector(ids[0]));
+        assert_eq!(Some(sparse), domain2.sparse_vector(ids[1]));
+        assert!(!domain2.is_deleted(ids[0]));
+        assert!(domain2.is_deleted(ids[1]));
+        assert_eq!(Some(model), domain2.embedding_model());
+        assert_eq!(Some(512), domain2.embedding_dimension());
+    }
+
+    #[test]
+    fn copy_and_drop_domains() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+
+        let mut e: Embedding = [0.0; 1536];
+        e[0] = 1.0;
+        let source = store.get_domain("admin/source").unwrap();
+        store.add_vecs(&source, [e].iter()).unwrap();
+        source.add_documents(&[(0, "Doc/1".to_string())]).unwrap();
+        source.add_tombstones(&[0]).unwrap();
+
+        let target = store.copy_domain("admin/source", "admin/target").unwrap();
+        assert_eq!(1, target.num_vecs());
+        assert_eq!(Some("Doc/1".to_string()), target.document(0));
+        assert_eq!(e, *store.get_vec(&target, 0).unwrap().unwrap());
+        assert!(matches!(
+            store.copy_domain("admin/source", "admin/target"),
+            Err(VectorlinkError::DomainExists(_))
+        ));
+        assert_eq!(
+            vec!["admin/source".to_string(), "admin/target".to_string()],
+            store.list_domains().unwrap()
+        );
+
+        assert!(store.drop_domain("admin/source").unwrap());
+        assert!(!store.drop_domain("admin/source").unwrap());
+        assert!(matches!(
+            store.get_existing_domain("admin/source"),
+            Err(VectorlinkError::DomainNotFound(_))
+        ));
+        assert!(!store.domain_exists("admin/source"));
+        assert_eq!(
+            vec!["admin/target".to_string()],
+            store.list_domains().unwrap()
+        );
+        // a new domain with the old name starts out empty
+        let recreated = store.get_domain("admin/source").unwrap();
+        assert_eq!(0, recreated.num_vecs());
+        store.add_vecs(&recreated, [e].iter()).unwrap();
+        assert!(!recreated.is_deleted(0));
+        assert!(target.is_deleted(0));
+    }
+
+    #[test]
+    fn corrupt_domain_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        std::fs::write(
+            domain_file_path(tempdir.path(), "admin/short", "vecs"),
+            [0; 5],
+        )
+        .unwrap();
+        let error = store.get_domain("admin/short").err().unwrap();
+        let VectorlinkError::Domain { domain, source } = &error else {
+            panic!("expected a domain error but got {error}");
+        };
+        assert_eq!("admin/short", domain);
+        assert!(matches!(**source, VectorlinkError::Corrupt { .. }));
+
+        std::fs::write(
+            domain_file_path(tempdir.path(), "admin/docs", "docs"),
+            "{\n",
+        )
+        .unwrap();
+        let error = store.get_domain("admin/docs").err().unwrap().to_string();
+        assert!(error.contains("line 1"));
+        assert!(error.contains(".docs"));
+    }
+
+    #[test]
+    fn async_store_operations() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = Arc::new(VectorStore::new(tempdir.path(), 100));
+        let mut rng = StdRng::seed_from_u64(42);
+        let e1 = random_embedding(&mut rng);
+        let e2 = random_embedding(&mut rng);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let domain = store.get_domain_async("foo").await.unwrap();
+            let ids = store
+                .add_vecs_async(domain.clone(), vec![e1, e2])
+                .await
+                .unwrap();
+            assert_eq!(vec![0, 1], ids);
+            let loaded = store
+                .add_and_load_vecs_async(domain.clone(), vec![e2])
+                .await
+                .unwrap();
+            assert_eq!(2, loaded[0].id());
+            assert_eq!(
+                e1,
+                *store
+                    .get_vec_async(domain.clone(), 0)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            );
+            assert!(store.get_vec_async(domain, 3).await.unwrap().is_none());
+            store.sync_async().await.unwrap();
+        });
+
+        // read from disk rather than from a cached page
+        let store = Arc::new(VectorStore::new(tempdir.path(), 100));
+        runtime.block_on(async {
+            let domain = store.get_domain_async("foo").await.unwrap();
+            assert_eq!(3, domain.num_vecs());
+            assert_eq!(e2, *store.get_vec_async(domain, 1).await.unwrap().unwrap());
+        });
+    }
+
+    #[test]
+    fn record_page_metrics() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 100);
+        let domain = store.get_domain("foo").unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let e = random_embedding(&mut rng);
+        store.add_vecs(&domain, [e, e].iter()).unwrap();
+
+        // other tests record too, so only lower bounds can be checked
+        let metrics = || metrics::registry().snapshot().counters;
+        let before = metrics();
+        let store = VectorStore::new(tempdir.path(), 100);
+        store.get_vec(&domain, 0).unwrap().unwrap();
+        store.get_vec(&domain, 1).unwrap().unwrap();
+        let after = metrics();
+        let delta = |name| after[name] - before.get(name).copied().unwrap_or(0);
+        assert!(delta("vectorlink_page_loads_total") >= 1);
+        assert!(delta("vectorlink_page_hits_total") >= 1);
+        assert!(delta("vectorlink_vector_bytes_read_total") >= 2 * EMBEDDING_BYTE_LENGTH as u64);
+
+        store.record_metrics();
+        let gauges = metrics::registry().snapshot().gauges;
+        assert!(gauges.contains_key("vectorlink_pages_loaded"));
+    }
+
+    #[test]
+    fn survive_panics() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = Arc::new(VectorStore::new(tempdir.path(), 100));
+        let domain = store.get_domain("foo").unwrap();
+
+        // poison the domain map, as a panic while opening a domain would
+        let poisoner = store.clone();
+        std::thread::spawn(move || {
+            let _domains = poisoner.domains.write().unwrap();
+            panic!("while opening a domain");
+        })
+        .join()
+        .unwrap_err();
+        assert!(store.domains.is_poisoned());
+        let mut rng = StdRng::seed_from_u64(42);
+        let e = random_embedding(&mut rng);
+        store.add_vecs(&domain, [e].iter()).unwrap();
+        assert_eq!("bar", store.get_domain("bar").unwrap().name());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime
+            .block_on(store.blocking(|_| -> error::Result<()> { panic!("bad domain") }))
+            .unwrap_err();
+        assert_eq!("a store operation panicked: bad domain", error.to_string());
+        let domain = runtime.block_on(store.get_domain_async("foo")).unwrap();
+        assert_eq!(1, domain.num_vecs());
+    }
+
+    #[test]
+    fn cluster_domain() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 10);
+        let domain = store.get_domain("admin/clustered").unwrap();
+        let mut vecs = vec![crate::vecmath::empty_embedding(); 7];
+        for (i, vec) in vecs.iter_mut().enumerate() {
+            vec[i % 2] = 1.0;
+            vec[2] = i as f32 * 0.01;
+            crate::vecmath::normalize_vec(vec);
+        }
+        let ids = store.add_vecs(&domain, vecs.iter()).unwrap();
+        domain.add_tombstones(&[ids[6]]).unwrap();
+
+        let params = ClusterParams {
+            iterations: 5,
+            seed: Some(7),
+        };
+        let clustering = domain
+            .cluster(2, &params, &crate::progress::NoProgress)
+            .unwrap();
+        assert_eq!(2, clustering.centroids.len());
+        assert_eq!(None, clustering.assignments[ids[6]]);
+        let even = clustering.assignments[ids[0]].unwrap();
+        let odd = clustering.assignments[ids[1]].unwrap();
+        assert_ne!(even, odd);
+        for (i, id) in ids[..6].iter().enumerate() {
+            let expected = if i % 2 == 0 { even } else { odd };
+            assert_eq!(Some(expected), clustering.assignments[*id]);
+        }
+        let mut sizes = clustering.sizes();
+        sizes.sort();
+        assert_eq!(vec![3, 3], sizes);
+        assert_eq!(Some(clustering), domain.clustering().unwrap());
+
+        assert!(store.drop_domain("admin/clustered").unwrap());
+        assert!(std::fs::read_dir(tempdir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn keep_bitmaps_of_metadata() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(tempdir.path(), 2);
+        let domain = store.get_domain("admin/tagged").unwrap();
+        let e: Embedding = [0.0; 1536];
+        let ids = store.add_vecs(&domain, [e, e, e].iter()).unwrap();
+        let filter = Filter::parse(r#"tenant = "a""#).unwrap();
+        domain
+            .add_metadata(&[(ids[0], serde_json::json!({"tenant": "a"}))])
+            .unwrap();
+        assert_eq!(None, domain.filter_candidates(&filter));
+
+        // bitmaps are built from what is there, then kept up to date
+        domain.index_fields(&["tenant".to_string()]);
+        domain
+            .add_metadata(&[
+                (ids[1], serde_json::json!({"tenant": "a"})),
+                (ids[0], serde_json::json!({"tenant": "b"})),
+            ])
+            .unwrap();
+        let candidates = domain.filter_candidates(&filter).unwrap();
+        assert!(candidates.exact);
+        assert!(!candidates.contains(ids[0]));
+        assert!(candidates.contains(ids[1]));
+        assert!(!candidates.contains(ids[2]));
+    }
+
+    #[test]
+    fn encrypt_domain_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path();
+        let key: Secret = serde_json::from_str(r#""correct horse battery staple""#).unwrap();
+        let store = VectorStore::new(path, 10).encrypted(&key).unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let vecs: Vec<_> = (0..3).map(|_| random_embedding(&mut rng)).collect();
+
+        let domain = store.get_domain("admin/secret").unwrap();
+        store.add_vecs(&domain, vecs[..1].iter()).unwrap();
+        // the page is in memory when the next vectors are added to it
+        assert_eq!(vecs[0], *store.get_vec(&domain, 0).unwrap().unwrap());
+        let ids = store.add_vecs(&domain, vecs[1..].iter()).unwrap();
+        assert_eq!(vecs[1], *store.get_vec(&domain, ids[0]).unwrap().unwrap());
+        domain
+            .add_metadata(&[(0, serde_json::json!({"tenant": "acme"}))])
+            .unwrap();
+        domain.add_tombstones(&[2]).unwrap();
+        domain
+            .cluster(1, &ClusterParams::default(), &crate::progress::NoProgress)
+            .unwrap();
+
+        let vecs_file = std::fs::read(domain_file_path(path, "admin/secret", "vecs")).unwrap();
+        assert_eq!(3 * (EMBEDDING_BYTE_LENGTH + SEAL_OVERHEAD), vecs_file.len());
+        for extension in ["meta", "clusters"] {
+            let file = std::fs::read(domain_file_path(path, "admin/secret", extension)).unwrap();
+            assert!(!String::from_utf8_lossy(&file).contains("acme"));
+            assert!(!String::from_utf8_lossy(&file).contains("params"));
+        }
+
+        let reopened = VectorStore::new(path, 10).encrypted(&key).unwrap();
+        let domain = reopened.get_domain("admin/secret").unwrap();
+        for (id, vec) in vecs.iter().enumerate() {
+            assert_eq!(*vec, *reopened.get_vec(&domain, id).unwrap().unwrap());
+        }
+        assert_eq!(
+            Some(serde_json::json!({"tenant": "acme"})),
+            domain.metadata(0)
+        );
+        assert!(domain.is_deleted(2));
+        assert_eq!(vec![2], domain.clustering().unwrap().unwrap().sizes());
+
+        // a copy of the domain is sealed again for its new name, while
+        // files copied from another domain are refused
+        let copy = reopened.copy_domain("admin/secret", "admin/copy").unwrap();
+        assert_eq!(vecs[1], *reopened.get_vec(&copy, 1).unwrap().unwrap());
+        assert_eq!(
+            Some(serde_json::json!({"tenant": "acme"})),
+            copy.metadata(0)
+        );
+        assert!(copy.is_deleted(2));
+        for extension in DOMAIN_FILE_EXTENSIONS {
+            std::fs::copy(
+                domain_file_path(path, "admin/secret", extension),
+                domain_file_path(path, "admin/stolen", extension),
+            )
+            .unwrap();
+        }
+        assert!(reopened.get_domain("admin/stolen").is_err());
+        assert!(reopened.drop_domain("admin/stolen").unwrap());
+
+        // the files are only opened with the same key
+        let check = std::fs::read_to_string(path.join(ENCRYPTION_CHECK_FILE)).unwrap();
+        assert!(reopened.accepts_key_check(&check));
+        let other: Secret = serde_json::from_str(r#""another key""#).unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        let other_store = VectorStore::new(elsewhere.path(), 10)
+            .encrypted(&other)
+            .unwrap();
+        assert!(!other_store.accepts_key_check(&check));
+        assert!(!VectorStore::new(elsewhere.path(), 10).accepts_key_check(&check));
+        assert!(VectorStore::new(path, 10).encrypted(&other).is_err());
+        assert!(VectorStore::new(path, 10)
+            .get_domain("admin/secret")
+            .is_err());
+        assert!(VectorStore::new(path, 10).check_key().is_err());
+        assert!(reopened.check_key().is_ok());
+
+        // nor are unencrypted domains encrypted after the fact
+        let plain = tempfile::tempdir().unwrap();
+        let store = VectorStore::new(plain.path(), 10);
+        store.get_domain("admin/plain").unwrap();
+        assert!(VectorStore::new(plain.path(), 10).encrypted(&key).is_err());
+    }
 }
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::{header, Client, StatusCode};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::config::WebhookConfig;
+
+/// Carries `sha256={hex}`, the HMAC-SHA256 of the body keyed with the
+/// webhook's secret.
+pub const SIGNATURE_HEADER: &str = "x-vectorlink-signature";
+pub const EVENT_HEADER: &str = "x-vectorlink-event";
+
+// Deliveries are retried after 1, 2 and 4 seconds.
+const ATTEMPTS: u32 = 4;
+/// Time a delivery attempt may take before it is given up and retried,
+/// so that a webhook that never answers doesn't hold its task forever.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The hex encoded HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("{0}")]
+    Http(#[from] reqwest::Error),
+    #[error("webhook answered with status {0}")]
+    Status(StatusCode),
+}
+
+/// Sends events to the configured webhooks.
+#[derive(Clone)]
+pub struct WebhookClient {
+    client: Client,
+}
+
+impl Default for WebhookClient {
+    fn default() -> Self {
+        WebhookClient {
+            client: Client::builder()
+                .timeout(ATTEMPT_TIMEOUT)
+                .build()
+                .expect("an HTTP client with a timeout can be built"),
+        }
+    }
+}
+
+impl WebhookClient {
+    /// Send an event to every webhook in the background.
+    pub fn notify(&self, hooks: &[WebhookConfig], event: &'static str, payload: serde_json::Value) {
+        let body = payload.to_string();
+        for hook in hooks {
+            let client = self.client.clone();
+            let hook = hook.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                if let Err(e) = deliver(&client, &hook, event, body).await {
+                    tracing::warn!(url = hook.url, event, error = %e, "webhook delivery failed");
+                }
+            });
+        }
+    }
+}
+
+/// Post an event to a webhook. Failed deliveries are retried with
+/// backoff, except when the webhook refuses the event with a 4xx.
+async fn deliver(
+    client: &Client,
+    hook: &WebhookConfig,
+    event: &str,
+    body: String,
+) -> Result<(), WebhookError> {
+    let signature = hook
+        .secret
+        .as_ref()
+        .map(|secret| format!("sha256={}", sign(secret.expose(), body.as_bytes())));
+    let mut attempt = 0;
+    loop {
+        let mut request = client
+            .post(&hook.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event)
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let result = match request.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status().is_client_error() => {
+                return Err(WebhookError::Status(response.status()))
+            }
+            Ok(response) => Err(WebhookError::Status(response.status())),
+            Err(e) => Err(e.into()),
+        };
+        attempt += 1;
+        if attempt == ATTEMPTS {
+            return result;
+        }
+        tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign("Jefe", b"what do ya want for nothing?")
+        );
+    }
+}
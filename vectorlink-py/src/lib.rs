@@ -0,0 +1,313 @@
+//! Python bindings, so that the vector store can be used from
+//! notebooks without going through the server. Domains and indexes
+//! live in a storage directory just as they do for the server, so an
+//! index saved here can be served, and the other way around.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use numpy::{PyReadonlyArray1, PyReadonlyArray2};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyList;
+
+use terminusdb_semantic_indexer::indexer::{
+    check_insertions, create_index_name, deserialize_index, new_index, records_to_point_operations,
+    search_with_ef, serialize_index, start_indexing_from_operations, HnswIndex, Point, PointQuery,
+    DEFAULT_EF,
+};
+use terminusdb_semantic_indexer::vecmath::{self, Embedding, EMBEDDING_LENGTH};
+use terminusdb_semantic_indexer::vectors::{self, VectorStore};
+
+create_exception!(vectorlink, VectorlinkError, PyException);
+
+fn error<E: Display>(e: E) -> PyErr {
+    VectorlinkError::new_err(e.to_string())
+}
+
+fn to_embedding(values: &[f32]) -> PyResult<Embedding> {
+    let mut vec: Embedding = values.try_into().map_err(|_| {
+        PyValueError::new_err(format!(
+            "vectors must have length {EMBEDDING_LENGTH}, not {}",
+            values.len()
+        ))
+    })?;
+    vecmath::normalize_vec(&mut vec);
+    Ok(vec)
+}
+
+fn to_embeddings(vectors: &PyReadonlyArray2<f32>) -> PyResult<Vec<Embedding>> {
+    vectors
+        .as_array()
+        .rows()
+        .into_iter()
+        .map(|row| to_embedding(&row.to_vec()))
+        .collect()
+}
+
+fn to_results(results: Vec<PointQuery>) -> Vec<(String, f32)> {
+    results
+        .into_iter()
+        .map(|result| (result.id().to_string(), f32::from_bits(result.distance())))
+        .collect()
+}
+
+/// A storage directory of domains and their indexes.
+#[pyclass]
+struct Store {
+    directory: PathBuf,
+    store: Arc<VectorStore>,
+}
+
+#[pymethods]
+impl Store {
+    /// Open the storage directory, keeping up to `pages` pages of
+    /// vectors in memory. An encrypted directory needs its `key`.
+    #[new]
+    #[pyo3(signature = (directory, pages = 10000, key = None))]
+    fn new(directory: PathBuf, pages: usize, key: Option<String>) -> PyResult<Self> {
+        let mut store = VectorStore::new(&directory, pages);
+        if let Some(key) = key {
+            let key = serde_json::from_value(serde_json::Value::String(key)).map_err(error)?;
+            store = store.encrypted(&key).map_err(error)?;
+        }
+        Ok(Store {
+            store: Arc::new(store),
+            directory,
+        })
+    }
+
+    /// The domain of the given name, which is created if it doesn't
+    /// exist yet.
+    fn domain(&self, name: &str) -> PyResult<Domain> {
+        let domain = self.store.get_domain(name).map_err(error)?;
+        Ok(Domain {
+            directory: self.directory.clone(),
+            store: self.store.clone(),
+            domain,
+        })
+    }
+
+    fn domains(&self) -> PyResult<Vec<String>> {
+        self.store.list_domains().map_err(error)
+    }
+
+    /// Remove a domain with its vectors. Returns whether it existed.
+    fn drop_domain(&self, name: &str) -> PyResult<bool> {
+        self.store.drop_domain(name).map_err(error)
+    }
+}
+
+#[pyclass]
+struct Domain {
+    directory: PathBuf,
+    store: Arc<VectorStore>,
+    domain: Arc<vectors::Domain>,
+}
+
+#[pymethods]
+impl Domain {
+    #[getter]
+    fn name(&self) -> &str {
+        self.domain.name()
+    }
+
+    fn __len__(&self) -> usize {
+        self.domain.num_vecs()
+    }
+
+    /// A new empty index over this domain.
+    #[pyo3(signature = (seed = None))]
+    fn new_index(&self, seed: Option<u64>) -> Index {
+        Index {
+            directory: self.directory.clone(),
+            store: self.store.clone(),
+            domain: self.domain.clone(),
+            hnsw: Some(new_index(seed)),
+        }
+    }
+
+    /// The index saved for the given commit.
+    #[pyo3(signature = (commit, strict = false))]
+    fn load_index(&self, py: Python, commit: &str, strict: bool) -> PyResult<Index> {
+        let name = create_index_name(self.domain.name(), commit);
+        let mut path = self.directory.clone();
+        let hnsw = py
+            .allow_threads(|| deserialize_index(&mut path, &name, &self.store, strict))
+            .map_err(error)?;
+        Ok(Index {
+            directory: self.directory.clone(),
+            store: self.store.clone(),
+            domain: self.domain.clone(),
+            hnsw: Some(hnsw),
+        })
+    }
+}
+
+/// An HNSW index over the vectors of a domain.
+#[pyclass]
+struct Index {
+    directory: PathBuf,
+    store: Arc<VectorStore>,
+    domain: Arc<vectors::Domain>,
+    // Only taken while points are being inserted, and lost if
+    // inserting them fails.
+    hnsw: Option<HnswIndex>,
+}
+
+fn lost_index() -> PyErr {
+    error("the index was lost to a failed insertion, load it again")
+}
+
+impl Index {
+    fn hnsw(&self) -> PyResult<&HnswIndex> {
+        self.hnsw.as_ref().ok_or_else(lost_index)
+    }
+}
+
+#[pymethods]
+impl Index {
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.hnsw()?.layer_len(0))
+    }
+
+    /// Store the rows of `vectors`, a float32 array of shape
+    /// `(len(ids), 1536)`, in the domain and insert them into the
+    /// index under `ids`. Metadata is a list of dicts, one for each
+    /// row, and a `document` string in them is recorded as the text
+    /// of the vector. Vectors are normalized before they are stored.
+    #[pyo3(signature = (ids, vectors, metadata = None))]
+    fn add(
+        &mut self,
+        py: Python,
+        ids: Vec<String>,
+        vectors: PyReadonlyArray2<f32>,
+        metadata: Option<&PyList>,
+    ) -> PyResult<()> {
+        let vecs = to_embeddings(&vectors)?;
+        if vecs.len() != ids.len() {
+            return Err(PyValueError::new_err(format!(
+                "{} ids were given for {} vectors",
+                ids.len(),
+                vecs.len()
+            )));
+        }
+        let metadata: Vec<Option<serde_json::Value>> = match metadata {
+            Some(metadata) => {
+                if metadata.len() != ids.len() {
+                    return Err(PyValueError::new_err(format!(
+                        "{} metadata entries were given for {} vectors",
+                        metadata.len(),
+                        ids.len()
+                    )));
+                }
+                let dumps = py.import("json")?.getattr("dumps")?;
+                metadata
+                    .iter()
+                    .map(|entry| {
+                        if entry.is_none() {
+                            return Ok(None);
+                        }
+                        let json: String = dumps.call1((entry,))?.extract()?;
+                        serde_json::from_str(&json)
+                            .map(Some)
+                            .map_err(|e| PyValueError::new_err(e.to_string()))
+                    })
+                    .collect::<PyResult<_>>()?
+            }
+            None => vec![None; ids.len()],
+        };
+        let records = ids
+            .into_iter()
+            .zip(vecs)
+            .zip(metadata)
+            .map(|((id, vec), metadata)| (id, vec, metadata))
+            .collect();
+
+        self.hnsw()?;
+        let operations = py.allow_threads(|| {
+            let operations =
+                records_to_point_operations(&self.domain, &self.store, records).map_err(error)?;
+            check_insertions(&operations).map_err(error)?;
+            Ok::<_, PyErr>(operations)
+        })?;
+        let hnsw = self.hnsw.take().ok_or_else(lost_index)?;
+        let hnsw = py
+            .allow_threads(|| start_indexing_from_operations(hnsw, operations))
+            .map_err(error)?;
+        self.hnsw = Some(hnsw);
+        Ok(())
+    }
+
+    /// The `k` nearest neighbours of `query`, as `(id, distance)`
+    /// pairs ordered by distance.
+    #[pyo3(signature = (query, k = 10, ef = DEFAULT_EF))]
+    fn search(
+        &self,
+        py: Python,
+        query: PyReadonlyArray1<f32>,
+        k: usize,
+        ef: usize,
+    ) -> PyResult<Vec<(String, f32)>> {
+        let query = Point::Mem {
+            vec: Box::new(to_embedding(&query.as_array().to_vec())?),
+        };
+        let hnsw = self.hnsw()?;
+        let results = py
+            .allow_threads(|| search_with_ef(&query, k, ef, hnsw))
+            .map_err(error)?;
+        Ok(to_results(results))
+    }
+
+    /// `search` for each row of `queries`.
+    #[pyo3(signature = (queries, k = 10, ef = DEFAULT_EF))]
+    fn search_batch(
+        &self,
+        py: Python,
+        queries: PyReadonlyArray2<f32>,
+        k: usize,
+        ef: usize,
+    ) -> PyResult<Vec<Vec<(String, f32)>>> {
+        let queries: Vec<Point> = to_embeddings(&queries)?
+            .into_iter()
+            .map(|vec| Point::Mem { vec: Box::new(vec) })
+            .collect();
+        let hnsw = self.hnsw()?;
+        py.allow_threads(|| {
+            queries
+                .iter()
+                .map(|query| search_with_ef(query, k, ef, hnsw).map(to_results))
+                .collect::<Result<_, _>>()
+        })
+        .map_err(error)
+    }
+
+    /// Write the index as the index of `commit`, where the server and
+    /// `load_index` will find it.
+    fn save(&self, py: Python, commit: &str) -> PyResult<()> {
+        let name = create_index_name(self.domain.name(), commit);
+        let hnsw = self.hnsw()?;
+        py.allow_threads(|| {
+            self.store.sync()?;
+            serialize_index(
+                self.directory.clone(),
+                &name,
+                hnsw.clone(),
+                self.store.cipher(),
+            )
+        })
+        .map_err(error)
+    }
+}
+
+#[pymodule]
+fn vectorlink(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Store>()?;
+    m.add_class::<Domain>()?;
+    m.add_class::<Index>()?;
+    m.add("VectorlinkError", py.get_type::<VectorlinkError>())?;
+    m.add("EMBEDDING_LENGTH", EMBEDDING_LENGTH)?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+import numpy as np
+import pytest
+
+import vectorlink
+
+
+def random_vectors(n, seed=42):
+    rng = np.random.default_rng(seed)
+    return rng.standard_normal((n, vectorlink.EMBEDDING_LENGTH)).astype(np.float32)
+
+
+def test_add_search_and_reload(tmp_path):
+    store = vectorlink.Store(str(tmp_path))
+    domain = store.domain("admin/papers")
+    index = domain.new_index(seed=42)
+    vectors = random_vectors(20)
+    ids = [f"Paper/{i}" for i in range(20)]
+    index.add(ids, vectors, metadata=[{"document": id} for id in ids])
+    assert len(index) == 20
+    assert len(domain) == 20
+
+    results = index.search(vectors[3], k=5)
+    assert results[0][0] == "Paper/3"
+    assert results[0][1] < 1e-6
+    batch = index.search_batch(vectors[:2], k=1)
+    assert [results[0][0] for results in batch] == ["Paper/0", "Paper/1"]
+
+    index.save("first")
+    loaded = store.domain("admin/papers").load_index("first")
+    assert len(loaded) == 20
+    assert loaded.search(vectors[3], k=1)[0][0] == "Paper/3"
+    assert store.domains() == ["admin/papers"]
+
+
+def test_refuse_bad_input(tmp_path):
+    index = vectorlink.Store(str(tmp_path)).domain("admin/papers").new_index()
+    with pytest.raises(ValueError):
+        index.add(["Paper/0"], random_vectors(2))
+    with pytest.raises(ValueError):
+        index.add(["Paper/0"], np.zeros((1, 3), dtype=np.float32))
+    with pytest.raises(ValueError):
+        index.add(["Paper/0"], random_vectors(1), metadata=[{}, {}])
+    # the index is still usable after refused input
+    index.add(["Paper/0"], random_vectors(1))
+    assert len(index) == 1
+
+
+def test_store_errors_are_exceptions(tmp_path):
+    domain = vectorlink.Store(str(tmp_path)).domain("admin/papers")
+    with pytest.raises(vectorlink.VectorlinkError):
+        domain.load_index("missing")